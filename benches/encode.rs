@@ -0,0 +1,66 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use redcon::Type;
+
+const FRAMES: usize = 1_000_000;
+
+/// Encodes a million `:12345\r\n` frames, as the elements of a single array so the per-call
+/// setup of the encoder doesn't hide the cost of the frames.
+fn integers(c: &mut Criterion) {
+    let value = Type::Array(vec![Type::Integer(12345); FRAMES]);
+    let mut buf = Vec::with_capacity(value.encoded_len());
+    let mut group = c.benchmark_group("encode");
+    group.throughput(Throughput::Elements(FRAMES as u64));
+    group.bench_function("integers", |b| {
+        b.iter(|| {
+            buf.clear();
+            black_box(&value).encode_to(&mut buf);
+            black_box(&buf);
+        })
+    });
+    group.finish();
+}
+
+/// Encodes a million short bulk strings, whose cost is mostly the length header.
+fn bulk_strings(c: &mut Criterion) {
+    let value = Type::Array(vec![Type::from("value"); FRAMES]);
+    let mut buf = Vec::with_capacity(value.encoded_len());
+    let mut group = c.benchmark_group("encode");
+    group.throughput(Throughput::Elements(FRAMES as u64));
+    group.bench_function("bulk_strings", |b| {
+        b.iter(|| {
+            buf.clear();
+            black_box(&value).encode_to(&mut buf);
+            black_box(&buf);
+        })
+    });
+    group.finish();
+}
+
+/// Encodes a 10k-element array mixing integers, bulk strings and nested arrays, as the
+/// replies of commands like `MGET` or `SCAN`.
+fn array_10k(c: &mut Criterion) {
+    const ELEMENTS: usize = 10_000;
+    let value = Type::Array(
+        (0..ELEMENTS as i64)
+            .map(|i| match i % 3 {
+                0 => Type::Integer(i),
+                1 => Type::from(format!("value:{}", i)),
+                _ => Type::Array(vec![Type::from("field"), Type::Integer(i), Type::Null]),
+            })
+            .collect(),
+    );
+    let mut buf = Vec::with_capacity(value.encoded_len());
+    let mut group = c.benchmark_group("encode");
+    group.throughput(Throughput::Elements(ELEMENTS as u64));
+    group.bench_function("array_10k", |b| {
+        b.iter(|| {
+            buf.clear();
+            black_box(&value).encode_to(&mut buf);
+            black_box(&buf);
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, integers, bulk_strings, array_10k);
+criterion_main!(benches);
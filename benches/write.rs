@@ -0,0 +1,103 @@
+use std::io::{self, IoSlice};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use redcon::Type;
+use tokio::io::{AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::runtime::Runtime;
+
+/// Discards what is written to it, like a socket that never blocks, so the cost measured is the
+/// one of getting the bytes to the destination.
+struct Discard {
+    vectored: bool,
+}
+
+impl AsyncWrite for Discard {
+    fn poll_write(self: Pin<&mut Self>, _: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(black_box(buf).len()))
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        _: &mut Context,
+        bufs: &[IoSlice],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(black_box(bufs).iter().map(|buf| buf.len()).sum()))
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.vectored
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+fn runtime() -> Runtime {
+    tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap()
+}
+
+/// Writes an array of a thousand 1 KB bulk strings through a buffer like the one of a
+/// connection, with the payloads written straight from the value by vectored writes, and
+/// copied into the buffer when the destination doesn't support them.
+fn bulk_array(c: &mut Criterion) {
+    const ELEMENTS: usize = 1_000;
+    let rt = runtime();
+    let value = Type::Array(vec![Type::BulkString(vec![b'x'; 1024]); ELEMENTS]);
+    let mut group = c.benchmark_group("write");
+    group.throughput(Throughput::Bytes(value.encoded_len() as u64));
+    for (name, vectored) in [("bulk_array_vectored", true), ("bulk_array_copied", false)] {
+        let mut dst = BufWriter::new(Discard { vectored });
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                rt.block_on(async {
+                    black_box(&value).write_to(&mut dst).await.unwrap();
+                    dst.flush().await.unwrap();
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Writes a thousand small replies into the buffer of a connection, encoded straight into it
+/// as `Conn` does, and with [`Type::write`] buffering and flushing each of them on its own.
+fn small_replies(c: &mut Criterion) {
+    const REPLIES: usize = 1_000;
+    let rt = runtime();
+    let replies = vec![Type::SimpleString("OK".to_string()); REPLIES];
+    let mut dst = BufWriter::new(Discard { vectored: true });
+    let mut group = c.benchmark_group("write");
+    group.throughput(Throughput::Elements(REPLIES as u64));
+    group.bench_function("small_replies_write_to", |b| {
+        b.iter(|| {
+            rt.block_on(async {
+                for reply in black_box(&replies) {
+                    reply.write_to(&mut dst).await.unwrap();
+                }
+                dst.flush().await.unwrap();
+            })
+        })
+    });
+    group.bench_function("small_replies_write", |b| {
+        b.iter(|| {
+            rt.block_on(async {
+                for reply in black_box(&replies) {
+                    reply.write(&mut dst).await.unwrap();
+                }
+            })
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bulk_array, small_replies);
+criterion_main!(benches);
@@ -0,0 +1,20 @@
+use std::time::Duration;
+
+use redcon::service::RespService;
+use redcon::{listen, Command, Conn};
+use tower::{service_fn, BoxError, ServiceBuilder};
+
+#[tokio::main]
+async fn main() {
+    let echo = ServiceBuilder::new()
+        .concurrency_limit(1024)
+        .timeout(Duration::from_secs(1))
+        .service(service_fn(|(conn, cmd): (Conn, Command)| async move {
+            conn.write_value(cmd).await?;
+            Ok::<_, BoxError>(())
+        }));
+
+    listen("127.0.0.1:6379", RespService::new(echo))
+        .await
+        .expect("could not listen");
+}
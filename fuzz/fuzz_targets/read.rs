@@ -0,0 +1,42 @@
+//! Feeds raw bytes to the reader, which must fail cleanly on anything invalid.
+
+#![no_main]
+
+use std::alloc::{GlobalAlloc, Layout, System};
+
+use libfuzzer_sys::fuzz_target;
+use redcon::{ReadOptions, RespReader};
+
+const MAX_BULK_LEN: usize = 1024 * 1024;
+
+/// Aborts on allocations larger than what the read options allow, which would mean a length
+/// read from the input was trusted.
+struct LimitedAllocator;
+
+unsafe impl GlobalAlloc for LimitedAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if layout.size() > 4 * MAX_BULK_LEN {
+            std::process::abort();
+        }
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: LimitedAllocator = LimitedAllocator;
+
+fuzz_target!(|data: &[u8]| {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let options = ReadOptions::new().max_bulk_len(MAX_BULK_LEN);
+        let mut reader = RespReader::with_options(data, options);
+        // Every successful read consumes input, so this ends.
+        while reader.read().await.is_ok() {}
+    });
+});
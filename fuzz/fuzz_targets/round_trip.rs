@@ -0,0 +1,17 @@
+//! Writes arbitrary values and checks they are read back the same.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use redcon::Type;
+
+fuzz_target!(|ty: Type| {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let mut buf = Vec::new();
+        ty.write(&mut buf).await.unwrap();
+        assert_eq!(Type::read(&mut buf.as_slice()).await.unwrap(), ty);
+    });
+});
@@ -1,11 +1,14 @@
-use redcon::{listen, Command, Conn, Type};
+use redcon::{listen, Command, Conn};
 
 #[tokio::main]
 async fn main() {
     listen("127.0.0.1:6379", |conn: Conn, cmd: Command| async move {
-        conn.write_array(cmd.into_iter().map(Type::BulkString).collect())
-            .await
-            .unwrap();
+        // Shortcuts like `write_pong` write constant replies without allocating.
+        if cmd.len() == 1 && cmd.is("ping") {
+            conn.write_pong().await.unwrap();
+            return;
+        }
+        conn.write_value(cmd).await.unwrap();
     })
     .await
     .expect("could not listen");
@@ -0,0 +1,26 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use redcon::{listen, Command, Conn, Handler, Type};
+
+struct Echo {
+    commands: AtomicU64,
+}
+
+impl Handler for Echo {
+    async fn call(&self, conn: Conn, cmd: Command) {
+        let n = self.commands.fetch_add(1, Ordering::Relaxed) + 1;
+        let mut reply = vec![Type::Integer(n as i64)];
+        reply.extend(cmd.into_iter().map(Type::BulkString));
+        conn.write_array(reply).await.unwrap();
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let echo = Echo {
+        commands: AtomicU64::new(0),
+    };
+    listen("127.0.0.1:6379", echo)
+        .await
+        .expect("could not listen");
+}
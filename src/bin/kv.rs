@@ -0,0 +1,235 @@
+//! An in-memory key-value server supporting a few string commands, with binary keys and
+//! values.
+//!
+//! Listens on `127.0.0.1:6379` by default, or on the address given as the first argument.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::env;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use redcon::{Command, CommandError, Conn, Handler, Router, Server, Type};
+
+struct Entry {
+    value: Vec<u8>,
+    expires_at: Option<Instant>,
+}
+
+impl Entry {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+}
+
+/// Keys are expired lazily, once they are accessed after their deadline.
+#[derive(Default)]
+struct Kv {
+    entries: RwLock<HashMap<Vec<u8>, Entry>>,
+}
+
+type Reply = Result<Type, CommandError>;
+
+impl Kv {
+    fn ping(&self, cmd: &Command) -> Reply {
+        match cmd.len() {
+            1 => Ok(Type::SimpleString("PONG".to_string())),
+            2 => Ok(Type::BulkString(cmd[1].clone())),
+            _ => Err(cmd.wrong_arity()),
+        }
+    }
+
+    fn get(&self, cmd: &Command) -> Reply {
+        cmd.require_exact_args(2)?;
+        let entries = self.entries.read().unwrap();
+        let value = entries
+            .get(&cmd[1])
+            .filter(|entry| !entry.is_expired(Instant::now()))
+            .map(|entry| Type::BulkString(entry.value.clone()));
+        Ok(value.into())
+    }
+
+    /// `SET key value [EX seconds]`
+    fn set(&self, cmd: &Command) -> Reply {
+        cmd.require_min_args(3)?;
+        let mut opts = cmd.opts(3);
+        let ex = opts.value_i64("EX")?;
+        opts.finish()?;
+
+        let expires_at = match ex {
+            Some(secs) => {
+                // Deadlines too far away to be represented are rejected like non-positive ones.
+                let deadline = u64::try_from(secs)
+                    .ok()
+                    .filter(|&secs| secs > 0)
+                    .and_then(|secs| Instant::now().checked_add(Duration::from_secs(secs)));
+                if deadline.is_none() {
+                    return Err(CommandError::Custom(
+                        "ERR invalid expire time in 'set' command".to_string(),
+                    ));
+                }
+                deadline
+            }
+            None => None,
+        };
+        let entry = Entry {
+            value: cmd[2].clone(),
+            expires_at,
+        };
+        self.entries.write().unwrap().insert(cmd[1].clone(), entry);
+        Ok(Type::SimpleString("OK".to_string()))
+    }
+
+    fn del(&self, cmd: &Command) -> Reply {
+        cmd.require_min_args(2)?;
+        let now = Instant::now();
+        let mut entries = self.entries.write().unwrap();
+        let deleted = cmd[1..]
+            .iter()
+            .filter_map(|key| entries.remove(key))
+            .filter(|entry| !entry.is_expired(now))
+            .count();
+        Ok(Type::Integer(deleted as i64))
+    }
+
+    fn exists(&self, cmd: &Command) -> Reply {
+        cmd.require_min_args(2)?;
+        let now = Instant::now();
+        let entries = self.entries.read().unwrap();
+        let found = cmd[1..]
+            .iter()
+            .filter(|key| {
+                entries
+                    .get(*key)
+                    .is_some_and(|entry| !entry.is_expired(now))
+            })
+            .count();
+        Ok(Type::Integer(found as i64))
+    }
+
+    fn keys(&self, cmd: &Command) -> Reply {
+        cmd.require_exact_args(2)?;
+        let now = Instant::now();
+        // Matched under the read lock, so the other commands don't wait for a long scan.
+        let entries = self.entries.read().unwrap();
+        let mut expired = false;
+        let mut keys: Vec<Vec<u8>> = entries
+            .iter()
+            .filter(|(key, entry)| {
+                let live = !entry.is_expired(now);
+                expired |= !live;
+                live && glob_match(&cmd[1], key)
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+        drop(entries);
+        if expired {
+            let mut entries = self.entries.write().unwrap();
+            entries.retain(|_, entry| !entry.is_expired(now));
+        }
+        keys.sort();
+        Ok(Type::Array(
+            keys.into_iter().map(Type::BulkString).collect(),
+        ))
+    }
+}
+
+/// Matches `*` against any sequence of bytes and `?` against a single one.
+///
+/// Only the last `*` is backtracked to, as whatever an earlier one matched more can be matched
+/// by the last one instead, so matching takes at most `pattern.len() * s.len()` steps.
+fn glob_match(pattern: &[u8], s: &[u8]) -> bool {
+    let (mut p, mut i) = (0, 0);
+    // The position after the last `*` seen, and where in `s` its match ends for now.
+    let mut star = None;
+    while i < s.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                p += 1;
+                star = Some((p, i));
+            }
+            Some(&c) if c == b'?' || c == s[i] => {
+                p += 1;
+                i += 1;
+            }
+            _ => match star {
+                // The `*` takes one more byte.
+                Some((after, end)) => {
+                    p = after;
+                    i = end + 1;
+                    star = Some((after, end + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Replies with what `f` returns for each command.
+fn route(kv: &Arc<Kv>, f: fn(&Kv, &Command) -> Reply) -> impl Handler {
+    let kv = Arc::clone(kv);
+    move |conn: Conn, cmd: Command| {
+        let reply = f(&kv, &cmd);
+        async move {
+            if let Err(err) = conn.write_value(reply).await {
+                eprintln!("could not write to client: {}", err);
+            }
+        }
+    }
+}
+
+fn router() -> Router {
+    let kv = Arc::new(Kv::default());
+    let mut router = Router::new();
+    router.command("PING").handler(route(&kv, Kv::ping));
+    router.command("GET").handler(route(&kv, Kv::get));
+    router.command("SET").handler(route(&kv, Kv::set));
+    router.command("DEL").handler(route(&kv, Kv::del));
+    router.command("EXISTS").handler(route(&kv, Kv::exists));
+    router.command("KEYS").handler(route(&kv, Kv::keys));
+    router
+}
+
+#[tokio::main]
+async fn main() {
+    let addr = env::args()
+        .nth(1)
+        .unwrap_or_else(|| "127.0.0.1:6379".to_string());
+    Server::builder()
+        .bind(addr)
+        .run(router())
+        .await
+        .expect("could not listen");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob() {
+        for (pattern, s, matches) in [
+            ("*", "", true),
+            ("user:*", "user:1", true),
+            ("?eam:?", "team:1", true),
+            ("a*b*c", "aXbYc", true),
+            ("a*b*c", "aXbY", false),
+            ("*a", "ba", true),
+            ("a?", "a", false),
+            ("**", "x", true),
+        ] {
+            assert_eq!(
+                glob_match(pattern.as_bytes(), s.as_bytes()),
+                matches,
+                "{} {}",
+                pattern,
+                s
+            );
+        }
+
+        // Used to backtrack exponentially.
+        let pattern = "*a".repeat(20) + "*b";
+        assert!(!glob_match(pattern.as_bytes(), &[b'a'; 40]));
+    }
+}
@@ -0,0 +1,686 @@
+//! A thread-per-connection server on `std::net`, for applications without an async runtime.
+//!
+//! Values are read and written by the same parser and encoder as the async server, driven by
+//! blocking IO so no runtime is needed.
+//!
+//! The crate still depends on tokio with this feature though: the parser and encoder are
+//! written against its IO traits, which blocking IO is adapted to, and the async server is
+//! built as well. Only the runtime goes unused.
+//!
+//! ```no_run
+//! use redcon::blocking::{self, BlockingConn};
+//! use redcon::Type;
+//!
+//! blocking::listen("127.0.0.1:6379", |conn: &BlockingConn, _cmd: Type| {
+//!     conn.write_pong().unwrap();
+//! })
+//! .unwrap();
+//! ```
+
+use std::io::{self, BufRead, BufReader, BufWriter, IoSlice, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll};
+use std::thread;
+
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::error_kind::{err_message, error_message, ErrorKind};
+use crate::resp::{
+    block_on, describe, Error, Protocol, ReadFailure, ReadOptions, RespReader, Type,
+};
+
+const DEFAULT_MAX_THREADS: usize = 128;
+
+const DEFAULT_BUFFER_SIZE: usize = 8 * 1024;
+
+impl Type {
+    /// Reads a value from a blocking reader.
+    pub fn read_blocking(src: &mut (impl BufRead + Send)) -> Result<Type, Error> {
+        Self::read_blocking_with(src, ReadOptions::default())
+    }
+
+    /// Reads a value from a blocking reader with the given options.
+    pub fn read_blocking_with(
+        src: &mut (impl BufRead + Send),
+        options: ReadOptions,
+    ) -> Result<Type, Error> {
+        block_on(RespReader::with_options(Blocking(src), options).read())
+    }
+
+    /// Writes the value to a blocking writer without flushing it, returns the number of bytes
+    /// written. Meant for buffered writers like [`BufWriter`], see [`Type::write_to`].
+    pub fn write_blocking(&self, dst: &mut (impl Write + Send)) -> Result<usize, Error> {
+        block_on(self.write_to(&mut Blocking(dst)))
+    }
+}
+
+/// Adapts blocking IO to the async traits, every operation completes right away.
+struct Blocking<T>(T);
+
+impl<R: Read + Unpin> AsyncRead for Blocking<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let n = self.0.read(buf.initialize_unfilled())?;
+        buf.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<R: BufRead + Unpin> AsyncBufRead for Blocking<R> {
+    fn poll_fill_buf(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        Poll::Ready(self.get_mut().0.fill_buf())
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        self.0.consume(amt)
+    }
+}
+
+impl<W: Write + Unpin> AsyncWrite for Blocking<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(self.0.write(buf))
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(self.0.write_vectored(bufs))
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(self.0.flush())
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+/// A connection of the blocking server, replies are flushed once the handler returns.
+#[derive(Debug)]
+pub struct BlockingConn {
+    writer: Mutex<BufWriter<TcpStream>>,
+    peer_addr: Option<SocketAddr>,
+    resp3: AtomicBool,
+}
+
+impl BlockingConn {
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    /// Returns the protocol of the connection, see [`Conn::protocol`](crate::Conn::protocol).
+    pub fn protocol(&self) -> Protocol {
+        if self.resp3.load(Ordering::Relaxed) {
+            Protocol::Resp3
+        } else {
+            Protocol::Resp2
+        }
+    }
+
+    /// Sets the protocol used for the following replies, e.g. after handling `HELLO 3`.
+    pub fn set_protocol(&self, protocol: Protocol) {
+        self.resp3
+            .store(protocol == Protocol::Resp3, Ordering::Relaxed);
+    }
+
+    pub fn write_simple_string(&self, str: String) -> Result<()> {
+        self.write_type(&Type::SimpleString(str))
+    }
+
+    pub fn write_error(&self, err: String) -> Result<()> {
+        self.write_type(&Type::Error(err))
+    }
+
+    /// See [`Conn::write_error_kind`](crate::Conn::write_error_kind).
+    pub fn write_error_kind(&self, kind: ErrorKind, message: &str) -> Result<()> {
+        self.write_type(&Type::Error(error_message(&kind, message)?))
+    }
+
+    /// See [`Conn::write_err`](crate::Conn::write_err).
+    pub fn write_err(&self, message: &str) -> Result<()> {
+        self.write_type(&Type::Error(err_message(message)?))
+    }
+
+    /// See [`Conn::write_moved`](crate::Conn::write_moved).
+    pub fn write_moved(&self, slot: u16, addr: &str) -> Result<()> {
+        self.write_error_kind(ErrorKind::Moved, &format!("{} {}", slot, addr))
+    }
+
+    /// See [`Conn::write_ask`](crate::Conn::write_ask).
+    pub fn write_ask(&self, slot: u16, addr: &str) -> Result<()> {
+        self.write_error_kind(ErrorKind::Ask, &format!("{} {}", slot, addr))
+    }
+
+    pub fn write_integer(&self, num: i64) -> Result<()> {
+        self.write_type(&Type::Integer(num))
+    }
+
+    /// Writes a boolean, which RESP2 clients get as `1` or `0`.
+    pub fn write_bool(&self, b: bool) -> Result<()> {
+        self.write_type(&Type::Boolean(b))
+    }
+
+    /// See [`Conn::write_verbatim`](crate::Conn::write_verbatim).
+    pub fn write_verbatim(&self, format: &str, text: String) -> Result<()> {
+        if format.len() != 3 {
+            bail!("verbatim string format must be three bytes: {:?}", format);
+        }
+        self.write_type(&Type::Verbatim {
+            format: format.to_string(),
+            text,
+        })
+    }
+
+    pub fn write_bulk_string(&self, str: String) -> Result<()> {
+        self.write_type(&Type::BulkString(str.into_bytes()))
+    }
+
+    pub fn write_bulk_bytes(&self, bytes: &[u8]) -> Result<()> {
+        self.write_type(&Type::BulkString(bytes.to_vec()))
+    }
+
+    pub fn write_bytes(&self, bytes: Bytes) -> Result<()> {
+        self.write_type(&Type::BulkString(bytes.into()))
+    }
+
+    pub fn write_ok(&self) -> Result<()> {
+        self.write_static(b"+OK\r\n")
+    }
+
+    pub fn write_pong(&self) -> Result<()> {
+        self.write_static(b"+PONG\r\n")
+    }
+
+    /// Writes `+QUEUED`, the reply to commands queued in a transaction.
+    pub fn write_queued(&self) -> Result<()> {
+        self.write_static(b"+QUEUED\r\n")
+    }
+
+    /// Writes the error Redis replies with when a command is used on a key of another type.
+    pub fn write_wrongtype(&self) -> Result<()> {
+        self.write_static(b"-WRONGTYPE Operation against a key holding the wrong kind of value\r\n")
+    }
+
+    /// Writes `:0`.
+    pub fn write_zero(&self) -> Result<()> {
+        self.write_static(b":0\r\n")
+    }
+
+    /// Writes `:1`.
+    pub fn write_one(&self) -> Result<()> {
+        self.write_static(b":1\r\n")
+    }
+
+    pub fn write_null(&self) -> Result<()> {
+        self.write_type(&Type::Null)
+    }
+
+    /// Same as [`BlockingConn::write_null`].
+    pub fn write_nil(&self) -> Result<()> {
+        self.write_null()
+    }
+
+    /// Writes an array of the values, see [`Conn::write_array`](crate::Conn::write_array).
+    pub fn write_array<I>(&self, arr: I) -> Result<()>
+    where
+        I: IntoIterator,
+        I::Item: Into<Type>,
+    {
+        self.write_type(&Type::Array(arr.into_iter().map(Into::into).collect()))
+    }
+
+    /// Writes the pairs as a map on RESP3 connections, and as a flat array of alternating keys
+    /// and values on RESP2 ones.
+    pub fn write_map<I, K, V>(&self, pairs: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<Type>,
+        V: Into<Type>,
+    {
+        let pairs = pairs
+            .into_iter()
+            .map(|(key, value)| (key.into(), value.into()));
+        self.write_type(&Type::Map(pairs.collect()))
+    }
+
+    /// Writes an out-of-band message as a push on RESP3 connections and as an array on RESP2
+    /// ones, see [`Conn::write_push`](crate::Conn::write_push).
+    pub fn write_push<I>(&self, elements: I) -> Result<()>
+    where
+        I: IntoIterator,
+        I::Item: Into<Type>,
+    {
+        self.write_type(&Type::Push(elements.into_iter().map(Into::into).collect()))
+    }
+
+    /// Writes the value with the attributes attached on RESP3 connections, and just the value
+    /// on RESP2 ones since they don't support attributes.
+    pub fn write_with_attrs<I, K, V>(&self, attrs: I, value: impl Into<Type>) -> Result<()>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<Type>,
+        V: Into<Type>,
+    {
+        let attrs = attrs
+            .into_iter()
+            .map(|(key, value)| (key.into(), value.into()));
+        self.write_type(&Type::Attribute {
+            attrs: attrs.collect(),
+            value: Box::new(value.into()),
+        })
+    }
+
+    /// Writes the value as is, e.g. the `Result<T, CommandError>` returned by a command.
+    pub fn write_value(&self, value: impl Into<Type>) -> Result<()> {
+        self.write_type(&value.into())
+    }
+
+    /// Writes the value without taking it, converted for RESP2 connections like
+    /// [`Conn::write_type`](crate::Conn::write_type) does.
+    pub fn write_type(&self, value: &Type) -> Result<()> {
+        let protocol = self.protocol();
+        let converted;
+        let value = match protocol {
+            Protocol::Resp2 if !value.is_resp2() => {
+                converted = value.clone().to_resp2();
+                &converted
+            }
+            _ => value,
+        };
+        let mut writer = self.writer.lock().unwrap();
+        block_on(value.write_to_for(&mut Blocking(&mut *writer), protocol))?;
+        Ok(())
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.writer.lock().unwrap().flush()?;
+        Ok(())
+    }
+
+    fn write_static(&self, reply: &'static [u8]) -> Result<()> {
+        self.writer.lock().unwrap().write_all(reply)?;
+        Ok(())
+    }
+}
+
+type BlockingHandler = Arc<dyn Fn(&BlockingConn, Type) + Send + Sync>;
+
+/// Builds a blocking server, see [`Server::builder`].
+pub struct Builder {
+    addr: Option<String>,
+    max_threads: usize,
+    read_options: ReadOptions,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self {
+            addr: None,
+            max_threads: DEFAULT_MAX_THREADS,
+            read_options: ReadOptions::default(),
+        }
+    }
+}
+
+impl Builder {
+    /// Sets the address to listen on.
+    pub fn bind(mut self, addr: impl Into<String>) -> Self {
+        self.addr = Some(addr.into());
+        self
+    }
+
+    /// Sets the maximum number of connections served at once, each on its own thread,
+    /// defaults to 128.
+    ///
+    /// Once reached, new connections wait to be accepted until another one is closed.
+    pub fn max_threads(mut self, n: usize) -> Self {
+        assert!(n > 0, "at least one thread is required");
+        self.max_threads = n;
+        self
+    }
+
+    /// Sets the options used for reading commands from the connections.
+    pub fn read_options(mut self, options: ReadOptions) -> Self {
+        self.read_options = options;
+        self
+    }
+
+    /// Starts accepting connections on a background thread, which runs as long as the process.
+    pub fn serve<H>(self, handler: H) -> Result<Server>
+    where
+        H: Fn(&BlockingConn, Type) + Send + Sync + 'static,
+    {
+        let listener = self.listen()?;
+        let local_addr = listener.local_addr()?;
+        let handler: BlockingHandler = Arc::new(handler);
+        let max_threads = self.max_threads;
+        let read_options = self.read_options;
+        thread::spawn(move || {
+            if let Err(err) = accept(listener, handler, max_threads, read_options) {
+                eprintln!("could not accept connections: {}", err);
+            }
+        });
+        Ok(Server { local_addr })
+    }
+
+    /// Accepts connections on the current thread, returns only if accepting fails.
+    pub fn run<H>(self, handler: H) -> Result<()>
+    where
+        H: Fn(&BlockingConn, Type) + Send + Sync + 'static,
+    {
+        let listener = self.listen()?;
+        accept(
+            listener,
+            Arc::new(handler),
+            self.max_threads,
+            self.read_options,
+        )
+    }
+
+    fn listen(&self) -> Result<TcpListener> {
+        let addr = self
+            .addr
+            .as_deref()
+            .ok_or_else(|| anyhow::anyhow!("no address to listen on"))?;
+        Ok(TcpListener::bind(addr)?)
+    }
+}
+
+/// A blocking server started with [`Builder::serve`].
+#[derive(Debug)]
+pub struct Server {
+    local_addr: SocketAddr,
+}
+
+impl Server {
+    pub fn builder() -> Builder {
+        Builder::default()
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+/// Serves the handler on the current thread, see [`Builder::run`].
+pub fn listen<H>(addr: &str, handler: H) -> Result<()>
+where
+    H: Fn(&BlockingConn, Type) + Send + Sync + 'static,
+{
+    Server::builder().bind(addr).run(handler)
+}
+
+/// Number of connection threads running, shared with the accept loop waiting for a free one.
+#[derive(Default)]
+struct Threads {
+    running: Mutex<usize>,
+    finished: Condvar,
+}
+
+/// Marks a connection thread as finished once dropped, even if the handler panicked.
+struct ThreadGuard(Arc<Threads>);
+
+impl Drop for ThreadGuard {
+    fn drop(&mut self) {
+        *self.0.running.lock().unwrap() -= 1;
+        self.0.finished.notify_one();
+    }
+}
+
+fn accept(
+    listener: TcpListener,
+    handler: BlockingHandler,
+    max_threads: usize,
+    read_options: ReadOptions,
+) -> Result<()> {
+    let threads = Arc::new(Threads::default());
+    loop {
+        {
+            let mut running = threads.running.lock().unwrap();
+            while *running >= max_threads {
+                running = threads.finished.wait(running).unwrap();
+            }
+            *running += 1;
+        }
+        let guard = ThreadGuard(Arc::clone(&threads));
+        let (stream, peer_addr) = listener.accept()?;
+        let handler = Arc::clone(&handler);
+        let read_options = read_options.clone();
+        thread::spawn(move || {
+            let _guard = guard;
+            if let Err(err) = serve_conn(stream, peer_addr, &*handler, read_options) {
+                eprintln!("could not serve {}: {}", peer_addr, err);
+            }
+        });
+    }
+}
+
+fn serve_conn(
+    stream: TcpStream,
+    peer_addr: SocketAddr,
+    handler: &(dyn Fn(&BlockingConn, Type) + Send + Sync),
+    read_options: ReadOptions,
+) -> Result<()> {
+    let reader = BufReader::with_capacity(DEFAULT_BUFFER_SIZE, stream.try_clone()?);
+    let mut reader = RespReader::with_options(Blocking(reader), read_options.for_commands());
+    let conn = BlockingConn {
+        writer: Mutex::new(BufWriter::with_capacity(DEFAULT_BUFFER_SIZE, stream)),
+        peer_addr: Some(peer_addr),
+        resp3: AtomicBool::new(false),
+    };
+
+    loop {
+        let ty = match block_on(reader.read_frame()) {
+            Ok(it) => it,
+            Err(ReadFailure::Closed) => return Ok(()),
+            // Whatever follows the garbage can't be trusted to be a frame, like in Redis.
+            Err(ReadFailure::Protocol(err, pos)) => {
+                let reason = describe(&err, pos.as_ref());
+                eprintln!(
+                    "closing connection {}: protocol error: {}",
+                    peer_addr, reason
+                );
+                conn.write_error(format!("ERR Protocol error: {}", reason))?;
+                conn.flush()?;
+                return Ok(());
+            }
+            Err(ReadFailure::Io(err)) => {
+                eprintln!("closing connection {}: could not read: {}", peer_addr, err);
+                return Ok(());
+            }
+            // The whole frame is consumed, the connection can go on with the next one.
+            Err(ReadFailure::Rejected(err, pos)) => {
+                let reason = describe(&err, pos.as_ref());
+                eprintln!("rejected command from {}: {}", peer_addr, reason);
+                conn.write_error(format!("ERR {}", err))?;
+                conn.flush()?;
+                continue;
+            }
+        };
+        handler(&conn, ty);
+        conn.flush()?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connect(server: &Server) -> Result<(BufReader<TcpStream>, BufWriter<TcpStream>)> {
+        let stream = TcpStream::connect(server.local_addr())?;
+        Ok((BufReader::new(stream.try_clone()?), BufWriter::new(stream)))
+    }
+
+    fn send(client: &mut BufWriter<TcpStream>, args: &[&str]) -> Result<()> {
+        Type::Array(args.iter().map(|&it| Type::from(it)).collect()).write_blocking(client)?;
+        client.flush()?;
+        Ok(())
+    }
+
+    #[test]
+    fn read_and_write() -> Result<()> {
+        let ty = Type::Array(vec![
+            Type::SimpleString("hello world".to_string()),
+            Type::from("x".repeat(1024)),
+            Type::Integer(-1),
+            Type::Null,
+        ]);
+        let mut buf = Vec::new();
+        let n = ty.write_blocking(&mut buf)?;
+        assert_eq!(n, buf.len());
+        assert_eq!(buf, ty.to_bytes());
+        assert_eq!(Type::read_blocking(&mut buf.as_slice())?, ty);
+
+        let options = ReadOptions::new().max_depth(1);
+        let err = Type::read_blocking_with(&mut &b"*1\r\n*0\r\n"[..], options).unwrap_err();
+        assert!(matches!(err, Error::NestingTooDeep));
+
+        Ok(())
+    }
+
+    #[test]
+    fn accept_connections() -> Result<()> {
+        let server =
+            Server::builder()
+                .bind("127.0.0.1:0")
+                .serve(|conn: &BlockingConn, cmd: Type| {
+                    assert_eq!(cmd, Type::Array(vec![Type::from("ping")]));
+                    conn.write_pong().unwrap();
+                })?;
+
+        let (mut read, mut write) = connect(&server)?;
+        send(&mut write, &["ping"])?;
+        assert_eq!(
+            Type::read_blocking(&mut read)?,
+            Type::SimpleString("PONG".to_string())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn echo() -> Result<()> {
+        let server =
+            Server::builder()
+                .bind("127.0.0.1:0")
+                .serve(|conn: &BlockingConn, cmd: Type| {
+                    conn.write_type(&cmd).unwrap();
+                })?;
+
+        let (mut read, mut write) = connect(&server)?;
+        for args in [&["echo", "hello"][..], &["set", "key", "value"]] {
+            send(&mut write, args)?;
+            assert_eq!(Type::read_blocking(&mut read)?, Type::from(args.to_vec()));
+        }
+
+        write.write_all(b"\"oops\r\n")?;
+        write.flush()?;
+        assert_eq!(
+            Type::read_blocking(&mut read)?,
+            Type::Error(
+                r#"ERR Protocol error: unbalanced quotes in inline command at byte 58 near "\"oops\r\n""#
+                    .to_string()
+            )
+        );
+        let err = Type::read_blocking(&mut read).unwrap_err();
+        assert!(matches!(err, Error::UnexpectedEof));
+
+        Ok(())
+    }
+
+    #[test]
+    fn writers() -> Result<()> {
+        let server =
+            Server::builder()
+                .bind("127.0.0.1:0")
+                .serve(|conn: &BlockingConn, cmd: Type| {
+                    if cmd == Type::from(vec!["resp3"]) {
+                        conn.set_protocol(Protocol::Resp3);
+                    }
+                    conn.write_push(vec!["message", "hi"]).unwrap();
+                    conn.write_map(vec![("a", 1)]).unwrap();
+                    conn.write_bool(true).unwrap();
+                    conn.write_err("not found").unwrap();
+                    conn.write_moved(3999, "127.0.0.1:6381").unwrap();
+                    conn.write_null().unwrap();
+                })?;
+
+        let (mut read, mut write) = connect(&server)?;
+        send(&mut write, &["resp2"])?;
+        let expected = vec![
+            Type::from(vec!["message", "hi"]),
+            Type::Array(vec![Type::from("a"), Type::Integer(1)]),
+            Type::Integer(1),
+            Type::Error("ERR not found".to_string()),
+            Type::Error("MOVED 3999 127.0.0.1:6381".to_string()),
+            Type::Null,
+        ];
+        for ty in expected {
+            assert_eq!(Type::read_blocking(&mut read)?, ty);
+        }
+
+        send(&mut write, &["resp3"])?;
+        let expected = vec![
+            Type::Push(vec![Type::from("message"), Type::from("hi")]),
+            Type::Map(vec![(Type::from("a"), Type::Integer(1))]),
+            Type::Boolean(true),
+            Type::Error("ERR not found".to_string()),
+            Type::Error("MOVED 3999 127.0.0.1:6381".to_string()),
+            Type::Null,
+        ];
+        for ty in expected {
+            assert_eq!(Type::read_blocking(&mut read)?, ty);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn max_threads() -> Result<()> {
+        let server = Server::builder().bind("127.0.0.1:0").max_threads(1).serve(
+            |conn: &BlockingConn, _cmd: Type| {
+                conn.write_ok().unwrap();
+            },
+        )?;
+
+        let (mut first_read, mut first) = connect(&server)?;
+        send(&mut first, &["ping"])?;
+        Type::read_blocking(&mut first_read)?;
+
+        // Only served once the first connection is closed.
+        let (mut second_read, mut second) = connect(&server)?;
+        send(&mut second, &["ping"])?;
+        second_read
+            .get_ref()
+            .set_read_timeout(Some(std::time::Duration::from_millis(100)))?;
+        assert!(Type::read_blocking(&mut second_read).is_err());
+
+        drop((first_read, first));
+        second_read.get_ref().set_read_timeout(None)?;
+        assert_eq!(
+            Type::read_blocking(&mut second_read)?,
+            Type::SimpleString("OK".to_string())
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,270 @@
+use std::ops::Range;
+
+use anyhow::Result;
+use bytes::{Bytes, BytesMut};
+
+use crate::resp::{
+    block_on, buffered_frame_len, parse_integer, parse_length, Error, ReadOptions, RespReader,
+    Type, MAX_PREALLOCATED_LEN,
+};
+
+/// A value decoded by [`BytesType::decode`], whose strings are slices of the buffer it was
+/// decoded from rather than copies, e.g. for proxies and pipelined workloads where copying
+/// every argument out of the read buffer adds up.
+///
+/// Commands and most replies are made of the values having a variant of their own. The others
+/// are decoded into a [`Type`] as usual.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BytesType {
+    /// Valid UTF-8, without line breaks.
+    SimpleString(Bytes),
+    /// Valid UTF-8, without line breaks.
+    Error(Bytes),
+    Integer(i64),
+    /// A bulk string, whether valid UTF-8 or not.
+    BulkString(Bytes),
+    Null,
+    Array(Vec<BytesType>),
+    /// Any other value, e.g. a RESP3 map.
+    Other(Type),
+}
+
+impl BytesType {
+    /// Decodes the frame at the start of the buffer and removes it from the buffer, or returns
+    /// `None` until the whole frame is buffered.
+    ///
+    /// The frame is split off the buffer without copying it, its strings point into it. Frames
+    /// holding values without a variant of their own, inline commands, or anything the options
+    /// have a say in, e.g. a bulk string over [`ReadOptions::max_value_len`], are read by a
+    /// [`RespReader`] instead, failing the same way. A frame failing with
+    /// [`Error::ValueTooLarge`] is removed from the buffer, the next one can still be decoded.
+    pub fn decode(buf: &mut BytesMut, options: &ReadOptions) -> Result<Option<BytesType>, Error> {
+        let len = match buffered_frame_len(buf, options) {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+        let frame = buf.split_to(len).freeze();
+        if let Some(value) = parse(&frame, options) {
+            return Ok(Some(value));
+        }
+
+        let mut reader = RespReader::with_options(&frame[..], options.clone());
+        match block_on(reader.read()) {
+            Ok(ty) => Ok(Some(ty.into())),
+            Err(Error::ValueTooLarge) => Err(Error::ValueTooLarge),
+            // Left in the buffer, like the frames the reader fails on.
+            Err(err) => {
+                let mut whole = BytesMut::with_capacity(frame.len() + buf.len());
+                whole.extend_from_slice(&frame);
+                whole.extend_from_slice(buf);
+                *buf = whole;
+                Err(err)
+            }
+        }
+    }
+
+    /// Returns the payload of a [`BytesType::BulkString`].
+    #[inline]
+    pub fn as_bulk(&self) -> Option<&Bytes> {
+        match self {
+            BytesType::BulkString(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+}
+
+/// Parses a buffered frame made of values having a variant of their own, `None` if it holds
+/// anything else or anything the reader could handle differently, e.g. a bare `\n` line ending.
+fn parse(frame: &Bytes, options: &ReadOptions) -> Option<BytesType> {
+    let mut pos = 0;
+    // The elements read so far and the number left of each array being parsed.
+    let mut open: Vec<(Vec<BytesType>, usize)> = Vec::new();
+    loop {
+        let line = line(frame, &mut pos)?;
+        let rest = line.start + 1..line.end;
+        let mut value = match frame[line].first()? {
+            prefix @ (b'+' | b'-') => {
+                std::str::from_utf8(&frame[rest.clone()]).ok()?;
+                match prefix {
+                    b'+' => BytesType::SimpleString(frame.slice(rest)),
+                    _ => BytesType::Error(frame.slice(rest)),
+                }
+            }
+            b':' => BytesType::Integer(parse_integer(&frame[rest]).ok()?),
+            b'_' if rest.is_empty() => BytesType::Null,
+            b'$' => match parse_length(&frame[rest]).ok()? {
+                Some(len) if len > options.max_value_len => return None,
+                Some(len) => {
+                    let end = pos.checked_add(len)?;
+                    if frame.get(end..end.checked_add(2)?)? != b"\r\n" {
+                        return None;
+                    }
+                    let payload = frame.slice(pos..end);
+                    pos = end + 2;
+                    BytesType::BulkString(payload)
+                }
+                None => BytesType::Null,
+            },
+            b'*' => match parse_length(&frame[rest]).ok()? {
+                Some(_) if open.len() >= options.max_depth => return None,
+                Some(len) if len > options.max_array_len => return None,
+                Some(0) => BytesType::Array(Vec::new()),
+                Some(len) => {
+                    open.push((Vec::with_capacity(len.min(MAX_PREALLOCATED_LEN)), len));
+                    continue;
+                }
+                None => BytesType::Null,
+            },
+            _ => return None,
+        };
+
+        // Completes the arrays the value is the last element of.
+        loop {
+            match open.last_mut() {
+                None => return Some(value),
+                Some((elements, remaining)) => {
+                    elements.push(value);
+                    *remaining -= 1;
+                    if *remaining > 0 {
+                        break;
+                    }
+                    value = BytesType::Array(open.pop()?.0);
+                }
+            }
+        }
+    }
+}
+
+/// Returns the range of the line starting at `pos` without its CRLF, and moves past it.
+fn line(frame: &[u8], pos: &mut usize) -> Option<Range<usize>> {
+    let start = *pos;
+    let end = start + frame[start..].iter().position(|&b| b == b'\n')?;
+    if end == start || frame[end - 1] != b'\r' {
+        return None;
+    }
+    *pos = end + 1;
+    Some(start..end - 1)
+}
+
+impl From<Type> for BytesType {
+    fn from(ty: Type) -> Self {
+        match ty {
+            Type::SimpleString(s) => BytesType::SimpleString(s.into()),
+            Type::Error(s) => BytesType::Error(s.into()),
+            Type::Integer(n) => BytesType::Integer(n),
+            Type::BulkString(bytes) => BytesType::BulkString(bytes.into()),
+            Type::Null => BytesType::Null,
+            Type::Array(elements) => {
+                BytesType::Array(elements.into_iter().map(BytesType::from).collect())
+            }
+            ty => BytesType::Other(ty),
+        }
+    }
+}
+
+/// Copies the strings out of the buffer, simple strings and errors that are not valid UTF-8
+/// are converted lossily.
+impl From<BytesType> for Type {
+    fn from(ty: BytesType) -> Self {
+        let string = |bytes: Bytes| String::from_utf8_lossy(&bytes).into_owned();
+        match ty {
+            BytesType::SimpleString(bytes) => Type::SimpleString(string(bytes)),
+            BytesType::Error(bytes) => Type::Error(string(bytes)),
+            BytesType::Integer(n) => Type::Integer(n),
+            BytesType::BulkString(bytes) => Type::BulkString(bytes.into()),
+            BytesType::Null => Type::Null,
+            BytesType::Array(elements) => {
+                Type::Array(elements.into_iter().map(Type::from).collect())
+            }
+            BytesType::Other(ty) => ty,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_copy() -> Result<()> {
+        let options = ReadOptions::default();
+        let mut buf = BytesMut::from(&b"*2\r\n$3\r\nget\r\n$3\r\nkey\r\n+OK\r\n"[..]);
+        let start = buf.as_ptr() as usize;
+
+        let value = BytesType::decode(&mut buf, &options)?.unwrap();
+        let elements = match &value {
+            BytesType::Array(elements) => elements,
+            _ => panic!("not an array: {:?}", value),
+        };
+        let key = elements[1].as_bulk().unwrap();
+        assert_eq!(key, &b"key"[..]);
+        // Points into the buffer the frame was decoded from.
+        assert_eq!(key.as_ptr() as usize, start + 17);
+        assert_eq!(Type::from(value), Type::from(vec!["get", "key"]));
+
+        assert_eq!(
+            BytesType::decode(&mut buf, &options)?,
+            Some(BytesType::SimpleString(Bytes::from_static(b"OK")))
+        );
+        assert!(buf.is_empty());
+        assert_eq!(BytesType::decode(&mut buf, &options)?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn other_frames() -> Result<()> {
+        let options = ReadOptions::new().inline_commands(true);
+        let cases: &[(&[u8], BytesType)] = &[
+            (
+                b"PING  x\r\n",
+                BytesType::Array(vec![
+                    BytesType::BulkString(Bytes::from_static(b"PING")),
+                    BytesType::BulkString(Bytes::from_static(b"x")),
+                ]),
+            ),
+            (b"#t\r\n", BytesType::Other(Type::Boolean(true))),
+            (
+                b"*2\r\n:1\r\n%1\r\n:2\r\n:3\r\n",
+                BytesType::Array(vec![
+                    BytesType::Integer(1),
+                    BytesType::Other(Type::Map(vec![(Type::Integer(2), Type::Integer(3))])),
+                ]),
+            ),
+            (
+                b"$2\r\n\xff\xfe\r\n",
+                BytesType::BulkString(Bytes::from_static(b"\xff\xfe")),
+            ),
+        ];
+        for (input, expected) in cases {
+            let mut buf = BytesMut::from(*input);
+            assert_eq!(
+                BytesType::decode(&mut buf, &options)?.as_ref(),
+                Some(expected)
+            );
+            assert!(buf.is_empty());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn errors() -> Result<()> {
+        let options = ReadOptions::new().max_value_len(4);
+
+        let mut buf = BytesMut::from(&b"*1\r\n$5\r\nhello\r\n:1\r\n"[..]);
+        let err = BytesType::decode(&mut buf, &options).unwrap_err();
+        assert!(matches!(err, Error::ValueTooLarge));
+        assert_eq!(
+            BytesType::decode(&mut buf, &options)?,
+            Some(BytesType::Integer(1))
+        );
+
+        let mut buf = BytesMut::from(&b"*2\r\n:1\r\n:x\r\n"[..]);
+        let err = BytesType::decode(&mut buf, &options).unwrap_err();
+        assert!(matches!(err, Error::InvalidInteger));
+        assert_eq!(&buf[..], b"*2\r\n:1\r\n:x\r\n");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,82 @@
+//! Helpers for servers speaking the Redis Cluster protocol to clients.
+
+/// Number of hash slots in a cluster.
+pub const SLOTS: u16 = 16384;
+
+/// Returns the hash slot of the key.
+///
+/// If the key contains a hash tag, i.e. a non-empty substring between the first `{` and the
+/// first `}` after it, only the hash tag is hashed so keys sharing it land in the same slot.
+pub fn key_slot(key: &[u8]) -> u16 {
+    crc16(hash_tag(key).unwrap_or(key)) % SLOTS
+}
+
+fn hash_tag(key: &[u8]) -> Option<&[u8]> {
+    let start = key.iter().position(|&b| b == b'{')? + 1;
+    let len = key[start..].iter().position(|&b| b == b'}')?;
+    if len == 0 {
+        return None;
+    }
+    Some(&key[start..(start + len)])
+}
+
+/// CRC16-CCITT (XMODEM) as used by Redis Cluster.
+fn crc16(buf: &[u8]) -> u16 {
+    buf.iter().fold(0, |crc, &b| {
+        (crc << 8) ^ CRC16_TABLE[usize::from(((crc >> 8) as u8) ^ b)]
+    })
+}
+
+const CRC16_TABLE: [u16; 256] = crc16_table();
+
+const fn crc16_table() -> [u16; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u16) << 8;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc16_check_value() {
+        // The reference value from the Redis Cluster specification.
+        assert_eq!(crc16(b"123456789"), 0x31c3);
+    }
+
+    #[test]
+    fn key_slots() {
+        assert_eq!(key_slot(b"foo"), 12182);
+        assert_eq!(key_slot(b"bar"), 5061);
+        assert_eq!(key_slot(b""), 0);
+    }
+
+    #[test]
+    fn hash_tags() {
+        // Examples from the Redis Cluster specification.
+        assert_eq!(
+            key_slot(b"{user1000}.following"),
+            key_slot(b"{user1000}.followers")
+        );
+        assert_eq!(key_slot(b"{user1000}.following"), key_slot(b"user1000"));
+        assert_eq!(key_slot(b"foo{}{bar}"), crc16(b"foo{}{bar}") % SLOTS);
+        assert_eq!(key_slot(b"foo{{bar}}zap"), key_slot(b"{bar"));
+        assert_eq!(key_slot(b"foo{bar}{zap}"), key_slot(b"bar"));
+        assert_eq!(key_slot(b"foo{bar"), crc16(b"foo{bar") % SLOTS);
+    }
+}
@@ -0,0 +1,189 @@
+//! A codec for [`tokio_util::codec`], to read and write values with [`Framed`] and the rest of
+//! the tokio-util ecosystem rather than the server's read loop.
+//!
+//! ```no_run
+//! use futures::{SinkExt, StreamExt};
+//! use redcon::codec::RespCodec;
+//! use redcon::Type;
+//! use tokio::net::TcpStream;
+//! use tokio_util::codec::Framed;
+//!
+//! # async fn ping() -> anyhow::Result<()> {
+//! let stream = TcpStream::connect("127.0.0.1:6379").await?;
+//! let mut framed = Framed::new(stream, RespCodec::new());
+//! framed.send(Type::from(vec!["ping"])).await?;
+//! assert_eq!(framed.next().await.transpose()?, Some(Type::SimpleString("PONG".into())));
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`Framed`]: tokio_util::codec::Framed
+
+use bytes::{Buf, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::bytes_type::BytesType;
+use crate::resp::{block_on, buffered_frame_len, Error, ReadOptions, RespReader, Type};
+
+/// Decodes and encodes values with the same parser and encoder as the server.
+///
+/// Frames are parsed once they are all buffered, so bulk payloads are copied out of the buffer
+/// once. The limits of the [`ReadOptions`] are checked as the frame arrives though, a frame
+/// going over them fails without buffering the rest of it. A bulk string over
+/// [`ReadOptions::max_value_len`] is buffered whole, then skipped before failing with
+/// [`Error::ValueTooLarge`].
+#[derive(Clone, Debug, Default)]
+pub struct RespCodec {
+    options: ReadOptions,
+}
+
+impl RespCodec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_options(options: ReadOptions) -> Self {
+        Self { options }
+    }
+}
+
+impl Decoder for RespCodec {
+    type Item = Type;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Type>, Error> {
+        if buffered_frame_len(src, &self.options).is_none() {
+            return Ok(None);
+        }
+        let mut reader = RespReader::with_options(&src[..], self.options.clone());
+        let res = block_on(reader.read());
+        let consumed = reader.offset() as usize;
+        match res {
+            Ok(ty) => {
+                src.advance(consumed);
+                Ok(Some(ty))
+            }
+            // The whole frame is read, the next one can still be decoded.
+            Err(Error::ValueTooLarge) => {
+                src.advance(consumed);
+                Err(Error::ValueTooLarge)
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl Encoder<Type> for RespCodec {
+    type Error = Error;
+
+    fn encode(&mut self, item: Type, dst: &mut BytesMut) -> Result<(), Error> {
+        dst.reserve(item.encoded_len());
+        item.encode_with(|bytes| dst.extend_from_slice(bytes));
+        Ok(())
+    }
+}
+
+/// Like [`RespCodec`], but decodes [`BytesType`]s whose strings are slices of the read buffer,
+/// see [`BytesType::decode`].
+#[derive(Clone, Debug, Default)]
+pub struct RespBytesCodec {
+    options: ReadOptions,
+}
+
+impl RespBytesCodec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_options(options: ReadOptions) -> Self {
+        Self { options }
+    }
+}
+
+impl Decoder for RespBytesCodec {
+    type Item = BytesType;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<BytesType>, Error> {
+        BytesType::decode(src, &self.options)
+    }
+}
+
+impl Encoder<Type> for RespBytesCodec {
+    type Error = Error;
+
+    fn encode(&mut self, item: Type, dst: &mut BytesMut) -> Result<(), Error> {
+        RespCodec::new().encode(item, dst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use futures::StreamExt;
+    use tokio::io::{duplex, AsyncWriteExt};
+    use tokio_util::codec::FramedRead;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn split_frames() -> Result<()> {
+        let (mut write, read) = duplex(64);
+        let mut read = FramedRead::new(read, RespCodec::new());
+        let frames = b"*2\r\n$3\r\nget\r\n$3\r\nkey\r\n+OK\r\n";
+        // Each byte is a read of its own.
+        let writer = tokio::spawn(async move {
+            for byte in frames {
+                write.write_all(&[*byte]).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+        });
+        assert_eq!(
+            read.next().await.transpose()?,
+            Some(Type::from(vec!["get", "key"]))
+        );
+        assert_eq!(
+            read.next().await.transpose()?,
+            Some(Type::SimpleString("OK".into()))
+        );
+        writer.await?;
+        assert!(read.next().await.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn split_frames_zero_copy() -> Result<()> {
+        let (mut write, read) = duplex(64);
+        let mut read = FramedRead::new(read, RespBytesCodec::new());
+        write
+            .write_all(b"*2\r\n$3\r\nget\r\n$3\r\nkey\r\n:1\r\n")
+            .await?;
+        drop(write);
+        let get = read.next().await.transpose()?.map(Type::from);
+        assert_eq!(get, Some(Type::from(vec!["get", "key"])));
+        assert_eq!(read.next().await.transpose()?, Some(BytesType::Integer(1)));
+        assert!(read.next().await.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn limits() -> Result<()> {
+        let options = ReadOptions::new().max_bulk_len(8).max_value_len(4);
+        let mut codec = RespCodec::with_options(options);
+
+        // Fails before the payload arrives.
+        let mut buf = BytesMut::from(&b"$9\r\n"[..]);
+        let err = codec.decode(&mut buf).unwrap_err();
+        assert!(matches!(err, Error::BulkTooLong));
+
+        // Skips the whole frame.
+        let mut buf = BytesMut::from(&b"*1\r\n$5\r\nhello\r\n:1\r\n"[..]);
+        let err = codec.decode(&mut buf).unwrap_err();
+        assert!(matches!(err, Error::ValueTooLarge));
+        assert_eq!(codec.decode(&mut buf)?, Some(Type::Integer(1)));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,490 @@
+use std::fmt;
+use std::iter::FromIterator;
+use std::ops::Deref;
+use std::slice;
+use std::str;
+use std::sync::OnceLock;
+use std::vec;
+
+use crate::resp::{escape, parse_integer, Type};
+
+/// A command sent by a client, the command name followed by its arguments.
+///
+/// Arguments are binary safe, so keys and values don't have to be UTF-8. Dereferences to the
+/// slice of arguments, so `cmd[0]` is the command name as sent by the client. Comparisons of
+/// the name ignore ASCII case, like Redis does.
+#[derive(Clone, Debug, Default)]
+pub struct Command {
+    args: Vec<Vec<u8>>,
+    name_uppercase: OnceLock<Vec<u8>>,
+}
+
+impl Command {
+    pub fn new(args: Vec<Vec<u8>>) -> Self {
+        Self {
+            args,
+            name_uppercase: OnceLock::new(),
+        }
+    }
+
+    /// Returns the command name, empty for an empty command.
+    pub fn name(&self) -> &[u8] {
+        self.args.first().map_or(&[], Vec::as_slice)
+    }
+
+    /// Returns whether the command name is `name`, ignoring ASCII case and without allocating.
+    pub fn is(&self, name: &str) -> bool {
+        self.name().eq_ignore_ascii_case(name.as_bytes())
+    }
+
+    /// Returns whether the command name is one of `names`, ignoring ASCII case.
+    pub fn name_eq_any(&self, names: &[&str]) -> bool {
+        names.iter().any(|name| self.is(name))
+    }
+
+    /// Returns the command name in uppercase, which is computed once and then cached.
+    ///
+    /// Only ASCII letters are changed, other bytes are kept as is.
+    pub fn name_uppercase(&self) -> &[u8] {
+        self.name_uppercase
+            .get_or_init(|| self.name().to_ascii_uppercase())
+    }
+
+    /// Returns the command name with non-printable bytes escaped, for error messages and logs.
+    pub fn name_escaped(&self) -> String {
+        escape(self.name())
+    }
+
+    /// Returns the argument at the index, the command name being at 0.
+    pub fn arg(&self, i: usize) -> Option<&[u8]> {
+        self.args.get(i).map(Vec::as_slice)
+    }
+
+    /// Returns the argument at the index as a string, failing if it isn't valid UTF-8.
+    pub fn arg_str(&self, i: usize) -> Result<&str, CommandError> {
+        str::from_utf8(self.required_arg(i)?).map_err(|_| CommandError::NotUtf8)
+    }
+
+    /// Parses the argument at the index as an integer.
+    pub fn arg_i64(&self, i: usize) -> Result<i64, CommandError> {
+        parse_i64(self.required_arg(i)?)
+    }
+
+    /// Parses the argument at the index as a float.
+    pub fn arg_f64(&self, i: usize) -> Result<f64, CommandError> {
+        parse_f64(self.required_arg(i)?)
+    }
+
+    fn required_arg(&self, i: usize) -> Result<&[u8], CommandError> {
+        self.arg(i).ok_or_else(|| self.wrong_arity())
+    }
+
+    /// Fails unless the command has exactly `n` arguments, counting the command name.
+    pub fn require_exact_args(&self, n: usize) -> Result<(), CommandError> {
+        if self.args.len() != n {
+            return Err(self.wrong_arity());
+        }
+        Ok(())
+    }
+
+    /// Fails unless the command has at least `n` arguments, counting the command name.
+    pub fn require_min_args(&self, n: usize) -> Result<(), CommandError> {
+        if self.args.len() < n {
+            return Err(self.wrong_arity());
+        }
+        Ok(())
+    }
+
+    /// Returns the error replied for a wrong number of arguments.
+    pub fn wrong_arity(&self) -> CommandError {
+        CommandError::WrongArity(escape(&self.name().to_ascii_lowercase()))
+    }
+
+    /// Returns a scanner for the options starting at the index, e.g. `NX` and `EX 10` in
+    /// `SET key value NX EX 10`.
+    pub fn opts(&self, start: usize) -> Opts<'_> {
+        let args = self.args.get(start..).unwrap_or_default();
+        Opts {
+            args,
+            used: vec![false; args.len()],
+            values: Vec::new(),
+        }
+    }
+
+    pub fn as_slice(&self) -> &[Vec<u8>] {
+        &self.args
+    }
+
+    pub fn into_args(self) -> Vec<Vec<u8>> {
+        self.args
+    }
+}
+
+impl Deref for Command {
+    type Target = [Vec<u8>];
+
+    fn deref(&self) -> &Self::Target {
+        &self.args
+    }
+}
+
+impl PartialEq for Command {
+    fn eq(&self, other: &Self) -> bool {
+        self.args == other.args
+    }
+}
+
+impl Eq for Command {}
+
+impl From<Vec<Vec<u8>>> for Command {
+    fn from(args: Vec<Vec<u8>>) -> Self {
+        Self::new(args)
+    }
+}
+
+impl From<Vec<String>> for Command {
+    fn from(args: Vec<String>) -> Self {
+        args.into_iter().collect()
+    }
+}
+
+impl From<Command> for Vec<Vec<u8>> {
+    fn from(cmd: Command) -> Self {
+        cmd.args
+    }
+}
+
+/// The command as clients send it, an array of bulk strings.
+impl From<Command> for Type {
+    fn from(cmd: Command) -> Self {
+        Type::Array(cmd.args.into_iter().map(Type::BulkString).collect())
+    }
+}
+
+impl FromIterator<Vec<u8>> for Command {
+    fn from_iter<I: IntoIterator<Item = Vec<u8>>>(iter: I) -> Self {
+        Self::new(iter.into_iter().collect())
+    }
+}
+
+impl FromIterator<String> for Command {
+    fn from_iter<I: IntoIterator<Item = String>>(iter: I) -> Self {
+        Self::new(iter.into_iter().map(String::into_bytes).collect())
+    }
+}
+
+impl IntoIterator for Command {
+    type Item = Vec<u8>;
+    type IntoIter = vec::IntoIter<Vec<u8>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.args.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a Command {
+    type Item = &'a Vec<u8>;
+    type IntoIter = slice::Iter<'a, Vec<u8>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.args.iter()
+    }
+}
+
+/// Scans the trailing options of a command, see [`Command::opts`].
+///
+/// Options can be given in any order and their names ignore ASCII case. Once all the known
+/// options are scanned, [`Opts::finish`] fails if there are any left.
+///
+/// Options are looked for in the order they are given, skipping the values of the options
+/// followed by one. Declare those with [`Opts::with_values`] when a flag is scanned before
+/// them, so that e.g. `NX` in `SCAN 0 MATCH NX` is taken for the pattern rather than the flag.
+#[derive(Debug)]
+pub struct Opts<'a> {
+    args: &'a [Vec<u8>],
+    used: Vec<bool>,
+    /// The options followed by a value, declared or scanned so far.
+    values: Vec<String>,
+}
+
+impl<'a> Opts<'a> {
+    /// Declares the options followed by a value. The options scanned with [`Opts::value`] are
+    /// declared as they are scanned.
+    pub fn with_values(mut self, names: &[&str]) -> Self {
+        self.values
+            .extend(names.iter().map(|name| name.to_string()));
+        self
+    }
+
+    /// Returns whether the flag is given.
+    pub fn flag(&mut self, name: &str) -> bool {
+        self.find(name).is_some()
+    }
+
+    /// Returns the value following the option if it is given.
+    pub fn value(&mut self, name: &str) -> Result<Option<&'a [u8]>, CommandError> {
+        if !self.takes_value(name.as_bytes()) {
+            self.values.push(name.to_string());
+        }
+        let i = match self.find(name) {
+            Some(i) => i,
+            None => return Ok(None),
+        };
+        match self.args.get(i + 1) {
+            Some(value) if !self.used[i + 1] => {
+                self.used[i + 1] = true;
+                Ok(Some(value))
+            }
+            _ => Err(CommandError::Syntax),
+        }
+    }
+
+    /// Returns the integer following the option if it is given.
+    pub fn value_i64(&mut self, name: &str) -> Result<Option<i64>, CommandError> {
+        self.value(name)?.map(parse_i64).transpose()
+    }
+
+    /// Returns the float following the option if it is given.
+    pub fn value_f64(&mut self, name: &str) -> Result<Option<f64>, CommandError> {
+        self.value(name)?.map(parse_f64).transpose()
+    }
+
+    /// Fails if there are options that weren't scanned.
+    pub fn finish(self) -> Result<(), CommandError> {
+        if self.used.iter().all(|&used| used) {
+            Ok(())
+        } else {
+            Err(CommandError::Syntax)
+        }
+    }
+
+    fn find(&mut self, name: &str) -> Option<usize> {
+        let mut i = 0;
+        while i < self.args.len() {
+            let arg = &self.args[i];
+            if !self.used[i] {
+                if arg.eq_ignore_ascii_case(name.as_bytes()) {
+                    self.used[i] = true;
+                    return Some(i);
+                }
+                if self.takes_value(arg) {
+                    // The next argument is its value, whatever it is named like.
+                    i += 1;
+                }
+            }
+            i += 1;
+        }
+        None
+    }
+
+    fn takes_value(&self, arg: &[u8]) -> bool {
+        self.values
+            .iter()
+            .any(|name| arg.eq_ignore_ascii_case(name.as_bytes()))
+    }
+}
+
+/// Parses integers as strictly as RESP ones, e.g. rejecting `+5` like Redis does.
+fn parse_i64(arg: &[u8]) -> Result<i64, CommandError> {
+    parse_integer(arg).map_err(|_| CommandError::NotInteger)
+}
+
+fn parse_f64(arg: &[u8]) -> Result<f64, CommandError> {
+    match str::from_utf8(arg).map(str::parse::<f64>) {
+        Ok(Ok(n)) if !n.is_nan() => Ok(n),
+        _ => Err(CommandError::NotFloat),
+    }
+}
+
+/// An invalid command, displayed as the error Redis replies with.
+///
+/// Converts into an error [`Type`], so it can be written as the reply.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CommandError {
+    /// Wrong number of arguments for the command with the given name.
+    WrongArity(String),
+    Syntax,
+    NotInteger,
+    NotFloat,
+    /// An argument read as a string isn't valid UTF-8.
+    NotUtf8,
+    /// Any other error, displayed as is.
+    Custom(String),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommandError::WrongArity(name) => {
+                write!(f, "ERR wrong number of arguments for '{}' command", name)
+            }
+            CommandError::Syntax => write!(f, "ERR syntax error"),
+            CommandError::NotInteger => write!(f, "ERR value is not an integer or out of range"),
+            CommandError::NotFloat => write!(f, "ERR value is not a valid float"),
+            CommandError::NotUtf8 => write!(f, "ERR value is not valid UTF-8"),
+            CommandError::Custom(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+impl From<CommandError> for Type {
+    fn from(err: CommandError) -> Self {
+        Type::Error(err.to_string())
+    }
+}
+
+impl<T: Into<Type>> From<Result<T, CommandError>> for Type {
+    fn from(res: Result<T, CommandError>) -> Self {
+        res.map_or_else(Type::from, Into::into)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(args: &[&str]) -> Command {
+        args.iter().map(|it| it.to_string()).collect()
+    }
+
+    #[test]
+    fn case_insensitive_names() {
+        for name in ["get", "GET", "Get", "gEt"] {
+            let cmd = command(&[name, "key"]);
+            assert!(cmd.is("GET"));
+            assert!(cmd.is("get"));
+            assert!(!cmd.is("GETSET"));
+            assert!(!cmd.is("GE"));
+            assert!(cmd.name_eq_any(&["MGET", "GET"]));
+            assert!(!cmd.name_eq_any(&["SET", "MGET"]));
+            assert_eq!(cmd.name_uppercase(), b"GET");
+            assert_eq!(cmd.name(), name.as_bytes());
+        }
+
+        let empty = Command::default();
+        assert_eq!(empty.name(), b"");
+        assert!(!empty.is("GET"));
+        assert_eq!(empty.name_uppercase(), b"");
+    }
+
+    #[test]
+    fn binary_args() -> Result<(), CommandError> {
+        let cmd = Command::new(vec![
+            b"get".to_vec(),
+            vec![0xde, 0xad, 0xbe, 0xef],
+            b"12".to_vec(),
+        ]);
+        assert!(cmd.is("GET"));
+        assert_eq!(cmd.arg(1), Some(&[0xde, 0xad, 0xbe, 0xef][..]));
+        assert_eq!(cmd.arg_str(1), Err(CommandError::NotUtf8));
+        assert_eq!(cmd.arg_i64(1), Err(CommandError::NotInteger));
+        assert_eq!(cmd.arg_str(2)?, "12");
+        assert_eq!(
+            Type::from(cmd),
+            Type::Array(vec![
+                Type::BulkString(b"get".to_vec()),
+                Type::BulkString(vec![0xde, 0xad, 0xbe, 0xef]),
+                Type::BulkString(b"12".to_vec()),
+            ])
+        );
+
+        let cmd = Command::new(vec![vec![b'g', 0xff, b't']]);
+        assert_eq!(cmd.name_uppercase(), [b'G', 0xff, b'T']);
+        assert_eq!(cmd.name_escaped(), "g\\xfft");
+        assert_eq!(
+            cmd.wrong_arity().to_string(),
+            "ERR wrong number of arguments for 'g\\xfft' command"
+        );
+        Ok(())
+    }
+
+    /// Parses `SET key value [NX] [EX seconds]`.
+    fn parse_set(cmd: &Command) -> Result<(bool, Option<i64>), CommandError> {
+        cmd.require_min_args(3)?;
+        let mut opts = cmd.opts(3).with_values(&["EX"]);
+        let nx = opts.flag("NX");
+        let ex = opts.value_i64("EX")?;
+        opts.finish()?;
+        Ok((nx, ex))
+    }
+
+    #[test]
+    fn arguments() -> Result<(), CommandError> {
+        let cmd = command(&["INCRBYFLOAT", "key", "1.5", "10"]);
+        assert_eq!(cmd.arg(1), Some(&b"key"[..]));
+        assert_eq!(cmd.arg(4), None);
+        assert_eq!(cmd.arg_f64(2)?, 1.5);
+        assert_eq!(cmd.arg_i64(3)?, 10);
+        assert_eq!(cmd.arg_i64(2), Err(CommandError::NotInteger));
+        for arg in ["+5", " 5", ""] {
+            let cmd = command(&["EXPIRE", "key", arg]);
+            assert_eq!(cmd.arg_i64(2), Err(CommandError::NotInteger));
+        }
+        assert_eq!(cmd.arg_f64(1), Err(CommandError::NotFloat));
+        assert!(cmd.require_exact_args(4).is_ok());
+        assert_eq!(
+            cmd.arg_i64(4).unwrap_err().to_string(),
+            "ERR wrong number of arguments for 'incrbyfloat' command"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn set_options() -> Result<(), CommandError> {
+        assert_eq!(
+            parse_set(&command(&["SET", "key", "value"]))?,
+            (false, None)
+        );
+        assert_eq!(
+            parse_set(&command(&["set", "key", "value", "ex", "10", "NX"]))?,
+            (true, Some(10))
+        );
+
+        let err = parse_set(&command(&["SET", "key"])).unwrap_err();
+        assert_eq!(
+            Type::from(err),
+            Type::Error("ERR wrong number of arguments for 'set' command".to_string())
+        );
+
+        for args in [
+            &["SET", "key", "value", "XX"][..],
+            &["SET", "key", "value", "EX"],
+            &["SET", "key", "value", "NX", "NX"],
+        ] {
+            let err = parse_set(&command(args)).unwrap_err();
+            assert_eq!(err.to_string(), "ERR syntax error");
+        }
+
+        let err = parse_set(&command(&["SET", "key", "value", "EX", "ten"])).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "ERR value is not an integer or out of range"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn values_named_like_options() -> Result<(), CommandError> {
+        let cmd = command(&["SCAN", "0", "MATCH", "NX", "COUNT", "count", "NX"]);
+        let mut opts = cmd.opts(2).with_values(&["MATCH", "COUNT"]);
+        assert!(opts.flag("NX"));
+        assert_eq!(opts.value("MATCH")?, Some(&b"NX"[..]));
+        assert_eq!(opts.value("COUNT")?, Some(&b"count"[..]));
+        opts.finish()?;
+
+        // Scanned options are declared too.
+        let cmd = command(&["SCAN", "0", "MATCH", "NX"]);
+        let mut opts = cmd.opts(2);
+        assert_eq!(opts.value("MATCH")?, Some(&b"NX"[..]));
+        assert!(!opts.flag("NX"));
+        opts.finish()?;
+
+        let cmd = command(&["SCAN", "0", "MATCH", "NX"]);
+        let mut opts = cmd.opts(2).with_values(&["MATCH"]);
+        assert!(!opts.flag("NX"));
+        assert_eq!(opts.value("MATCH")?, Some(&b"NX"[..]));
+        opts.finish()
+    }
+}
@@ -1,134 +1,1469 @@
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::fmt;
 use std::future::Future;
-use std::str;
-use std::sync::Arc;
+use std::net::SocketAddr;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::{Duration, Instant};
 
-use anyhow::Result;
-use tokio::io::{BufReader, BufWriter};
-use tokio::sync::Mutex;
-use tokio::{net::tcp::OwnedWriteHalf, net::TcpListener};
+use anyhow::{anyhow, bail, Result};
+use bytes::Bytes;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufWriter};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::sync::{oneshot, watch, Mutex, MutexGuard, Notify};
+use tokio::time::{self, sleep};
+use tokio_util::sync::CancellationToken;
 
-use crate::resp::{Error, Type};
+use crate::conn_stats::{ConnStats, Counters};
+use crate::error_kind::{err_message, error_message, ErrorKind};
+use crate::handler::Handler;
+use crate::metrics::Metrics;
+use crate::output::{Output, OutputLimit, OutputWriter};
+use crate::reply_mode::ReplyMode;
+use crate::resp::{Header, Protocol, Type};
+use crate::server::Server;
+use crate::tap::{Tap, TapFn};
 
-pub type Command = Vec<String>;
+/// Controls when the replies written to a connection are flushed to the socket.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum FlushPolicy {
+    /// Flushes after every write.
+    #[default]
+    Eager,
+    /// Buffers the writes and flushes once the handler finishes handling the command, saving a
+    /// syscall per write for handlers replying with many values. Handlers can still flush
+    /// explicitly with [`Conn::flush`].
+    OnHandlerCompletion,
+}
+
+/// Why a connection was closed, see [`Conn::closed`] and
+/// [`Builder::on_disconnect`](crate::server::Builder::on_disconnect).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The client closed the connection, possibly in the middle of a command.
+    ClientClosed,
+    /// The client sent something that is not RESP.
+    ProtocolError,
+    /// Reading from the socket failed.
+    ReadError,
+    /// The output waiting to be sent exceeded its limit, see
+    /// [`Builder::output_limit`](crate::server::Builder::output_limit).
+    OutputLimitExceeded,
+    /// The client sent too many commands over the rate limit, see
+    /// [`RateLimitPolicy::Reject`](crate::RateLimitPolicy::Reject).
+    RateLimited,
+    /// Closed with [`Server::kill`] or [`Server::kill_addr`].
+    Killed,
+    /// The server stopped, see [`Server::shutdown`] and [`Server::drain`].
+    ServerShutdown,
+    /// A handler panicked, see
+    /// [`Builder::close_on_panic`](crate::server::Builder::close_on_panic).
+    HandlerPanicked,
+    /// A handler timed out after writing part of its reply, see
+    /// [`Builder::handler_timeout`](crate::server::Builder::handler_timeout).
+    HandlerTimedOut,
+    /// A reply was left incomplete, e.g. an [`ArrayWriter`] dropped before all its elements
+    /// were written.
+    IncompleteReply,
+    /// The connection was dropped without a server serving it, e.g. one made with
+    /// [`Conn::new`].
+    Dropped,
+    /// Closed with [`Conn::drain`].
+    Drained,
+}
+
+/// How [`Conn::drain`] ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DrainOutcome {
+    /// The commands in flight finished and all the replies were written before the socket was
+    /// shut down.
+    Completed,
+    /// The commands in flight didn't finish before the timeout, the socket was shut down
+    /// without waiting for them nor writing the final frame.
+    TimedOut,
+}
+
+/// A drain asked for with [`Conn::drain`], carried out by the read loop of the connection.
+pub(crate) struct DrainRequest {
+    pub(crate) final_frame: Option<Type>,
+    pub(crate) deadline: time::Instant,
+    /// Whether a handler of the connection asked for it, that handler isn't waited for as it
+    /// waits for the drain.
+    pub(crate) from_handler: bool,
+    pub(crate) done: oneshot::Sender<DrainOutcome>,
+}
+
+tokio::task_local! {
+    /// Set by the server while a handler runs.
+    pub(crate) static SCOPE: CommandScope;
+}
+
+/// State of the command being handled.
+pub(crate) struct CommandScope {
+    /// The connection the command was sent on.
+    conn: ConnId,
+    /// Whether the handler started writing a reply.
+    pub(crate) replying: Cell<bool>,
+    /// Cleared by [`Conn::no_reply`].
+    pub(crate) expects_reply: Cell<bool>,
+    /// Whether the replies are dropped, see [`ReplyMode::Skip`].
+    muted: bool,
+}
+
+impl CommandScope {
+    pub(crate) fn new(conn: ConnId, muted: bool) -> Self {
+        Self {
+            conn,
+            replying: Cell::new(false),
+            expects_reply: Cell::new(true),
+            muted,
+        }
+    }
+}
+
+/// Default capacity of the read and write buffers of a connection.
+pub(crate) const DEFAULT_BUFFER_SIZE: usize = 8 * 1024;
+
+/// Bulk strings written from a reader are copied in chunks of this size.
+const BULK_CHUNK_LEN: usize = 64 * 1024;
+
+/// Marks the running handler as having written part of a reply.
+fn replying() {
+    let _ = SCOPE.try_with(|it| it.replying.set(true));
+}
+
+/// Unique identifier of a connection, assigned when the connection is accepted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ConnId(u64);
+
+impl ConnId {
+    pub(crate) fn next() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        Self(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for ConnId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+type Writer = BufWriter<OutputWriter>;
+
+/// Options of the connections accepted by the server.
+#[derive(Clone)]
+pub(crate) struct ConnOptions {
+    pub(crate) capacity: usize,
+    pub(crate) flush_policy: FlushPolicy,
+    pub(crate) metrics: Option<Arc<dyn Metrics>>,
+    pub(crate) deferred_timeout: Option<Duration>,
+    pub(crate) output_limit: Option<OutputLimit>,
+    pub(crate) tap: Option<Arc<TapFn>>,
+}
+
+impl Default for ConnOptions {
+    fn default() -> Self {
+        Self {
+            capacity: DEFAULT_BUFFER_SIZE,
+            flush_policy: FlushPolicy::default(),
+            metrics: None,
+            deferred_timeout: None,
+            output_limit: None,
+            tap: None,
+        }
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct Conn {
+    inner: Arc<Inner>,
+    /// Where the replies go instead of the socket, see [`Conn::capture`].
+    captured: Option<Arc<StdMutex<Vec<Type>>>>,
+}
+
+struct Inner {
+    id: ConnId,
+    peer_addr: Option<SocketAddr>,
     // TODO: is it possible without mutex?
     // TODO: maket it generic over writer?
-    writer: Arc<Mutex<BufWriter<OwnedWriteHalf>>>,
+    writer: Mutex<Writer>,
+    flush_policy: FlushPolicy,
+    killed: Notify,
+    /// Why the connection is killed, set by the first call to `kill`.
+    kill_reason: StdMutex<Option<DisconnectReason>>,
+    /// Set by the first call to `drain`.
+    draining: AtomicBool,
+    /// Waiting for the read loop, see [`Conn::drain`].
+    drain: StdMutex<Option<DrainRequest>>,
+    drain_requested: Notify,
+    /// Set once the server stops serving the connection.
+    closed: watch::Sender<Option<DisconnectReason>>,
+    cancel: CancellationToken,
+    resp3: AtomicBool,
+    reply_mode: AtomicU8,
+    db: AtomicUsize,
+    /// Set once a reply is left incomplete, nothing can be written after it.
+    poisoned: AtomicBool,
+    metrics: Option<Arc<dyn Metrics>>,
+    /// Replies waiting for a deferred reply written before them, see [`Conn::defer`].
+    deferred: StdMutex<DeferredQueue>,
+    deferred_timeout: Option<Duration>,
+    output: Arc<Output>,
+    counters: Arc<Counters>,
+}
+
+#[derive(Default)]
+struct DeferredQueue {
+    next_id: u64,
+    replies: VecDeque<Reply>,
+}
+
+enum Reply {
+    /// A deferred reply that is not resolved yet.
+    Pending(u64),
+    Ready(Vec<u8>),
+}
+
+impl fmt::Debug for Inner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Inner")
+            .field("id", &self.id)
+            .field("peer_addr", &self.peer_addr)
+            .field("writer", &self.writer)
+            .field("flush_policy", &self.flush_policy)
+            .finish_non_exhaustive()
+    }
 }
 
 impl Conn {
     pub fn new(writer: OwnedWriteHalf) -> Self {
-        let writer = Arc::new(Mutex::new(BufWriter::new(writer)));
-        Self { writer }
+        Self::with_capacity(DEFAULT_BUFFER_SIZE, writer)
+    }
+
+    /// Creates a new connection whose write buffer has at least the specified capacity.
+    pub fn with_capacity(capacity: usize, writer: OwnedWriteHalf) -> Self {
+        let options = ConnOptions {
+            capacity,
+            ..ConnOptions::default()
+        };
+        Self::with_options(options, CancellationToken::new(), writer)
+    }
+
+    pub(crate) fn with_options(
+        options: ConnOptions,
+        cancel: CancellationToken,
+        writer: OwnedWriteHalf,
+    ) -> Self {
+        let id = ConnId::next();
+        let peer_addr = writer.peer_addr().ok();
+        let output = Arc::new(Output::new(options.output_limit));
+        let tap = options.tap.map(|f| Tap::new(id, f));
+        let counters = Arc::new(Counters::new());
+        let writer = OutputWriter::new(writer, Arc::clone(&output), Arc::clone(&counters), tap);
+        let inner = Arc::new(Inner {
+            id,
+            peer_addr,
+            writer: Mutex::new(BufWriter::with_capacity(options.capacity, writer)),
+            flush_policy: options.flush_policy,
+            killed: Notify::new(),
+            kill_reason: StdMutex::new(None),
+            draining: AtomicBool::new(false),
+            drain: StdMutex::new(None),
+            drain_requested: Notify::new(),
+            closed: watch::channel(None).0,
+            cancel,
+            resp3: AtomicBool::new(false),
+            reply_mode: AtomicU8::new(ReplyMode::On.to_u8()),
+            db: AtomicUsize::new(0),
+            poisoned: AtomicBool::new(false),
+            metrics: options.metrics,
+            deferred: StdMutex::new(DeferredQueue::default()),
+            deferred_timeout: options.deferred_timeout,
+            output,
+            counters,
+        });
+        Self {
+            inner,
+            captured: None,
+        }
+    }
+
+    pub fn id(&self) -> ConnId {
+        self.inner.id
+    }
+
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.inner.peer_addr
+    }
+
+    /// Returns the counters of the connection. They are atomics, so this is cheap and doesn't
+    /// wait for the writes in progress.
+    pub fn stats(&self) -> ConnStats {
+        self.inner.counters.snapshot()
+    }
+
+    pub(crate) fn counters(&self) -> &Arc<Counters> {
+        &self.inner.counters
+    }
+
+    /// Returns whether the connection is closed for exceeding its output limit, see
+    /// [`Builder::output_limit`](crate::server::Builder::output_limit).
+    pub fn is_output_limit_exceeded(&self) -> bool {
+        self.inner.output.is_exceeded()
+    }
+
+    /// Returns the protocol negotiated on the connection, [`Protocol::Resp2`] until it is
+    /// changed with [`Conn::set_protocol`].
+    pub fn protocol(&self) -> Protocol {
+        if self.inner.resp3.load(Ordering::Relaxed) {
+            Protocol::Resp3
+        } else {
+            Protocol::Resp2
+        }
+    }
+
+    /// Sets the protocol used for the following replies, e.g. after handling `HELLO 3`.
+    pub fn set_protocol(&self, protocol: Protocol) {
+        self.inner
+            .resp3
+            .store(protocol == Protocol::Resp3, Ordering::Relaxed);
+    }
+
+    /// Returns the index of the database selected on the connection, 0 until it is changed
+    /// with [`Conn::set_db`].
+    pub fn db(&self) -> usize {
+        self.inner.db.load(Ordering::Relaxed)
+    }
+
+    /// Selects a database for the following commands, e.g. after handling `SELECT`. See
+    /// [`Builder::databases`](crate::server::Builder::databases) to have the server handle it.
+    pub fn set_db(&self, index: usize) {
+        self.inner.db.store(index, Ordering::Relaxed);
+    }
+
+    pub fn reply_mode(&self) -> ReplyMode {
+        ReplyMode::from_u8(self.inner.reply_mode.load(Ordering::Relaxed))
+    }
+
+    /// Sets whether the replies are sent, e.g. after handling `CLIENT REPLY`.
+    ///
+    /// Writes silently do nothing while replies are turned off. The mode applies to the
+    /// commands read by the server after it is set, with all the replies written while
+    /// handling a command being treated as one, including its deferred reply.
+    /// [`ReplyMode::Skip`] doesn't apply to replies written from outside of a handler.
+    pub fn set_reply_mode(&self, mode: ReplyMode) {
+        self.inner.reply_mode.store(mode.to_u8(), Ordering::Relaxed);
+    }
+
+    /// Returns whether the replies to the next command are dropped, switching
+    /// [`ReplyMode::Skip`] back to [`ReplyMode::On`].
+    pub(crate) fn take_muted(&self) -> bool {
+        let skipped = self
+            .inner
+            .reply_mode
+            .compare_exchange(
+                ReplyMode::Skip.to_u8(),
+                ReplyMode::On.to_u8(),
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            )
+            .is_ok();
+        skipped || self.reply_mode() == ReplyMode::Off
+    }
+
+    /// Returns whether replies written now are dropped.
+    fn muted(&self) -> bool {
+        SCOPE
+            .try_with(|it| it.muted)
+            .unwrap_or_else(|_| self.reply_mode() == ReplyMode::Off)
+    }
+
+    /// Returns the reply of a deferred reply, which is empty if replies are turned off.
+    fn deferred_reply(&self, ty: Type) -> Vec<u8> {
+        if self.reply_mode() == ReplyMode::Off {
+            return Vec::new();
+        }
+        self.for_protocol(ty).to_bytes_for(self.protocol())
+    }
+
+    /// Converts the value for RESP2 connections, see [`Type::to_resp2`].
+    fn for_protocol(&self, ty: Type) -> Type {
+        match self.protocol() {
+            Protocol::Resp2 => ty.to_resp2(),
+            Protocol::Resp3 => ty,
+        }
+    }
+
+    /// Returns whether the connection is closed, i.e. the client went away, the connection was
+    /// killed or the server stopped serving it.
+    pub fn is_closed(&self) -> bool {
+        self.inner.closed.borrow().is_some()
+    }
+
+    /// Returns why the connection is closed, if it is.
+    pub fn disconnect_reason(&self) -> Option<DisconnectReason> {
+        *self.inner.closed.borrow()
+    }
+
+    /// Resolves once the connection is closed with the reason, see [`Conn::is_closed`].
+    ///
+    /// Long running handlers can stop working for clients that are gone:
+    ///
+    /// ```no_run
+    /// # async fn scan(conn: redcon::Conn) {
+    /// tokio::select! {
+    ///     _ = conn.closed() => return,
+    ///     _ = tokio::time::sleep(std::time::Duration::from_secs(10)) => {}
+    /// }
+    /// # }
+    /// ```
+    pub fn closed(&self) -> impl Future<Output = DisconnectReason> + Send + 'static {
+        let mut closed = self.inner.closed.subscribe();
+        async move {
+            match closed.wait_for(Option::is_some).await {
+                Ok(reason) => reason.expect("waited for a reason"),
+                // The connection was dropped while open.
+                Err(_) => DisconnectReason::Dropped,
+            }
+        }
+    }
+
+    /// Returns a token cancelled once the connection is closed or the server starts draining,
+    /// so handlers can give up on expensive work early.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.inner.cancel.clone()
+    }
+
+    /// Closes the connection politely, e.g. for `SHUTDOWN`-like commands: stops reading
+    /// commands, waits for the commands in flight to finish and their replies to be written in
+    /// order, writes the final frame if any, then shuts down the socket.
+    ///
+    /// Deferred replies still pending once the commands finished are resolved with nulls, see
+    /// [`Conn::defer`]. If the commands don't finish before the timeout, the socket is shut down
+    /// without waiting for them. A handler can drain its own connection, it isn't waited for:
+    ///
+    /// ```no_run
+    /// # async fn quit(conn: redcon::Conn) -> anyhow::Result<()> {
+    /// use std::time::Duration;
+    ///
+    /// use redcon::Type;
+    ///
+    /// conn.write_ok().await?;
+    /// let bye = Type::Error("ERR server is going away".to_string());
+    /// conn.drain(Some(bye), Duration::from_secs(5)).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// Fails if the connection is closed, or draining already, or if it closes for another
+    /// reason before draining. Only works for connections served by a [`Server`].
+    pub async fn drain(
+        &self,
+        final_frame: Option<Type>,
+        timeout: Duration,
+    ) -> Result<DrainOutcome> {
+        if self.is_closed() {
+            bail!("connection is closed");
+        }
+        if self.inner.draining.swap(true, Ordering::Relaxed) {
+            bail!("connection is draining already");
+        }
+        let (done, outcome) = oneshot::channel();
+        let from_handler = SCOPE.try_with(|it| it.conn == self.id()).unwrap_or(false);
+        *self.inner.drain.lock().unwrap() = Some(DrainRequest {
+            final_frame,
+            deadline: time::Instant::now() + timeout,
+            from_handler,
+            done,
+        });
+        self.inner.drain_requested.notify_one();
+        let closed = self.closed();
+        tokio::select! {
+            biased;
+            outcome = outcome => outcome.map_err(|_| anyhow!("connection closed before draining")),
+            reason = closed => bail!("connection closed before draining: {:?}", reason),
+        }
+    }
+
+    /// Writes the value, e.g. `conn.write(42)`, `conn.write("hello")`, `conn.write(None::<String>)`
+    /// or the `Result<T, CommandError>` returned by a command.
+    ///
+    /// All the replies go through here or [`Conn::write_type`], the named helpers below are
+    /// shortcuts. Values that RESP2 doesn't support are downgraded on RESP2 connections, see
+    /// [`Type::to_resp2`], nulls are written as `_` on RESP3 ones, and nothing is written while
+    /// replies are turned off, see [`Conn::set_reply_mode`].
+    pub async fn write(&self, value: impl Into<Type>) -> Result<()> {
+        self.write_type(&value.into()).await
     }
 
     pub async fn write_simple_string(&self, str: String) -> Result<()> {
-        let mut writer = self.writer.lock().await;
-        Type::SimpleString(str).write(&mut *writer).await?;
-        Ok(())
+        self.write(Type::SimpleString(str)).await
     }
 
     pub async fn write_error(&self, err: String) -> Result<()> {
-        let mut writer = self.writer.lock().await;
-        Type::Error(err).write(&mut *writer).await?;
-        Ok(())
+        self.write(Type::Error(err)).await
+    }
+
+    /// Writes an error reply prefixed with the given kind, e.g. `-WRONGTYPE <message>`.
+    ///
+    /// Fails if the kind or the message contains CR or LF.
+    pub async fn write_error_kind(&self, kind: ErrorKind, message: &str) -> Result<()> {
+        self.write(Type::Error(error_message(&kind, message)?))
+            .await
+    }
+
+    /// Writes an error reply, prefixing it with `ERR ` unless it already starts with an
+    /// uppercase error kind.
+    ///
+    /// Fails if the message contains CR or LF.
+    pub async fn write_err(&self, message: &str) -> Result<()> {
+        self.write(Type::Error(err_message(message)?)).await
+    }
+
+    /// Writes a `-MOVED <slot> <addr>` redirect, telling the client that the slot is served
+    /// by another node from now on.
+    pub async fn write_moved(&self, slot: u16, addr: &str) -> Result<()> {
+        self.write_error_kind(ErrorKind::Moved, &format!("{} {}", slot, addr))
+            .await
+    }
+
+    /// Writes an `-ASK <slot> <addr>` redirect, telling the client to send only the next
+    /// command for the slot to another node.
+    pub async fn write_ask(&self, slot: u16, addr: &str) -> Result<()> {
+        self.write_error_kind(ErrorKind::Ask, &format!("{} {}", slot, addr))
+            .await
     }
 
     pub async fn write_integer(&self, num: i64) -> Result<()> {
-        let mut writer = self.writer.lock().await;
-        Type::Integer(num).write(&mut *writer).await?;
-        Ok(())
+        self.write(Type::Integer(num)).await
+    }
+
+    /// Writes a boolean, which RESP2 clients get as `1` or `0`.
+    pub async fn write_bool(&self, b: bool) -> Result<()> {
+        self.write(Type::Boolean(b)).await
+    }
+
+    /// Writes text meant to be shown as it is, e.g. a report, with a three byte `format` of
+    /// `txt` for plain text or `mkd` for markdown. RESP2 clients get a bulk string of the text.
+    pub async fn write_verbatim(&self, format: &str, text: String) -> Result<()> {
+        if format.len() != 3 {
+            bail!("verbatim string format must be three bytes: {:?}", format);
+        }
+        self.write(Type::Verbatim {
+            format: format.to_string(),
+            text,
+        })
+        .await
     }
 
     pub async fn write_bulk_string(&self, str: String) -> Result<()> {
-        let mut writer = self.writer.lock().await;
-        Type::BulkString(str).write(&mut *writer).await?;
-        Ok(())
+        self.write(Type::BulkString(str.into_bytes())).await
+    }
+
+    /// Writes `+OK`.
+    pub async fn write_ok(&self) -> Result<()> {
+        self.write_encoded(b"+OK\r\n").await
+    }
+
+    /// Writes `+PONG`.
+    pub async fn write_pong(&self) -> Result<()> {
+        self.write_encoded(b"+PONG\r\n").await
+    }
+
+    /// Writes `+QUEUED`, the reply to commands queued in a transaction.
+    pub async fn write_queued(&self) -> Result<()> {
+        self.write_encoded(b"+QUEUED\r\n").await
+    }
+
+    /// Same as [`Conn::write_null`].
+    pub async fn write_nil(&self) -> Result<()> {
+        self.write_null().await
+    }
+
+    /// Writes the error Redis replies with when a command is used on a key of another type.
+    pub async fn write_wrongtype(&self) -> Result<()> {
+        self.write_encoded(
+            b"-WRONGTYPE Operation against a key holding the wrong kind of value\r\n",
+        )
+        .await
+    }
+
+    /// Writes `:0`.
+    pub async fn write_zero(&self) -> Result<()> {
+        self.write_encoded(b":0\r\n").await
+    }
+
+    /// Writes `:1`.
+    pub async fn write_one(&self) -> Result<()> {
+        self.write_encoded(b":1\r\n").await
+    }
+
+    /// Writes a bulk string that doesn't have to be valid UTF-8.
+    ///
+    /// The bytes are written from the slice, they are only copied when the reply is captured or
+    /// has to wait for earlier ones.
+    pub async fn write_bulk_bytes(&self, bytes: &[u8]) -> Result<()> {
+        self.check_poisoned()?;
+        if let Some(captured) = &self.captured {
+            captured
+                .lock()
+                .unwrap()
+                .push(Type::BulkString(bytes.to_vec()));
+            return Ok(());
+        }
+        if self.muted() {
+            return Ok(());
+        }
+        replying();
+        let header = format!("${}\r\n", bytes.len());
+        self.reserve(|| header.len() + bytes.len() + 2)?;
+        let mut writer = self.inner.writer.lock().await;
+        if self.queue_reply(|| [header.as_bytes(), bytes, b"\r\n"].concat()) {
+            return self.write_ready(&mut writer).await;
+        }
+        self.write_reserved(&mut writer, header.as_bytes()).await?;
+        self.write_reserved(&mut writer, bytes).await?;
+        self.write_reserved(&mut writer, b"\r\n").await?;
+        self.flush_if_eager(&mut writer).await
+    }
+
+    /// Like [`Conn::write_bulk_bytes`], reusing the buffer of `bytes` if it isn't shared.
+    pub async fn write_bytes(&self, bytes: Bytes) -> Result<()> {
+        self.write(Type::BulkString(bytes.into())).await
     }
 
     pub async fn write_null(&self) -> Result<()> {
-        let mut writer = self.writer.lock().await;
-        Type::Null.write(&mut *writer).await?;
+        self.write(Type::Null).await
+    }
+
+    /// Writes an array of the values, e.g. `conn.write_array(keys.iter().map(String::as_str))`.
+    ///
+    /// Arrays are prefixed with their length, so the values are collected before writing.
+    pub async fn write_array<I>(&self, arr: I) -> Result<()>
+    where
+        I: IntoIterator,
+        I::Item: Into<Type>,
+    {
+        self.write(Type::Array(arr.into_iter().map(Into::into).collect()))
+            .await
+    }
+
+    /// Same as [`Conn::write`].
+    pub async fn write_value(&self, value: impl Into<Type>) -> Result<()> {
+        self.write(value).await
+    }
+
+    /// Like [`Conn::write`], without taking the value, so a reply built once can be written to
+    /// many connections, e.g. a message published to all the subscribers of a channel.
+    pub async fn write_type(&self, ty: &Type) -> Result<()> {
+        self.check_poisoned()?;
+        let ty = match self.protocol() {
+            Protocol::Resp2 if !ty.is_resp2() => Cow::Owned(ty.clone().to_resp2()),
+            _ => Cow::Borrowed(ty),
+        };
+        if let Some(captured) = &self.captured {
+            captured.lock().unwrap().push(ty.into_owned());
+            return Ok(());
+        }
+        if self.muted() {
+            return Ok(());
+        }
+        replying();
+        // Counted before waiting for the writer, as the waiting replies are output too.
+        let protocol = self.protocol();
+        self.reserve(|| ty.encoded_len_for(protocol))?;
+        let mut writer = self.inner.writer.lock().await;
+        if self.queue_reply(|| ty.to_bytes_for(protocol)) {
+            return self.write_ready(&mut writer).await;
+        }
+        let n = ty.write_to_for(&mut *writer, protocol).await?;
+        self.written(n);
+        self.flush_if_eager(&mut writer).await
+    }
+
+    /// Writes an out-of-band message, e.g. a message published to a channel, as a push on
+    /// RESP3 connections and as an array on RESP2 ones.
+    ///
+    /// Pushes are not replies: they are written right away, even while replies are turned off,
+    /// captured, or waiting for a deferred reply. Write a [`Type::Push`] with [`Conn::write`]
+    /// instead when it is the reply to a command, e.g. to confirm a `SUBSCRIBE`.
+    pub async fn write_push<I>(&self, elements: I) -> Result<()>
+    where
+        I: IntoIterator,
+        I::Item: Into<Type>,
+    {
+        self.check_poisoned()?;
+        let push = self.for_protocol(Type::Push(elements.into_iter().map(Into::into).collect()));
+        let protocol = self.protocol();
+        self.reserve(|| push.encoded_len_for(protocol))?;
+        let mut writer = self.inner.writer.lock().await;
+        let n = push.write_to_for(&mut *writer, protocol).await?;
+        self.written(n);
+        self.flush_if_eager(&mut writer).await
+    }
+
+    /// Returns a guard capturing the replies written through it instead of sending them to the
+    /// socket, e.g. to run the commands of a transaction and reply with all their replies at
+    /// once:
+    ///
+    /// ```no_run
+    /// # async fn exec(conn: redcon::Conn, handler: impl redcon::Handler, queued: Vec<redcon::Command>) -> anyhow::Result<()> {
+    /// let capture = conn.capture();
+    /// for cmd in queued {
+    ///     handler.call(redcon::Conn::clone(&capture), cmd).await;
+    /// }
+    /// conn.write_array(capture.take()).await
+    /// # }
+    /// ```
+    ///
+    /// Only the writes through the guard, or the clones of the connection made from it, are
+    /// captured; the writes of other tasks holding the connection go to the socket as usual.
+    /// Captures stack: capturing from a guard starts a new, empty capture, and its replies don't
+    /// show up in the outer one. Replies are captured even if replies are turned off, see
+    /// [`Conn::set_reply_mode`], as they are not sent.
+    ///
+    /// Replies written element by element or in chunks fail while capturing, and deferred
+    /// replies are not captured, see [`Conn::begin_array`], [`Conn::begin_streamed_bulk`] and
+    /// [`Conn::defer`].
+    pub fn capture(&self) -> Capture {
+        Capture {
+            conn: Conn {
+                inner: Arc::clone(&self.inner),
+                captured: Some(Arc::default()),
+            },
+        }
+    }
+
+    fn check_not_captured(&self) -> Result<()> {
+        if self.captured.is_some() {
+            bail!("replies written element by element can't be captured");
+        }
         Ok(())
     }
 
-    pub async fn write_array(&self, arr: Vec<Type>) -> Result<()> {
-        let mut writer = self.writer.lock().await;
-        Type::Array(arr).write(&mut *writer).await?;
+    /// Tells the server that the command being handled doesn't reply on purpose, e.g. because
+    /// its reply is written by another task, so the
+    /// [`MissingReplyPolicy`](crate::MissingReplyPolicy) isn't applied to it.
+    ///
+    /// Does nothing outside of a handler.
+    pub fn no_reply(&self) {
+        let _ = SCOPE.try_with(|it| it.expects_reply.set(false));
+    }
+
+    /// Reserves the place of a reply that is written later, e.g. once the element a blocking
+    /// command waits for is available.
+    ///
+    /// The handler can return right away and resolve the returned token from any task. Replies
+    /// written to the connection in the meantime are held back and written after the deferred
+    /// one, in order. Dropping the token without resolving it replies with a null, as does
+    /// the timeout set with
+    /// [`Builder::deferred_timeout`](crate::server::Builder::deferred_timeout).
+    pub fn defer(&self) -> Deferred {
+        if self.muted() {
+            // Resolving the token does nothing, as there is no reply waiting for it.
+            return Deferred {
+                conn: self.clone(),
+                id: u64::MAX,
+                resolved: true,
+            };
+        }
+        replying();
+        let id = {
+            let mut queue = self.inner.deferred.lock().unwrap();
+            let id = queue.next_id;
+            queue.next_id += 1;
+            queue.replies.push_back(Reply::Pending(id));
+            id
+        };
+        if let Some(timeout) = self.inner.deferred_timeout {
+            let conn = self.clone();
+            tokio::spawn(async move {
+                sleep(timeout).await;
+                let reply = conn.deferred_reply(Type::Null);
+                if let Err(err) = conn.resolve_deferred(id, reply).await {
+                    eprintln!("could not write to client: {}", err);
+                }
+            });
+        }
+        Deferred {
+            conn: self.clone(),
+            id,
+            resolved: false,
+        }
+    }
+
+    /// Writes the pairs as a map on RESP3 connections, and as a flat array of alternating keys
+    /// and values on RESP2 ones.
+    pub async fn write_map<I, K, V>(&self, pairs: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<Type>,
+        V: Into<Type>,
+    {
+        let pairs = pairs
+            .into_iter()
+            .map(|(key, value)| (key.into(), value.into()));
+        self.write(Type::Map(pairs.collect())).await
+    }
+
+    /// Writes the value with the attributes attached on RESP3 connections, and just the value
+    /// on RESP2 ones since they don't support attributes.
+    pub async fn write_with_attrs<I, K, V>(&self, attrs: I, value: impl Into<Type>) -> Result<()>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<Type>,
+        V: Into<Type>,
+    {
+        let attrs = attrs
+            .into_iter()
+            .map(|(key, value)| (key.into(), value.into()));
+        self.write(Type::Attribute {
+            attrs: attrs.collect(),
+            value: Box::new(value.into()),
+        })
+        .await
+    }
+
+    /// Flushes the buffered writes to the socket.
+    pub async fn flush(&self) -> Result<()> {
+        let mut writer = self.inner.writer.lock().await;
+        writer.flush().await?;
         Ok(())
     }
-}
 
-pub async fn listen<Handler, Fut>(addr: &str, handler: Handler) -> Result<()>
-where
-    Handler: Fn(Conn, Command) -> Fut + Send + Sync + 'static,
-    Fut: Future<Output = ()> + Send + 'static,
-{
-    let listener = TcpListener::bind(addr).await?;
-    let handler = Arc::new(handler);
+    /// Starts writing an array of `len` elements, for elements that are produced one at a
+    /// time.
+    ///
+    /// Other writes to the connection wait until the returned guard is finished. Exactly `len`
+    /// elements must be written, as a partial array can't be told apart from the following
+    /// replies: finishing early or dropping the guard before finishing closes the connection
+    /// and fails the following writes.
+    pub async fn begin_array(&self, len: usize) -> Result<ArrayWriter<'_>> {
+        self.check_poisoned()?;
+        self.check_not_captured()?;
+        let mut writer = self.inner.writer.lock().await;
+        let muted = self.muted();
+        if !muted {
+            if !self.inner.deferred.lock().unwrap().replies.is_empty() {
+                bail!("arrays written element by element can't wait for a deferred reply");
+            }
+            let header = Header::new(b'*', len as i64);
+            self.write_raw(&mut writer, header.as_bytes()).await?;
+        }
+        Ok(ArrayWriter {
+            conn: self,
+            writer: WriterRef::Owned(writer),
+            remaining: len,
+            muted,
+            finished: false,
+        })
+    }
 
-    loop {
-        let (socket, _) = listener.accept().await?;
-        let handler = Arc::clone(&handler);
-        tokio::spawn(async move {
-            let (read, write) = socket.into_split();
-            let mut read = BufReader::new(read);
-            let conn = Conn::new(write);
-
-            loop {
-                let ty = match Type::read(&mut read).await {
-                    Ok(it) => it,
-                    Err(err) => {
-                        if let Some(Error::UnexpectedEof) = err.downcast_ref::<Error>() {
-                            break;
-                        }
-                        eprintln!("could not read command: {}", err);
-                        continue;
-                    }
-                };
-
-                let cmd = match type_to_command(ty) {
-                    Some(it) => it,
-                    None => {
-                        eprintln!("invalid command");
-                        if let Err(err) = conn
-                            .write_error("ERR expected array of bulk strings".to_string())
-                            .await
-                        {
-                            eprintln!("could not write to client: {}", err);
-                        }
-                        continue;
-                    }
-                };
-
-                let conn = conn.clone();
-                let handler = Arc::clone(&handler);
-                tokio::spawn(handler(conn, cmd));
+    /// Starts writing a bulk string in chunks, for strings whose length is not known up front.
+    ///
+    /// Other writes to the connection wait until the returned guard is finished. Dropping it
+    /// without finishing, e.g. when returning early on an error, leaves the reply incomplete
+    /// so it closes the connection and fails the following writes. Fails on RESP2 connections.
+    pub async fn begin_streamed_bulk(&self) -> Result<StreamedBulk<'_>> {
+        Ok(StreamedBulk(self.begin_streamed(b"$?\r\n").await?))
+    }
+
+    /// Writes a bulk string of `len` bytes read from `reader`, e.g. the contents of a file,
+    /// copying it in chunks instead of holding it in memory.
+    ///
+    /// Other writes to the connection wait until the string is written. The reader must provide
+    /// at least `len` bytes, the rest is left unread: failing to read them closes the
+    /// connection, as the reply is left incomplete.
+    pub async fn write_bulk_from_reader(
+        &self,
+        len: usize,
+        mut reader: impl AsyncRead + Unpin + Send,
+    ) -> Result<()> {
+        self.check_poisoned()?;
+        self.check_not_captured()?;
+        let mut writer = self.inner.writer.lock().await;
+        if self.muted() {
+            return Ok(());
+        }
+        if !self.inner.deferred.lock().unwrap().replies.is_empty() {
+            bail!("bulk strings read from a reader can't wait for a deferred reply");
+        }
+        let header = Header::new(b'$', len as i64);
+        self.write_raw(&mut writer, header.as_bytes()).await?;
+
+        let mut buf = vec![0; len.min(BULK_CHUNK_LEN)];
+        let mut left = len;
+        while left > 0 {
+            match reader.read(&mut buf[..left.min(BULK_CHUNK_LEN)]).await {
+                Ok(0) => {
+                    self.poison();
+                    bail!("reader ended {} bytes short of the bulk string", left);
+                }
+                Ok(n) => {
+                    self.write_raw(&mut writer, &buf[..n]).await?;
+                    left -= n;
+                }
+                Err(err) => {
+                    self.poison();
+                    return Err(err.into());
+                }
+            }
+        }
+        self.write_raw(&mut writer, b"\r\n").await?;
+        self.flush_if_eager(&mut writer).await
+    }
+
+    /// Starts writing an array element by element, for arrays whose length is not known up
+    /// front.
+    ///
+    /// Other writes to the connection wait until the returned guard is finished. Dropping it
+    /// without finishing, e.g. when returning early on an error, leaves the reply incomplete
+    /// so it closes the connection and fails the following writes. Fails on RESP2 connections.
+    pub async fn begin_streamed_array(&self) -> Result<StreamedArray<'_>> {
+        Ok(StreamedArray(self.begin_streamed(b"*?\r\n").await?))
+    }
+
+    /// Starts writing a map pair by pair, for maps whose length is not known up front.
+    ///
+    /// Other writes to the connection wait until the returned guard is finished. Dropping it
+    /// without finishing, e.g. when returning early on an error, leaves the reply incomplete
+    /// so it closes the connection and fails the following writes. Fails on RESP2 connections.
+    pub async fn begin_streamed_map(&self) -> Result<StreamedMap<'_>> {
+        Ok(StreamedMap(self.begin_streamed(b"%?\r\n").await?))
+    }
+
+    /// Locks the writer and writes the header unless replies are dropped, returns whether
+    /// they are.
+    async fn begin_streamed(&self, header: &[u8]) -> Result<Streamed<'_>> {
+        if self.protocol() != Protocol::Resp3 {
+            bail!("streamed replies require RESP3");
+        }
+        self.check_poisoned()?;
+        self.check_not_captured()?;
+        let mut writer = self.inner.writer.lock().await;
+        let muted = self.muted();
+        if !muted {
+            if !self.inner.deferred.lock().unwrap().replies.is_empty() {
+                bail!("streamed replies can't wait for a deferred reply");
+            }
+            self.write_raw(&mut writer, header).await?;
+        }
+        Ok(Streamed {
+            conn: self,
+            writer,
+            muted,
+            finished: false,
+        })
+    }
+
+    /// Writes an already encoded reply.
+    pub(crate) async fn write_encoded(&self, reply: &[u8]) -> Result<()> {
+        self.check_poisoned()?;
+        if let Some(captured) = &self.captured {
+            let ty = Type::read(&mut &*reply).await?;
+            captured.lock().unwrap().push(ty);
+            return Ok(());
+        }
+        if self.muted() {
+            return Ok(());
+        }
+        replying();
+        self.reserve(|| reply.len())?;
+        let mut writer = self.inner.writer.lock().await;
+        if self.queue_reply(|| reply.to_vec()) {
+            return self.write_ready(&mut writer).await;
+        }
+        self.write_reserved(&mut writer, reply).await?;
+        self.flush_if_eager(&mut writer).await
+    }
+
+    fn check_poisoned(&self) -> Result<()> {
+        if self.inner.poisoned.load(Ordering::Relaxed) {
+            bail!("connection closed after an incomplete reply");
+        }
+        Ok(())
+    }
+
+    /// Closes the connection after a reply is left incomplete.
+    fn poison(&self) {
+        eprintln!("closing connection {}: incomplete reply", self.id());
+        self.inner.poisoned.store(true, Ordering::Relaxed);
+        self.kill(DisconnectReason::IncompleteReply);
+    }
+
+    /// Counts the output of a reply before writing or queueing it, closing the connection if
+    /// that exceeds the output limit.
+    fn reserve(&self, len: impl FnOnce() -> usize) -> Result<()> {
+        let output = &self.inner.output;
+        if !output.is_limited() {
+            return Ok(());
+        }
+        if output.reserve(len(), Instant::now()) {
+            return Ok(());
+        }
+        if output.first_exceeded() {
+            eprintln!(
+                "closing connection {} ({}): output limit exceeded with {} bytes pending",
+                self.id(),
+                self.peer_addr()
+                    .map_or_else(|| "unknown peer".to_string(), |it| it.to_string()),
+                output.pending()
+            );
+            if let Some(metrics) = &self.inner.metrics {
+                metrics.on_output_limit_exceeded(self);
+            }
+            self.kill(DisconnectReason::OutputLimitExceeded);
+        }
+        bail!("output limit exceeded");
+    }
+
+    /// Queues the reply if it has to wait for deferred replies, returns whether it did.
+    ///
+    /// Must be called with the writer locked, so replies are queued in the order they would
+    /// have been written.
+    fn queue_reply(&self, reply: impl FnOnce() -> Vec<u8>) -> bool {
+        let mut queue = self.inner.deferred.lock().unwrap();
+        if queue.replies.is_empty() {
+            return false;
+        }
+        queue.replies.push_back(Reply::Ready(reply()));
+        true
+    }
+
+    /// Resolves the deferred reply unless it is resolved already, and writes the replies that
+    /// are not waiting anymore.
+    async fn resolve_deferred(&self, id: u64, reply: Vec<u8>) -> Result<()> {
+        if !self.fill_deferred(id, reply) {
+            return Ok(());
+        }
+        let mut writer = self.inner.writer.lock().await;
+        self.write_ready(&mut writer).await
+    }
+
+    /// Sets the deferred reply, returns `false` if it is resolved already.
+    fn fill_deferred(&self, id: u64, reply: Vec<u8>) -> bool {
+        let mut queue = self.inner.deferred.lock().unwrap();
+        for it in queue.replies.iter_mut() {
+            if matches!(it, Reply::Pending(pending) if *pending == id) {
+                // Going over the limit closes the connection, the reply is never written then.
+                let _ = self.reserve(|| reply.len());
+                *it = Reply::Ready(reply);
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Writes the queued replies up to the first deferred one that is not resolved yet.
+    async fn write_ready(&self, writer: &mut Writer) -> Result<()> {
+        let ready: Vec<_> = {
+            let mut queue = self.inner.deferred.lock().unwrap();
+            let n = queue
+                .replies
+                .iter()
+                .take_while(|it| matches!(it, Reply::Ready(_)))
+                .count();
+            queue.replies.drain(..n).collect()
+        };
+        if ready.is_empty() {
+            return Ok(());
+        }
+        for reply in ready {
+            if let Reply::Ready(bytes) = reply {
+                self.write_reserved(writer, &bytes).await?;
             }
+        }
+        self.flush_if_eager(writer).await
+    }
+
+    async fn write_raw(&self, writer: &mut Writer, bytes: &[u8]) -> Result<()> {
+        self.reserve(|| bytes.len())?;
+        self.write_reserved(writer, bytes).await
+    }
+
+    /// Writes bytes already counted by [`Conn::reserve`].
+    async fn write_reserved(&self, writer: &mut Writer, bytes: &[u8]) -> Result<()> {
+        replying();
+        writer.write_all(bytes).await?;
+        self.written(bytes.len());
+        Ok(())
+    }
+
+    fn written(&self, n: usize) {
+        if let Some(metrics) = &self.inner.metrics {
+            metrics.on_bytes_written(n);
+        }
+    }
+
+    async fn flush_if_eager(&self, writer: &mut Writer) -> Result<()> {
+        if self.inner.flush_policy == FlushPolicy::Eager {
+            writer.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Resolves with the request once [`Conn::drain`] is called.
+    pub(crate) async fn drain_requested(&self) -> DrainRequest {
+        loop {
+            self.inner.drain_requested.notified().await;
+            if let Some(request) = self.inner.drain.lock().unwrap().take() {
+                return request;
+            }
+        }
+    }
+
+    /// Resolves the deferred replies that are still pending with nulls, and writes the replies
+    /// that were waiting for them.
+    pub(crate) async fn cancel_deferred(&self) -> Result<()> {
+        let mut writer = self.inner.writer.lock().await;
+        {
+            let mut queue = self.inner.deferred.lock().unwrap();
+            for it in queue.replies.iter_mut() {
+                if let Reply::Pending(_) = it {
+                    let reply = self.deferred_reply(Type::Null);
+                    // Going over the limit closes the connection, the reply is never written then.
+                    let _ = self.reserve(|| reply.len());
+                    *it = Reply::Ready(reply);
+                }
+            }
+        }
+        self.write_ready(&mut writer).await
+    }
+
+    /// Asks the connection's read loop to stop and close the socket.
+    pub(crate) fn kill(&self, reason: DisconnectReason) {
+        self.inner.kill_reason.lock().unwrap().get_or_insert(reason);
+        self.inner.killed.notify_one();
+    }
+
+    /// Returns the reason given to the first call to `kill`, if any.
+    pub(crate) fn kill_reason(&self) -> Option<DisconnectReason> {
+        *self.inner.kill_reason.lock().unwrap()
+    }
+
+    /// Resolves once `kill` has been called for this connection.
+    pub(crate) async fn killed(&self) {
+        self.inner.killed.notified().await
+    }
+
+    /// Marks the connection as closed, waking the [`Conn::closed`] futures and cancelling the
+    /// token of the connection.
+    pub(crate) fn set_closed(&self, reason: DisconnectReason) {
+        self.inner.closed.send_if_modified(|closed| {
+            let unset = closed.is_none();
+            closed.get_or_insert(reason);
+            unset
         });
+        self.inner.cancel.cancel();
     }
+
+    /// Flushes pending writes and shuts down the write half of the socket.
+    pub(crate) async fn shutdown(&self) -> Result<()> {
+        let mut writer = self.inner.writer.lock().await;
+        if self.is_output_limit_exceeded() {
+            // The buffered output is dropped rather than flushed.
+            writer.get_mut().shutdown().await?;
+        } else {
+            writer.shutdown().await?;
+        }
+        Ok(())
+    }
+}
+
+/// A reply to be written later, see [`Conn::defer`].
+#[derive(Debug)]
+pub struct Deferred {
+    conn: Conn,
+    id: u64,
+    resolved: bool,
 }
 
-fn type_to_command(ty: Type) -> Option<Command> {
-    if let Type::Array(arr) = ty {
-        arr.into_iter()
-            .map(|t| {
-                if let Type::BulkString(s) = t {
-                    Some(s)
-                } else {
-                    None
+impl Deferred {
+    /// Writes the reply, along with the replies that were waiting for it.
+    ///
+    /// Does nothing if the deferred reply timed out already.
+    pub async fn resolve(mut self, value: impl Into<Type>) -> Result<()> {
+        self.resolved = true;
+        let reply = self.conn.deferred_reply(value.into());
+        self.conn.resolve_deferred(self.id, reply).await
+    }
+
+    /// Replies with a null, like blocking commands do once their timeout expires.
+    pub async fn timeout_null(self) -> Result<()> {
+        self.resolve(Type::Null).await
+    }
+}
+
+impl Drop for Deferred {
+    fn drop(&mut self) {
+        if self.resolved {
+            return;
+        }
+        let reply = self.conn.deferred_reply(Type::Null);
+        if !self.conn.fill_deferred(self.id, reply) {
+            return;
+        }
+        // Otherwise the null is written along with the next reply.
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            let conn = self.conn.clone();
+            handle.spawn(async move {
+                let mut writer = conn.inner.writer.lock().await;
+                if let Err(err) = conn.write_ready(&mut writer).await {
+                    eprintln!("could not write to client: {}", err);
                 }
-            })
-            .collect()
-    } else {
-        None
+            });
+        }
     }
 }
 
+/// Replies captured instead of written to the socket, see [`Conn::capture`].
+///
+/// Dereferences to the connection to write the replies to.
+#[derive(Debug)]
+pub struct Capture {
+    conn: Conn,
+}
+
+impl Capture {
+    /// Returns the replies captured so far, leaving the capture empty.
+    pub fn take(&self) -> Vec<Type> {
+        let captured = self.conn.captured.as_ref().expect("capturing");
+        std::mem::take(&mut *captured.lock().unwrap())
+    }
+}
+
+impl Deref for Capture {
+    type Target = Conn;
+
+    fn deref(&self) -> &Conn {
+        &self.conn
+    }
+}
+
+enum WriterRef<'a> {
+    Owned(MutexGuard<'a, Writer>),
+    /// The writer of the enclosing array.
+    Borrowed(&'a mut Writer),
+}
+
+impl Deref for WriterRef<'_> {
+    type Target = Writer;
+
+    fn deref(&self) -> &Writer {
+        match self {
+            WriterRef::Owned(writer) => writer,
+            WriterRef::Borrowed(writer) => writer,
+        }
+    }
+}
+
+impl DerefMut for WriterRef<'_> {
+    fn deref_mut(&mut self) -> &mut Writer {
+        match self {
+            WriterRef::Owned(writer) => writer,
+            WriterRef::Borrowed(writer) => writer,
+        }
+    }
+}
+
+/// An array of known length being written element by element, see [`Conn::begin_array`].
+pub struct ArrayWriter<'a> {
+    conn: &'a Conn,
+    writer: WriterRef<'a>,
+    remaining: usize,
+    muted: bool,
+    finished: bool,
+}
+
+impl ArrayWriter<'_> {
+    /// Writes the next element, fails if all the elements are written already.
+    pub async fn element(&mut self, value: impl Into<Type>) -> Result<()> {
+        self.take_element()?;
+        if self.muted {
+            return Ok(());
+        }
+        let value = self.conn.for_protocol(value.into());
+        let protocol = self.conn.protocol();
+        self.conn.reserve(|| value.encoded_len_for(protocol))?;
+        let n = value.write_to_for(&mut *self.writer, protocol).await?;
+        self.conn.written(n);
+        self.conn.flush_if_eager(&mut self.writer).await
+    }
+
+    /// Starts writing an array of `len` elements as the next element.
+    pub async fn begin_array(&mut self, len: usize) -> Result<ArrayWriter<'_>> {
+        self.take_element()?;
+        if !self.muted {
+            let header = Header::new(b'*', len as i64);
+            self.conn
+                .write_raw(&mut self.writer, header.as_bytes())
+                .await?;
+        }
+        Ok(ArrayWriter {
+            conn: self.conn,
+            writer: WriterRef::Borrowed(&mut self.writer),
+            remaining: len,
+            muted: self.muted,
+            finished: false,
+        })
+    }
+
+    /// Ends the array, fails and closes the connection if elements are missing.
+    pub async fn finish(mut self) -> Result<()> {
+        self.finished = true;
+        if self.remaining > 0 {
+            self.conn.poison();
+            bail!("array finished with {} elements missing", self.remaining);
+        }
+        if self.muted {
+            return Ok(());
+        }
+        self.conn.flush_if_eager(&mut self.writer).await
+    }
+
+    fn take_element(&mut self) -> Result<()> {
+        self.conn.check_poisoned()?;
+        if self.remaining == 0 {
+            bail!("all the elements of the array are written already");
+        }
+        self.remaining -= 1;
+        Ok(())
+    }
+}
+
+impl Drop for ArrayWriter<'_> {
+    fn drop(&mut self) {
+        if !self.finished && self.remaining > 0 {
+            self.conn.poison();
+        }
+    }
+}
+
+/// The state shared by the streamed replies: the writer they hold until they are finished, and
+/// closing the connection when one is dropped before.
+struct Streamed<'a> {
+    conn: &'a Conn,
+    writer: MutexGuard<'a, Writer>,
+    muted: bool,
+    finished: bool,
+}
+
+impl Streamed<'_> {
+    /// Fails if the connection is closed, returns whether the reply is written.
+    fn writing(&self) -> Result<bool> {
+        self.conn.check_poisoned()?;
+        Ok(!self.muted)
+    }
+
+    async fn write_value(&mut self, value: Type) -> Result<()> {
+        let protocol = self.conn.protocol();
+        self.conn.reserve(|| value.encoded_len_for(protocol))?;
+        let n = value.write_to_for(&mut *self.writer, protocol).await?;
+        self.conn.written(n);
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        self.conn.flush_if_eager(&mut self.writer).await
+    }
+
+    /// Ends the reply with the given terminator.
+    async fn finish(&mut self, end: &[u8]) -> Result<()> {
+        self.finished = true;
+        if self.muted {
+            return Ok(());
+        }
+        self.conn.write_raw(&mut self.writer, end).await?;
+        self.flush().await
+    }
+}
+
+impl Drop for Streamed<'_> {
+    fn drop(&mut self) {
+        if !self.finished && !self.muted {
+            self.conn.poison();
+        }
+    }
+}
+
+/// A bulk string being written in chunks, see [`Conn::begin_streamed_bulk`].
+pub struct StreamedBulk<'a>(Streamed<'a>);
+
+impl StreamedBulk<'_> {
+    /// Writes a chunk of the string, empty chunks are skipped.
+    pub async fn chunk(&mut self, chunk: &[u8]) -> Result<()> {
+        let streamed = &mut self.0;
+        if !streamed.writing()? || chunk.is_empty() {
+            // An empty chunk would end the string.
+            return Ok(());
+        }
+        let header = Header::new(b';', chunk.len() as i64);
+        let conn = streamed.conn;
+        conn.write_raw(&mut streamed.writer, header.as_bytes())
+            .await?;
+        conn.write_raw(&mut streamed.writer, chunk).await?;
+        conn.write_raw(&mut streamed.writer, b"\r\n").await?;
+        streamed.flush().await
+    }
+
+    /// Ends the string.
+    pub async fn finish(mut self) -> Result<()> {
+        self.0.finish(b";0\r\n").await
+    }
+}
+
+/// An array being written element by element, see [`Conn::begin_streamed_array`].
+pub struct StreamedArray<'a>(Streamed<'a>);
+
+impl StreamedArray<'_> {
+    pub async fn push(&mut self, value: impl Into<Type>) -> Result<()> {
+        if !self.0.writing()? {
+            return Ok(());
+        }
+        self.0.write_value(value.into()).await?;
+        self.0.flush().await
+    }
+
+    /// Ends the array.
+    pub async fn finish(mut self) -> Result<()> {
+        self.0.finish(b".\r\n").await
+    }
+}
+
+/// A map being written pair by pair, see [`Conn::begin_streamed_map`].
+pub struct StreamedMap<'a>(Streamed<'a>);
+
+impl StreamedMap<'_> {
+    pub async fn pair(&mut self, key: impl Into<Type>, value: impl Into<Type>) -> Result<()> {
+        if !self.0.writing()? {
+            return Ok(());
+        }
+        self.0.write_value(key.into()).await?;
+        self.0.write_value(value.into()).await?;
+        self.0.flush().await
+    }
+
+    /// Ends the map.
+    pub async fn finish(mut self) -> Result<()> {
+        self.0.finish(b".\r\n").await
+    }
+}
+
+pub async fn listen(addr: &str, handler: impl Handler) -> Result<()> {
+    Server::builder().bind(addr).run(handler).await
+}
+
 #[cfg(test)]
 mod tests {
+    use std::future::Future;
     use std::time::Duration;
 
     use tokio::io::BufStream;
@@ -137,6 +1472,7 @@ mod tests {
     use tokio::time::{sleep, timeout};
 
     use super::*;
+    use crate::command::Command;
 
     struct Server {
         _shutdown_tx: oneshot::Sender<()>,
@@ -181,13 +1517,12 @@ mod tests {
     async fn accept_connections() -> Result<()> {
         let (_server, mut client) =
             server_and_client("127.0.0.1:6379", |conn: Conn, cmd: Command| async move {
-                // FIXME: this panic is not propagated.
-                assert!(matches!(cmd.as_slice(), &[ref c] if c == "ping"));
+                assert!(matches!(cmd.as_slice(), [c] if c == b"ping"));
                 conn.write_simple_string("pong".to_string()).await.unwrap();
             })
             .await?;
 
-        Type::Array(vec![Type::BulkString("ping".to_string())])
+        Type::Array(vec![Type::BulkString(b"ping".to_vec())])
             .write(&mut client)
             .await?;
 
@@ -218,7 +1553,7 @@ mod tests {
             })
             .await?;
 
-        Type::Array(vec![Type::BulkString("start".to_string())])
+        Type::Array(vec![Type::BulkString(b"start".to_vec())])
             .write(&mut client)
             .await?;
 
@@ -233,7 +1568,7 @@ mod tests {
         assert_eq!(Type::read(&mut client).await?, Type::Integer(42));
         assert_eq!(
             Type::read(&mut client).await?,
-            Type::BulkString("bulk string".to_string())
+            Type::BulkString(b"bulk string".to_vec())
         );
         assert_eq!(Type::read(&mut client).await?, Type::Null);
         assert_eq!(
@@ -261,7 +1596,7 @@ mod tests {
         );
 
         Type::Array(vec![
-            Type::BulkString("ping".to_string()),
+            Type::BulkString(b"ping".to_vec()),
             Type::SimpleString("ok".to_string()),
         ])
         .write(&mut client)
@@ -271,7 +1606,7 @@ mod tests {
             Type::Error("ERR expected array of bulk strings".to_string())
         );
 
-        Type::Array(vec![Type::BulkString("ping".to_string())])
+        Type::Array(vec![Type::BulkString(b"ping".to_vec())])
             .write(&mut client)
             .await?;
         assert_eq!(
@@ -281,4 +1616,27 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn dropping_listen_closes_connections() -> Result<()> {
+        let (server, mut client) =
+            server_and_client("127.0.0.1:6382", |conn: Conn, _cmd: Command| async move {
+                conn.write_simple_string("ok".to_string()).await.unwrap();
+            })
+            .await?;
+
+        Type::Array(vec![Type::BulkString(b"ping".to_vec())])
+            .write(&mut client)
+            .await?;
+        assert_eq!(
+            Type::read(&mut client).await?,
+            Type::SimpleString("ok".to_string())
+        );
+
+        drop(server);
+        let res = timeout(Duration::from_secs(1), Type::read(&mut client)).await?;
+        assert!(matches!(res.unwrap_err(), crate::Error::UnexpectedEof));
+
+        Ok(())
+    }
 }
@@ -0,0 +1,74 @@
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Snapshot of the counters of a connection, see [`Conn::stats`](crate::Conn::stats).
+///
+/// Bytes are counted as they go through the socket, so they include the protocol overhead,
+/// e.g. the `*2\r\n$3\r\n` around the arguments of a command. Replies still sitting in the
+/// write buffer are not counted until they are flushed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnStats {
+    /// Number of commands handled, counted once their handler finishes.
+    pub commands: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    /// Time since the connection was accepted.
+    pub age: Duration,
+    /// Time since the last command was read, or since the connection was accepted if it
+    /// didn't send any.
+    pub idle: Duration,
+}
+
+/// Counters of a connection, bumped by its read loop and its socket.
+#[derive(Debug)]
+pub(crate) struct Counters {
+    created: Instant,
+    commands: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    /// When the last command was read, in microseconds since `created`.
+    last_command: AtomicU64,
+}
+
+impl Counters {
+    pub(crate) fn new() -> Self {
+        Self {
+            created: Instant::now(),
+            commands: AtomicU64::new(0),
+            bytes_read: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+            last_command: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn command_read(&self) {
+        let micros = self.created.elapsed().as_micros();
+        self.last_command
+            .store(u64::try_from(micros).unwrap_or(u64::MAX), Ordering::Relaxed);
+    }
+
+    pub(crate) fn commands_handled(&self, n: u64) {
+        self.commands.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub(crate) fn read(&self, n: usize) {
+        self.bytes_read.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn written(&self, n: usize) {
+        self.bytes_written.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> ConnStats {
+        let age = self.created.elapsed();
+        let last_command = Duration::from_micros(self.last_command.load(Ordering::Relaxed));
+        ConnStats {
+            commands: self.commands.load(Ordering::Relaxed),
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            age,
+            idle: age.saturating_sub(last_command),
+        }
+    }
+}
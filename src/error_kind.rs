@@ -0,0 +1,158 @@
+use std::fmt;
+
+use anyhow::{bail, Result};
+
+/// Prefix of an error reply that tells clients what kind of error it is.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    Err,
+    WrongType,
+    NoAuth,
+    NoPerm,
+    WrongPass,
+    Moved,
+    Ask,
+    BusyKey,
+    Busy,
+    Loading,
+    NoScript,
+    ReadOnly,
+    Oom,
+    ExecAbort,
+    CrossSlot,
+    TryAgain,
+    ClusterDown,
+    Custom(String),
+}
+
+impl ErrorKind {
+    pub fn as_str(&self) -> &str {
+        match self {
+            ErrorKind::Err => "ERR",
+            ErrorKind::WrongType => "WRONGTYPE",
+            ErrorKind::NoAuth => "NOAUTH",
+            ErrorKind::NoPerm => "NOPERM",
+            ErrorKind::WrongPass => "WRONGPASS",
+            ErrorKind::Moved => "MOVED",
+            ErrorKind::Ask => "ASK",
+            ErrorKind::BusyKey => "BUSYKEY",
+            ErrorKind::Busy => "BUSY",
+            ErrorKind::Loading => "LOADING",
+            ErrorKind::NoScript => "NOSCRIPT",
+            ErrorKind::ReadOnly => "READONLY",
+            ErrorKind::Oom => "OOM",
+            ErrorKind::ExecAbort => "EXECABORT",
+            ErrorKind::CrossSlot => "CROSSSLOT",
+            ErrorKind::TryAgain => "TRYAGAIN",
+            ErrorKind::ClusterDown => "CLUSTERDOWN",
+            ErrorKind::Custom(kind) => kind,
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Formats the message of an error reply of the given kind.
+pub(crate) fn error_message(kind: &ErrorKind, message: &str) -> Result<String> {
+    let kind = kind.as_str();
+    if kind.is_empty() || kind.contains(char::is_whitespace) {
+        bail!("invalid error kind: {:?}", kind);
+    }
+    check_line(message)?;
+    Ok(format!("{} {}", kind, message))
+}
+
+/// Prepends `ERR ` to the message unless it already starts with an uppercase error kind.
+///
+/// Kinds are at least two letters long, so sentences like "A key is missing" still get one.
+pub(crate) fn err_message(message: &str) -> Result<String> {
+    check_line(message)?;
+    let code = message.split(' ').next().unwrap_or_default();
+    if code.len() >= 2 && code.bytes().all(|b| b.is_ascii_uppercase()) {
+        Ok(message.to_string())
+    } else {
+        Ok(format!("ERR {}", message))
+    }
+}
+
+fn check_line(message: &str) -> Result<()> {
+    if message.contains(['\r', '\n']) {
+        bail!("error message can't contain CR or LF: {:?}", message);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Type;
+
+    use super::*;
+
+    async fn wire(message: String) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        Type::Error(message).write(&mut buf).await?;
+        Ok(buf)
+    }
+
+    #[tokio::test]
+    async fn error_kinds() -> Result<()> {
+        assert_eq!(
+            wire(error_message(
+                &ErrorKind::WrongType,
+                "Operation against a key holding the wrong kind of value"
+            )?)
+            .await?,
+            b"-WRONGTYPE Operation against a key holding the wrong kind of value\r\n"
+        );
+        assert_eq!(
+            wire(error_message(
+                &ErrorKind::NoAuth,
+                "Authentication required."
+            )?)
+            .await?,
+            b"-NOAUTH Authentication required.\r\n"
+        );
+        assert_eq!(
+            wire(error_message(
+                &ErrorKind::Custom("MYERR".to_string()),
+                "custom"
+            )?)
+            .await?,
+            b"-MYERR custom\r\n"
+        );
+
+        assert!(error_message(&ErrorKind::Err, "line\r\nbreak").is_err());
+        assert!(error_message(&ErrorKind::Err, "line\nbreak").is_err());
+        assert!(error_message(&ErrorKind::Custom("MY\r\nERR".to_string()), "custom").is_err());
+        assert!(error_message(&ErrorKind::Custom(String::new()), "custom").is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn err_prefix() -> Result<()> {
+        assert_eq!(
+            wire(err_message("unknown command")?).await?,
+            b"-ERR unknown command\r\n"
+        );
+        assert_eq!(
+            wire(err_message("Unknown command")?).await?,
+            b"-ERR Unknown command\r\n"
+        );
+        assert_eq!(
+            wire(err_message("BUSYKEY Target key name already exists.")?).await?,
+            b"-BUSYKEY Target key name already exists.\r\n"
+        );
+        assert_eq!(
+            wire(err_message("A key is missing")?).await?,
+            b"-ERR A key is missing\r\n"
+        );
+        assert!(err_message("line\r\nbreak").is_err());
+
+        Ok(())
+    }
+}
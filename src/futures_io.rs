@@ -0,0 +1,81 @@
+//! Reading and writing values with the `futures` IO traits, for runtimes other than tokio.
+//!
+//! The values go through the same parser and encoder as with tokio, the IO is only adapted.
+
+use futures_io::{AsyncBufRead, AsyncWrite};
+use tokio_util::compat::{FuturesAsyncReadCompatExt, FuturesAsyncWriteCompatExt};
+
+use crate::resp::{Error, ReadOptions, RespReader, Type};
+
+impl Type {
+    /// Reads a value from a [`futures_io::AsyncBufRead`], see [`Type::read`].
+    pub async fn read_futures(src: impl AsyncBufRead + Unpin + Send) -> Result<Type, Error> {
+        Self::read_futures_with(src, ReadOptions::default()).await
+    }
+
+    /// Reads a value from a [`futures_io::AsyncBufRead`] with the given options.
+    pub async fn read_futures_with(
+        src: impl AsyncBufRead + Unpin + Send,
+        options: ReadOptions,
+    ) -> Result<Type, Error> {
+        RespReader::with_options(src.compat(), options).read().await
+    }
+
+    /// Writes the value to a [`futures_io::AsyncWrite`] and flushes it, see [`Type::write`].
+    pub async fn write_futures(&self, dst: impl AsyncWrite + Unpin + Send) -> Result<(), Error> {
+        self.write(dst.compat_write()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use futures::io::{AsyncWriteExt, Cursor};
+
+    use super::*;
+
+    #[test]
+    fn round_trip() -> anyhow::Result<()> {
+        let table: Vec<(&[u8], Type)> = vec![
+            (
+                b"+hello world\r\n",
+                Type::SimpleString("hello world".to_string()),
+            ),
+            (
+                b"-error message\r\n",
+                Type::Error("error message".to_string()),
+            ),
+            (b":1000\r\n", Type::Integer(1000)),
+            (
+                b"$11\r\nhello world\r\n",
+                Type::BulkString(b"hello world".to_vec()),
+            ),
+            (b"$-1\r\n", Type::Null),
+            (
+                b"*2\r\n+hello world\r\n$11\r\nhello world\r\n",
+                Type::Array(vec![
+                    Type::SimpleString("hello world".to_string()),
+                    Type::BulkString(b"hello world".to_vec()),
+                ]),
+            ),
+        ];
+
+        block_on(async {
+            for (bytes, ty) in table {
+                assert_eq!(Type::read_futures(Cursor::new(bytes)).await?, ty);
+
+                let mut buf = Cursor::new(Vec::new());
+                ty.write_futures(&mut buf).await?;
+                assert_eq!(buf.get_ref().as_slice(), bytes);
+            }
+
+            let mut buf = Cursor::new(Vec::new());
+            buf.write_all(b"*1\r\n*0\r\n").await?;
+            buf.set_position(0);
+            let options = ReadOptions::new().max_depth(1);
+            let err = Type::read_futures_with(buf, options).await.unwrap_err();
+            assert!(matches!(err, Error::NestingTooDeep));
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,122 @@
+use std::future::Future;
+use std::panic;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use tokio::task;
+
+use crate::command::Command;
+use crate::conn::Conn;
+use crate::resp::Type;
+
+/// Handles the commands received by a server.
+///
+/// It is implemented for closures of the form `Fn(Conn, Command) -> impl Future<Output = ()>`,
+/// and can be implemented for types holding state shared between the connections:
+///
+/// ```no_run
+/// use redcon::{Command, Conn, Handler};
+///
+/// struct Echo;
+///
+/// impl Handler for Echo {
+///     async fn call(&self, conn: Conn, cmd: Command) {
+///         conn.write_bulk_bytes(&cmd.join(&b' ')).await.unwrap();
+///     }
+/// }
+/// ```
+pub trait Handler: Send + Sync + 'static {
+    fn call(&self, conn: Conn, cmd: Command) -> impl Future<Output = ()> + Send;
+
+    /// Handles a batch of pipelined commands, see [`Builder::max_batch`].
+    ///
+    /// The replies must be written in the order of the commands. Calls [`Handler::call`] for
+    /// each command in turn by default.
+    ///
+    /// [`Builder::max_batch`]: crate::server::Builder::max_batch
+    fn call_batch(&self, conn: Conn, cmds: Vec<Command>) -> impl Future<Output = ()> + Send {
+        async move {
+            for cmd in cmds {
+                self.call(conn.clone(), cmd).await;
+            }
+        }
+    }
+}
+
+pub(crate) type BoxFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+pub(crate) type BoxHandler = Box<dyn Fn(Conn, Command) -> BoxFuture + Send + Sync>;
+
+/// Erases the type of the handler, as [`Handler`] can't be made into an object.
+pub(crate) fn boxed<H: Handler>(handler: H) -> BoxHandler {
+    let handler = Arc::new(handler);
+    Box::new(move |conn, cmd| {
+        let handler = handler.clone();
+        Box::pin(async move { handler.call(conn, cmd).await })
+    })
+}
+
+impl<F, Fut> Handler for F
+where
+    F: Fn(Conn, Command) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    fn call(&self, conn: Conn, cmd: Command) -> impl Future<Output = ()> + Send {
+        self(conn, cmd)
+    }
+}
+
+/// Runs a synchronous handler on the blocking thread pool with [`task::spawn_blocking`], for
+/// handlers that block, e.g. on an embedded database, and would stall the runtime otherwise.
+///
+/// The handler returns the reply rather than writing it, and the reply is written once it
+/// returns, so it keeps its place among the replies of the connection. Panics are propagated
+/// to the server, see [`Builder::on_panic`](crate::server::Builder::on_panic).
+///
+/// ```no_run
+/// use redcon::{Command, CommandError, Router, SpawnBlocking, Type};
+///
+/// let mut router = Router::new();
+/// router.command("GET").handler(SpawnBlocking::new(|cmd: Command| {
+///     let key = cmd.arg(1).ok_or_else(|| cmd.wrong_arity())?;
+///     // e.g. `db.get(key)` on a synchronous database.
+///     Ok::<_, CommandError>(Some(Type::BulkString(key.to_vec())))
+/// }));
+/// ```
+pub struct SpawnBlocking<F> {
+    f: Arc<F>,
+}
+
+impl<F> SpawnBlocking<F> {
+    pub fn new(f: F) -> Self {
+        Self { f: Arc::new(f) }
+    }
+}
+
+impl<F, R> Handler for SpawnBlocking<F>
+where
+    F: Fn(Command) -> R + Send + Sync + 'static,
+    R: Into<Type>,
+{
+    async fn call(&self, conn: Conn, cmd: Command) {
+        let f = Arc::clone(&self.f);
+        let reply = match task::spawn_blocking(move || f(cmd).into()).await {
+            Ok(reply) => reply,
+            Err(err) if err.is_panic() => panic::resume_unwind(err.into_panic()),
+            // The runtime is shutting down.
+            Err(_) => return,
+        };
+        if let Err(err) = conn.write(reply).await {
+            eprintln!("could not write to client: {}", err);
+        }
+    }
+}
+
+impl<H: Handler> Handler for Arc<H> {
+    fn call(&self, conn: Conn, cmd: Command) -> impl Future<Output = ()> + Send {
+        (**self).call(conn, cmd)
+    }
+
+    fn call_batch(&self, conn: Conn, cmds: Vec<Command>) -> impl Future<Output = ()> + Send {
+        (**self).call_batch(conn, cmds)
+    }
+}
@@ -0,0 +1,98 @@
+use anyhow::Result;
+
+use crate::command::Command;
+use crate::conn::Conn;
+use crate::resp::{Protocol, Type};
+
+/// Fields of the reply to `HELLO`, see [`Builder::hello_info`](crate::server::Builder::hello_info).
+///
+/// The reply holds `server`, `version`, `proto`, `id`, `mode`, `role` and `modules` like the
+/// one of Redis, followed by the extra fields. `proto` and `id` are filled in per connection.
+#[derive(Clone, Debug)]
+pub struct HelloInfo {
+    server: String,
+    version: String,
+    fields: Vec<(String, Type)>,
+}
+
+impl Default for HelloInfo {
+    fn default() -> Self {
+        Self {
+            server: "redcon".to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            fields: vec![
+                ("mode".to_string(), Type::from("standalone")),
+                ("role".to_string(), Type::from("master")),
+                ("modules".to_string(), Type::Array(vec![])),
+            ],
+        }
+    }
+}
+
+impl HelloInfo {
+    /// Sets the server name, defaults to `redcon`.
+    pub fn server(&mut self, name: impl Into<String>) -> &mut Self {
+        self.server = name.into();
+        self
+    }
+
+    /// Sets the server version, defaults to the version of this crate.
+    pub fn version(&mut self, version: impl Into<String>) -> &mut Self {
+        self.version = version.into();
+        self
+    }
+
+    /// Sets a field, replacing the default one with the same key if any.
+    pub fn extra(&mut self, key: impl Into<String>, value: impl Into<Type>) -> &mut Self {
+        let key = key.into();
+        let value = value.into();
+        match self.fields.iter_mut().find(|(it, _)| *it == key) {
+            Some((_, it)) => *it = value,
+            None => self.fields.push((key, value)),
+        }
+        self
+    }
+}
+
+/// Returns whether the value is a `HELLO` command.
+pub(crate) fn is_hello(ty: &Type) -> bool {
+    matches!(ty, Type::Array(arr) if matches!(
+        arr.first(),
+        Some(Type::BulkString(name)) if name.eq_ignore_ascii_case(b"hello")
+    ))
+}
+
+/// Switches the protocol of the connection as asked and replies with the info.
+pub(crate) async fn hello(conn: &Conn, info: &HelloInfo, cmd: Command) -> Result<()> {
+    if cmd.len() > 1 {
+        match cmd.arg_i64(1) {
+            Ok(2) => conn.set_protocol(Protocol::Resp2),
+            Ok(3) => conn.set_protocol(Protocol::Resp3),
+            Ok(_) => {
+                return conn
+                    .write_error("NOPROTO unsupported protocol version".to_string())
+                    .await
+            }
+            Err(_) => {
+                return conn
+                    .write_error(
+                        "ERR Protocol version is not an integer or out of range".to_string(),
+                    )
+                    .await
+            }
+        }
+    }
+
+    let proto = match conn.protocol() {
+        Protocol::Resp2 => 2,
+        Protocol::Resp3 => 3,
+    };
+    let mut fields = vec![
+        ("server".to_string(), Type::from(info.server.as_str())),
+        ("version".to_string(), Type::from(info.version.as_str())),
+        ("proto".to_string(), Type::Integer(proto)),
+        ("id".to_string(), Type::Integer(conn.id().as_u64() as i64)),
+    ];
+    fields.extend(info.fields.iter().cloned());
+    conn.write_map(fields).await
+}
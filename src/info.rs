@@ -0,0 +1,121 @@
+use std::fmt::Write;
+
+use crate::resp::Type;
+
+/// Application sections appended to the reply to `INFO`, see
+/// [`Builder::info`](crate::server::Builder::info).
+pub(crate) type InfoFn = dyn Fn(&mut InfoBuilder) + Send + Sync;
+
+/// Builds a reply in the format of Redis' `INFO`, which monitoring agents know how to parse:
+///
+/// ```text
+/// # Server
+/// redcon_version:0.1.0
+///
+/// # Clients
+/// connected_clients:2
+/// ```
+///
+/// Lines end with CRLF and sections are separated by an empty line.
+///
+/// ```
+/// use redcon::{InfoBuilder, Type};
+///
+/// let info = InfoBuilder::new()
+///     .section("Keyspace", [("db0", "keys=1,expires=0")])
+///     .build();
+/// assert_eq!(info, Type::from("# Keyspace\r\ndb0:keys=1,expires=0\r\n"));
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct InfoBuilder {
+    sections: Vec<(String, Vec<(String, String)>)>,
+}
+
+impl InfoBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a section, or adds the fields to the section with the same name if any.
+    pub fn section<I, K, V>(&mut self, name: &str, fields: I) -> &mut Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<String>,
+        V: ToString,
+    {
+        let fields = fields
+            .into_iter()
+            .map(|(key, value)| (key.into(), value.to_string()));
+        match self.sections.iter_mut().find(|(it, _)| it == name) {
+            Some((_, it)) => it.extend(fields),
+            None => self.sections.push((name.to_string(), fields.collect())),
+        }
+        self
+    }
+
+    /// Returns the reply holding all the sections.
+    pub fn build(&self) -> Type {
+        self.build_filtered::<&[u8]>(&[])
+    }
+
+    /// Returns the reply holding the sections asked for by the arguments of `INFO`, ignoring
+    /// ASCII case. No arguments or any of `all`, `everything` and `default` asks for all of
+    /// them.
+    pub fn build_filtered<S: AsRef<[u8]>>(&self, sections: &[S]) -> Type {
+        let all = sections.is_empty()
+            || sections.iter().any(|it| {
+                ["all", "everything", "default"]
+                    .iter()
+                    .any(|all| it.as_ref().eq_ignore_ascii_case(all.as_bytes()))
+            });
+        let mut info = String::new();
+        for (name, fields) in &self.sections {
+            if !all
+                && !sections
+                    .iter()
+                    .any(|it| it.as_ref().eq_ignore_ascii_case(name.as_bytes()))
+            {
+                continue;
+            }
+            if !info.is_empty() {
+                info.push_str("\r\n");
+            }
+            let _ = write!(info, "# {}\r\n", name);
+            for (key, value) in fields {
+                let _ = write!(info, "{}:{}\r\n", key, value);
+            }
+        }
+        Type::BulkString(info.into_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sections() {
+        let mut info = InfoBuilder::new();
+        info.section("Server", [("redcon_version", "0.1.0")])
+            .section("Clients", [("connected_clients", 2)])
+            .section("Server", [("uptime_in_seconds", 7)]);
+        assert_eq!(
+            info.build(),
+            Type::from(
+                "# Server\r\nredcon_version:0.1.0\r\nuptime_in_seconds:7\r\n\
+                 \r\n# Clients\r\nconnected_clients:2\r\n"
+            )
+        );
+
+        let filter = |sections: &[&str]| {
+            let sections: Vec<_> = sections.iter().map(|it| it.to_string()).collect();
+            info.build_filtered(&sections)
+        };
+        assert_eq!(
+            filter(&["clients"]),
+            Type::from("# Clients\r\nconnected_clients:2\r\n")
+        );
+        assert_eq!(filter(&["clients", "EVERYTHING"]), info.build());
+        assert_eq!(filter(&["keyspace"]), Type::from(""));
+    }
+}
@@ -1,5 +1,57 @@
+#[cfg(feature = "blocking")]
+pub mod blocking;
+mod bytes_type;
+pub mod cluster;
+#[cfg(feature = "codec")]
+pub mod codec;
+mod command;
 mod conn;
+mod conn_stats;
+mod error_kind;
+#[cfg(feature = "futures-io")]
+mod futures_io;
+mod handler;
+mod hello;
+mod info;
+mod metrics;
+mod middleware;
+mod output;
+pub mod proxy;
+mod rate_limit;
+#[cfg(feature = "redis-interop")]
+mod redis_interop;
+mod reply_mode;
 mod resp;
+mod router;
+mod select;
+pub mod server;
+#[cfg(feature = "tower")]
+pub mod service;
+mod slowlog;
+mod tap;
 
-pub use conn::{listen, Command, Conn};
-pub use resp::{Error, Type};
+pub use bytes_type::BytesType;
+pub use command::{Command, CommandError, Opts};
+pub use conn::{
+    listen, ArrayWriter, Capture, Conn, ConnId, Deferred, DisconnectReason, DrainOutcome,
+    FlushPolicy, StreamedArray, StreamedBulk, StreamedMap,
+};
+pub use conn_stats::ConnStats;
+pub use error_kind::ErrorKind;
+pub use handler::{Handler, SpawnBlocking};
+pub use hello::HelloInfo;
+pub use info::InfoBuilder;
+pub use metrics::{AtomicMetrics, Metrics, MetricsSnapshot};
+pub use middleware::Next;
+pub use output::OutputLimit;
+pub use rate_limit::{RateLimit, RateLimitPolicy};
+pub use reply_mode::ReplyMode;
+pub use resp::{
+    BulkSink, ConversionError, Error, Position, Protocol, ReadOptions, RespReader, Type, Utf8Policy,
+};
+pub use router::{Route, Router};
+pub use server::{
+    AcceptDecision, ConnInfo, MissingReplyPolicy, ProcessingMode, ProtocolErrorStyle, Server,
+};
+pub use slowlog::SlowlogEntry;
+pub use tap::{hexdump_tap, Direction};
@@ -0,0 +1,235 @@
+use std::convert::TryFrom;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, ReadBuf};
+
+use crate::conn::Conn;
+use crate::conn_stats::Counters;
+use crate::tap::Tap;
+
+/// Receives events from the server, e.g. to export them as metrics.
+///
+/// All methods do nothing by default. Servers without metrics don't pay for them, the events
+/// are only produced once a [`Metrics`] is set with
+/// [`Builder::metrics`](crate::server::Builder::metrics).
+pub trait Metrics: Send + Sync + 'static {
+    fn on_connection_opened(&self, _conn: &Conn) {}
+
+    fn on_connection_closed(&self, _conn: &Conn) {}
+
+    /// Called once the handler finishes handling a command, with the name of the command as
+    /// sent by the client. Commands handled in a batch share the duration of the batch.
+    fn on_command(&self, _name: &str, _duration: Duration) {}
+
+    /// Called with the number of bytes read from a socket.
+    fn on_bytes_read(&self, _n: usize) {}
+
+    /// Called with the number of bytes of a reply written to a connection.
+    fn on_bytes_written(&self, _n: usize) {}
+
+    /// Called when a client sends something that is not a valid command.
+    fn on_protocol_error(&self) {}
+
+    /// Called when a client closes its connection, including in the middle of a command.
+    fn on_client_disconnected(&self) {}
+
+    /// Called when reading from a socket fails, which closes the connection.
+    fn on_read_error(&self) {}
+
+    /// Called when a connection is closed for exceeding its output limit, see
+    /// [`Builder::output_limit`](crate::server::Builder::output_limit).
+    fn on_output_limit_exceeded(&self, _conn: &Conn) {}
+}
+
+/// Aggregates the events into counters that can be read with [`AtomicMetrics::snapshot`].
+#[derive(Debug, Default)]
+pub struct AtomicMetrics {
+    connections_opened: AtomicU64,
+    connections_closed: AtomicU64,
+    commands: AtomicU64,
+    command_nanos: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    protocol_errors: AtomicU64,
+    client_disconnects: AtomicU64,
+    read_errors: AtomicU64,
+    output_limit_disconnects: AtomicU64,
+}
+
+/// Values of the counters of an [`AtomicMetrics`] at some point.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    pub connections_opened: u64,
+    pub connections_closed: u64,
+    pub commands: u64,
+    /// Total time spent handling commands.
+    pub command_time: Duration,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub protocol_errors: u64,
+    /// Connections closed by their client.
+    pub client_disconnects: u64,
+    /// Connections closed as reading from their socket failed.
+    pub read_errors: u64,
+    /// Connections closed for exceeding their output limit.
+    pub output_limit_disconnects: u64,
+}
+
+impl MetricsSnapshot {
+    pub fn active_connections(&self) -> u64 {
+        self.connections_opened - self.connections_closed
+    }
+}
+
+impl AtomicMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            connections_opened: self.connections_opened.load(Ordering::Relaxed),
+            connections_closed: self.connections_closed.load(Ordering::Relaxed),
+            commands: self.commands.load(Ordering::Relaxed),
+            command_time: Duration::from_nanos(self.command_nanos.load(Ordering::Relaxed)),
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            protocol_errors: self.protocol_errors.load(Ordering::Relaxed),
+            client_disconnects: self.client_disconnects.load(Ordering::Relaxed),
+            read_errors: self.read_errors.load(Ordering::Relaxed),
+            output_limit_disconnects: self.output_limit_disconnects.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Metrics for AtomicMetrics {
+    fn on_connection_opened(&self, _conn: &Conn) {
+        self.connections_opened.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_connection_closed(&self, _conn: &Conn) {
+        self.connections_closed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_command(&self, _name: &str, duration: Duration) {
+        self.commands.fetch_add(1, Ordering::Relaxed);
+        let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+        self.command_nanos.fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    fn on_bytes_read(&self, n: usize) {
+        self.bytes_read.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    fn on_bytes_written(&self, n: usize) {
+        self.bytes_written.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    fn on_protocol_error(&self) {
+        self.protocol_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_client_disconnected(&self) {
+        self.client_disconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_read_error(&self) {
+        self.read_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_output_limit_exceeded(&self, _conn: &Conn) {
+        self.output_limit_disconnects
+            .fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl<M: Metrics> Metrics for Arc<M> {
+    fn on_connection_opened(&self, conn: &Conn) {
+        (**self).on_connection_opened(conn)
+    }
+
+    fn on_connection_closed(&self, conn: &Conn) {
+        (**self).on_connection_closed(conn)
+    }
+
+    fn on_command(&self, name: &str, duration: Duration) {
+        (**self).on_command(name, duration)
+    }
+
+    fn on_bytes_read(&self, n: usize) {
+        (**self).on_bytes_read(n)
+    }
+
+    fn on_bytes_written(&self, n: usize) {
+        (**self).on_bytes_written(n)
+    }
+
+    fn on_protocol_error(&self) {
+        (**self).on_protocol_error()
+    }
+
+    fn on_client_disconnected(&self) {
+        (**self).on_client_disconnected()
+    }
+
+    fn on_read_error(&self) {
+        (**self).on_read_error()
+    }
+
+    fn on_output_limit_exceeded(&self, conn: &Conn) {
+        (**self).on_output_limit_exceeded(conn)
+    }
+}
+
+/// Reports the bytes read from the inner reader.
+pub(crate) struct CountingReader<R> {
+    inner: R,
+    counters: Arc<Counters>,
+    metrics: Option<Arc<dyn Metrics>>,
+    tap: Option<Tap>,
+}
+
+impl<R> CountingReader<R> {
+    pub(crate) fn new(
+        inner: R,
+        counters: Arc<Counters>,
+        metrics: Option<Arc<dyn Metrics>>,
+        tap: Option<Tap>,
+    ) -> Self {
+        Self {
+            inner,
+            counters,
+            metrics,
+            tap,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for CountingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = &buf.filled()[filled..];
+        self.counters.read(read.len());
+        if let Some(metrics) = &self.metrics {
+            if !read.is_empty() {
+                metrics.on_bytes_read(read.len());
+            }
+        }
+        if let Some(tap) = &self.tap {
+            if !read.is_empty() {
+                tap.read(read);
+            }
+        }
+        res
+    }
+}
@@ -0,0 +1,83 @@
+use std::sync::Arc;
+
+use crate::command::Command;
+use crate::conn::Conn;
+use crate::handler::{boxed, BoxFuture, BoxHandler, Handler};
+
+pub(crate) type BoxMiddleware = Box<dyn Fn(Conn, Command, Next) -> BoxFuture + Send + Sync>;
+
+struct Chain {
+    middlewares: Vec<BoxMiddleware>,
+    handler: BoxHandler,
+}
+
+/// The rest of the chain a middleware passes the command to, see [`Builder::wrap`].
+///
+/// [`Builder::wrap`]: crate::server::Builder::wrap
+#[derive(Clone)]
+pub struct Next {
+    chain: Arc<Chain>,
+    index: usize,
+}
+
+impl Next {
+    /// Calls the next middleware, or the handler after the last one.
+    pub async fn run(self, conn: Conn, cmd: Command) {
+        match self.chain.middlewares.get(self.index) {
+            Some(middleware) => {
+                let next = Next {
+                    chain: Arc::clone(&self.chain),
+                    index: self.index + 1,
+                };
+                middleware(conn, cmd, next).await
+            }
+            None => (self.chain.handler)(conn, cmd).await,
+        }
+    }
+}
+
+/// Runs the commands through the middlewares before the handler.
+pub(crate) struct Wrapped<H> {
+    handler: Arc<H>,
+    /// `None` without middlewares, so the handler is called as is.
+    chain: Option<Arc<Chain>>,
+}
+
+impl<H: Handler> Wrapped<H> {
+    pub(crate) fn new(handler: H, middlewares: Vec<BoxMiddleware>) -> Self {
+        let handler = Arc::new(handler);
+        let chain = (!middlewares.is_empty()).then(|| {
+            Arc::new(Chain {
+                middlewares,
+                handler: boxed(Arc::clone(&handler)),
+            })
+        });
+        Self { handler, chain }
+    }
+}
+
+impl<H: Handler> Handler for Wrapped<H> {
+    async fn call(&self, conn: Conn, cmd: Command) {
+        match &self.chain {
+            Some(chain) => {
+                let next = Next {
+                    chain: Arc::clone(chain),
+                    index: 0,
+                };
+                next.run(conn, cmd).await
+            }
+            None => self.handler.call(conn, cmd).await,
+        }
+    }
+
+    /// Middlewares see the commands one at a time, so the handler's own batching is only
+    /// used without them.
+    async fn call_batch(&self, conn: Conn, cmds: Vec<Command>) {
+        if self.chain.is_none() {
+            return self.handler.call_batch(conn, cmds).await;
+        }
+        for cmd in cmds {
+            self.call(conn.clone(), cmd).await;
+        }
+    }
+}
@@ -0,0 +1,274 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use tokio::io::AsyncWrite;
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio_util::sync::{CancellationToken, WaitForCancellationFutureOwned};
+
+use crate::conn_stats::Counters;
+use crate::tap::Tap;
+
+/// Limits the output waiting to be sent to each connection, like Redis'
+/// `client-output-buffer-limit`.
+///
+/// The output is counted from when a reply is written to the connection until the socket
+/// takes it, so it includes the replies of handlers waiting for a client that doesn't read.
+/// A connection is closed once its output goes over the hard limit, or stays over the soft
+/// limit for longer than allowed.
+#[derive(Clone, Debug)]
+pub struct OutputLimit {
+    hard: usize,
+    soft: Option<(usize, Duration)>,
+}
+
+impl OutputLimit {
+    pub fn new(hard: usize) -> Self {
+        Self { hard, soft: None }
+    }
+
+    /// Also closes connections whose output stays over `soft` bytes for longer than `duration`.
+    pub fn soft(mut self, soft: usize, duration: Duration) -> Self {
+        self.soft = Some((soft, duration));
+        self
+    }
+}
+
+/// Output of a connection, shared between the connection and its socket.
+#[derive(Debug)]
+pub(crate) struct Output {
+    limit: Option<OutputLimit>,
+    /// Bytes written to the connection that the socket didn't take yet.
+    pending: AtomicUsize,
+    /// Since when the output is over the soft limit.
+    over_soft_since: Mutex<Option<Instant>>,
+    /// Set by the first caller to see the limit exceeded.
+    reported: AtomicBool,
+    /// Cancelled once the limit is exceeded, failing the writes stuck on the socket.
+    exceeded: CancellationToken,
+}
+
+impl Output {
+    pub(crate) fn new(limit: Option<OutputLimit>) -> Self {
+        Self {
+            limit,
+            pending: AtomicUsize::new(0),
+            over_soft_since: Mutex::new(None),
+            reported: AtomicBool::new(false),
+            exceeded: CancellationToken::new(),
+        }
+    }
+
+    pub(crate) fn is_limited(&self) -> bool {
+        self.limit.is_some()
+    }
+
+    pub(crate) fn is_exceeded(&self) -> bool {
+        self.exceeded.is_cancelled()
+    }
+
+    /// Returns `true` the first time it is called once the limit is exceeded, so it is only
+    /// reported once.
+    pub(crate) fn first_exceeded(&self) -> bool {
+        self.is_exceeded() && !self.reported.swap(true, Ordering::Relaxed)
+    }
+
+    pub(crate) fn pending(&self) -> usize {
+        self.pending.load(Ordering::Relaxed)
+    }
+
+    /// Counts `n` more bytes of output, returns `false` if that exceeds the limit.
+    pub(crate) fn reserve(&self, n: usize, now: Instant) -> bool {
+        let limit = match &self.limit {
+            Some(limit) => limit,
+            None => return true,
+        };
+        if self.is_exceeded() {
+            return false;
+        }
+        let pending = self.pending.fetch_add(n, Ordering::Relaxed) + n;
+        let mut exceeded = pending > limit.hard;
+        if let Some((soft, duration)) = limit.soft {
+            let mut since = self.over_soft_since.lock().unwrap();
+            if pending <= soft {
+                *since = None;
+            } else if now.duration_since(*since.get_or_insert(now)) > duration {
+                exceeded = true;
+            }
+        }
+        if exceeded {
+            self.exceeded.cancel();
+        }
+        !exceeded
+    }
+
+    fn sent(&self, n: usize) {
+        let limit = match &self.limit {
+            Some(limit) => limit,
+            None => return,
+        };
+        let pending = match self
+            .pending
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |it| {
+                Some(it.saturating_sub(n))
+            }) {
+            Ok(it) | Err(it) => it.saturating_sub(n),
+        };
+        // Draining under the soft limit resets the timer, even without more output.
+        if let Some((soft, _)) = limit.soft {
+            if pending <= soft {
+                *self.over_soft_since.lock().unwrap() = None;
+            }
+        }
+    }
+}
+
+/// The socket of a connection, counting the output it takes.
+pub(crate) struct OutputWriter {
+    inner: OwnedWriteHalf,
+    output: Arc<Output>,
+    exceeded: Pin<Box<WaitForCancellationFutureOwned>>,
+    counters: Arc<Counters>,
+    tap: Option<Tap>,
+}
+
+impl OutputWriter {
+    pub(crate) fn new(
+        inner: OwnedWriteHalf,
+        output: Arc<Output>,
+        counters: Arc<Counters>,
+        tap: Option<Tap>,
+    ) -> Self {
+        let exceeded = Box::pin(output.exceeded.clone().cancelled_owned());
+        Self {
+            inner,
+            output,
+            exceeded,
+            counters,
+            tap,
+        }
+    }
+
+    /// Fails once the limit is exceeded, even if the socket is waiting for the client.
+    fn poll_exceeded(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        match self.exceeded.as_mut().poll(cx) {
+            Poll::Ready(()) => Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "output buffer limit exceeded",
+            )),
+            Poll::Pending => Ok(()),
+        }
+    }
+}
+
+impl std::fmt::Debug for OutputWriter {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("OutputWriter")
+            .field("inner", &self.inner)
+            .field("output", &self.output)
+            .finish_non_exhaustive()
+    }
+}
+
+impl AsyncWrite for OutputWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        this.poll_exceeded(cx)?;
+        let res = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            this.output.sent(n);
+            this.counters.written(n);
+            if let Some(tap) = &this.tap {
+                tap.written(&buf[..n]);
+            }
+        }
+        res
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        this.poll_exceeded(cx)?;
+        let res = Pin::new(&mut this.inner).poll_write_vectored(cx, bufs);
+        if let Poll::Ready(Ok(n)) = res {
+            this.output.sent(n);
+            this.counters.written(n);
+            if let Some(tap) = &this.tap {
+                // Only the first `n` bytes of the slices were written.
+                let mut left = n;
+                for buf in bufs {
+                    let len = buf.len().min(left);
+                    if len > 0 {
+                        tap.written(&buf[..len]);
+                    }
+                    left -= len;
+                }
+            }
+        }
+        res
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.poll_exceeded(cx)?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits() {
+        let output = Output::new(None);
+        assert!(output.reserve(usize::MAX, Instant::now()));
+
+        let output = Output::new(Some(OutputLimit::new(100)));
+        assert!(output.reserve(60, Instant::now()));
+        output.sent(60);
+        assert!(output.reserve(60, Instant::now()));
+        assert!(!output.reserve(60, Instant::now()));
+        assert!(output.is_exceeded());
+        assert!(!output.reserve(0, Instant::now()));
+
+        let limit = OutputLimit::new(100).soft(10, Duration::from_secs(5));
+        let output = Output::new(Some(limit.clone()));
+        let start = Instant::now();
+        assert!(output.reserve(20, start));
+        assert!(output.reserve(20, start + Duration::from_secs(4)));
+        // Going under the soft limit resets the timer.
+        output.sent(40);
+        assert!(output.reserve(5, start + Duration::from_secs(4)));
+        assert!(output.reserve(20, start + Duration::from_secs(6)));
+        assert!(output.reserve(0, start + Duration::from_secs(11)));
+        assert!(!output.reserve(0, start + Duration::from_secs(12)));
+
+        // Draining under the soft limit resets the timer without another write.
+        let output = Output::new(Some(limit));
+        assert!(output.reserve(20, start));
+        output.sent(20);
+        assert!(output.reserve(20, start + Duration::from_secs(60)));
+        assert!(output.reserve(0, start + Duration::from_secs(64)));
+        assert!(!output.reserve(0, start + Duration::from_secs(66)));
+    }
+}
@@ -0,0 +1,128 @@
+//! Helpers for servers forwarding commands to an upstream server, e.g. a proxy in front of
+//! Redis.
+//!
+//! ```no_run
+//! # async fn run() -> anyhow::Result<()> {
+//! use std::sync::Arc;
+//!
+//! use redcon::proxy::{self, Client};
+//! use redcon::{Command, Conn, Server};
+//! use tokio::sync::Mutex;
+//!
+//! let upstream = Arc::new(Mutex::new(Client::connect("127.0.0.1:6379").await?));
+//! Server::builder()
+//!     .bind("127.0.0.1:6380")
+//!     .run(move |conn: Conn, cmd: Command| {
+//!         let upstream = Arc::clone(&upstream);
+//!         async move {
+//!             let mut upstream = upstream.lock().await;
+//!             if let Err(err) = proxy::forward(&conn, &mut upstream, cmd.into()).await {
+//!                 eprintln!("could not forward command: {}", err);
+//!             }
+//!         }
+//!     })
+//!     .await
+//! # }
+//! ```
+
+use std::fmt;
+use std::io;
+
+use anyhow::Result;
+use tokio::io::{AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+use crate::conn::Conn;
+use crate::resp::{Error, ReadOptions, RespReader, Type, Utf8Policy};
+
+/// The upstream server closed the connection, or it broke, so the [`Client`] can't be used
+/// anymore. Found with `downcast_ref` on the errors of [`Client`] and [`forward`].
+#[derive(Debug)]
+pub struct Disconnected;
+
+impl fmt::Display for Disconnected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "upstream disconnected")
+    }
+}
+
+impl std::error::Error for Disconnected {}
+
+/// A connection to an upstream server, sending one command at a time.
+#[derive(Debug)]
+pub struct Client {
+    read: RespReader<BufReader<OwnedReadHalf>>,
+    write: BufWriter<OwnedWriteHalf>,
+}
+
+impl Client {
+    pub async fn connect(addr: impl ToSocketAddrs) -> Result<Self> {
+        let (read, write) = TcpStream::connect(addr).await?.into_split();
+        // Replies are forwarded as they are, whatever they contain.
+        let options = ReadOptions::new().utf8_policy(Utf8Policy::Lossy);
+        Ok(Self {
+            read: RespReader::with_options(BufReader::new(read), options),
+            write: BufWriter::new(write),
+        })
+    }
+
+    /// Sends the command and returns the reply.
+    pub async fn call(&mut self, cmd: &Type) -> Result<Type> {
+        Ok(self.call_raw(cmd).await?.0)
+    }
+
+    /// Sends the command and returns the reply along with the bytes it was read from.
+    async fn call_raw(&mut self, cmd: &Type) -> Result<(Type, Vec<u8>)> {
+        self.send(cmd).await?;
+        self.read_raw().await
+    }
+
+    async fn send(&mut self, cmd: &Type) -> Result<()> {
+        let sent = async {
+            cmd.write_to(&mut self.write).await?;
+            self.write.flush().await?;
+            Ok(())
+        };
+        sent.await.map_err(disconnected)
+    }
+
+    async fn read_raw(&mut self) -> Result<(Type, Vec<u8>)> {
+        self.read.read_raw().await.map_err(disconnected)
+    }
+}
+
+/// Reports errors of the connection itself as [`Disconnected`].
+fn disconnected(err: Error) -> anyhow::Error {
+    let closed = match &err {
+        Error::Io(err) => matches!(
+            err.kind(),
+            io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::BrokenPipe
+        ),
+        err => matches!(err, Error::UnexpectedEof),
+    };
+    if closed {
+        anyhow::Error::from(err).context(Disconnected)
+    } else {
+        err.into()
+    }
+}
+
+/// Sends the command upstream and writes the reply back to the connection byte for byte.
+///
+/// RESP3 pushes read before the reply, e.g. invalidation messages when client tracking is on,
+/// are not taken for it: they are passed on with [`Conn::write_push`].
+///
+/// Fails with [`Disconnected`] if the upstream connection is gone, the caller can then connect
+/// again and retry, or reply with an error.
+pub async fn forward(conn: &Conn, upstream: &mut Client, cmd: Type) -> Result<()> {
+    upstream.send(&cmd).await?;
+    loop {
+        match upstream.read_raw().await? {
+            (Type::Push(elements), _) => conn.write_push(elements).await?,
+            (_, raw) => return conn.write_encoded(&raw).await,
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use std::time::{Duration, Instant};
+
+/// Limits the rate of commands accepted from each connection.
+///
+/// Each connection gets its own token bucket holding up to `burst` tokens, refilled at
+/// `per_second` tokens a second. Every command takes a token.
+#[derive(Clone, Debug)]
+pub struct RateLimit {
+    per_second: u32,
+    burst: u32,
+    policy: RateLimitPolicy,
+}
+
+/// What to do with a command once a connection is out of tokens.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RateLimitPolicy {
+    /// Waits for a token before reading further commands, so the client is slowed down by
+    /// TCP backpressure.
+    #[default]
+    Delay,
+    /// Replies with `ERR rate limit exceeded` without calling the handler, and closes the
+    /// connection after `close_after` consecutive rejected commands if set.
+    Reject { close_after: Option<u32> },
+}
+
+impl RateLimit {
+    pub fn new(per_second: u32, burst: u32) -> Self {
+        assert!(per_second > 0, "rate must be positive");
+        assert!(burst > 0, "burst must hold at least one command");
+        Self {
+            per_second,
+            burst,
+            policy: RateLimitPolicy::default(),
+        }
+    }
+
+    /// Sets what to do with the commands over the limit, defaults to
+    /// [`RateLimitPolicy::Delay`].
+    pub fn policy(mut self, policy: RateLimitPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub(crate) fn get_policy(&self) -> RateLimitPolicy {
+        self.policy
+    }
+}
+
+/// Token bucket state of a single connection.
+pub(crate) struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(limit: &RateLimit) -> Self {
+        Self {
+            rate: f64::from(limit.per_second),
+            burst: f64::from(limit.burst),
+            tokens: f64::from(limit.burst),
+            refilled_at: Instant::now(),
+        }
+    }
+
+    /// Takes a token, or returns how long to wait until one is available.
+    pub(crate) fn acquire(&mut self, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.saturating_duration_since(self.refilled_at);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.burst);
+        self.refilled_at = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_bucket() {
+        let mut bucket = TokenBucket::new(&RateLimit::new(10, 2));
+        let now = bucket.refilled_at;
+
+        assert!(bucket.acquire(now).is_ok());
+        assert!(bucket.acquire(now).is_ok());
+        let wait = bucket.acquire(now).unwrap_err();
+        assert_eq!(wait, Duration::from_millis(100));
+
+        assert!(bucket.acquire(now + Duration::from_millis(100)).is_ok());
+        assert!(bucket.acquire(now + Duration::from_millis(100)).is_err());
+
+        // The bucket doesn't fill past the burst size.
+        let later = now + Duration::from_secs(10);
+        assert!(bucket.acquire(later).is_ok());
+        assert!(bucket.acquire(later).is_ok());
+        assert!(bucket.acquire(later).is_err());
+    }
+}
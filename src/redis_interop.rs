@@ -0,0 +1,115 @@
+//! Conversions to and from [`redis::Value`], enabled by the `redis-interop` feature.
+//!
+//! Values both sides can represent convert losslessly. The rest are mapped as follows:
+//!
+//! - `Type::SimpleString("OK")` becomes `Value::Okay`, as the `redis` crate parses `+OK`
+//!   into it, and `Value::Okay` becomes `Type::SimpleString("OK")`.
+//! - `Type::Error` becomes a `Value::Status` with the message, errors have no `Value`.
+//! - `Type::BulkString` becomes `Value::Data` like `Type::BulkString`, which of the two comes
+//!   back depends on whether the data is valid UTF-8.
+//! - `Type::Boolean` becomes a `Value::Int` of 1 or 0, as RESP2 sends it.
+//! - `Type::BigNumber` becomes a `Value::Data` of its digits, as RESP2 sends it.
+//! - `Type::Verbatim` becomes a `Value::Data` of its text, the format is dropped.
+//! - `Type::Push` becomes a `Value::Bulk`, as RESP2 sends it.
+//! - `Type::Map` becomes a `Value::Bulk` of its keys and values in turn, as RESP2 sends it.
+//! - `Type::Attribute` becomes its value, the attributes are dropped.
+
+use redis::Value;
+
+use crate::resp::Type;
+
+impl From<Type> for Value {
+    fn from(ty: Type) -> Self {
+        match ty {
+            Type::SimpleString(s) if s == "OK" => Value::Okay,
+            Type::SimpleString(s) | Type::Error(s) => Value::Status(s),
+            Type::Integer(n) => Value::Int(n),
+            Type::Boolean(b) => Value::Int(i64::from(b)),
+            Type::BigNumber(s) => Value::Data(s.into_bytes()),
+            Type::BulkString(bytes) => Value::Data(bytes),
+            Type::Verbatim { text, .. } => Value::Data(text.into_bytes()),
+            Type::Null => Value::Nil,
+            Type::Array(elements) | Type::Push(elements) => {
+                Value::Bulk(elements.into_iter().map(Value::from).collect())
+            }
+            Type::Map(pairs) => Value::Bulk(
+                pairs
+                    .into_iter()
+                    .flat_map(|(k, v)| [Value::from(k), Value::from(v)])
+                    .collect(),
+            ),
+            Type::Attribute { value, .. } => Value::from(*value),
+        }
+    }
+}
+
+/// Every [`Value`] has a counterpart, so `Type::try_from(value)` never fails either.
+impl From<Value> for Type {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Nil => Type::Null,
+            Value::Int(n) => Type::Integer(n),
+            Value::Data(bytes) => Type::BulkString(bytes),
+            Value::Bulk(values) => Type::Array(values.into_iter().map(Type::from).collect()),
+            Value::Status(s) => Type::SimpleString(s),
+            Value::Okay => Type::SimpleString("OK".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        let types = vec![
+            Type::SimpleString("PONG".to_string()),
+            Type::SimpleString("OK".to_string()),
+            Type::Integer(-42),
+            Type::BulkString(b"value".to_vec()),
+            Type::BulkString(vec![0xff, 0x00]),
+            Type::Null,
+            Type::Array(vec![
+                Type::Integer(1),
+                Type::Array(vec![Type::from("nested"), Type::Null]),
+            ]),
+        ];
+        for ty in types {
+            assert_eq!(Type::from(Value::from(ty.clone())), ty);
+        }
+
+        let values = vec![
+            Value::Nil,
+            Value::Int(7),
+            Value::Data(b"caf\xe9".to_vec()),
+            Value::Bulk(vec![Value::Okay, Value::Status("QUEUED".to_string())]),
+        ];
+        for value in values {
+            assert_eq!(Value::from(Type::from(value.clone())), value);
+        }
+    }
+
+    #[test]
+    fn lossy_conversions() {
+        assert_eq!(
+            Value::from(Type::Error("ERR oops".to_string())),
+            Value::Status("ERR oops".to_string())
+        );
+        assert_eq!(
+            Value::from(Type::BulkString(b"utf-8".to_vec())),
+            Value::Data(b"utf-8".to_vec())
+        );
+        assert_eq!(
+            Value::from(Type::Map(vec![(Type::from("key"), Type::Integer(1))])),
+            Value::Bulk(vec![Value::Data(b"key".to_vec()), Value::Int(1)])
+        );
+        assert_eq!(
+            Value::from(Type::Attribute {
+                attrs: vec![(Type::from("ttl"), Type::Integer(3600))],
+                value: Box::new(Type::from("value")),
+            }),
+            Value::Data(b"value".to_vec())
+        );
+    }
+}
@@ -0,0 +1,63 @@
+use anyhow::Result;
+
+use crate::command::Command;
+use crate::conn::Conn;
+
+/// Whether the replies to a connection are sent, see [`Conn::set_reply_mode`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReplyMode {
+    #[default]
+    On,
+    /// Drops all the replies.
+    Off,
+    /// Drops the replies to the next command, then switches back to [`ReplyMode::On`].
+    Skip,
+}
+
+impl ReplyMode {
+    pub(crate) fn to_u8(self) -> u8 {
+        match self {
+            ReplyMode::On => 0,
+            ReplyMode::Off => 1,
+            ReplyMode::Skip => 2,
+        }
+    }
+
+    pub(crate) fn from_u8(n: u8) -> Self {
+        match n {
+            1 => ReplyMode::Off,
+            2 => ReplyMode::Skip,
+            _ => ReplyMode::On,
+        }
+    }
+}
+
+/// Returns whether the command is `CLIENT REPLY`.
+pub(crate) fn is_client_reply(cmd: &Command) -> bool {
+    cmd.is("client")
+        && cmd
+            .arg(1)
+            .is_some_and(|it| it.eq_ignore_ascii_case(b"reply"))
+}
+
+/// Switches the reply mode as asked, only `CLIENT REPLY ON` gets a reply like in Redis.
+pub(crate) async fn client_reply(conn: &Conn, cmd: &Command) -> Result<()> {
+    if cmd.len() != 3 {
+        return conn
+            .write_error("ERR wrong number of arguments for 'client|reply' command".to_string())
+            .await;
+    }
+    let mode = &cmd[2];
+    if mode.eq_ignore_ascii_case(b"on") {
+        conn.set_reply_mode(ReplyMode::On);
+        conn.write_ok().await
+    } else if mode.eq_ignore_ascii_case(b"off") {
+        conn.set_reply_mode(ReplyMode::Off);
+        Ok(())
+    } else if mode.eq_ignore_ascii_case(b"skip") {
+        conn.set_reply_mode(ReplyMode::Skip);
+        Ok(())
+    } else {
+        conn.write_error("ERR syntax error".to_string()).await
+    }
+}
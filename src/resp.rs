@@ -1,15 +1,80 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::fmt;
+use std::future::Future;
+use std::io::{self, IoSlice};
+use std::mem;
+use std::ops::Range;
+use std::pin::pin;
+use std::str;
+use std::task::{Context, Poll, Waker};
 
-use anyhow::{anyhow, bail, Result};
-use async_recursion::async_recursion;
 use tokio::io::{
-    AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter,
+    copy_buf, sink, AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite,
+    AsyncWriteExt, BufReader, BufWriter,
 };
 
+/// Why a value couldn't be read or written, the error of [`Type::read`], [`Type::write`] and
+/// [`RespReader`].
+///
+/// Apart from [`Error::UnexpectedEof`], where the peer is gone, [`Error::ValueTooLarge`],
+/// after which the next value can still be read, and [`Error::Io`], these are protocol
+/// errors: what follows can't be trusted.
 #[derive(Debug)]
 pub enum Error {
+    /// The reader ended before the end of the value.
     UnexpectedEof,
+    /// A line or a bulk payload not followed by CRLF.
     ExpectedLine,
+    /// An integer that is not an optional `-` followed by digits.
+    InvalidInteger,
+    /// An integer that doesn't fit into `i64`.
+    IntegerOverflow,
+    /// A negative bulk string or array length other than -1.
+    InvalidLength,
+    /// A line that is not valid UTF-8, unless read with [`Utf8Policy::Lossy`].
+    InvalidUtf8,
+    /// Arrays nested deeper than allowed, see [`ReadOptions::max_depth`].
+    NestingTooDeep,
+    /// A line starting with a byte that is not a known type prefix.
+    UnknownType(u8),
+    /// A bulk string longer than allowed, see [`ReadOptions::max_bulk_len`].
+    BulkTooLong,
+    /// An aggregate with more elements than allowed, see [`ReadOptions::max_array_len`].
+    ArrayTooLong,
+    /// A line longer than allowed, see [`ReadOptions::max_line_len`].
+    LineTooLong,
+    /// A boolean other than `#t` or `#f`.
+    InvalidBoolean,
+    /// A null other than `_`.
+    InvalidNull,
+    /// A streamed bulk string with a line other than a `;` chunk header.
+    ExpectedChunk,
+    /// A line starting with `.` other than the end marker of a streamed aggregate.
+    InvalidEndMarker,
+    /// An inline command with a quoted argument that is not closed, or not followed by a space.
+    UnbalancedQuotes,
+    /// A verbatim string without a three byte format and a `:` before its text, or with text
+    /// that is not valid UTF-8.
+    InvalidVerbatim,
+    /// A bulk string longer than the application allows, see [`ReadOptions::max_value_len`].
+    ///
+    /// The rest of the frame is read and discarded, so the next one can still be read.
+    ValueTooLarge,
+    /// The underlying reader or writer failed.
+    Io(io::Error),
+}
+
+impl From<io::Error> for Error {
+    /// Reports the reader ending early as [`Error::UnexpectedEof`], like the reader ending
+    /// between frames.
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::UnexpectedEof => Error::UnexpectedEof,
+            _ => Error::Io(err),
+        }
+    }
 }
 
 impl fmt::Display for Error {
@@ -17,195 +82,3435 @@ impl fmt::Display for Error {
         match *self {
             Error::UnexpectedEof => write!(f, "unexpected eof"),
             Error::ExpectedLine => write!(f, "expected line"),
+            Error::InvalidInteger => write!(f, "invalid integer"),
+            Error::IntegerOverflow => write!(f, "integer overflow"),
+            Error::InvalidLength => write!(f, "invalid length"),
+            Error::InvalidUtf8 => write!(f, "invalid utf-8"),
+            Error::NestingTooDeep => write!(f, "nesting too deep"),
+            Error::UnknownType(byte) => write!(f, "unknown type '{}'", escape(&[byte])),
+            Error::BulkTooLong => write!(f, "bulk string too long"),
+            Error::ArrayTooLong => write!(f, "array too long"),
+            Error::LineTooLong => write!(f, "line too long"),
+            Error::InvalidBoolean => write!(f, "invalid boolean"),
+            Error::InvalidNull => write!(f, "invalid null"),
+            Error::ExpectedChunk => write!(f, "expected string chunk"),
+            Error::InvalidEndMarker => write!(f, "invalid end marker"),
+            Error::UnbalancedQuotes => write!(f, "unbalanced quotes in inline command"),
+            Error::InvalidVerbatim => write!(f, "invalid verbatim string"),
+            Error::ValueTooLarge => write!(f, "value too large"),
+            Error::Io(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl Error {
+    /// Returns whether the peer sent something that is not RESP, as opposed to the peer going
+    /// away, a value being rejected or the socket failing. Servers reply to those with
+    /// `-ERR Protocol error` and close the connection.
+    pub fn is_protocol_error(&self) -> bool {
+        !matches!(
+            self,
+            Error::UnexpectedEof | Error::ValueTooLarge | Error::Io(_)
+        )
+    }
+}
+
+/// Maximum number of bytes shown in a [`Position`] snippet.
+const SNIPPET_LEN: usize = 32;
+
+/// Where a read failed, see [`RespReader::error_position`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Position {
+    /// Offset of the offending bytes from the start of the stream.
+    pub offset: u64,
+    /// The offending bytes, escaped and capped at 32 bytes.
+    pub snippet: String,
+}
+
+impl Position {
+    fn new(offset: u64, bytes: &[u8]) -> Self {
+        Self {
+            offset,
+            snippet: snippet(bytes),
+        }
+    }
+}
+
+/// Escapes the bytes, capped at [`SNIPPET_LEN`].
+fn snippet(bytes: &[u8]) -> String {
+    let mut snippet = escape(&bytes[..bytes.len().min(SNIPPET_LEN)]);
+    if bytes.len() > SNIPPET_LEN {
+        snippet.push_str("...");
+    }
+    snippet
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "at byte {} near \"{}\"", self.offset, self.snippet)
+    }
+}
+
+/// Describes a read error with its position if known, e.g.
+/// `unknown type '!' at byte 5 near "!x\r\n"`.
+pub(crate) fn describe(err: &Error, pos: Option<&Position>) -> String {
+    match pos {
+        Some(pos) => format!("{} {}", err, pos),
+        None => err.to_string(),
+    }
+}
+
+/// Describes a read error the way Redis does in its `-ERR Protocol error: ...` replies, e.g.
+/// `invalid multibulk length` or `unbalanced quotes in request`.
+///
+/// Redis reads bulk strings inside arrays only, so which message applies depends on the
+/// prefix of the offending line. Errors Redis can't run into are described without position.
+pub(crate) fn redis_reason(err: &Error, pos: Option<&Position>) -> String {
+    let prefix = pos.and_then(|pos| pos.snippet.bytes().next());
+    let reason = match (err, prefix) {
+        (Error::UnbalancedQuotes, _) => "unbalanced quotes in request",
+        (Error::LineTooLong, Some(b'*')) => "too big mbulk count string",
+        (Error::LineTooLong, Some(b'$')) => "too big bulk count string",
+        (Error::LineTooLong, _) => "too big inline request",
+        (
+            Error::InvalidInteger
+            | Error::IntegerOverflow
+            | Error::InvalidLength
+            | Error::ArrayTooLong,
+            Some(b'*'),
+        ) => "invalid multibulk length",
+        (
+            Error::InvalidInteger
+            | Error::IntegerOverflow
+            | Error::InvalidLength
+            | Error::BulkTooLong,
+            Some(b'$'),
+        ) => "invalid bulk length",
+        (Error::UnknownType(byte), _) => {
+            return format!("expected '$', got '{}'", escape(&[*byte]))
+        }
+        (Error::NestingTooDeep, Some(byte)) => {
+            return format!("expected '$', got '{}'", escape(&[byte]))
+        }
+        _ => return err.to_string(),
+    };
+    reason.to_string()
+}
+
+/// Why reading a frame failed, for servers that react to each case differently.
+#[derive(Debug)]
+pub(crate) enum ReadFailure {
+    /// The peer closed the connection, possibly in the middle of a frame.
+    Closed,
+    /// The peer sent something that is not RESP.
+    Protocol(Error, Option<Position>),
+    /// The socket failed.
+    Io(io::Error),
+    /// The frame was read whole but rejected, the peer can keep sending frames.
+    Rejected(Error, Option<Position>),
+}
+
+impl ReadFailure {
+    fn new(err: Error, pos: Option<Position>) -> Self {
+        match err {
+            Error::UnexpectedEof => ReadFailure::Closed,
+            Error::ValueTooLarge => ReadFailure::Rejected(err, pos),
+            Error::Io(err) => ReadFailure::Io(err),
+            err => ReadFailure::Protocol(err, pos),
+        }
+    }
+}
+
+/// Runs a future that never has to wait for a wakeup, e.g. one reading from a slice.
+pub(crate) fn block_on<F: Future>(fut: F) -> F::Output {
+    let mut fut = pin!(fut);
+    match fut.as_mut().poll(&mut Context::from_waker(Waker::noop())) {
+        Poll::Ready(output) => output,
+        Poll::Pending => unreachable!("the future is always ready"),
+    }
+}
+
+/// Parses an integer as specified by RESP: an optional `-` followed by one or more digits.
+pub(crate) fn parse_integer(buf: &[u8]) -> Result<i64, Error> {
+    let (negative, digits) = match buf.split_first() {
+        Some((b'-', digits)) => (true, digits),
+        _ => (false, buf),
+    };
+    if digits.is_empty() {
+        return Err(Error::InvalidInteger);
+    }
+
+    let mut n: i64 = 0;
+    for &digit in digits {
+        if !digit.is_ascii_digit() {
+            return Err(Error::InvalidInteger);
+        }
+        let digit = i64::from(digit - b'0');
+        // Accumulating negative numbers as negative lets `i64::MIN` be parsed.
+        n = n
+            .checked_mul(10)
+            .and_then(|n| {
+                if negative {
+                    n.checked_sub(digit)
+                } else {
+                    n.checked_add(digit)
+                }
+            })
+            .ok_or(Error::IntegerOverflow)?;
+    }
+    Ok(n)
+}
+
+/// Checks a big number like [`parse_integer`] does, without a limit on the number of digits.
+fn check_big_number(buf: &[u8]) -> Result<(), Error> {
+    let digits = buf.strip_prefix(b"-").unwrap_or(buf);
+    if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+        return Err(Error::InvalidInteger);
+    }
+    Ok(())
+}
+
+/// Parses the length of a bulk string or an array, `None` stands for the null value.
+pub(crate) fn parse_length(buf: &[u8]) -> Result<Option<usize>, Error> {
+    match parse_integer(buf)? {
+        -1 => Ok(None),
+        n => usize::try_from(n)
+            .map(Some)
+            .map_err(|_| Error::InvalidLength),
+    }
+}
+
+/// Splits an inline command into its arguments the way Redis does: on whitespace, with
+/// arguments in double quotes supporting escapes like `\n` and `\x41`, and arguments in single
+/// quotes supporting only `\'`.
+fn split_inline(line: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+    let hex = |b: u8| (b as char).to_digit(16).map(|it| it as u8);
+    let mut args = Vec::new();
+    let mut i = 0;
+    loop {
+        while line.get(i).is_some_and(u8::is_ascii_whitespace) {
+            i += 1;
+        }
+        if i == line.len() {
+            return Ok(args);
+        }
+        let mut arg = Vec::new();
+        // The quote the argument is in, if any.
+        let mut quote = None;
+        loop {
+            let b = match (line.get(i), quote) {
+                (None, None) => break,
+                (None, Some(_)) => return Err(Error::UnbalancedQuotes),
+                (Some(&b), _) => b,
+            };
+            i += 1;
+            match (quote, b) {
+                (None, b) if b.is_ascii_whitespace() => break,
+                (None, b'"' | b'\'') => quote = Some(b),
+                (None, b) => arg.push(b),
+                (Some(q), b) if b == q => {
+                    // A closing quote must end the argument.
+                    if line.get(i).is_some_and(|it| !it.is_ascii_whitespace()) {
+                        return Err(Error::UnbalancedQuotes);
+                    }
+                    break;
+                }
+                (Some(b'"'), b'\\') => {
+                    let byte = match line.get(i..i + 3) {
+                        Some([b'x', hi, lo]) => hex(*hi).zip(hex(*lo)).map(|(hi, lo)| hi << 4 | lo),
+                        _ => None,
+                    };
+                    if let Some(byte) = byte {
+                        arg.push(byte);
+                        i += 3;
+                        continue;
+                    }
+                    arg.push(match line.get(i) {
+                        Some(b'n') => b'\n',
+                        Some(b'r') => b'\r',
+                        Some(b't') => b'\t',
+                        Some(b'b') => 0x08,
+                        Some(b'a') => 0x07,
+                        Some(&b) => b,
+                        None => return Err(Error::UnbalancedQuotes),
+                    });
+                    i += 1;
+                }
+                (Some(b'\''), b'\\') if line.get(i) == Some(&b'\'') => {
+                    arg.push(b'\'');
+                    i += 1;
+                }
+                (Some(_), b) => arg.push(b),
+            }
+        }
+        args.push(arg);
+    }
+}
+
+/// Part of an encoded frame.
+enum Segment<'a> {
+    /// Tags, lengths and line endings, stored in the encoder's buffer.
+    Buf(Range<usize>),
+    /// Payload borrowed from the value being encoded.
+    Payload(&'a [u8]),
+}
+
+impl<'a> Segment<'a> {
+    fn bytes<'b>(&'b self, buf: &'b [u8]) -> &'b [u8] {
+        match self {
+            Self::Buf(range) => &buf[range.clone()],
+            Self::Payload(payload) => payload,
+        }
+    }
+}
+
+/// A line holding an integer or a length, e.g. `*3\r\n`, formatted on the stack as nearly
+/// every frame has one.
+pub(crate) struct Header {
+    /// The tag, the sign and the 19 digits of the longest `i64`, then the line ending.
+    buf: [u8; 23],
+    start: usize,
+}
+
+impl Header {
+    pub(crate) fn new(tag: u8, n: i64) -> Self {
+        let mut buf = [0; 23];
+        let mut start = buf.len() - 2;
+        buf[start..].copy_from_slice(b"\r\n");
+        // Two digits at a time, like `itoa` does.
+        const PAIRS: &[u8; 200] = b"\
+            0001020304050607080910111213141516171819\
+            2021222324252627282930313233343536373839\
+            4041424344454647484950515253545556575859\
+            6061626364656667686970717273747576777879\
+            8081828384858687888990919293949596979899";
+        let mut abs = n.unsigned_abs();
+        while abs >= 10 {
+            let pair = (abs % 100) as usize * 2;
+            abs /= 100;
+            start -= 2;
+            buf[start..start + 2].copy_from_slice(&PAIRS[pair..pair + 2]);
         }
+        if start == buf.len() - 2 || abs > 0 {
+            start -= 1;
+            buf[start] = b'0' + abs as u8;
+        }
+        if n < 0 {
+            start -= 1;
+            buf[start] = b'-';
+        }
+        start -= 1;
+        buf[start] = tag;
+        Self { buf, start }
+    }
+
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        &self.buf[self.start..]
     }
 }
 
-impl std::error::Error for Error {}
+/// Encodes frames into a list of segments that can be written with a single vectored write.
+#[derive(Default)]
+struct Encoder<'a> {
+    buf: Vec<u8>,
+    segments: Vec<Segment<'a>>,
+    /// Start of the bytes in `buf` that are not covered by a segment yet.
+    pending: usize,
+    /// Nulls are written as `_` rather than `$-1` for RESP3.
+    protocol: Protocol,
+}
+
+impl<'a> Encoder<'a> {
+    /// Payloads smaller than this are copied into the buffer instead of getting their own
+    /// segment, as a slice per tiny payload costs more than the copy.
+    const INLINE_PAYLOAD_LEN: usize = 64;
+
+    /// Encodes the value depth first with an explicit stack, so deeply nested values don't
+    /// overflow the call stack.
+    fn encode(&mut self, ty: &'a Type) {
+        // Values left to encode, the next one on top.
+        let mut stack = vec![ty];
+        let pairs =
+            |pairs: &'a [(Type, Type)]| pairs.iter().rev().flat_map(|(key, value)| [value, key]);
+        while let Some(ty) = stack.pop() {
+            match ty {
+                Type::SimpleString(s) => self.line(b'+', s.as_bytes()),
+                Type::Error(s) => self.line(b'-', s.as_bytes()),
+                Type::Integer(n) => self.header(b':', *n),
+                Type::Boolean(true) => self.buf.extend_from_slice(b"#t\r\n"),
+                Type::Boolean(false) => self.buf.extend_from_slice(b"#f\r\n"),
+                Type::BigNumber(s) => self.line(b'(', s.as_bytes()),
+                Type::BulkString(bytes) => self.bulk(bytes),
+                Type::Verbatim { format, text } => {
+                    self.header(b'=', (format.len() + 1 + text.len()) as i64);
+                    self.buf.extend_from_slice(format.as_bytes());
+                    self.buf.push(b':');
+                    self.payload(text.as_bytes());
+                    self.buf.extend_from_slice(b"\r\n");
+                }
+                Type::Array(elements) => {
+                    self.header(b'*', elements.len() as i64);
+                    stack.extend(elements.iter().rev());
+                }
+                Type::Push(elements) => {
+                    self.header(b'>', elements.len() as i64);
+                    stack.extend(elements.iter().rev());
+                }
+                Type::Map(map) => {
+                    self.header(b'%', map.len() as i64);
+                    stack.extend(pairs(map));
+                }
+                Type::Attribute { attrs, value } => {
+                    self.header(b'|', attrs.len() as i64);
+                    stack.push(value);
+                    stack.extend(pairs(attrs));
+                }
+                Type::Null if self.protocol == Protocol::Resp3 => {
+                    self.buf.extend_from_slice(b"_\r\n")
+                }
+                Type::Null => self.header(b'$', -1),
+            }
+        }
+    }
+
+    fn bulk(&mut self, payload: &'a [u8]) {
+        self.header(b'$', payload.len() as i64);
+        self.payload(payload);
+        self.buf.extend_from_slice(b"\r\n");
+    }
+
+    /// Copies a line holding an integer or a length into the buffer.
+    fn header(&mut self, tag: u8, n: i64) {
+        self.buf.extend_from_slice(Header::new(tag, n).as_bytes());
+    }
+
+    /// Like `header`, but borrows the line if it is big enough.
+    fn line(&mut self, tag: u8, line: &'a [u8]) {
+        self.buf.push(tag);
+        self.payload(line);
+        self.buf.extend_from_slice(b"\r\n");
+    }
+
+    fn payload(&mut self, payload: &'a [u8]) {
+        if payload.len() < Self::INLINE_PAYLOAD_LEN {
+            self.buf.extend_from_slice(payload);
+            return;
+        }
+        self.flush_pending();
+        self.segments.push(Segment::Payload(payload));
+    }
+
+    fn flush_pending(&mut self) {
+        if self.pending < self.buf.len() {
+            self.segments
+                .push(Segment::Buf(self.pending..self.buf.len()));
+            self.pending = self.buf.len();
+        }
+    }
+
+    fn finish(mut self) -> (Vec<u8>, Vec<Segment<'a>>) {
+        self.flush_pending();
+        (self.buf, self.segments)
+    }
+}
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Type {
     SimpleString(String),
     Error(String),
     Integer(i64),
-    BulkString(String),
+    /// A boolean, only available on RESP3 connections. RESP2 clients get `1` or `0`.
+    Boolean(bool),
+    /// The decimal digits of an integer out of the range of [`Type::Integer`], with an optional
+    /// `-`. Only available on RESP3 connections, RESP2 clients get a bulk string.
+    BigNumber(String),
+    /// A binary safe string, whose text if it is valid UTF-8 is returned by [`Type::as_str`].
+    BulkString(Vec<u8>),
+    /// Text meant to be shown as it is, e.g. the report of `LATENCY DOCTOR`, only available on
+    /// RESP3 connections. RESP2 clients get a bulk string of the text.
+    Verbatim {
+        /// Three bytes telling what the text is, `txt` for plain text or `mkd` for markdown.
+        format: String,
+        text: String,
+    },
+    /// Written as `$-1`, or as `_` by [`Conn`](crate::Conn) on RESP3 connections. Both are read.
     Null,
     Array(Vec<Type>),
+    /// An out-of-band message, e.g. a message published to a channel or an invalidation of
+    /// client side caching, only available on RESP3 connections. RESP2 clients get an array.
+    Push(Vec<Type>),
+    /// Key/value pairs in order, only available on RESP3 connections.
+    Map(Vec<(Type, Type)>),
+    /// A value with out-of-band metadata attached, only available on RESP3 connections.
+    Attribute {
+        attrs: Vec<(Type, Type)>,
+        value: Box<Type>,
+    },
 }
 
-impl Type {
-    pub async fn write(self, dst: impl AsyncWrite + Unpin + Send) -> Result<()> {
-        let mut dst = BufWriter::new(dst);
-        self.write_buf(&mut dst).await?;
-        dst.flush().await?;
-        Ok(())
+/// Generates values that can be written and read back as they are, e.g. simple strings
+/// without line breaks. Nesting and collection sizes are bounded so the values stay small.
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for Type {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        arbitrary_type(u, ARBITRARY_DEPTH)
     }
+}
 
-    #[async_recursion]
-    async fn write_buf(self, dst: &mut BufWriter<impl AsyncWrite + Unpin + Send>) -> Result<()> {
-        async fn write_line(
-            dst: &mut BufWriter<impl AsyncWrite + Unpin + Send>,
-            tag: u8,
-            buf: &[u8],
-        ) -> Result<()> {
-            dst.write_u8(tag).await?;
-            dst.write_all(buf).await?;
-            dst.write_all(&[b'\r', b'\n']).await?;
-            Ok(())
-        }
+#[cfg(feature = "arbitrary")]
+const ARBITRARY_DEPTH: usize = 4;
 
-        match self {
-            Self::SimpleString(s) => {
-                write_line(dst, b'+', s.as_bytes()).await?;
-            }
-            Self::Error(s) => {
-                write_line(dst, b'-', s.as_bytes()).await?;
-            }
-            Self::Integer(n) => {
-                write_line(dst, b':', n.to_string().as_bytes()).await?;
-            }
-            Self::BulkString(s) => {
-                let buf = s.as_bytes();
+#[cfg(feature = "arbitrary")]
+const ARBITRARY_LEN: usize = 8;
 
-                write_line(dst, b'$', buf.len().to_string().as_bytes()).await?;
+#[cfg(feature = "arbitrary")]
+fn arbitrary_type(u: &mut arbitrary::Unstructured, depth: usize) -> arbitrary::Result<Type> {
+    let line = |u: &mut arbitrary::Unstructured| -> arbitrary::Result<String> {
+        let mut s: String = u.arbitrary()?;
+        s.retain(|c| c != '\r' && c != '\n');
+        Ok(s)
+    };
+    let pairs = |u: &mut arbitrary::Unstructured| -> arbitrary::Result<Vec<(Type, Type)>> {
+        (0..u.int_in_range(0..=ARBITRARY_LEN)?)
+            .map(|_| Ok((arbitrary_type(u, depth - 1)?, arbitrary_type(u, depth - 1)?)))
+            .collect()
+    };
 
-                dst.write_all(buf).await?;
-                dst.write_all(&[b'\r', b'\n']).await?;
-            }
-            Self::Array(elements) => {
-                write_line(dst, b'*', elements.len().to_string().as_bytes()).await?;
+    // Aggregates are only generated while there is depth left.
+    let variants = if depth == 0 { 9 } else { 13 };
+    Ok(match u.choose_index(variants)? {
+        0 => Type::SimpleString(line(u)?),
+        1 => Type::Error(line(u)?),
+        2 => Type::Integer(u.arbitrary()?),
+        3 => Type::Boolean(u.arbitrary()?),
+        4 => Type::BigNumber(u.arbitrary::<i128>()?.to_string()),
+        5 => Type::BulkString(u.arbitrary::<String>()?.into_bytes()),
+        6 => Type::BulkString(u.arbitrary()?),
+        7 => Type::Verbatim {
+            format: u.choose(&["txt", "mkd"])?.to_string(),
+            text: u.arbitrary()?,
+        },
+        8 => Type::Null,
+        9 => Type::Array(
+            (0..u.int_in_range(0..=ARBITRARY_LEN)?)
+                .map(|_| arbitrary_type(u, depth - 1))
+                .collect::<arbitrary::Result<_>>()?,
+        ),
+        10 => Type::Push(
+            (0..u.int_in_range(0..=ARBITRARY_LEN)?)
+                .map(|_| arbitrary_type(u, depth - 1))
+                .collect::<arbitrary::Result<_>>()?,
+        ),
+        11 => Type::Map(pairs(u)?),
+        _ => Type::Attribute {
+            attrs: pairs(u)?,
+            value: Box::new(arbitrary_type(u, depth - 1)?),
+        },
+    })
+}
+
+/// Formats the value the way `redis-cli` does, e.g. `(integer) 42` or a numbered list for an
+/// array. Meant for humans, bulk strings longer than 256 bytes are cut short.
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        pretty(f, self, 0)
+    }
+}
 
-                for elem in elements {
-                    elem.write_buf(dst).await?;
+/// Bulk strings are cut after this many bytes when displayed.
+const DISPLAY_BULK_LEN: usize = 256;
+
+/// Writes the value, with the lines after the first one indented by `indent` spaces.
+fn pretty(f: &mut fmt::Formatter, ty: &Type, indent: usize) -> fmt::Result {
+    match ty {
+        Type::SimpleString(s) => write!(f, "{}", s),
+        Type::Error(s) => write!(f, "(error) {}", s),
+        Type::Integer(n) => write!(f, "(integer) {}", n),
+        Type::Boolean(b) => write!(f, "({})", b),
+        Type::BigNumber(s) => write!(f, "(big number) {}", s),
+        Type::BulkString(bytes) => quoted(f, bytes),
+        Type::Verbatim { text, .. } => write!(f, "{}", text),
+        Type::Null => write!(f, "(nil)"),
+        Type::Array(elements) | Type::Push(elements) if elements.is_empty() => {
+            write!(f, "(empty array)")
+        }
+        Type::Array(elements) | Type::Push(elements) => {
+            let width = elements.len().to_string().len();
+            for (i, elem) in elements.iter().enumerate() {
+                if i > 0 {
+                    write!(f, "\n{:indent$}", "", indent = indent)?;
                 }
+                write!(f, "{:>width$}) ", i + 1, width = width)?;
+                pretty(f, elem, indent + width + 2)?;
             }
-            Self::Null => {
-                write_line(dst, b'$', b"-1").await?;
+            Ok(())
+        }
+        Type::Map(pairs) if pairs.is_empty() => write!(f, "(empty hash)"),
+        Type::Map(pairs) => {
+            let width = pairs.len().to_string().len();
+            for (i, (key, value)) in pairs.iter().enumerate() {
+                if i > 0 {
+                    write!(f, "\n{:indent$}", "", indent = indent)?;
+                }
+                write!(f, "{:>width$}# ", i + 1, width = width)?;
+                pretty(f, key, indent + width + 2)?;
+                write!(f, " => ")?;
+                pretty(f, value, indent + width + 2)?;
             }
+            Ok(())
         }
-        Ok(())
+        // Attributes are out-of-band metadata, only the value is shown.
+        Type::Attribute { value, .. } => pretty(f, value, indent),
+    }
+}
+
+/// Writes the bytes quoted, escaping the non-printable ones.
+fn quoted(f: &mut fmt::Formatter, bytes: &[u8]) -> fmt::Result {
+    write!(f, "\"")?;
+    for &b in bytes.iter().take(DISPLAY_BULK_LEN) {
+        write_escaped(f, b)?;
     }
+    if bytes.len() > DISPLAY_BULK_LEN {
+        write!(f, "...\" ({} bytes)", bytes.len())
+    } else {
+        write!(f, "\"")
+    }
+}
 
-    #[async_recursion]
-    pub async fn read(src: &mut (impl AsyncBufRead + Unpin + Send)) -> Result<Self> {
-        async fn read_line(src: &mut (impl AsyncBufRead + Unpin + Send)) -> Result<String> {
-            let mut buf = vec![];
-            match src.read_until(b'\n', &mut buf).await {
-                Ok(0) => bail!(Error::UnexpectedEof),
-                Ok(_) => (),
-                Err(err) => bail!(err),
-            };
+fn write_escaped(f: &mut impl fmt::Write, b: u8) -> fmt::Result {
+    match b {
+        b'\\' => write!(f, "\\\\"),
+        b'"' => write!(f, "\\\""),
+        b'\n' => write!(f, "\\n"),
+        b'\r' => write!(f, "\\r"),
+        b'\t' => write!(f, "\\t"),
+        b' '..=b'~' => write!(f, "{}", b as char),
+        _ => write!(f, "\\x{:02x}", b),
+    }
+}
 
-            let len = buf.len();
-            if len < 2 || buf[(len - 2)..] != [b'\r', b'\n'] {
-                bail!(Error::ExpectedLine)
-            }
+/// Escapes the bytes like [`quoted`] does, without the quotes.
+pub(crate) fn escape(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len());
+    for &b in bytes {
+        // Writing to a `String` can't fail.
+        let _ = write_escaped(&mut s, b);
+    }
+    s
+}
+
+/// Version of the protocol spoken on a connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Protocol {
+    #[default]
+    Resp2,
+    Resp3,
+}
+
+impl From<String> for Type {
+    fn from(s: String) -> Self {
+        Type::BulkString(s.into_bytes())
+    }
+}
+
+impl From<&str> for Type {
+    fn from(s: &str) -> Self {
+        Type::BulkString(s.as_bytes().to_vec())
+    }
+}
+
+impl From<i64> for Type {
+    fn from(n: i64) -> Self {
+        Type::Integer(n)
+    }
+}
+
+impl From<bool> for Type {
+    fn from(b: bool) -> Self {
+        Type::Boolean(b)
+    }
+}
 
-            // FIXME: use from_utf8_lossy?
-            String::from_utf8(buf[..(len - 2)].into())
-                .map_err(|err| anyhow!("expected utf-8: {}", err))
+impl<T: Into<Type>> From<Option<T>> for Type {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Type::Null, Into::into)
+    }
+}
+
+impl<T: Into<Type>> From<Vec<T>> for Type {
+    fn from(elements: Vec<T>) -> Self {
+        Type::Array(elements.into_iter().map(Into::into).collect())
+    }
+}
+
+impl From<Vec<(String, String)>> for Type {
+    fn from(pairs: Vec<(String, String)>) -> Self {
+        Type::Map(
+            pairs
+                .into_iter()
+                .map(|(key, value)| (key.into(), value.into()))
+                .collect(),
+        )
+    }
+}
+
+/// Why a [`Type`] couldn't be converted into a Rust type with `TryFrom`, e.g.
+/// `expected an integer, found "abc"`.
+///
+/// Attributes are skipped, the value they decorate is converted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConversionError {
+    expected: &'static str,
+    found: String,
+}
+
+impl ConversionError {
+    fn new(expected: &'static str, found: &Type) -> Self {
+        let found = match found {
+            Type::SimpleString(s) => format!("\"{}\"", snippet(s.as_bytes())),
+            Type::BulkString(bytes) => format!("\"{}\"", snippet(bytes)),
+            Type::Error(_) => "an error".to_string(),
+            Type::Integer(n) => format!("integer {}", n),
+            Type::Boolean(b) => format!("boolean {}", b),
+            Type::BigNumber(_) => "a big number".to_string(),
+            Type::Verbatim { .. } => "a verbatim string".to_string(),
+            Type::Null => "null".to_string(),
+            Type::Array(_) => "an array".to_string(),
+            Type::Push(_) => "a push message".to_string(),
+            Type::Map(_) => "a map".to_string(),
+            Type::Attribute { .. } => "an attribute".to_string(),
+        };
+        Self { expected, found }
+    }
+}
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "expected {}, found {}", self.expected, self.found)
+    }
+}
+
+impl std::error::Error for ConversionError {}
+
+/// Returns the value decorated by attributes, if any.
+fn without_attrs(mut ty: Type) -> Type {
+    while let Type::Attribute { value, .. } = ty {
+        ty = *value;
+    }
+    ty
+}
+
+/// Accepts simple, bulk and verbatim strings, and big numbers.
+impl TryFrom<Type> for String {
+    type Error = ConversionError;
+
+    fn try_from(ty: Type) -> Result<Self, Self::Error> {
+        match without_attrs(ty) {
+            Type::SimpleString(s) | Type::BigNumber(s) => Ok(s),
+            Type::BulkString(bytes) => String::from_utf8(bytes).map_err(|err| {
+                ConversionError::new("a string", &Type::BulkString(err.into_bytes()))
+            }),
+            Type::Verbatim { text, .. } => Ok(text),
+            ty => Err(ConversionError::new("a string", &ty)),
         }
+    }
+}
 
-        let line = read_line(src).await?;
+/// Accepts integers, and strings holding one like command arguments do.
+impl TryFrom<Type> for i64 {
+    type Error = ConversionError;
 
-        match line.as_bytes().first() {
-            // FIXME: is str to String allocates?
-            Some(b'+') => Ok(Self::SimpleString(line[1..].into())),
-            Some(b'-') => Ok(Self::Error(line[1..].into())),
-            Some(b':') => Ok(Self::Integer(line[1..].parse()?)),
-            Some(b'$') => {
-                if line == "$-1" {
-                    return Ok(Self::Null);
-                }
+    fn try_from(ty: Type) -> Result<Self, Self::Error> {
+        match without_attrs(ty) {
+            Type::Integer(n) => Ok(n),
+            Type::SimpleString(s) => parse_integer(s.as_bytes())
+                .map_err(|_| ConversionError::new("an integer", &Type::SimpleString(s))),
+            Type::BulkString(bytes) => parse_integer(&bytes)
+                .map_err(|_| ConversionError::new("an integer", &Type::BulkString(bytes))),
+            ty => Err(ConversionError::new("an integer", &ty)),
+        }
+    }
+}
 
-                let len: usize = line[1..].parse()?;
-                let mut buf = vec![0; len + 2];
-                src.read_exact(&mut buf).await?;
+/// Accepts integers, and strings holding a number other than NaN like the replies of
+/// `INCRBYFLOAT` do.
+impl TryFrom<Type> for f64 {
+    type Error = ConversionError;
 
-                if buf[len..] != [b'\r', b'\n'] {
-                    bail!(Error::ExpectedLine)
+    fn try_from(ty: Type) -> Result<Self, Self::Error> {
+        match without_attrs(ty) {
+            Type::Integer(n) => Ok(n as f64),
+            ty @ (Type::SimpleString(_) | Type::BulkString(_)) => {
+                match ty.as_str().map(str::parse::<f64>) {
+                    Some(Ok(n)) if !n.is_nan() => Ok(n),
+                    _ => Err(ConversionError::new("a float", &ty)),
                 }
-
-                Ok(Self::BulkString(String::from_utf8(buf[..len].into())?))
             }
-            Some(b'*') => {
-                if line == "*-1" {
-                    return Ok(Self::Null);
-                }
+            ty => Err(ConversionError::new("a float", &ty)),
+        }
+    }
+}
 
-                let len: usize = line[1..].parse()?;
-                let mut res = Vec::with_capacity(len);
-                for _ in 0..len {
-                    res.push(Self::read(src).await?);
-                }
+/// Accepts booleans, and the `1` and `0` RESP2 connections get instead.
+impl TryFrom<Type> for bool {
+    type Error = ConversionError;
+
+    fn try_from(ty: Type) -> Result<Self, Self::Error> {
+        match without_attrs(ty) {
+            Type::Boolean(b) => Ok(b),
+            Type::Integer(1) => Ok(true),
+            Type::Integer(0) => Ok(false),
+            ty => Err(ConversionError::new("a boolean", &ty)),
+        }
+    }
+}
+
+/// Accepts arrays and push messages of strings, failing on the first element that isn't one.
+impl TryFrom<Type> for Vec<String> {
+    type Error = ConversionError;
 
-                Ok(Self::Array(res))
+    fn try_from(ty: Type) -> Result<Self, Self::Error> {
+        match without_attrs(ty) {
+            Type::Array(elements) | Type::Push(elements) => {
+                elements.into_iter().map(String::try_from).collect()
             }
-            _ => bail!("unknown type"),
+            ty => Err(ConversionError::new("an array", &ty)),
         }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use tokio::io::{duplex, BufReader};
+/// Converts null into `None`, and anything else like [`String`] does.
+impl TryFrom<Type> for Option<String> {
+    type Error = ConversionError;
 
-    use super::*;
+    fn try_from(ty: Type) -> Result<Self, Self::Error> {
+        match without_attrs(ty) {
+            Type::Null => Ok(None),
+            ty => String::try_from(ty).map(Some),
+        }
+    }
+}
 
-    macro_rules! type_tests {
-        ($($str:expr => $ty:expr,)*) => {
-            #[tokio::test]
-            async fn read() -> Result<()> {
-                $(
-                    assert_eq!(
-                        Type::read(&mut $str.to_vec().as_slice()).await?,
-                        $ty
-                    );
-                )*
-                Ok(())
-            }
+/// Accepts maps with string keys, and arrays of alternating keys and values like the RESP2
+/// replies to `HGETALL`. Later values replace earlier ones of the same key.
+impl TryFrom<Type> for HashMap<String, Type> {
+    type Error = ConversionError;
 
-            #[tokio::test]
-            async fn write() -> Result<()> {
-                $(
-                    let mut buf = vec![];
-                    $ty.write(&mut buf).await?;
-                    assert_eq!(buf, $str);
-                )*
-                Ok(())
+    fn try_from(ty: Type) -> Result<Self, Self::Error> {
+        match without_attrs(ty) {
+            Type::Map(pairs) => pairs
+                .into_iter()
+                .map(|(key, value)| Ok((String::try_from(key)?, value)))
+                .collect(),
+            Type::Array(elements) if elements.len() % 2 == 0 => {
+                let mut map = HashMap::with_capacity(elements.len() / 2);
+                let mut elements = elements.into_iter();
+                while let (Some(key), Some(value)) = (elements.next(), elements.next()) {
+                    map.insert(String::try_from(key)?, value);
+                }
+                Ok(map)
             }
+            ty => Err(ConversionError::new("a map", &ty)),
+        }
+    }
+}
 
-            #[tokio::test]
-            async fn write_and_read() -> Result<()> {
-                let (mut write, read) = duplex(8096);
-                let mut read = BufReader::new(read);
-                $(
-                    $ty.clone().write(&mut write).await?;
-                    assert_eq!(Type::read(&mut read).await?, $ty);
-                )*
-                Ok(())
-            }
+impl Type {
+    /// Returns the string of a [`Type::SimpleString`], or of a [`Type::BulkString`] that is
+    /// valid UTF-8.
+    #[inline]
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Type::SimpleString(s) => Some(s),
+            Type::BulkString(bytes) => str::from_utf8(bytes).ok(),
+            _ => None,
         }
     }
 
-    type_tests! {
-        b"+hello world\r\n" => Type::SimpleString("hello world".to_string()),
-        b"-error message\r\n" => Type::Error("error message".to_string()),
-        b":1000\r\n" => Type::Integer(1000),
-        b"$11\r\nhello world\r\n" => Type::BulkString("hello world".to_string()),
-        b"$-1\r\n" => Type::Null,
-        b"*2\r\n+hello world\r\n$11\r\nhello world\r\n" => Type::Array(vec![
-            Type::SimpleString("hello world".to_string()),
-            Type::BulkString("hello world".to_string()),
-        ]),
+    /// Returns the payload of a [`Type::BulkString`].
+    #[inline]
+    pub fn as_bulk(&self) -> Option<&[u8]> {
+        match self {
+            Type::BulkString(bytes) => Some(bytes),
+            _ => None,
+        }
     }
 
-    #[tokio::test]
-    async fn null_array() -> Result<()> {
-        assert_eq!(
-            Type::read(&mut b"*-1\r\n".to_vec().as_slice()).await?,
+    /// Returns the value of a [`Type::Integer`].
+    #[inline]
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Type::Integer(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    /// Returns the elements of a [`Type::Array`].
+    #[inline]
+    pub fn as_array(&self) -> Option<&[Type]> {
+        match self {
+            Type::Array(elements) => Some(elements),
+            _ => None,
+        }
+    }
+
+    /// Returns the message of a [`Type::Error`].
+    #[inline]
+    pub fn as_error(&self) -> Option<&str> {
+        match self {
+            Type::Error(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Returns whether the value is [`Type::Null`].
+    #[inline]
+    pub fn is_null(&self) -> bool {
+        matches!(self, Type::Null)
+    }
+
+    /// Returns the element at the index of a [`Type::Array`], `None` for other values.
+    #[inline]
+    pub fn get(&self, i: usize) -> Option<&Type> {
+        self.as_array()?.get(i)
+    }
+
+    /// Converts the value to what RESP2 clients get in place of RESP3 types, the same as
+    /// Redis does: pushes become arrays, maps become flat arrays of alternating keys and
+    /// values, booleans become `1` or `0`, big numbers and verbatim strings become bulk
+    /// strings, and attributes are dropped leaving the value they decorate. Nested values are
+    /// converted as well.
+    pub fn to_resp2(mut self) -> Type {
+        // Values left to convert.
+        let mut stack = vec![&mut self];
+        while let Some(ty) = stack.pop() {
+            match ty {
+                Type::Map(pairs) => {
+                    let elements = mem::take(pairs)
+                        .into_iter()
+                        .flat_map(|(key, value)| [key, value])
+                        .collect();
+                    *ty = Type::Array(elements);
+                    stack.push(ty);
+                }
+                Type::Attribute { value, .. } => {
+                    let value = mem::replace(&mut **value, Type::Null);
+                    *ty = value;
+                    stack.push(ty);
+                }
+                Type::Boolean(b) => *ty = Type::Integer(i64::from(*b)),
+                Type::BigNumber(s) => *ty = Type::BulkString(mem::take(s).into_bytes()),
+                Type::Verbatim { text, .. } => *ty = Type::BulkString(mem::take(text).into_bytes()),
+                Type::Push(elements) => {
+                    *ty = Type::Array(mem::take(elements));
+                    stack.push(ty);
+                }
+                Type::Array(elements) => stack.extend(elements),
+                _ => {}
+            }
+        }
+        self
+    }
+
+    /// Returns whether the value is made of RESP2 types only, i.e. [`Type::to_resp2`] would
+    /// leave it as it is.
+    pub(crate) fn is_resp2(&self) -> bool {
+        let mut stack = vec![self];
+        while let Some(ty) = stack.pop() {
+            match ty {
+                Type::Push(_)
+                | Type::Map(_)
+                | Type::Attribute { .. }
+                | Type::Boolean(_)
+                | Type::BigNumber(_)
+                | Type::Verbatim { .. } => return false,
+                Type::Array(elements) => stack.extend(elements),
+                _ => {}
+            }
+        }
+        true
+    }
+
+    /// Writes the value and flushes it, buffering the writes so an unbuffered destination like
+    /// a socket gets few of them. See [`Type::write_to`] for destinations that are buffered
+    /// already.
+    pub async fn write(&self, dst: impl AsyncWrite + Unpin + Send) -> Result<(), Error> {
+        let mut dst = BufWriter::new(dst);
+        self.write_to(&mut dst).await?;
+        dst.flush().await?;
+        Ok(())
+    }
+
+    /// Returns the number of bytes the value is encoded into, without encoding it, e.g. to
+    /// check it against a limit before writing it.
+    pub fn encoded_len(&self) -> usize {
+        self.encoded_len_for(Protocol::Resp2)
+    }
+
+    /// Like [`Type::encoded_len`], for the encoding of the given protocol.
+    pub(crate) fn encoded_len_for(&self, protocol: Protocol) -> usize {
+        fn digits(mut n: u64) -> usize {
+            let mut digits = 1;
+            while n >= 10 {
+                n /= 10;
+                digits += 1;
+            }
+            digits
+        }
+        // A tag, a length and CRLF.
+        let header = |len: usize| 1 + digits(len as u64) + 2;
+        fn pairs(pairs: &[(Type, Type)]) -> impl Iterator<Item = &Type> {
+            pairs.iter().flat_map(|(key, value)| [key, value])
+        }
+
+        // Walks the values like the encoder does, so deeply nested values don't overflow the
+        // call stack.
+        let mut len = 0;
+        let mut stack = vec![self];
+        while let Some(ty) = stack.pop() {
+            len += match ty {
+                Type::SimpleString(s) | Type::Error(s) | Type::BigNumber(s) => 1 + s.len() + 2,
+                Type::Integer(n) => 1 + usize::from(*n < 0) + digits(n.unsigned_abs()) + 2,
+                Type::Boolean(_) => 4,
+                Type::BulkString(bytes) => header(bytes.len()) + bytes.len() + 2,
+                Type::Verbatim { format, text } => {
+                    let len = format.len() + 1 + text.len();
+                    header(len) + len + 2
+                }
+                Type::Null if protocol == Protocol::Resp3 => 3,
+                Type::Null => 5,
+                Type::Array(elements) | Type::Push(elements) => {
+                    stack.extend(elements);
+                    header(elements.len())
+                }
+                Type::Map(map) => {
+                    stack.extend(pairs(map));
+                    header(map.len())
+                }
+                Type::Attribute { attrs, value } => {
+                    stack.push(value);
+                    stack.extend(pairs(attrs));
+                    header(attrs.len())
+                }
+            };
+        }
+        len
+    }
+
+    /// Encodes the value into a new buffer.
+    #[cfg(test)]
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        self.to_bytes_for(Protocol::Resp2)
+    }
+
+    /// Encodes the value into a new buffer, writing nulls as `_` for RESP3.
+    pub(crate) fn to_bytes_for(&self, protocol: Protocol) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.encoded_len_for(protocol));
+        let mut encoder = Encoder {
+            protocol,
+            ..Encoder::default()
+        };
+        encoder.encode(self);
+        let (encoded, segments) = encoder.finish();
+        for segment in &segments {
+            buf.extend_from_slice(segment.bytes(&encoded));
+        }
+        buf
+    }
+
+    /// Appends the encoded value to `buf`, for writers that buffer frames themselves.
+    ///
+    /// This is the encoder behind [`Type::write_to`], [`Type::write`] and [`Conn`], so the
+    /// bytes are the same as theirs. See [`Type::write_to`] to write into an [`AsyncWrite`]
+    /// instead.
+    ///
+    /// [`Conn`]: crate::Conn
+    pub fn encode_to(&self, buf: &mut Vec<u8>) {
+        buf.reserve(self.encoded_len());
+        self.encode_with(|bytes| buf.extend_from_slice(bytes));
+    }
+
+    /// Encodes the value, passing the encoded bytes to `f` in order.
+    pub(crate) fn encode_with(&self, mut f: impl FnMut(&[u8])) {
+        let mut encoder = Encoder::default();
+        encoder.encode(self);
+        let (encoded, segments) = encoder.finish();
+        for segment in &segments {
+            f(segment.bytes(&encoded));
+        }
+    }
+
+    /// Writes the value without buffering nor flushing it, returns the number of bytes
+    /// written. Meant for destinations that are buffered already, e.g. a [`BufWriter`], or
+    /// that frame the bytes themselves, like a stream of a custom transport.
+    ///
+    /// Bulk payloads are written straight from the value with vectored writes when the
+    /// destination supports them, so big payloads are not copied into the buffer.
+    pub async fn write_to(
+        &self,
+        dst: &mut (impl AsyncWrite + Unpin + Send),
+    ) -> Result<usize, Error> {
+        self.write_to_for(dst, Protocol::Resp2).await
+    }
+
+    /// Like [`Type::write_to`], writing nulls as `_` for RESP3.
+    pub(crate) async fn write_to_for(
+        &self,
+        dst: &mut (impl AsyncWrite + Unpin + Send),
+        protocol: Protocol,
+    ) -> Result<usize, Error> {
+        let mut encoder = Encoder {
+            protocol,
+            ..Encoder::default()
+        };
+        encoder.encode(self);
+        let (buf, segments) = encoder.finish();
+        let len = segments.iter().map(|it| it.bytes(&buf).len()).sum();
+
+        if dst.is_write_vectored() {
+            let mut slices: Vec<_> = segments
+                .iter()
+                .map(|segment| IoSlice::new(segment.bytes(&buf)))
+                .collect();
+            let mut slices = &mut slices[..];
+            while !slices.is_empty() {
+                let n = dst.write_vectored(slices).await?;
+                if n == 0 {
+                    return Err(io::Error::from(io::ErrorKind::WriteZero).into());
+                }
+                IoSlice::advance_slices(&mut slices, n);
+            }
+        } else {
+            for segment in &segments {
+                dst.write_all(segment.bytes(&buf)).await?;
+            }
+        }
+        Ok(len)
+    }
+
+    pub async fn read(src: &mut (impl AsyncBufRead + Unpin + Send)) -> Result<Self, Error> {
+        RespReader::new(src).read().await
+    }
+
+    /// Reads a value like [`Type::read`], with the given options.
+    pub async fn read_with(
+        src: &mut (impl AsyncBufRead + Unpin + Send),
+        options: ReadOptions,
+    ) -> Result<Self, Error> {
+        RespReader::with_options(src, options).read().await
+    }
+
+    /// Reads a value like [`Type::read`], handing big bulk strings to the sink, see
+    /// [`RespReader::read_streaming`].
+    pub async fn read_streaming(
+        src: &mut (impl AsyncBufRead + Unpin + Send),
+        threshold: usize,
+        sink: &mut impl BulkSink,
+    ) -> Result<Self, Error> {
+        RespReader::new(src).read_streaming(threshold, sink).await
+    }
+}
+
+/// Options controlling how values are read.
+#[derive(Clone, Debug)]
+pub struct ReadOptions {
+    lenient_line_endings: bool,
+    utf8_policy: Utf8Policy,
+    pub(crate) max_depth: usize,
+    max_bulk_len: usize,
+    pub(crate) max_array_len: usize,
+    pub(crate) max_value_len: usize,
+    max_line_len: usize,
+    /// `None` until set, see [`ReadOptions::for_commands`].
+    inline_commands: Option<bool>,
+}
+
+impl Default for ReadOptions {
+    fn default() -> Self {
+        Self {
+            lenient_line_endings: false,
+            utf8_policy: Utf8Policy::default(),
+            max_depth: DEFAULT_MAX_DEPTH,
+            max_bulk_len: DEFAULT_MAX_BULK_LEN,
+            max_array_len: DEFAULT_MAX_ARRAY_LEN,
+            max_value_len: usize::MAX,
+            max_line_len: DEFAULT_MAX_LINE_LEN,
+            inline_commands: None,
+        }
+    }
+}
+
+const DEFAULT_MAX_DEPTH: usize = 64;
+
+/// The same as Redis' `proto-max-bulk-len`.
+const DEFAULT_MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+
+/// The same as Redis' limit on the length of arrays.
+const DEFAULT_MAX_ARRAY_LEN: usize = i32::MAX as usize;
+
+/// The same as Redis' limit on inline commands.
+const DEFAULT_MAX_LINE_LEN: usize = 64 * 1024;
+
+/// Arrays are grown as their elements are read past this length, so a bogus length doesn't
+/// allocate up front.
+pub(crate) const MAX_PREALLOCATED_LEN: usize = 1024;
+
+/// Bulk payloads are read in chunks of this size, for the same reason.
+const PAYLOAD_CHUNK_LEN: usize = 64 * 1024;
+
+/// How invalid UTF-8 in lines is handled, i.e. in simple strings, errors and the headers of
+/// other values. Bulk strings are not affected.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Utf8Policy {
+    /// Fails with [`Error::InvalidUtf8`].
+    #[default]
+    Strict,
+    /// Replaces invalid sequences with `U+FFFD REPLACEMENT CHARACTER`.
+    Lossy,
+}
+
+impl ReadOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Accepts a bare `\n` as well as `\r\n` at the end of lines, defaults to `false`.
+    ///
+    /// This is handy for commands typed or piped by hand. Bulk string payloads still have to
+    /// be followed by `\r\n` since their length is known.
+    pub fn lenient_line_endings(mut self, lenient: bool) -> Self {
+        self.lenient_line_endings = lenient;
+        self
+    }
+
+    /// Reads a line that doesn't start with a type prefix, outside of any aggregate, as an
+    /// inline command like `PING` or `SET key "hello world"`. Defaults to `true` when the
+    /// servers read commands and to `false` otherwise, so garbage in replies still fails.
+    ///
+    /// The command is read as an array of bulk strings, split like Redis does, and blank lines
+    /// between commands are skipped. Without it such lines fail with [`Error::UnknownType`].
+    pub fn inline_commands(mut self, inline: bool) -> Self {
+        self.inline_commands = Some(inline);
+        self
+    }
+
+    /// Turns inline commands on unless they were turned off, for reading commands.
+    pub(crate) fn for_commands(mut self) -> Self {
+        self.inline_commands.get_or_insert(true);
+        self
+    }
+
+    fn reads_inline_commands(&self) -> bool {
+        self.inline_commands.unwrap_or(false)
+    }
+
+    /// Sets how invalid UTF-8 in lines is handled, defaults to [`Utf8Policy::Strict`].
+    pub fn utf8_policy(mut self, policy: Utf8Policy) -> Self {
+        self.utf8_policy = policy;
+        self
+    }
+
+    /// Sets how deep arrays can be nested, defaults to 64.
+    ///
+    /// Reading a value nested deeper fails with [`Error::NestingTooDeep`].
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = depth;
+        self
+    }
+
+    /// Sets the maximum length of bulk strings, defaults to 512 MiB like in Redis.
+    ///
+    /// Reading a longer one fails with [`Error::BulkTooLong`]. Streamed strings are limited
+    /// as a whole.
+    pub fn max_bulk_len(mut self, len: usize) -> Self {
+        self.max_bulk_len = len;
+        self
+    }
+
+    /// Sets the maximum number of elements of arrays and pushes, and of pairs of maps and
+    /// attributes, defaults to 2^31 - 1 like in Redis.
+    ///
+    /// Reading a longer one fails with [`Error::ArrayTooLong`], streamed ones as soon as they
+    /// go over the limit.
+    pub fn max_array_len(mut self, len: usize) -> Self {
+        self.max_array_len = len;
+        self
+    }
+
+    /// Sets the maximum length of bulk strings the application accepts, below the protocol
+    /// limit set with [`ReadOptions::max_bulk_len`]. Not limited by default.
+    ///
+    /// Unlike the protocol limit, going over it doesn't leave the stream in the middle of a
+    /// frame: the payload is read in chunks and discarded, along with the rest of the frame,
+    /// before failing with [`Error::ValueTooLarge`]. Servers reply with an error and keep
+    /// serving the connection.
+    pub fn max_value_len(mut self, len: usize) -> Self {
+        self.max_value_len = len;
+        self
+    }
+
+    /// Sets the maximum length of lines including their line ending, defaults to 64 KiB like
+    /// inline commands in Redis.
+    ///
+    /// This covers every line, from simple strings to the headers of other values. Reading a
+    /// longer one fails with [`Error::LineTooLong`] without buffering more than the limit.
+    pub fn max_line_len(mut self, len: usize) -> Self {
+        self.max_line_len = len;
+        self
+    }
+}
+
+/// Reads values from a buffered reader.
+///
+/// Unlike [`Type::read`], the buffer used for reading lines is kept between reads, so reading
+/// many values from the same source doesn't allocate a new one for each line.
+#[derive(Debug)]
+pub struct RespReader<R> {
+    inner: R,
+    options: ReadOptions,
+    line: Vec<u8>,
+    /// Number of bytes consumed so far.
+    offset: u64,
+    /// Offset of the line in the line buffer.
+    line_start: u64,
+    /// The bytes of the value being read, kept by [`RespReader::read_raw`].
+    raw: Option<Vec<u8>>,
+    /// Where the value being read went over [`ReadOptions::max_value_len`], the payloads are
+    /// discarded from there on.
+    oversized: Option<Position>,
+    /// Where the last read failed.
+    position: Option<Position>,
+}
+
+impl<R> RespReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self::with_options(inner, ReadOptions::default())
+    }
+
+    pub fn with_options(inner: R, options: ReadOptions) -> Self {
+        Self {
+            inner,
+            options,
+            line: Vec::new(),
+            offset: 0,
+            line_start: 0,
+            raw: None,
+            oversized: None,
+            position: None,
+        }
+    }
+
+    /// Returns where the last read failed, pointing at the offending line unless a more
+    /// precise position is known.
+    pub fn error_position(&self) -> Option<&Position> {
+        self.position.as_ref()
+    }
+
+    /// Returns the number of bytes consumed so far.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: AsyncRead> RespReader<BufReader<R>> {
+    /// Returns whether a complete frame is already buffered, so the next read won't wait for
+    /// the underlying reader.
+    pub(crate) fn has_buffered_frame(&self) -> bool {
+        buffered_frame_len(self.inner.buffer(), &self.options).is_some()
+    }
+}
+
+/// Returns the length of the frame at the start of the buffer if the whole frame is there.
+///
+/// Only the framing and the limits of the options are checked, a frame that is complete but
+/// invalid is still reported. So is a frame already known to be invalid or over the limits,
+/// with the length scanned so far, as reading it fails without waiting for the rest of it.
+pub(crate) fn buffered_frame_len(buf: &[u8], options: &ReadOptions) -> Option<usize> {
+    match scan_frame(buf, options) {
+        Ok(len) | Err(Scan::Invalid(len)) => Some(len),
+        Err(Scan::Incomplete) => None,
+    }
+}
+
+/// Why [`scan_frame`] stopped before the end of the frame.
+enum Scan {
+    Incomplete,
+    /// Invalid at the given position.
+    Invalid(usize),
+}
+
+fn scan_frame(buf: &[u8], options: &ReadOptions) -> Result<usize, Scan> {
+    let length = |len: &[u8], pos: usize| parse_length(len).map_err(|_| Scan::Invalid(pos));
+    let mut pos = 0;
+    // Values left to complete each aggregate being scanned, `None` for streamed ones.
+    let mut open: Vec<Option<usize>> = Vec::new();
+    loop {
+        let line = scan_line(buf, &mut pos, options)?;
+        let nested = |open: &Vec<_>| {
+            if open.len() >= options.max_depth {
+                return Err(Scan::Invalid(pos));
+            }
+            Ok(())
+        };
+        match line {
+            b"$?" => {
+                let mut total: usize = 0;
+                loop {
+                    let len = match scan_line(buf, &mut pos, options)? {
+                        [b';', len @ ..] => length(len, pos)?.ok_or(Scan::Invalid(pos))?,
+                        _ => return Err(Scan::Invalid(pos)),
+                    };
+                    if len == 0 {
+                        break;
+                    }
+                    total = total
+                        .checked_add(len)
+                        .filter(|&total| total <= options.max_bulk_len)
+                        .ok_or(Scan::Invalid(pos))?;
+                    pos = skip_payload(buf, pos, len)?;
+                }
+            }
+            [b'$' | b'=', len @ ..] => {
+                if let Some(len) = length(len, pos)? {
+                    if len > options.max_bulk_len {
+                        return Err(Scan::Invalid(pos));
+                    }
+                    pos = skip_payload(buf, pos, len)?;
+                }
+            }
+            b"*?" | b"%?" => {
+                nested(&open)?;
+                open.push(None);
+                continue;
+            }
+            [b'*' | b'>', len @ ..] => {
+                if let Some(len) = length(len, pos)? {
+                    nested(&open)?;
+                    if len > options.max_array_len {
+                        return Err(Scan::Invalid(pos));
+                    }
+                    if len > 0 {
+                        open.push(Some(len));
+                        continue;
+                    }
+                }
+            }
+            [b'%', len @ ..] => {
+                let len = length(len, pos)?.ok_or(Scan::Invalid(pos))?;
+                nested(&open)?;
+                if len > options.max_array_len {
+                    return Err(Scan::Invalid(pos));
+                }
+                if len > 0 {
+                    let values = len.checked_mul(2).ok_or(Scan::Invalid(pos))?;
+                    open.push(Some(values));
+                    continue;
+                }
+            }
+            // The attributes are followed by the value they decorate.
+            [b'|', len @ ..] => {
+                let len = length(len, pos)?.ok_or(Scan::Invalid(pos))?;
+                nested(&open)?;
+                if len > options.max_array_len {
+                    return Err(Scan::Invalid(pos));
+                }
+                let values = len.checked_mul(2).and_then(|it| it.checked_add(1));
+                open.push(Some(values.ok_or(Scan::Invalid(pos))?));
+                continue;
+            }
+            b"." if open.last() == Some(&None) => {
+                open.pop();
+            }
+            // Blank lines before an inline command.
+            line if open.is_empty()
+                && options.reads_inline_commands()
+                && line.iter().all(u8::is_ascii_whitespace) =>
+            {
+                continue
+            }
+            _ => {}
+        }
+
+        // Adds the value to the enclosing aggregates, completing them in turn.
+        loop {
+            match open.last_mut() {
+                None => return Ok(pos),
+                Some(Some(1)) => {
+                    open.pop();
+                }
+                Some(Some(n)) => {
+                    *n -= 1;
+                    break;
+                }
+                Some(None) => break,
+            }
+        }
+    }
+}
+
+/// Returns the line at `pos` without its line ending and moves past it.
+fn scan_line<'a>(buf: &'a [u8], pos: &mut usize, options: &ReadOptions) -> Result<&'a [u8], Scan> {
+    let rest = &buf[*pos..];
+    match rest
+        .iter()
+        .take(options.max_line_len)
+        .position(|&b| b == b'\n')
+    {
+        Some(end) => {
+            *pos += end + 1;
+            // The line ending is checked while reading, bare `\n` is fine here.
+            let line = &rest[..end];
+            Ok(line.strip_suffix(b"\r").unwrap_or(line))
+        }
+        None if rest.len() >= options.max_line_len => Err(Scan::Invalid(*pos)),
+        None => Err(Scan::Incomplete),
+    }
+}
+
+/// Returns the position after the payload at `pos` and its CRLF.
+fn skip_payload(buf: &[u8], pos: usize, len: usize) -> Result<usize, Scan> {
+    let end = pos
+        .checked_add(len)
+        .and_then(|it| it.checked_add(2))
+        .ok_or(Scan::Invalid(pos))?;
+    if end > buf.len() {
+        return Err(Scan::Incomplete);
+    }
+    Ok(end)
+}
+
+/// An aggregate being read by [`RespReader::read`].
+enum Frame {
+    /// An array with `remaining` elements left to read, or a streamed one until its end marker
+    /// if `None`.
+    Array {
+        elements: Vec<Type>,
+        remaining: Option<usize>,
+    },
+    /// A push with `remaining` elements left to read.
+    Push {
+        elements: Vec<Type>,
+        remaining: usize,
+    },
+    /// A map with `remaining` pairs left to read, or a streamed one until its end marker if
+    /// `None`.
+    Map {
+        pairs: Vec<(Type, Type)>,
+        /// The key of the pair being read.
+        key: Option<Type>,
+        remaining: Option<usize>,
+    },
+    /// Attributes with `remaining` pairs left to read, followed by the value they decorate.
+    Attribute {
+        attrs: Vec<(Type, Type)>,
+        /// The key of the pair being read.
+        key: Option<Type>,
+        remaining: usize,
+    },
+}
+
+/// Receives the payloads of big bulk strings as they are read, see
+/// [`RespReader::read_streaming`].
+///
+/// ```no_run
+/// use redcon::{BulkSink, RespReader, Type};
+/// use tokio::io::{AsyncRead, AsyncWriteExt};
+///
+/// /// Stores the payloads in files, replacing them with the paths.
+/// struct Spool {
+///     next: usize,
+/// }
+///
+/// impl BulkSink for Spool {
+///     async fn bulk(
+///         &mut self,
+///         _len: usize,
+///         body: &mut (dyn AsyncRead + Unpin + Send),
+///     ) -> Result<Type, redcon::Error> {
+///         let path = format!("/tmp/bulk-{}", self.next);
+///         self.next += 1;
+///         let mut file = tokio::fs::File::create(&path).await?;
+///         tokio::io::copy(body, &mut file).await?;
+///         file.flush().await?;
+///         Ok(Type::from(path))
+///     }
+/// }
+///
+/// # async fn read(mut reader: RespReader<tokio::io::BufReader<tokio::net::TcpStream>>) -> anyhow::Result<()> {
+/// let cmd = reader.read_streaming(1024 * 1024, &mut Spool { next: 0 }).await?;
+/// # Ok(())
+/// # }
+/// ```
+pub trait BulkSink: Send {
+    /// Consumes the payload of a bulk string of `len` bytes from `body`, and returns the value
+    /// that takes its place. The part of the payload left unread is skipped.
+    fn bulk(
+        &mut self,
+        len: usize,
+        body: &mut (dyn AsyncRead + Unpin + Send),
+    ) -> impl Future<Output = Result<Type, Error>> + Send;
+}
+
+/// Buffers the payloads, for reading without a sink.
+struct Buffered;
+
+impl BulkSink for Buffered {
+    async fn bulk(
+        &mut self,
+        len: usize,
+        body: &mut (dyn AsyncRead + Unpin + Send),
+    ) -> Result<Type, Error> {
+        let mut buf = Vec::with_capacity(len.min(PAYLOAD_CHUNK_LEN));
+        body.read_to_end(&mut buf).await?;
+        Ok(Type::BulkString(buf))
+    }
+}
+
+/// What a single line read, see [`RespReader::read_item`].
+enum Item {
+    Value(Type),
+    Open(Frame),
+    /// A blank line before an inline command.
+    Blank,
+}
+
+impl<R: AsyncBufRead + Unpin + Send> RespReader<R> {
+    /// Reads a value. Where it failed can be found with [`RespReader::error_position`].
+    pub async fn read(&mut self) -> Result<Type, Error> {
+        self.read_streaming(usize::MAX, &mut Buffered).await
+    }
+
+    /// Reads a value like [`RespReader::read`], handing the payloads of bulk strings of at
+    /// least `threshold` bytes to `sink` as they arrive instead of buffering them, e.g. to store
+    /// a big `SET` on disk. The value the sink returns takes the place of the bulk string.
+    ///
+    /// The limits of the options apply to the payloads handed to the sink as well. Streamed
+    /// strings, see [`Conn::begin_streamed_bulk`](crate::Conn::begin_streamed_bulk), are
+    /// still buffered.
+    pub async fn read_streaming(
+        &mut self,
+        threshold: usize,
+        sink: &mut impl BulkSink,
+    ) -> Result<Type, Error> {
+        self.position = None;
+        let res = self.read_value(threshold, sink).await;
+        let oversized = self.oversized.take();
+        if res.is_err() && self.position.is_none() {
+            self.position = Some(Position::new(self.line_start, &self.line));
+        }
+        let value = res?;
+        match oversized {
+            Some(pos) => {
+                self.position = Some(pos);
+                Err(Error::ValueTooLarge)
+            }
+            None => Ok(value),
+        }
+    }
+
+    /// Reads a value like [`RespReader::read`], telling the failures servers react to
+    /// differently apart.
+    pub(crate) async fn read_frame(&mut self) -> Result<Type, ReadFailure> {
+        self.read()
+            .await
+            .map_err(|err| ReadFailure::new(err, self.position.clone()))
+    }
+
+    /// Reads a value along with the bytes it was read from, e.g. to forward it as it is.
+    pub async fn read_raw(&mut self) -> Result<(Type, Vec<u8>), Error> {
+        self.raw = Some(Vec::new());
+        let res = self.read().await;
+        let raw = self.raw.take().unwrap_or_default();
+        Ok((res?, raw))
+    }
+
+    /// Reads a value iteratively, keeping the aggregates being read on an explicit stack so
+    /// nesting costs neither call stack nor a boxed future per level.
+    async fn read_value(
+        &mut self,
+        threshold: usize,
+        sink: &mut impl BulkSink,
+    ) -> Result<Type, Error> {
+        let max_len = self.options.max_array_len;
+        let mut stack: Vec<Frame> = Vec::new();
+        loop {
+            // Streamed maps can only end between pairs.
+            let streamed = matches!(
+                stack.last(),
+                Some(Frame::Array {
+                    remaining: None,
+                    ..
+                }) | Some(Frame::Map {
+                    key: None,
+                    remaining: None,
+                    ..
+                })
+            );
+            let mut value = if streamed && self.at_end_marker().await? {
+                match stack.pop() {
+                    Some(Frame::Array { elements, .. }) => Type::Array(elements),
+                    Some(Frame::Map { pairs, .. }) => Type::Map(pairs),
+                    _ => unreachable!("the top frame is a streamed aggregate"),
+                }
+            } else {
+                match self.read_item(stack.len(), threshold, sink).await? {
+                    Item::Value(value) => value,
+                    Item::Open(frame) => {
+                        stack.push(frame);
+                        continue;
+                    }
+                    Item::Blank => continue,
+                }
+            };
+
+            // Adds the value to the enclosing aggregates, completing them in turn.
+            loop {
+                match stack.last_mut() {
+                    None => return Ok(value),
+                    Some(Frame::Array {
+                        elements,
+                        remaining,
+                    }) => {
+                        elements.push(value);
+                        if remaining.is_none() && elements.len() > max_len {
+                            return Err(Error::ArrayTooLong);
+                        }
+                        match remaining {
+                            Some(1) => value = Type::Array(mem::take(elements)),
+                            Some(n) => {
+                                *n -= 1;
+                                break;
+                            }
+                            None => break,
+                        }
+                    }
+                    Some(Frame::Push {
+                        elements,
+                        remaining,
+                    }) => {
+                        elements.push(value);
+                        *remaining -= 1;
+                        if *remaining > 0 {
+                            break;
+                        }
+                        value = Type::Push(mem::take(elements));
+                    }
+                    Some(Frame::Map {
+                        pairs,
+                        key,
+                        remaining,
+                    }) => match key.take() {
+                        None => {
+                            *key = Some(value);
+                            break;
+                        }
+                        Some(key) => {
+                            pairs.push((key, value));
+                            if remaining.is_none() && pairs.len() > max_len {
+                                return Err(Error::ArrayTooLong);
+                            }
+                            match remaining {
+                                Some(1) => value = Type::Map(mem::take(pairs)),
+                                Some(n) => {
+                                    *n -= 1;
+                                    break;
+                                }
+                                None => break,
+                            }
+                        }
+                    },
+                    Some(Frame::Attribute {
+                        attrs,
+                        key,
+                        remaining,
+                    }) if *remaining > 0 => {
+                        match key.take() {
+                            None => *key = Some(value),
+                            Some(key) => {
+                                attrs.push((key, value));
+                                *remaining -= 1;
+                            }
+                        }
+                        break;
+                    }
+                    // The value the attributes decorate.
+                    Some(Frame::Attribute { attrs, .. }) => {
+                        value = Type::Attribute {
+                            attrs: mem::take(attrs),
+                            value: Box::new(value),
+                        }
+                    }
+                }
+                stack.pop();
+            }
+        }
+    }
+
+    /// Reads the next line, along with the payload of a bulk string, returning the value it
+    /// completes or the aggregate it starts inside `depth` others.
+    async fn read_item(
+        &mut self,
+        depth: usize,
+        threshold: usize,
+        sink: &mut impl BulkSink,
+    ) -> Result<Item, Error> {
+        let max_depth = self.options.max_depth;
+        let max_len = self.options.max_array_len;
+        let inline = depth == 0 && self.options.reads_inline_commands();
+        let line = self.read_line().await?;
+
+        let value = match line.as_bytes().first() {
+            Some(b'+') => Type::SimpleString(line[1..].into()),
+            Some(b'-') => Type::Error(line[1..].into()),
+            Some(b':') => Type::Integer(parse_integer(&line.as_bytes()[1..])?),
+            Some(b'(') => {
+                check_big_number(&line.as_bytes()[1..])?;
+                Type::BigNumber(line[1..].into())
+            }
+            Some(b'_') if line == "_" => Type::Null,
+            Some(b'_') => return Err(Error::InvalidNull),
+            Some(b'#') => match &line[1..] {
+                "t" => Type::Boolean(true),
+                "f" => Type::Boolean(false),
+                _ => return Err(Error::InvalidBoolean),
+            },
+            Some(b'$') if line == "$?" => {
+                let mut buf = Vec::new();
+                loop {
+                    let line = self.read_line().await?;
+                    let len = match line.as_bytes() {
+                        [b';', len @ ..] => parse_length(len)?.ok_or(Error::InvalidLength)?,
+                        _ => return Err(Error::ExpectedChunk),
+                    };
+                    if len == 0 {
+                        break;
+                    }
+                    self.read_payload(len, &mut buf).await?;
+                }
+                Type::BulkString(buf)
+            }
+            Some(b'$') => match parse_length(&line.as_bytes()[1..])? {
+                Some(len) if len >= threshold => self.stream_payload(len, sink).await?,
+                Some(len) => {
+                    let mut buf = Vec::new();
+                    self.read_payload(len, &mut buf).await?;
+                    Type::BulkString(buf)
+                }
+                None => Type::Null,
+            },
+            Some(b'=') => {
+                let len = parse_length(&line.as_bytes()[1..])?.ok_or(Error::InvalidLength)?;
+                let mut buf = Vec::new();
+                self.read_payload(len, &mut buf).await?;
+                if self.oversized.is_some() {
+                    // Discarded, the read fails with `ValueTooLarge` once the frame is done.
+                    return Ok(Item::Value(Type::Null));
+                }
+                if buf.get(3) != Some(&b':') {
+                    return Err(Error::InvalidVerbatim);
+                }
+                let text = buf.split_off(4);
+                buf.truncate(3);
+                match (String::from_utf8(buf), String::from_utf8(text)) {
+                    (Ok(format), Ok(text)) => Type::Verbatim { format, text },
+                    _ => return Err(Error::InvalidVerbatim),
+                }
+            }
+            Some(b'*') if line == "*?" => {
+                if depth >= max_depth {
+                    return Err(Error::NestingTooDeep);
+                }
+                return Ok(Item::Open(Frame::Array {
+                    elements: Vec::new(),
+                    remaining: None,
+                }));
+            }
+            Some(b'*') => match parse_length(&line.as_bytes()[1..])? {
+                Some(_) if depth >= max_depth => return Err(Error::NestingTooDeep),
+                Some(len) if len > max_len => return Err(Error::ArrayTooLong),
+                Some(0) => Type::Array(Vec::new()),
+                Some(len) => {
+                    return Ok(Item::Open(Frame::Array {
+                        elements: Vec::with_capacity(len.min(MAX_PREALLOCATED_LEN)),
+                        remaining: Some(len),
+                    }))
+                }
+                None => Type::Null,
+            },
+            Some(b'>') => match parse_length(&line.as_bytes()[1..])? {
+                Some(_) if depth >= max_depth => return Err(Error::NestingTooDeep),
+                Some(len) if len > max_len => return Err(Error::ArrayTooLong),
+                Some(0) => Type::Push(Vec::new()),
+                Some(len) => {
+                    return Ok(Item::Open(Frame::Push {
+                        elements: Vec::with_capacity(len.min(MAX_PREALLOCATED_LEN)),
+                        remaining: len,
+                    }))
+                }
+                None => return Err(Error::InvalidLength),
+            },
+            Some(b'%') if line == "%?" => {
+                if depth >= max_depth {
+                    return Err(Error::NestingTooDeep);
+                }
+                return Ok(Item::Open(Frame::Map {
+                    pairs: Vec::new(),
+                    key: None,
+                    remaining: None,
+                }));
+            }
+            Some(b'%') => match parse_length(&line.as_bytes()[1..])? {
+                Some(_) if depth >= max_depth => return Err(Error::NestingTooDeep),
+                Some(len) if len > max_len => return Err(Error::ArrayTooLong),
+                Some(0) => Type::Map(Vec::new()),
+                Some(len) => {
+                    return Ok(Item::Open(Frame::Map {
+                        pairs: Vec::with_capacity(len.min(MAX_PREALLOCATED_LEN)),
+                        key: None,
+                        remaining: Some(len),
+                    }))
+                }
+                None => return Err(Error::InvalidLength),
+            },
+            Some(b'|') => {
+                let len = parse_length(&line.as_bytes()[1..])?.ok_or(Error::InvalidLength)?;
+                if depth >= max_depth {
+                    return Err(Error::NestingTooDeep);
+                }
+                if len > max_len {
+                    return Err(Error::ArrayTooLong);
+                }
+                return Ok(Item::Open(Frame::Attribute {
+                    attrs: Vec::with_capacity(len.min(MAX_PREALLOCATED_LEN)),
+                    key: None,
+                    remaining: len,
+                }));
+            }
+            _ if inline => {
+                let args = split_inline(line.as_bytes())?;
+                if args.is_empty() {
+                    return Ok(Item::Blank);
+                }
+                Type::Array(args.into_iter().map(Type::BulkString).collect())
+            }
+            Some(&byte) => return Err(Error::UnknownType(byte)),
+            // An empty line, reported as starting with its line ending.
+            None => return Err(Error::UnknownType(b'\r')),
+        };
+        Ok(Item::Value(value))
+    }
+
+    /// Reads a bulk payload of the given length followed by CRLF, appending it to `buf`.
+    ///
+    /// The buffer grows in chunks as the payload arrives rather than up front, so a bogus
+    /// length fails at the end of the input instead of allocating it all.
+    async fn read_payload(&mut self, len: usize, buf: &mut Vec<u8>) -> Result<(), Error> {
+        let total = match buf.len().checked_add(len) {
+            Some(total) if total <= self.options.max_bulk_len => total,
+            _ => return Err(Error::BulkTooLong),
+        };
+        if total > self.options.max_value_len && self.oversized.is_none() {
+            self.oversized = Some(Position::new(self.line_start, &self.line));
+        }
+        if self.oversized.is_some() {
+            self.discard_payload(len).await?;
+        } else {
+            self.read_payload_into(len, buf).await?;
+        }
+        self.read_crlf().await
+    }
+
+    /// Hands a bulk payload of the given length to the sink as it arrives, followed by reading
+    /// its CRLF, see [`RespReader::read_streaming`].
+    async fn stream_payload(
+        &mut self,
+        len: usize,
+        sink: &mut impl BulkSink,
+    ) -> Result<Type, Error> {
+        if len > self.options.max_bulk_len {
+            return Err(Error::BulkTooLong);
+        }
+        if len > self.options.max_value_len && self.oversized.is_none() {
+            self.oversized = Some(Position::new(self.line_start, &self.line));
+        }
+        let value = if self.oversized.is_some() {
+            self.discard_payload(len).await?;
+            // Discarded, the read fails with `ValueTooLarge` once the frame is done.
+            Type::Null
+        } else {
+            let mut body = (&mut self.inner).take(len as u64);
+            let value = sink.bulk(len, &mut body).await?;
+            // What the sink left unread is skipped.
+            copy_buf(&mut body, &mut tokio::io::sink()).await?;
+            let left = body.limit();
+            self.offset += len as u64 - left;
+            if left > 0 {
+                return Err(Error::UnexpectedEof);
+            }
+            value
+        };
+        self.read_crlf().await?;
+        Ok(value)
+    }
+
+    async fn read_crlf(&mut self) -> Result<(), Error> {
+        let mut crlf = [0; 2];
+        self.inner.read_exact(&mut crlf).await?;
+        if crlf != *b"\r\n" {
+            self.position = Some(Position::new(self.offset, &crlf));
+            return Err(Error::ExpectedLine);
+        }
+        self.offset += 2;
+        if let Some(raw) = &mut self.raw {
+            raw.extend_from_slice(&crlf);
+        }
+        Ok(())
+    }
+
+    async fn read_payload_into(&mut self, len: usize, buf: &mut Vec<u8>) -> Result<(), Error> {
+        let mut left = len;
+        while left > 0 {
+            let start = buf.len();
+            let chunk = left.min(PAYLOAD_CHUNK_LEN);
+            buf.resize(start + chunk, 0);
+            self.inner.read_exact(&mut buf[start..]).await?;
+            self.offset += chunk as u64;
+            left -= chunk;
+            if let Some(raw) = &mut self.raw {
+                raw.extend_from_slice(&buf[start..]);
+            }
+        }
+        Ok(())
+    }
+
+    /// Skips a payload of the given length, consuming it from the reader's buffer as it
+    /// arrives rather than buffering it.
+    async fn discard_payload(&mut self, len: usize) -> Result<(), Error> {
+        let mut payload = (&mut self.inner).take(len as u64);
+        let n = copy_buf(&mut payload, &mut sink()).await?;
+        self.offset += n;
+        if n < len as u64 {
+            return Err(Error::UnexpectedEof);
+        }
+        Ok(())
+    }
+
+    /// Consumes the end marker of a streamed aggregate if it is next.
+    async fn at_end_marker(&mut self) -> Result<bool, Error> {
+        match self.inner.fill_buf().await?.first() {
+            None => return Err(Error::UnexpectedEof),
+            Some(b'.') => {}
+            Some(_) => return Ok(false),
+        }
+        match self.read_line().await?.as_ref() {
+            "." => Ok(true),
+            _ => Err(Error::InvalidEndMarker),
+        }
+    }
+
+    /// Reads a line into the line buffer and returns it without the trailing CRLF.
+    async fn read_line(&mut self) -> Result<Cow<'_, str>, Error> {
+        self.line.clear();
+        self.line_start = self.offset;
+        loop {
+            let buf = self.inner.fill_buf().await?;
+            if buf.is_empty() {
+                return Err(Error::UnexpectedEof);
+            }
+            let len = buf
+                .iter()
+                .position(|&b| b == b'\n')
+                .map_or(buf.len(), |pos| pos + 1);
+            // Past the limit, the line is too long whether or not it ends there.
+            let len = len.min(self.options.max_line_len - self.line.len());
+            self.line.extend_from_slice(&buf[..len]);
+            self.inner.consume(len);
+            self.offset += len as u64;
+            if self.line.ends_with(b"\n") {
+                break;
+            }
+            if self.line.len() == self.options.max_line_len {
+                return Err(Error::LineTooLong);
+            }
+        }
+        if let Some(raw) = &mut self.raw {
+            raw.extend_from_slice(&self.line);
+        }
+
+        let len = match self.line.as_slice() {
+            [line @ .., b'\r', b'\n'] => line.len(),
+            [line @ .., b'\n'] if self.options.lenient_line_endings => line.len(),
+            _ => return Err(Error::ExpectedLine),
+        };
+
+        let line = &self.line[..len];
+        match self.options.utf8_policy {
+            Utf8Policy::Strict => str::from_utf8(line)
+                .map(Cow::Borrowed)
+                .map_err(|_| Error::InvalidUtf8),
+            Utf8Policy::Lossy => Ok(String::from_utf8_lossy(line)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use tokio::io::{duplex, BufReader};
+
+    use super::*;
+
+    macro_rules! type_tests {
+        ($($str:expr => $ty:expr,)*) => {
+            #[tokio::test]
+            async fn read() -> Result<()> {
+                $(
+                    assert_eq!(
+                        Type::read(&mut $str.to_vec().as_slice()).await?,
+                        $ty
+                    );
+                )*
+                Ok(())
+            }
+
+            #[tokio::test]
+            async fn write() -> Result<()> {
+                $(
+                    let mut buf = vec![];
+                    $ty.write(&mut buf).await?;
+                    assert_eq!(buf, $str);
+                )*
+                Ok(())
+            }
+
+            #[tokio::test]
+            async fn encode_to() -> Result<()> {
+                $(
+                    let mut written = vec![];
+                    $ty.write(&mut written).await?;
+                    let mut encoded = b"prefix".to_vec();
+                    $ty.encode_to(&mut encoded);
+                    assert_eq!(&encoded[b"prefix".len()..], written);
+                    assert_eq!($ty.encoded_len(), written.len());
+
+                    let mut written_to = vec![];
+                    let n = $ty.write_to(&mut written_to).await?;
+                    assert_eq!(written_to, written);
+                    assert_eq!(n, written.len());
+                )*
+                Ok(())
+            }
+
+            #[tokio::test]
+            async fn write_and_read() -> Result<()> {
+                let (mut write, read) = duplex(8096);
+                let mut read = BufReader::new(read);
+                $(
+                    $ty.write(&mut write).await?;
+                    assert_eq!(Type::read(&mut read).await?, $ty);
+                )*
+                Ok(())
+            }
+
+            #[cfg(feature = "codec")]
+            #[tokio::test]
+            async fn framed() -> Result<()> {
+                use bytes::BytesMut;
+                use futures::{SinkExt, StreamExt};
+                use tokio_util::codec::{Decoder, Encoder, Framed};
+
+                use crate::codec::RespCodec;
+
+                // Small enough for most frames to be split across reads.
+                let (client, server) = duplex(4);
+                let mut client = Framed::new(client, RespCodec::new());
+                let mut server = Framed::new(server, RespCodec::new());
+                $(
+                    let mut encoded = BytesMut::new();
+                    RespCodec::new().encode($ty, &mut encoded)?;
+                    assert_eq!(&encoded[..], &$str[..]);
+                    assert_eq!(RespCodec::new().decode(&mut encoded)?, Some($ty));
+                    assert!(encoded.is_empty());
+
+                    let (sent, received) = tokio::join!(client.send($ty), server.next());
+                    sent?;
+                    assert_eq!(received.transpose()?, Some($ty));
+                )*
+                Ok(())
+            }
+        }
+    }
+
+    type_tests! {
+        b"+hello world\r\n" => Type::SimpleString("hello world".to_string()),
+        b"-error message\r\n" => Type::Error("error message".to_string()),
+        b":1000\r\n" => Type::Integer(1000),
+        b"#t\r\n" => Type::Boolean(true),
+        b"#f\r\n" => Type::Boolean(false),
+        b"(3492890328409238509324850943850943825024385\r\n" => Type::BigNumber(
+            "3492890328409238509324850943850943825024385".to_string()
+        ),
+        b"(-170141183460469231731687303715884105728\r\n" => Type::BigNumber(
+            "-170141183460469231731687303715884105728".to_string()
+        ),
+        b"$11\r\nhello world\r\n" => Type::BulkString(b"hello world".to_vec()),
+        b"=15\r\ntxt:Some string\r\n" => Type::Verbatim {
+            format: "txt".to_string(),
+            text: "Some string".to_string(),
+        },
+        b"=6\r\nmkd:\r\n\r\n" => Type::Verbatim {
+            format: "mkd".to_string(),
+            text: "\r\n".to_string(),
+        },
+        b"$-1\r\n" => Type::Null,
+        b">3\r\n$7\r\nmessage\r\n$4\r\nnews\r\n$5\r\nhello\r\n" => Type::Push(vec![
+            Type::from("message"),
+            Type::from("news"),
+            Type::from("hello"),
+        ]),
+        b">0\r\n" => Type::Push(vec![]),
+        b"*2\r\n+hello world\r\n$11\r\nhello world\r\n" => Type::Array(vec![
+            Type::SimpleString("hello world".to_string()),
+            Type::BulkString(b"hello world".to_vec()),
+        ]),
+    }
+
+    #[tokio::test]
+    async fn eof_in_frame() -> Result<()> {
+        for input in [&b"$10\r\nabc"[..], b"$3\r\nabc", b"*2\r\n:1\r\n", b"+OK"] {
+            let err = Type::read(&mut &input[..]).await.unwrap_err();
+            assert!(matches!(err, Error::UnexpectedEof), "{:?}", err);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn null_array() -> Result<()> {
+        assert_eq!(
+            Type::read(&mut b"*-1\r\n".to_vec().as_slice()).await?,
+            Type::Null,
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn write_large_bulk_strings() -> Result<()> {
+        let payload = "x".repeat(1024);
+        let ty = Type::Array(vec![
+            Type::from(payload.clone()),
+            Type::Integer(42),
+            Type::from(payload.clone()),
+        ]);
+        let expected = format!(
+            "*3\r\n$1024\r\n{}\r\n:42\r\n$1024\r\n{}\r\n",
+            payload, payload
+        );
+
+        // `Vec` supports vectored writes, `DuplexStream` does not.
+        let mut buf = vec![];
+        ty.write(&mut buf).await?;
+        assert_eq!(buf, expected.as_bytes());
+
+        let (mut write, mut read) = duplex(8096);
+        ty.write(&mut write).await?;
+        drop(write);
+        let mut buf = vec![];
+        read.read_to_end(&mut buf).await?;
+        assert_eq!(buf, expected.as_bytes());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn write_large_values() -> Result<()> {
+        let ty = Type::Array((0..10_000).map(Type::Integer).collect());
+        let mut expected = b"*10000\r\n".to_vec();
+        for i in 0..10_000 {
+            expected.extend_from_slice(format!(":{}\r\n", i).as_bytes());
+        }
+        let mut buf = Vec::new();
+        ty.write(&mut buf).await?;
+        assert_eq!(buf, expected);
+
+        let depth = 10_000;
+        let mut ty = Type::Map(vec![(Type::from("key"), Type::Null)]);
+        for _ in 0..depth {
+            ty = Type::Array(vec![ty, Type::Integer(1)]);
+        }
+        let mut expected = b"*2\r\n".repeat(depth);
+        expected.extend_from_slice(b"%1\r\n$3\r\nkey\r\n$-1\r\n");
+        expected.extend_from_slice(&b":1\r\n".repeat(depth));
+        assert_eq!(ty.to_bytes(), expected);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn write_to_buffered() -> Result<()> {
+        use std::pin::Pin;
+        use std::task::{Context, Poll};
+
+        /// Counts the writes and flushes reaching it.
+        #[derive(Default)]
+        struct Counting {
+            buf: Vec<u8>,
+            writes: usize,
+            flushes: usize,
+        }
+
+        impl AsyncWrite for Counting {
+            fn poll_write(
+                self: Pin<&mut Self>,
+                _cx: &mut Context<'_>,
+                buf: &[u8],
+            ) -> Poll<io::Result<usize>> {
+                let this = self.get_mut();
+                this.writes += 1;
+                this.buf.extend_from_slice(buf);
+                Poll::Ready(Ok(buf.len()))
+            }
+
+            fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                self.get_mut().flushes += 1;
+                Poll::Ready(Ok(()))
+            }
+
+            fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                Poll::Ready(Ok(()))
+            }
+        }
+
+        let replies = vec![Type::SimpleString("OK".to_string()); 100];
+        let mut expected = Vec::new();
+        for reply in replies.clone() {
+            reply.write(&mut expected).await?;
+        }
+
+        // Many small replies are a single write once flushed, as nothing else buffers them.
+        let mut dst = BufWriter::new(Counting::default());
+        let mut n = 0;
+        for reply in &replies {
+            n += reply.write_to(&mut dst).await?;
+        }
+        dst.flush().await?;
+        let dst = dst.into_inner();
+        assert_eq!(dst.buf, expected);
+        assert_eq!(n, expected.len());
+        assert_eq!((dst.writes, dst.flushes), (1, 1));
+
+        Ok(())
+    }
+
+    /// One value of each variant.
+    fn variants() -> Vec<Type> {
+        vec![
+            Type::SimpleString("OK".to_string()),
+            Type::Error("ERR boom".to_string()),
+            Type::Integer(42),
+            Type::BulkString(b"value".to_vec()),
+            Type::BulkString(vec![0xff]),
+            Type::Null,
+            Type::Array(vec![Type::Integer(1), Type::from("two")]),
+            Type::Map(vec![(Type::from("key"), Type::Integer(1))]),
+            Type::Attribute {
+                attrs: vec![],
+                value: Box::new(Type::Integer(42)),
+            },
+        ]
+    }
+
+    #[test]
+    fn accessors() {
+        let as_str: Vec<_> = variants()
+            .iter()
+            .map(|it| it.as_str().map(str::to_string))
+            .collect();
+        let some = |s: &str| Some(s.to_string());
+        assert_eq!(
+            as_str,
+            [
+                some("OK"),
+                None,
+                None,
+                some("value"),
+                None,
+                None,
+                None,
+                None,
+                None
+            ]
+        );
+
+        let as_bulk: Vec<_> = variants()
+            .iter()
+            .map(|it| it.as_bulk().map(<[u8]>::to_vec))
+            .collect();
+        assert_eq!(
+            as_bulk,
+            [
+                None,
+                None,
+                None,
+                Some(b"value".to_vec()),
+                Some(vec![0xff]),
+                None,
+                None,
+                None,
+                None
+            ]
+        );
+
+        let as_int: Vec<_> = variants().iter().map(Type::as_int).collect();
+        assert_eq!(
+            as_int,
+            [None, None, Some(42), None, None, None, None, None, None]
+        );
+
+        let as_error: Vec<_> = variants()
+            .iter()
+            .map(|it| it.as_error().map(str::to_string))
+            .collect();
+        assert_eq!(
+            as_error,
+            [
+                None,
+                some("ERR boom"),
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None
+            ]
+        );
+
+        let is_null: Vec<_> = variants().iter().map(Type::is_null).collect();
+        assert_eq!(
+            is_null,
+            [false, false, false, false, false, true, false, false, false]
+        );
+
+        let arrays: Vec<_> = variants()
+            .iter()
+            .map(|it| it.as_array().is_some())
+            .collect();
+        assert_eq!(
+            arrays,
+            [false, false, false, false, false, false, true, false, false]
+        );
+    }
+
+    #[test]
+    fn array_elements() {
+        let reply = Type::Array(vec![
+            Type::SimpleString("OK".to_string()),
+            Type::Array(vec![Type::Integer(1)]),
+        ]);
+        assert_eq!(reply.as_array().map(<[Type]>::len), Some(2));
+        assert_eq!(reply.get(0).and_then(Type::as_str), Some("OK"));
+        assert_eq!(
+            reply.get(1).and_then(|it| it.get(0)).and_then(Type::as_int),
+            Some(1)
+        );
+        assert_eq!(reply.get(2), None);
+        assert_eq!(Type::Integer(1).get(0), None);
+        assert_eq!(Type::Null.get(0), None);
+    }
+
+    #[test]
+    fn display() {
+        let reply = Type::Array(vec![
+            Type::SimpleString("OK".to_string()),
+            Type::Integer(42),
             Type::Null,
+            Type::Error("ERR boom".to_string()),
+            Type::BulkString(b"say \"hi\"\r\n".to_vec()),
+            Type::BulkString(vec![b'a', 0x00, 0xff]),
+            Type::Array(vec![
+                Type::from("nested"),
+                Type::Array(vec![Type::Integer(1), Type::Integer(2)]),
+            ]),
+            Type::Array(vec![]),
+            Type::Map(vec![
+                (Type::from("key"), Type::from("value")),
+                (Type::from("list"), Type::from(vec!["a", "b"])),
+            ]),
+            Type::Attribute {
+                attrs: vec![(Type::from("ttl"), Type::Integer(10))],
+                value: Box::new(Type::from("decorated")),
+            },
+        ]);
+        let expected = [
+            r#" 1) OK"#,
+            r#" 2) (integer) 42"#,
+            r#" 3) (nil)"#,
+            r#" 4) (error) ERR boom"#,
+            r#" 5) "say \"hi\"\r\n""#,
+            r#" 6) "a\x00\xff""#,
+            r#" 7) 1) "nested""#,
+            r#"    2) 1) (integer) 1"#,
+            r#"       2) (integer) 2"#,
+            r#" 8) (empty array)"#,
+            r#" 9) 1# "key" => "value""#,
+            r#"    2# "list" => 1) "a""#,
+            r#"       2) "b""#,
+            r#"10) "decorated""#,
+        ];
+        assert_eq!(reply.to_string(), expected.join("\n"));
+        assert_eq!(Type::Boolean(true).to_string(), "(true)");
+        let verbatim = Type::Verbatim {
+            format: "txt".to_string(),
+            text: "line 1\nline 2".to_string(),
+        };
+        assert_eq!(verbatim.to_string(), "line 1\nline 2");
+
+        let long = Type::from("x".repeat(1000));
+        assert_eq!(
+            long.to_string(),
+            format!("\"{}...\" (1000 bytes)", "x".repeat(256))
+        );
+    }
+
+    #[test]
+    fn buffered_frame_len() {
+        let options = ReadOptions::new();
+        let frame_len = |buf: &[u8]| super::buffered_frame_len(buf, &options);
+        let frame = b"*3\r\n$3\r\nset\r\n*-1\r\n:1\r\n";
+        assert_eq!(frame_len(frame), Some(frame.len()));
+        assert_eq!(frame_len(b"+OK\r\n:1\r\n"), Some(5));
+        assert_eq!(frame_len(b"$-1\r\n"), Some(5));
+
+        for len in 0..frame.len() {
+            assert_eq!(frame_len(&frame[..len]), None);
+        }
+
+        // An attribute is not complete without the value it decorates.
+        let frame = b"|1\r\n+key\r\n:1\r\n$5\r\nvalue\r\n";
+        assert_eq!(frame_len(frame), Some(frame.len()));
+        assert_eq!(frame_len(&frame[..17]), None);
+
+        let frame = b"*?\r\n$?\r\n;2\r\nab\r\n;0\r\n*?\r\n.\r\n.\r\n";
+        assert_eq!(frame_len(frame), Some(frame.len()));
+        assert_eq!(frame_len(&frame[..frame.len() - 3]), None);
+
+        // Reported before the rest of the frame arrives, so reading it fails right away.
+        let options = ReadOptions::new()
+            .max_line_len(8)
+            .max_bulk_len(4)
+            .max_depth(1);
+        let frame_len = |buf: &[u8]| super::buffered_frame_len(buf, &options);
+        assert_eq!(frame_len(b"+too long"), Some(0));
+        assert_eq!(frame_len(b"$5\r\nab"), Some(4));
+        assert_eq!(frame_len(b"*1\r\n*1\r\n"), Some(8));
+        assert_eq!(frame_len(b"*x\r\n"), Some(4));
+    }
+
+    #[tokio::test]
+    async fn strict_integers() -> Result<()> {
+        let valid: &[(&[u8], i64)] = &[
+            (b":0\r\n", 0),
+            (b":-0\r\n", 0),
+            (b":9223372036854775807\r\n", i64::MAX),
+            (b":-9223372036854775808\r\n", i64::MIN),
+        ];
+        for (input, n) in valid {
+            assert_eq!(Type::read(&mut &input[..]).await?, Type::Integer(*n));
+        }
+
+        let invalid: &[&[u8]] = &[
+            b":\r\n",
+            b":-\r\n",
+            b":+5\r\n",
+            b": 5\r\n",
+            b":5 \r\n",
+            b":1a\r\n",
+            b":--1\r\n",
+            b"$+3\r\nfoo\r\n",
+            b"* 1\r\n:1\r\n",
+        ];
+        for input in [&b"(\r\n"[..], b"(-\r\n", b"(1.5\r\n", b"(+1\r\n"] {
+            let err = Type::read(&mut &input[..]).await.unwrap_err();
+            assert!(matches!(err, Error::InvalidInteger));
+        }
+        for input in [
+            &b"=3\r\ntxt\r\n"[..],
+            b"=4\r\ntxt \r\n",
+            b"=5\r\ntxt:\xff\r\n",
+        ] {
+            let err = Type::read(&mut &input[..]).await.unwrap_err();
+            assert!(matches!(err, Error::InvalidVerbatim));
+        }
+        for input in [&b"#\r\n"[..], b"#true\r\n", b"#T\r\n"] {
+            let err = Type::read(&mut &input[..]).await.unwrap_err();
+            assert!(matches!(err, Error::InvalidBoolean));
+        }
+        for input in invalid {
+            let err = Type::read(&mut &input[..]).await.unwrap_err();
+            assert!(
+                matches!(err, Error::InvalidInteger),
+                "{:?}: {}",
+                String::from_utf8_lossy(input),
+                err
+            );
+        }
+
+        let overflow: &[&[u8]] = &[
+            b":9223372036854775808\r\n",
+            b":-9223372036854775809\r\n",
+            b":99999999999999999999\r\n",
+        ];
+        for input in overflow {
+            let err = Type::read(&mut &input[..]).await.unwrap_err();
+            assert!(matches!(err, Error::IntegerOverflow));
+        }
+
+        let invalid_length: &[&[u8]] = &[b"$-2\r\n", b"*-5\r\n"];
+        for input in invalid_length {
+            let err = Type::read(&mut &input[..]).await.unwrap_err();
+            assert!(matches!(err, Error::InvalidLength));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lenient_line_endings() -> Result<()> {
+        let input = b"*2\n$3\r\nget\r\n$3\nkey\r\n+OK\n:1\r\n";
+        let mut src = &input[..];
+        let mut reader =
+            RespReader::with_options(&mut src, ReadOptions::new().lenient_line_endings(true));
+        assert_eq!(
+            reader.read().await?,
+            Type::Array(vec![
+                Type::BulkString(b"get".to_vec()),
+                Type::BulkString(b"key".to_vec()),
+            ])
+        );
+        assert_eq!(reader.read().await?, Type::SimpleString("OK".to_string()));
+        assert_eq!(reader.read().await?, Type::Integer(1));
+
+        // Bulk string payloads are still framed by their length.
+        let err = Type::read_with(
+            &mut &b"$3\r\nkey\n+OK\r\n"[..],
+            ReadOptions::new().lenient_line_endings(true),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, Error::ExpectedLine));
+
+        let err = Type::read(&mut &input[..]).await.unwrap_err();
+        assert!(matches!(err, Error::ExpectedLine));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn utf8_policy() -> Result<()> {
+        // "caf\xe9" is latin-1, not UTF-8.
+        let input = b"+caf\xe9\r\n-ERR caf\xe9\r\n";
+        let read = |policy| async move {
+            let mut src = &input[..];
+            let mut reader =
+                RespReader::with_options(&mut src, ReadOptions::new().utf8_policy(policy));
+            Ok::<_, Error>((reader.read().await?, reader.read().await?))
+        };
+
+        let err = read(Utf8Policy::Strict).await.unwrap_err();
+        assert!(matches!(err, Error::InvalidUtf8));
+
+        assert_eq!(
+            read(Utf8Policy::Lossy).await?,
+            (
+                Type::SimpleString("caf\u{fffd}".to_string()),
+                Type::Error("ERR caf\u{fffd}".to_string())
+            )
+        );
+
+        // Bulk strings are not affected by the policy.
+        assert_eq!(
+            Type::read_with(
+                &mut &b"$4\r\ncaf\xe9\r\n"[..],
+                ReadOptions::new().utf8_policy(Utf8Policy::Lossy),
+            )
+            .await?,
+            Type::BulkString(b"caf\xe9".to_vec())
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn max_depth() -> Result<()> {
+        let input = b"*1\r\n".repeat(10_000);
+        let err = Type::read(&mut &input[..]).await.unwrap_err();
+        assert!(matches!(err, Error::NestingTooDeep));
+
+        let input = b"*1\r\n*1\r\n*0\r\n";
+        let nested = Type::Array(vec![Type::Array(vec![Type::Array(vec![])])]);
+        let options = ReadOptions::new().max_depth(3);
+        assert_eq!(Type::read_with(&mut &input[..], options).await?, nested);
+        let options = ReadOptions::new().max_depth(2);
+        let err = Type::read_with(&mut &input[..], options).await.unwrap_err();
+        assert!(matches!(err, Error::NestingTooDeep));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn read_deeply_nested() -> Result<()> {
+        let depth = 2_000;
+        let mut input = b"*2\r\n|1\r\n+ttl\r\n:3\r\n".repeat(depth);
+        input.extend_from_slice(b"*?\r\n$3\r\nkey\r\n.\r\n");
+        input.extend_from_slice(&b":1\r\n".repeat(depth));
+        let mut expected = Type::Array(vec![Type::from("key")]);
+        for _ in 0..depth {
+            let attrs = vec![(Type::SimpleString("ttl".to_string()), Type::Integer(3))];
+            expected = Type::Array(vec![
+                Type::Attribute {
+                    attrs,
+                    value: Box::new(expected),
+                },
+                Type::Integer(1),
+            ]);
+        }
+
+        // Each level nests an array and the attributes of its first element.
+        let options = ReadOptions::new().max_depth(2 * depth + 1);
+        assert_eq!(Type::read_with(&mut &input[..], options).await?, expected);
+        let options = ReadOptions::new().max_depth(2 * depth);
+        let err = Type::read_with(&mut &input[..], options).await.unwrap_err();
+        assert!(matches!(err, Error::NestingTooDeep));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn read_pipelined() -> Result<()> {
+        let frames = [
+            Type::Array(vec![Type::from("GET"), Type::from("key")]),
+            Type::Array(vec![]),
+            Type::Attribute {
+                attrs: vec![(Type::from("a"), Type::Array(vec![Type::Integer(1)]))],
+                value: Box::new(Type::Array(vec![Type::Null, Type::from("b")])),
+            },
+            Type::Integer(7),
+            Type::Array(vec![Type::Array(vec![]), Type::Array(vec![Type::Null])]),
+        ];
+        let mut input = Vec::new();
+        for frame in &frames {
+            frame.write_to(&mut input).await?;
+        }
+        // A streamed array between the others.
+        input.extend_from_slice(b"*?\r\n:1\r\n*?\r\n.\r\n.\r\n+OK\r\n");
+
+        let mut reader = RespReader::new(&input[..]);
+        for frame in &frames {
+            assert_eq!(&reader.read().await?, frame);
+        }
+        assert_eq!(
+            reader.read().await?,
+            Type::Array(vec![Type::Integer(1), Type::Array(vec![])])
+        );
+        assert_eq!(reader.read().await?, Type::SimpleString("OK".to_string()));
+        assert_eq!(reader.offset(), input.len() as u64);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn read_maps() -> Result<()> {
+        let input = b"%2\r\n$5\r\nfield\r\n$5\r\nvalue\r\n+n\r\n:1\r\n";
+        let hgetall = Type::Map(vec![
+            (Type::from("field"), Type::from("value")),
+            (Type::SimpleString("n".to_string()), Type::Integer(1)),
+        ]);
+        assert_eq!(Type::read(&mut &input[..]).await?, hgetall);
+
+        let nested = Type::Array(vec![
+            Type::Map(vec![]),
+            Type::Map(vec![(
+                Type::Map(vec![(Type::Integer(1), Type::Null)]),
+                Type::Array(vec![hgetall]),
+            )]),
+            Type::from("after"),
+        ]);
+        let buf = nested.to_bytes();
+        assert_eq!(Type::read(&mut buf.as_slice()).await?, nested);
+        let options = ReadOptions::new();
+        assert_eq!(super::buffered_frame_len(&buf, &options), Some(buf.len()));
+        assert_eq!(
+            super::buffered_frame_len(&buf[..buf.len() - 1], &options),
+            None
+        );
+
+        for input in [&b"%-1\r\n"[..], b">-1\r\n"] {
+            let err = Type::read(&mut &input[..]).await.unwrap_err();
+            assert!(matches!(err, Error::InvalidLength));
+        }
+        let err = Type::read(&mut &b"%1\r\n:1\r\n"[..]).await.unwrap_err();
+        assert!(matches!(err, Error::UnexpectedEof));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn read_streaming() -> Result<()> {
+        /// Keeps the first bytes of each payload, replacing it with its length.
+        #[derive(Default)]
+        struct Heads(Vec<Vec<u8>>);
+
+        impl BulkSink for Heads {
+            async fn bulk(
+                &mut self,
+                len: usize,
+                body: &mut (dyn AsyncRead + Unpin + Send),
+            ) -> Result<Type, Error> {
+                let mut head = vec![0; 4];
+                body.read_exact(&mut head).await?;
+                self.0.push(head);
+                Ok(Type::Integer(len as i64))
+            }
+        }
+
+        let big = "x".repeat(100_000);
+        let cmd = Type::from(vec!["SET", "key", big.as_str(), "PX", "100000"]);
+        let mut input = cmd.to_bytes();
+        input.extend_from_slice(b"+next\r\n");
+        let mut reader = RespReader::new(BufReader::with_capacity(1024, &input[..]));
+        let mut heads = Heads::default();
+        assert_eq!(
+            reader.read_streaming(6, &mut heads).await?,
+            Type::Array(vec![
+                Type::from("SET"),
+                Type::from("key"),
+                Type::Integer(100_000),
+                Type::from("PX"),
+                Type::Integer(6),
+            ])
+        );
+        assert_eq!(heads.0, [b"xxxx", b"1000"]);
+        assert_eq!(reader.read().await?, Type::SimpleString("next".to_string()));
+        assert_eq!(reader.offset(), input.len() as u64);
+
+        let err = RespReader::new(&b"$10\r\nshort"[..])
+            .read_streaming(1, &mut heads)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::UnexpectedEof));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn inline_commands() -> Result<()> {
+        let command = |args: &[&[u8]]| {
+            Type::Array(
+                args.iter()
+                    .map(|it| Type::BulkString(it.to_vec()))
+                    .collect(),
+            )
+        };
+        let input = b"PING\r\n\r\n  \r\nset  key \"hello \\\"world\\\"\\n\\x41\" \r\nSET 'it\\'s' a\"b c\"\r\n";
+        let options = ReadOptions::new().inline_commands(true);
+        let mut reader = RespReader::with_options(&input[..], options.clone());
+        assert_eq!(reader.read().await?, command(&[b"PING"]));
+        assert_eq!(
+            reader.read().await?,
+            command(&[b"set", b"key", b"hello \"world\"\nA"])
+        );
+        assert_eq!(reader.read().await?, command(&[b"SET", b"it's", b"ab c"]));
+
+        // Binary arguments.
+        let args = split_inline(br#""\xde\xAD" "\xzz""#)?;
+        assert_eq!(args, [&b"\xde\xad"[..], b"xzz"]);
+
+        for input in [&b"get \"key\r\n"[..], b"get \"key\"x\r\n", b"get 'key\r\n"] {
+            let err = Type::read_with(&mut &input[..], options.clone())
+                .await
+                .unwrap_err();
+            assert!(matches!(err, Error::UnbalancedQuotes));
+        }
+
+        // Only at the top level, and not by default outside of the servers.
+        let err = Type::read_with(&mut &b"*1\r\nPING\r\n"[..], options.clone())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::UnknownType(b'P')));
+        let err = Type::read(&mut &b"PING\r\n"[..]).await.unwrap_err();
+        assert!(matches!(err, Error::UnknownType(b'P')));
+        let commands = ReadOptions::new().for_commands();
+        let ping = Type::read_with(&mut &b"PING\r\n"[..], commands).await?;
+        assert_eq!(ping, command(&[b"PING"]));
+
+        assert_eq!(
+            super::buffered_frame_len(b"\r\n  \r\nPING\r\n", &options),
+            Some(12)
+        );
+        assert_eq!(super::buffered_frame_len(b"\r\n \r\n", &options), None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn resp3_null() -> Result<()> {
+        assert_eq!(Type::read(&mut &b"_\r\n"[..]).await?, Type::Null);
+        let err = Type::read(&mut &b"_1\r\n"[..]).await.unwrap_err();
+        assert!(matches!(err, Error::InvalidNull));
+
+        let ty = Type::Array(vec![Type::Null, Type::Map(vec![(Type::Null, Type::Null)])]);
+        assert_eq!(ty.to_bytes(), b"*2\r\n$-1\r\n%1\r\n$-1\r\n$-1\r\n");
+        let resp3 = ty.to_bytes_for(Protocol::Resp3);
+        assert_eq!(resp3, b"*2\r\n_\r\n%1\r\n_\r\n_\r\n");
+        assert_eq!(ty.encoded_len_for(Protocol::Resp3), resp3.len());
+        let mut written = Vec::new();
+        ty.write_to_for(&mut written, Protocol::Resp3).await?;
+        assert_eq!(written, resp3);
+        assert_eq!(Type::read(&mut resp3.as_slice()).await?, ty);
+        Ok(())
+    }
+
+    #[test]
+    fn to_resp2() {
+        let value = Type::Array(vec![
+            Type::Map(vec![(
+                Type::from("key"),
+                Type::Attribute {
+                    attrs: vec![(Type::from("ttl"), Type::Integer(3))],
+                    value: Box::new(Type::Map(vec![(Type::Integer(1), Type::Null)])),
+                },
+            )]),
+            Type::from("plain"),
+            Type::Boolean(false),
+            Type::BigNumber("-18446744073709551616".to_string()),
+            Type::Verbatim {
+                format: "txt".to_string(),
+                text: "report".to_string(),
+            },
+            Type::Push(vec![Type::from("invalidate"), Type::Push(vec![])]),
+        ]);
+        assert!(!value.is_resp2());
+        let expected = Type::Array(vec![
+            Type::Array(vec![
+                Type::from("key"),
+                Type::Array(vec![Type::Integer(1), Type::Null]),
+            ]),
+            Type::from("plain"),
+            Type::Integer(0),
+            Type::from("-18446744073709551616"),
+            Type::from("report"),
+            Type::Array(vec![Type::from("invalidate"), Type::Array(vec![])]),
+        ]);
+        assert!(expected.is_resp2());
+        assert_eq!(value.to_resp2(), expected);
+        assert_eq!(expected.clone().to_resp2(), expected);
+    }
+
+    #[tokio::test]
+    async fn map_from_pairs() -> Result<()> {
+        let map = Type::from(vec![("key".to_string(), "value".to_string())]);
+        let mut buf = Vec::new();
+        map.write(&mut buf).await?;
+        assert_eq!(buf, b"%1\r\n$3\r\nkey\r\n$5\r\nvalue\r\n");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn binary_bulk_strings() -> Result<()> {
+        let payload = b"\0\xff\r\n\xfe".to_vec();
+        let mut buf = Vec::new();
+        Type::BulkString(payload.clone()).write(&mut buf).await?;
+        assert_eq!(buf, b"$5\r\n\0\xff\r\n\xfe\r\n");
+        let ty = Type::read(&mut buf.as_slice()).await?;
+        assert_eq!(ty, Type::BulkString(payload.clone()));
+        assert_eq!(ty.as_bulk(), Some(&payload[..]));
+        assert_eq!(ty.as_str(), None);
+        assert_eq!(Type::from("a\0\r\n").as_str(), Some("a\0\r\n"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn attributes() -> Result<()> {
+        let popularity = || {
+            vec![(
+                Type::SimpleString("key-popularity".to_string()),
+                Type::Array(vec![Type::BulkString(b"a".to_vec()), Type::Integer(1)]),
+            )]
+        };
+        let decorated = Type::Attribute {
+            attrs: popularity(),
+            value: Box::new(Type::BulkString(b"value".to_vec())),
+        };
+
+        let mut buf = Vec::new();
+        decorated.write(&mut buf).await?;
+        assert_eq!(
+            buf,
+            b"|1\r\n+key-popularity\r\n*2\r\n$1\r\na\r\n:1\r\n$5\r\nvalue\r\n"
+        );
+        assert_eq!(Type::read(&mut buf.as_slice()).await?, decorated);
+
+        let nested = Type::Array(vec![
+            Type::Integer(1),
+            Type::Attribute {
+                attrs: popularity(),
+                value: Box::new(Type::Array(vec![Type::Null])),
+            },
+            Type::Integer(2),
+        ]);
+        let mut buf = Vec::new();
+        nested.write(&mut buf).await?;
+        assert_eq!(Type::read(&mut buf.as_slice()).await?, nested);
+
+        // A bare attribute is not a complete value.
+        let err = Type::read(&mut &b"|1\r\n+key\r\n:1\r\n"[..])
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::UnexpectedEof));
+
+        Ok(())
+    }
+
+    #[test]
+    fn headers() {
+        for n in [0, 7, -1, 10, 12345, -987654321, i64::MAX, i64::MIN] {
+            let header = Header::new(b':', n);
+            assert_eq!(header.as_bytes(), format!(":{}\r\n", n).as_bytes());
+        }
+    }
+
+    #[tokio::test]
+    async fn encoded_len() -> Result<()> {
+        let mut types = variants();
+        types.extend([
+            Type::Integer(i64::MIN),
+            Type::from("x".repeat(1234)),
+            Type::Array(variants()),
+        ]);
+        for ty in types {
+            let mut buf = Vec::new();
+            ty.write(&mut buf).await?;
+            assert_eq!(ty.encoded_len(), buf.len(), "{:?}", ty);
+        }
+
+        // Random nested values, from a xorshift generator so failures can be reproduced.
+        fn random(state: &mut u64, depth: usize) -> Type {
+            *state ^= *state << 13;
+            *state ^= *state >> 7;
+            *state ^= *state << 17;
+            let n = *state;
+            let len = (n >> 8) as usize % 300;
+            match n % if depth == 0 { 6 } else { 9 } {
+                0 => Type::SimpleString("s".repeat(len)),
+                1 => Type::Error("e".repeat(len)),
+                2 => Type::Integer((n >> 4) as i64),
+                3 => Type::from("b".repeat(len)),
+                4 => Type::BulkString(vec![0xff; len]),
+                5 => Type::Null,
+                6 => Type::Array((0..len % 8).map(|_| random(state, depth - 1)).collect()),
+                7 => Type::Map(
+                    (0..len % 4)
+                        .map(|_| (random(state, depth - 1), random(state, depth - 1)))
+                        .collect(),
+                ),
+                _ => Type::Attribute {
+                    attrs: vec![(random(state, depth - 1), random(state, depth - 1))],
+                    value: Box::new(random(state, depth - 1)),
+                },
+            }
+        }
+        let mut state = 0x2545_f491_4f6c_dd1d;
+        for _ in 0..500 {
+            let ty = random(&mut state, 4);
+            assert_eq!(ty.encoded_len(), ty.to_bytes().len(), "{:?}", ty);
+        }
+
+        let mut deep = Type::Null;
+        for _ in 0..100_000 {
+            deep = Type::Array(vec![deep]);
+        }
+        assert_eq!(deep.encoded_len(), deep.to_bytes().len());
+        // Dropped bit by bit, as dropping deeply nested values recurses.
+        while let Type::Array(mut elements) = deep {
+            deep = elements.pop().unwrap();
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn error_positions() -> Result<()> {
+        async fn position(input: &[u8]) -> Position {
+            let mut reader = RespReader::new(input);
+            loop {
+                if reader.read().await.is_err() {
+                    return reader.error_position().unwrap().clone();
+                }
+            }
+        }
+
+        let pos = position(b"+OK\r\n:12a\r\n").await;
+        assert_eq!(pos.offset, 5);
+        assert_eq!(pos.snippet, r":12a\r\n");
+
+        // Bad bulk string endings point at the bytes following the payload.
+        let pos = position(b"*2\r\n$3\r\nget\r\n$3\r\nkeyXX").await;
+        assert_eq!(pos.offset, 20);
+        assert_eq!(pos.snippet, "XX");
+
+        let pos = position(b":1\r\n\x01\xff\r\n").await;
+        assert_eq!(pos.offset, 4);
+        assert_eq!(pos.snippet, r"\x01\xff\r\n");
+
+        let long = [&b"+"[..], &[b'"'; 40], b"\n"].concat();
+        let pos = position(&long).await;
+        assert_eq!(pos.offset, 0);
+        assert_eq!(pos.snippet, format!("+{}...", r#"\""#.repeat(31)));
+
+        let options = ReadOptions::new().inline_commands(false);
+        let mut reader = RespReader::with_options(&b"\r\n"[..], options);
+        let err = reader.read().await.unwrap_err();
+        assert!(matches!(err, Error::UnknownType(b'\r')));
+        assert_eq!(
+            describe(&err, reader.error_position()),
+            r#"unknown type '\r' at byte 0 near "\r\n""#
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn try_from() -> Result<()> {
+        let bulk = Type::from;
+
+        assert_eq!(String::try_from(Type::SimpleString("OK".into()))?, "OK");
+        assert_eq!(i64::try_from(Type::Integer(-3))?, -3);
+        assert_eq!(i64::try_from(bulk("42"))?, 42);
+        assert_eq!(f64::try_from(bulk("1.5"))?, 1.5);
+        assert_eq!(f64::try_from(Type::Integer(2))?, 2.0);
+        assert!(bool::try_from(Type::Boolean(true))?);
+        assert!(!bool::try_from(Type::Integer(0))?);
+        assert_eq!(
+            Vec::<String>::try_from(Type::from(vec!["a", "b"]))?,
+            vec!["a", "b"]
+        );
+        assert_eq!(Option::<String>::try_from(Type::Null)?, None);
+        assert_eq!(
+            Option::<String>::try_from(bulk("a"))?,
+            Some("a".to_string())
+        );
+        let attributed = Type::Attribute {
+            attrs: vec![(bulk("ttl"), Type::Integer(1))],
+            value: Box::new(Type::Integer(7)),
+        };
+        assert_eq!(i64::try_from(attributed)?, 7);
+
+        let expected: HashMap<_, _> = vec![("a".to_string(), Type::Integer(1))]
+            .into_iter()
+            .collect();
+        let map = Type::Map(vec![(bulk("a"), Type::Integer(1))]);
+        assert_eq!(HashMap::try_from(map)?, expected);
+        let pairs = Type::Array(vec![bulk("a"), Type::Integer(1)]);
+        assert_eq!(HashMap::try_from(pairs)?, expected);
+
+        let errors = vec![
+            (
+                i64::try_from(bulk("abc")).unwrap_err(),
+                r#"expected an integer, found "abc""#,
+            ),
+            (
+                i64::try_from(bulk("+5")).unwrap_err(),
+                r#"expected an integer, found "+5""#,
+            ),
+            (
+                String::try_from(Type::Integer(1)).unwrap_err(),
+                "expected a string, found integer 1",
+            ),
+            (
+                String::try_from(Type::BulkString(b"caf\xe9".to_vec())).unwrap_err(),
+                r#"expected a string, found "caf\xe9""#,
+            ),
+            (
+                f64::try_from(bulk("nan")).unwrap_err(),
+                r#"expected a float, found "nan""#,
+            ),
+            (
+                bool::try_from(Type::Integer(2)).unwrap_err(),
+                "expected a boolean, found integer 2",
+            ),
+            (
+                Vec::<String>::try_from(Type::Array(vec![bulk("a"), Type::Null])).unwrap_err(),
+                "expected a string, found null",
+            ),
+            (
+                HashMap::try_from(Type::Array(vec![bulk("a")])).unwrap_err(),
+                "expected a map, found an array",
+            ),
+            (
+                String::try_from(Type::Error("ERR oops".into())).unwrap_err(),
+                "expected a string, found an error",
+            ),
+        ];
+        for (err, expected) in errors {
+            assert_eq!(err.to_string(), expected);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn redis_reasons() -> Result<()> {
+        async fn reason(input: &[u8], options: ReadOptions) -> String {
+            let mut reader = RespReader::with_options(input, options);
+            let err = reader.read().await.unwrap_err();
+            redis_reason(&err, reader.error_position())
+        }
+
+        let options = ReadOptions::new()
+            .max_depth(1)
+            .max_line_len(8)
+            .inline_commands(true);
+        let cases: &[(&[u8], &str)] = &[
+            (b"*x\r\n", "invalid multibulk length"),
+            (b"*-2\r\n", "invalid multibulk length"),
+            (b"*1\r\n$x\r\n", "invalid bulk length"),
+            (b"*1\r\n$999999999999\r\n", "too big bulk count string"),
+            (b"*123456789\r\n", "too big mbulk count string"),
+            (b"GET 'k\r\n", "unbalanced quotes in request"),
+            (b"GET key value\r\n", "too big inline request"),
+            (b"*1\r\n!x\r\n", "expected '$', got '!'"),
+            (b"*1\r\n*1\r\n", "expected '$', got '*'"),
+            (b"*1\r\n#x\r\n", "invalid boolean"),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(reason(input, options.clone()).await, *expected);
+        }
+
+        let options = ReadOptions::new().max_bulk_len(4).max_array_len(4);
+        let cases: &[(&[u8], &str)] = &[
+            (b"*1\r\n$5\r\n", "invalid bulk length"),
+            (b"*5\r\n", "invalid multibulk length"),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(reason(input, options.clone()).await, *expected);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn max_line_len() -> Result<()> {
+        // 10 MiB without a line ending, never buffered as a whole.
+        let src = tokio::io::repeat(b'a').take(10 * 1024 * 1024);
+        let mut reader = RespReader::new(BufReader::new(src));
+        let err = reader.read().await.unwrap_err();
+        assert!(matches!(err, Error::LineTooLong));
+        assert_eq!(reader.offset(), DEFAULT_MAX_LINE_LEN as u64);
+        assert!(reader.line.capacity() <= 2 * DEFAULT_MAX_LINE_LEN);
+
+        let options = ReadOptions::new().max_line_len(8);
+        assert_eq!(
+            Type::read_with(&mut &b"+12345\r\n"[..], options.clone()).await?,
+            Type::SimpleString("12345".to_string())
+        );
+        for input in [&b"+123456\r\n"[..], b"*1\r\n:1234567\r\n"] {
+            let err = Type::read_with(&mut &input[..], options.clone())
+                .await
+                .unwrap_err();
+            assert!(matches!(err, Error::LineTooLong));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn max_array_len() -> Result<()> {
+        let options = ReadOptions::new().max_array_len(2);
+        assert_eq!(
+            Type::read_with(&mut &b"*2\r\n:1\r\n:2\r\n"[..], options.clone()).await?,
+            Type::Array(vec![Type::Integer(1), Type::Integer(2)])
+        );
+        for input in [
+            &b"*3\r\n"[..],
+            b"*4611686018427387903\r\n",
+            b">3\r\n",
+            b"%3\r\n",
+            b"|3\r\n",
+            b"*1\r\n*3\r\n",
+            b"*?\r\n:1\r\n:2\r\n:3\r\n",
+            b"%?\r\n:1\r\n:1\r\n:2\r\n:2\r\n:3\r\n:3\r\n",
+        ] {
+            let err = Type::read_with(&mut &input[..], options.clone())
+                .await
+                .unwrap_err();
+            assert!(matches!(err, Error::ArrayTooLong));
+        }
+        assert_eq!(super::buffered_frame_len(b"*3\r\n", &options), Some(4));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn max_value_len() -> Result<()> {
+        let big = vec![b'x'; 3 * PAYLOAD_CHUNK_LEN];
+        let input = [
+            &b"*3\r\n$3\r\nset\r\n$"[..],
+            big.len().to_string().as_bytes(),
+            b"\r\n",
+            &big,
+            b"\r\n$?\r\n;4\r\nabcd\r\n;0\r\n",
+            b"$?\r\n;3\r\nabc\r\n;3\r\nabc\r\n;0\r\n",
+            b"*1\r\n$4\r\nping\r\n",
+        ]
+        .concat();
+        let options = ReadOptions::new().max_value_len(5);
+        let mut reader = RespReader::with_options(&input[..], options);
+
+        let err = reader.read().await.unwrap_err();
+        assert!(matches!(err, Error::ValueTooLarge));
+        // Points at the header of the oversized string.
+        assert_eq!(reader.error_position().unwrap().offset, 13);
+        // Streamed strings are limited as a whole.
+        let err = reader.read().await.unwrap_err();
+        assert!(matches!(err, Error::ValueTooLarge));
+        assert_eq!(reader.read().await?, Type::from(vec!["ping"]));
+        assert_eq!(reader.offset(), input.len() as u64);
+
+        // The protocol limit still closes the stream.
+        let options = ReadOptions::new().max_bulk_len(4).max_value_len(2);
+        let err = Type::read_with(&mut &b"$5\r\nhello\r\n"[..], options)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::BulkTooLong));
+
+        Ok(())
+    }
+
+    /// Inputs that used to panic, overflow the stack or allocate whatever length they claimed.
+    #[tokio::test]
+    async fn fuzz_regressions() -> Result<()> {
+        for input in [
+            &b"$4611686018427387903\r\nabc"[..],
+            b"*4611686018427387903\r\n:1\r\n",
+            b"|4611686018427387903\r\n",
+        ] {
+            let err = Type::read(&mut &input[..]).await.unwrap_err();
+            assert!(matches!(
+                err,
+                Error::BulkTooLong | Error::ArrayTooLong | Error::UnexpectedEof
+            ));
+        }
+
+        let err = Type::read_with(
+            &mut &b"$?\r\n;3\r\nabc\r\n;3\r\ndef\r\n;0\r\n"[..],
+            ReadOptions::new().max_bulk_len(5),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, Error::BulkTooLong));
+
+        let input = [&b"|0\r\n".repeat(100_000)[..], b":1\r\n"].concat();
+        let err = Type::read(&mut &input[..]).await.unwrap_err();
+        assert!(matches!(err, Error::NestingTooDeep));
+
+        let options = ReadOptions::new().inline_commands(false);
+        assert_eq!(super::buffered_frame_len(b"\r\n", &options), Some(2));
+
+        Ok(())
+    }
+
+    #[cfg(feature = "arbitrary")]
+    #[tokio::test]
+    async fn arbitrary_round_trip() -> Result<()> {
+        use arbitrary::{Arbitrary, Unstructured};
+
+        let data: Vec<u8> = (0..4096u32).map(|i| (i * 7919 % 251) as u8).collect();
+        let mut u = Unstructured::new(&data);
+        while !u.is_empty() {
+            let ty = Type::arbitrary(&mut u)?;
+            let mut buf = Vec::new();
+            ty.write(&mut buf).await?;
+            assert_eq!(Type::read(&mut buf.as_slice()).await?, ty);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn streamed_values() -> Result<()> {
+        let input = b"$?\r\n;4\r\nHell\r\n;5\r\no wor\r\n;2\r\nld\r\n;0\r\n";
+        assert_eq!(
+            Type::read(&mut &input[..]).await?,
+            Type::BulkString(b"Hello world".to_vec())
+        );
+
+        let input = b"*?\r\n:1\r\n*?\r\n.\r\n$?\r\n;1\r\na\r\n;0\r\n.\r\n";
+        assert_eq!(
+            Type::read(&mut &input[..]).await?,
+            Type::Array(vec![
+                Type::Integer(1),
+                Type::Array(vec![]),
+                Type::BulkString(b"a".to_vec()),
+            ])
+        );
+
+        let input = b"%?\r\n+a\r\n%?\r\n.\r\n+b\r\n*?\r\n.\r\n.\r\n";
+        let map = Type::Map(vec![
+            (Type::SimpleString("a".to_string()), Type::Map(vec![])),
+            (Type::SimpleString("b".to_string()), Type::Array(vec![])),
+        ]);
+        assert_eq!(Type::read(&mut &input[..]).await?, map);
+        let options = ReadOptions::new();
+        assert_eq!(
+            super::buffered_frame_len(input, &options),
+            Some(input.len())
+        );
+        assert_eq!(
+            super::buffered_frame_len(&input[..input.len() - 3], &options),
+            None
         );
+
+        let err = Type::read(&mut &b"$?\r\n:1\r\n"[..]).await.unwrap_err();
+        assert!(matches!(err, Error::ExpectedChunk));
+        let err = Type::read(&mut &b"*?\r\n.x\r\n"[..]).await.unwrap_err();
+        assert!(matches!(err, Error::InvalidEndMarker));
+        assert!(err.is_protocol_error());
+        assert!(!Error::UnexpectedEof.is_protocol_error());
+
+        // The end marker can't stand for the value of a pair.
+        let err = Type::read(&mut &b"%?\r\n+a\r\n.\r\n"[..])
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::UnknownType(b'.')));
+
+        for input in [
+            &b"$?\r\n;4\r\nHell\r\n"[..],
+            b"*?\r\n:1\r\n",
+            b"%?\r\n:1\r\n:2\r\n",
+        ] {
+            let err = Type::read(&mut &input[..]).await.unwrap_err();
+            assert!(matches!(err, Error::UnexpectedEof));
+        }
+
         Ok(())
     }
 }
@@ -0,0 +1,310 @@
+use std::collections::HashMap;
+
+use crate::command::Command;
+use crate::conn::Conn;
+use crate::handler::{boxed, BoxHandler, Handler, SpawnBlocking};
+use crate::resp::{escape, Type};
+
+/// Dispatches commands to handlers by name, ignoring ASCII case.
+///
+/// Names are matched as bytes, so commands that aren't valid UTF-8 are dispatched too, and
+/// the error replies escape the bytes that aren't printable.
+///
+/// ```no_run
+/// use redcon::{Command, Conn, Router};
+///
+/// let mut router = Router::new();
+/// router.command("PING").handler(|conn: Conn, _cmd: Command| async move {
+///     conn.write_pong().await.unwrap();
+/// });
+/// router
+///     .command("CONFIG")
+///     .sub("GET", |conn: Conn, cmd: Command| async move {
+///         // `cmd[0]` is the parameter, e.g. `maxmemory` in `CONFIG GET maxmemory`.
+///         conn.write_array(Vec::<String>::new()).await.unwrap();
+///     });
+/// ```
+#[derive(Default)]
+pub struct Router {
+    routes: HashMap<Vec<u8>, Route>,
+    unknown: Option<BoxHandler>,
+}
+
+/// Handlers of a command registered with [`Router::command`].
+pub struct Route {
+    name: String,
+    handler: Option<BoxHandler>,
+    subs: HashMap<Vec<u8>, BoxHandler>,
+    unknown_sub: Option<BoxHandler>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the route of the command, adding it if needed.
+    pub fn command(&mut self, name: &str) -> &mut Route {
+        let name = name.to_ascii_uppercase();
+        self.routes
+            .entry(name.clone().into_bytes())
+            .or_insert_with(|| Route {
+                name,
+                handler: None,
+                subs: HashMap::new(),
+                unknown_sub: None,
+            })
+    }
+
+    /// Sets the handler of unknown commands, which reply with
+    /// `ERR unknown command '<name>'` by default.
+    pub fn unknown<H: Handler>(&mut self, handler: H) -> &mut Self {
+        self.unknown = Some(boxed(handler));
+        self
+    }
+}
+
+impl Route {
+    /// Sets the handler of the command, called with the whole command.
+    ///
+    /// Once subcommands are added, the handler is only called for commands without a
+    /// subcommand.
+    pub fn handler<H: Handler>(&mut self, handler: H) -> &mut Self {
+        self.handler = Some(boxed(handler));
+        self
+    }
+
+    /// Sets a synchronous handler of the command, run on the blocking thread pool and returning
+    /// the reply, see [`SpawnBlocking`].
+    pub fn blocking<F, R>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(Command) -> R + Send + Sync + 'static,
+        R: Into<Type>,
+    {
+        self.handler(SpawnBlocking::new(f))
+    }
+
+    /// Adds a subcommand, matched against the first argument ignoring ASCII case.
+    ///
+    /// The handler is called with the arguments following the subcommand, so `cmd[0]` is the
+    /// first of them rather than the command name.
+    pub fn sub<H: Handler>(&mut self, name: &str, handler: H) -> &mut Self {
+        self.subs
+            .insert(name.as_bytes().to_ascii_uppercase(), boxed(handler));
+        self
+    }
+
+    /// Sets the handler of unknown subcommands, called with the whole command. Replies with
+    /// `ERR Unknown <COMMAND> subcommand or wrong number of arguments for '<sub>'` by default.
+    pub fn unknown_sub<H: Handler>(&mut self, handler: H) -> &mut Self {
+        self.unknown_sub = Some(boxed(handler));
+        self
+    }
+
+    async fn call(&self, conn: Conn, cmd: Command) {
+        let sub = match cmd.get(1) {
+            Some(sub) if !self.subs.is_empty() => sub,
+            _ => {
+                return match &self.handler {
+                    Some(handler) => handler(conn, cmd).await,
+                    None => reply(&conn, cmd.wrong_arity().to_string()).await,
+                }
+            }
+        };
+
+        if let Some(handler) = self.subs.get(&sub.to_ascii_uppercase()) {
+            return handler(conn, cmd.into_iter().skip(2).collect()).await;
+        }
+        match &self.unknown_sub {
+            Some(handler) => handler(conn, cmd).await,
+            None => {
+                let msg = format!(
+                    "ERR Unknown {} subcommand or wrong number of arguments for '{}'",
+                    self.name,
+                    escape(sub)
+                );
+                reply(&conn, msg).await
+            }
+        }
+    }
+}
+
+async fn reply(conn: &Conn, err: String) {
+    if let Err(err) = conn.write_error(err).await {
+        eprintln!("could not write to client: {}", err);
+    }
+}
+
+impl Handler for Router {
+    async fn call(&self, conn: Conn, cmd: Command) {
+        if let Some(route) = self.routes.get(cmd.name_uppercase()) {
+            return route.call(conn, cmd).await;
+        }
+        match &self.unknown {
+            Some(handler) => handler(conn, cmd).await,
+            None => {
+                let msg = format!("ERR unknown command '{}'", cmd.name_escaped());
+                reply(&conn, msg).await
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use anyhow::Result;
+    use tokio::io::{AsyncWriteExt, BufStream};
+    use tokio::net::TcpStream;
+    use tokio::time::timeout;
+
+    use super::*;
+    use crate::server::Server;
+
+    async fn send(client: &mut BufStream<TcpStream>, args: &[&str]) -> Result<Type> {
+        let args: Vec<&[u8]> = args.iter().map(|it| it.as_bytes()).collect();
+        send_bytes(client, &args).await
+    }
+
+    async fn send_bytes(client: &mut BufStream<TcpStream>, args: &[&[u8]]) -> Result<Type> {
+        Type::Array(
+            args.iter()
+                .map(|it| Type::BulkString(it.to_vec()))
+                .collect(),
+        )
+        .write(&mut *client)
+        .await?;
+        Ok(Type::read(client).await?)
+    }
+
+    #[tokio::test]
+    async fn subcommands() -> Result<()> {
+        let mut router = Router::new();
+        router
+            .command("CONFIG")
+            .sub("GET", |conn: Conn, cmd: Command| async move {
+                conn.write_array(vec![cmd.arg_str(0).unwrap(), "yes"])
+                    .await
+                    .unwrap();
+            })
+            .sub("SET", |conn: Conn, cmd: Command| async move {
+                let reply = format!("{}={}", cmd.arg_str(0).unwrap(), cmd.arg_str(1).unwrap());
+                conn.write_simple_string(reply).await.unwrap();
+            });
+        let server = Server::builder().bind("127.0.0.1:0").serve(router).await?;
+        let mut client = BufStream::new(TcpStream::connect(server.local_addr()).await?);
+
+        assert_eq!(
+            send(&mut client, &["config", "get", "appendonly"]).await?,
+            Type::from(vec!["appendonly", "yes"])
+        );
+        assert_eq!(
+            send(&mut client, &["CONFIG", "Set", "maxmemory", "10mb"]).await?,
+            Type::SimpleString("maxmemory=10mb".to_string())
+        );
+        assert_eq!(
+            send(&mut client, &["CONFIG", "foo"]).await?,
+            Type::Error(
+                "ERR Unknown CONFIG subcommand or wrong number of arguments for 'foo'".to_string()
+            )
+        );
+        assert_eq!(
+            send(&mut client, &["config"]).await?,
+            Type::Error("ERR wrong number of arguments for 'config' command".to_string())
+        );
+        assert_eq!(
+            send(&mut client, &["FLUSHALL"]).await?,
+            Type::Error("ERR unknown command 'FLUSHALL'".to_string())
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn blocking_handlers() -> Result<()> {
+        let mut router = Router::new();
+        router.command("SLOW").blocking(|cmd: Command| {
+            std::thread::sleep(Duration::from_millis(200));
+            Type::BulkString(cmd[1].clone())
+        });
+        router
+            .command("PING")
+            .handler(|conn: Conn, _cmd: Command| async move {
+                conn.write_pong().await.unwrap();
+            });
+        let server = Server::builder().bind("127.0.0.1:0").serve(router).await?;
+        let mut client = BufStream::new(TcpStream::connect(server.local_addr()).await?);
+        let mut other = BufStream::new(TcpStream::connect(server.local_addr()).await?);
+
+        let mut pipeline = Vec::new();
+        Type::from(vec!["slow", "done"])
+            .write(&mut pipeline)
+            .await?;
+        Type::from(vec!["ping"]).write(&mut pipeline).await?;
+        client.write_all(&pipeline).await?;
+        client.flush().await?;
+
+        // Served while the blocking handler sleeps.
+        let pong = timeout(Duration::from_millis(100), send(&mut other, &["ping"])).await??;
+        assert_eq!(pong, Type::SimpleString("PONG".to_string()));
+
+        assert_eq!(Type::read(&mut client).await?, Type::from("done"));
+        assert_eq!(
+            Type::read(&mut client).await?,
+            Type::SimpleString("PONG".to_string())
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn binary_keys() -> Result<()> {
+        let store = Arc::new(Mutex::new(HashMap::new()));
+        let mut router = Router::new();
+        let set_store = store.clone();
+        router
+            .command("SET")
+            .handler(move |conn: Conn, cmd: Command| {
+                let store = set_store.clone();
+                async move {
+                    let mut args = cmd.into_iter().skip(1);
+                    let (key, value) = (args.next().unwrap(), args.next().unwrap());
+                    store.lock().unwrap().insert(key, value);
+                    conn.write_ok().await.unwrap();
+                }
+            });
+        router
+            .command("GET")
+            .handler(move |conn: Conn, cmd: Command| {
+                let value = store.lock().unwrap().get(&cmd[1]).cloned();
+                async move {
+                    conn.write_value(value.map(Type::BulkString)).await.unwrap();
+                }
+            });
+        let server = Server::builder().bind("127.0.0.1:0").serve(router).await?;
+        let mut client = BufStream::new(TcpStream::connect(server.local_addr()).await?);
+
+        let key = [0xde, 0xad, 0xbe, 0xef];
+        assert_eq!(
+            send_bytes(&mut client, &[b"set", &key, b"\xff\x00"]).await?,
+            Type::SimpleString("OK".to_string())
+        );
+        assert_eq!(
+            send_bytes(&mut client, &[b"GET", &key]).await?,
+            Type::BulkString(b"\xff\x00".to_vec())
+        );
+        assert_eq!(
+            send_bytes(&mut client, &[b"get", b"\xde\xad"]).await?,
+            Type::Null
+        );
+        assert_eq!(
+            send_bytes(&mut client, &[b"G\xc3T", &key]).await?,
+            Type::Error("ERR unknown command 'G\\xc3T'".to_string())
+        );
+        assert_eq!(
+            send_bytes(&mut client, &[b"\r\n\x00"]).await?,
+            Type::Error("ERR unknown command '\\r\\n\\x00'".to_string())
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,33 @@
+use std::convert::TryFrom;
+
+use anyhow::Result;
+
+use crate::command::Command;
+use crate::conn::Conn;
+
+/// Switches the database of the connection as asked by `SELECT <index>`, replying like Redis.
+pub(crate) async fn select(conn: &Conn, cmd: &Command, databases: usize) -> Result<()> {
+    if cmd.len() != 2 {
+        return conn
+            .write_error("ERR wrong number of arguments for 'select' command".to_string())
+            .await;
+    }
+    let index = match cmd.arg_i64(1) {
+        Ok(it) => it,
+        Err(_) => {
+            return conn
+                .write_error("ERR value is not an integer or out of range".to_string())
+                .await
+        }
+    };
+    match usize::try_from(index) {
+        Ok(index) if index < databases => {
+            conn.set_db(index);
+            conn.write_ok().await
+        }
+        _ => {
+            conn.write_error("ERR DB index is out of range".to_string())
+                .await
+        }
+    }
+}
@@ -0,0 +1,4167 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::future::{self, Future};
+use std::io;
+use std::net::SocketAddr;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::pin;
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use tokio::io::BufReader;
+use tokio::net::{lookup_host, TcpListener, TcpSocket, TcpStream};
+use tokio::sync::{watch, Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::task::{self, JoinSet};
+use tokio::time::{self, sleep};
+use tokio_util::sync::CancellationToken;
+
+use crate::command::Command;
+use crate::conn::{
+    CommandScope, Conn, ConnId, ConnOptions, DisconnectReason, DrainOutcome, DrainRequest,
+    FlushPolicy, DEFAULT_BUFFER_SIZE, SCOPE,
+};
+use crate::conn_stats::ConnStats;
+use crate::handler::Handler;
+use crate::hello::{hello, is_hello, HelloInfo};
+use crate::info::{InfoBuilder, InfoFn};
+use crate::metrics::{CountingReader, Metrics};
+use crate::middleware::{BoxMiddleware, Next, Wrapped};
+use crate::output::OutputLimit;
+use crate::rate_limit::{RateLimit, RateLimitPolicy, TokenBucket};
+use crate::reply_mode::{client_reply, is_client_reply, ReplyMode};
+use crate::resp::{describe, redis_reason, ReadFailure, ReadOptions, RespReader, Type};
+use crate::select::select;
+use crate::slowlog::{Slowlog, SlowlogEntry};
+use crate::tap::{Direction, Tap, TapFn};
+
+type DisconnectHook = dyn Fn(&Conn, DisconnectReason) + Send + Sync;
+type PanicHook = dyn Fn(&Conn, &str) + Send + Sync;
+type AcceptFilter = dyn Fn(SocketAddr) -> AcceptDecision + Send + Sync;
+
+/// Whether to serve a connection, returned by the filter set with [`Builder::accept_filter`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AcceptDecision {
+    Accept,
+    /// Closes the connection right away.
+    Reject,
+    /// Replies with the error, e.g. `ERR max number of clients reached`, then closes the
+    /// connection.
+    RejectWithError(String),
+}
+
+/// How the commands of a connection are handled, see [`Builder::processing_mode`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ProcessingMode {
+    /// Handles the commands of a connection one at a time, in order, like Redis. While a
+    /// handler runs, the next command is read and parsed ahead but not handled until the
+    /// handler finishes. Nothing more is read meanwhile, so a slow handler slows its client
+    /// down through TCP backpressure.
+    #[default]
+    Sequential,
+    /// Handles up to [`Builder::max_in_flight`] commands of a connection concurrently. The
+    /// handlers may run and reply in any order, unless they coordinate the order of their
+    /// replies with e.g. [`Conn::defer`].
+    Concurrent,
+}
+
+/// What the server does when a handler returns without replying to its command, which leaves
+/// the client waiting and shifts the replies to the commands pipelined after it.
+///
+/// Commands handled in a batch, see [`Builder::max_batch`], and commands whose handler calls
+/// [`Conn::no_reply`] are not checked, nor are replies written by tasks other than the handler.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum MissingReplyPolicy {
+    /// Writes a null and logs a warning naming the command.
+    #[default]
+    WriteNullAndWarn,
+    /// Writes nothing, e.g. for servers pushing messages to subscribers instead of replying.
+    Ignore,
+    /// Writes `-ERR no reply produced`.
+    Error,
+}
+
+/// How the server words the `-ERR Protocol error: ...` reply it sends before closing a
+/// connection that sent something that is not RESP.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ProtocolErrorStyle {
+    /// Names the error and where it occurred, e.g.
+    /// `-ERR Protocol error: invalid length at byte 0 near "*-2\r\n"`.
+    #[default]
+    Detailed,
+    /// Uses the messages of Redis, e.g. `-ERR Protocol error: invalid multibulk length`, for
+    /// clients and test suites that match on them.
+    Redis,
+}
+
+/// Configures and starts a server.
+pub struct Builder {
+    addrs: Vec<String>,
+    on_disconnect: Option<Arc<DisconnectHook>>,
+    on_panic: Option<Arc<PanicHook>>,
+    accept_filter: Option<Arc<AcceptFilter>>,
+    close_on_panic: bool,
+    accept_loops: usize,
+    read_buffer: usize,
+    write_buffer: usize,
+    flush_policy: FlushPolicy,
+    max_batch: usize,
+    max_in_flight: usize,
+    processing_mode: ProcessingMode,
+    yield_every: usize,
+    missing_reply: MissingReplyPolicy,
+    read_options: ReadOptions,
+    protocol_error_style: ProtocolErrorStyle,
+    rate_limit: Option<RateLimit>,
+    metrics: Option<Arc<dyn Metrics>>,
+    hello: Option<HelloInfo>,
+    handler_timeout: Option<Duration>,
+    timeout_error: String,
+    deferred_timeout: Option<Duration>,
+    client_reply: bool,
+    databases: Option<usize>,
+    info: Option<Arc<InfoFn>>,
+    middlewares: Vec<BoxMiddleware>,
+    slowlog: Option<(Duration, usize)>,
+    slowlog_arg_len: usize,
+    output_limit: Option<OutputLimit>,
+    tap: Option<Arc<TapFn>>,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self {
+            addrs: Vec::new(),
+            on_disconnect: None,
+            on_panic: None,
+            accept_filter: None,
+            close_on_panic: false,
+            accept_loops: 1,
+            read_buffer: DEFAULT_BUFFER_SIZE,
+            write_buffer: DEFAULT_BUFFER_SIZE,
+            flush_policy: FlushPolicy::default(),
+            max_batch: 1,
+            max_in_flight: 128,
+            processing_mode: ProcessingMode::default(),
+            yield_every: 64,
+            missing_reply: MissingReplyPolicy::default(),
+            read_options: ReadOptions::default(),
+            protocol_error_style: ProtocolErrorStyle::default(),
+            rate_limit: None,
+            metrics: None,
+            hello: None,
+            handler_timeout: None,
+            timeout_error: "ERR command timed out".to_string(),
+            deferred_timeout: None,
+            client_reply: false,
+            databases: None,
+            info: None,
+            middlewares: Vec::new(),
+            slowlog: None,
+            slowlog_arg_len: 128,
+            output_limit: None,
+            tap: None,
+        }
+    }
+}
+
+impl Builder {
+    /// Adds an address to listen on.
+    ///
+    /// Can be called multiple times to serve the same handler on several addresses.
+    pub fn bind(mut self, addr: impl Into<String>) -> Self {
+        self.addrs.push(addr.into());
+        self
+    }
+
+    /// Sets a hook that is called once a connection is closed, with the reason it was closed.
+    pub fn on_disconnect(
+        mut self,
+        hook: impl Fn(&Conn, DisconnectReason) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_disconnect = Some(Arc::new(hook));
+        self
+    }
+
+    /// Sets a hook that is called with the panic message when a handler panics.
+    pub fn on_panic(mut self, hook: impl Fn(&Conn, &str) + Send + Sync + 'static) -> Self {
+        self.on_panic = Some(Arc::new(hook));
+        self
+    }
+
+    /// Decides whether to serve each connection from its peer address, before anything else
+    /// happens to it.
+    ///
+    /// Rejected connections are closed without being counted nor reported to
+    /// [`Metrics`], and [`Builder::on_disconnect`] isn't called for them.
+    pub fn accept_filter(
+        mut self,
+        filter: impl Fn(SocketAddr) -> AcceptDecision + Send + Sync + 'static,
+    ) -> Self {
+        self.accept_filter = Some(Arc::new(filter));
+        self
+    }
+
+    /// Closes the connection when a handler panics, instead of replying with an error.
+    ///
+    /// A handler panicking after it started replying always closes the connection, as an
+    /// error would be taken for the reply to the next command.
+    pub fn close_on_panic(mut self, close: bool) -> Self {
+        self.close_on_panic = close;
+        self
+    }
+
+    /// Sets the number of accept loops per address, defaults to 1.
+    ///
+    /// With more than one loop, each loop gets its own listener socket bound with
+    /// `SO_REUSEPORT` so the kernel balances incoming connections between them. Binding fails
+    /// on platforms without `SO_REUSEPORT` support.
+    pub fn accept_loops(mut self, n: usize) -> Self {
+        assert!(n > 0, "at least one accept loop is required");
+        self.accept_loops = n;
+        self
+    }
+
+    /// Sets the capacity of the read buffer of each connection, defaults to 8 KB.
+    ///
+    /// Smaller buffers save memory with many idle connections, bigger ones save syscalls when
+    /// clients send large payloads.
+    pub fn read_buffer(mut self, capacity: usize) -> Self {
+        self.read_buffer = capacity;
+        self
+    }
+
+    /// Sets the capacity of the write buffer of each connection, defaults to 8 KB.
+    pub fn write_buffer(mut self, capacity: usize) -> Self {
+        self.write_buffer = capacity;
+        self
+    }
+
+    /// Sets when the replies are flushed to the socket, defaults to [`FlushPolicy::Eager`].
+    pub fn flush_policy(mut self, policy: FlushPolicy) -> Self {
+        self.flush_policy = policy;
+        self
+    }
+
+    /// Sets the maximum number of commands delivered to the handler at once, defaults to 1.
+    ///
+    /// With a maximum above 1, pipelined commands that are already buffered on a connection
+    /// are collected and delivered together to [`Handler::call_batch`], which is expected to
+    /// reply to them in order.
+    pub fn max_batch(mut self, n: usize) -> Self {
+        assert!(n > 0, "batches must hold at least one command");
+        self.max_batch = n;
+        self
+    }
+
+    /// Sets the maximum number of commands handled concurrently for each connection with
+    /// [`ProcessingMode::Concurrent`], defaults to 128.
+    ///
+    /// Once a connection reaches the limit, its next command waits for a handler to finish and
+    /// the server stops reading from it meanwhile, so clients pipelining faster than the
+    /// handlers keep up are slowed down by TCP backpressure. A batch counts as a single
+    /// command, see [`Builder::max_batch`].
+    pub fn max_in_flight(mut self, n: usize) -> Self {
+        assert!(n > 0, "at least one command must be allowed in flight");
+        self.max_in_flight = n;
+        self
+    }
+
+    /// Sets after how many commands read in a row without waiting for the client a connection
+    /// yields to the runtime, defaults to 64.
+    ///
+    /// A client pipelining megabytes of commands at once would otherwise keep the runtime
+    /// thread busy until all of them are dispatched, delaying the other connections served by
+    /// the same thread. A batch counts as a single command, see [`Builder::max_batch`].
+    pub fn yield_every(mut self, n: usize) -> Self {
+        assert!(n > 0, "yielding requires at least one command in between");
+        self.yield_every = n;
+        self
+    }
+
+    /// Sets what happens when a handler returns without replying, defaults to
+    /// [`MissingReplyPolicy::WriteNullAndWarn`].
+    pub fn missing_reply(mut self, policy: MissingReplyPolicy) -> Self {
+        self.missing_reply = policy;
+        self
+    }
+
+    /// Sets whether the commands of a connection are handled one at a time or concurrently,
+    /// defaults to [`ProcessingMode::Sequential`].
+    ///
+    /// Different connections are always handled concurrently.
+    pub fn processing_mode(mut self, mode: ProcessingMode) -> Self {
+        self.processing_mode = mode;
+        self
+    }
+
+    /// Sets the options used for reading commands from the connections.
+    pub fn read_options(mut self, options: ReadOptions) -> Self {
+        self.read_options = options;
+        self
+    }
+
+    /// Sets how the replies to protocol errors are worded, defaults to
+    /// [`ProtocolErrorStyle::Detailed`].
+    pub fn protocol_error_style(mut self, style: ProtocolErrorStyle) -> Self {
+        self.protocol_error_style = style;
+        self
+    }
+
+    /// Limits the rate of commands accepted from each connection, unlimited by default.
+    pub fn rate_limit(mut self, limit: RateLimit) -> Self {
+        self.rate_limit = Some(limit);
+        self
+    }
+
+    /// Sets the receiver of the connection, command and traffic events.
+    pub fn metrics(mut self, metrics: impl Metrics) -> Self {
+        self.metrics = Some(Arc::new(metrics));
+        self
+    }
+
+    /// Replies to `HELLO` without calling the handler, with the info customized by `f`.
+    ///
+    /// `HELLO 2` and `HELLO 3` switch the protocol of the connection before replying, so the
+    /// reply is a map on RESP3. Other versions are rejected with `NOPROTO`, and the rest of the
+    /// options like `AUTH` are ignored.
+    pub fn hello_info(mut self, f: impl FnOnce(&mut HelloInfo)) -> Self {
+        let mut info = HelloInfo::default();
+        f(&mut info);
+        self.hello = Some(info);
+        self
+    }
+
+    /// Aborts handlers that take longer than the timeout, unlimited by default.
+    ///
+    /// The client gets the error set with [`Builder::timeout_error`] in place of the reply and
+    /// the connection keeps serving commands. If the handler already wrote part of its reply,
+    /// the connection can't tell where the reply ends anymore and is closed instead. A batch
+    /// of commands gets the timeout as a whole, see [`Builder::max_batch`].
+    pub fn handler_timeout(mut self, timeout: Duration) -> Self {
+        self.handler_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the error replied when a handler times out, defaults to `ERR command timed out`.
+    pub fn timeout_error(mut self, err: impl Into<String>) -> Self {
+        self.timeout_error = err.into();
+        self
+    }
+
+    /// Replies with a null to deferred replies that are not resolved within the timeout,
+    /// unlimited by default. See [`Conn::defer`].
+    pub fn deferred_timeout(mut self, timeout: Duration) -> Self {
+        self.deferred_timeout = Some(timeout);
+        self
+    }
+
+    /// Handles `CLIENT REPLY ON|OFF|SKIP` without calling the handler, see
+    /// [`Conn::set_reply_mode`].
+    pub fn client_reply(mut self, enabled: bool) -> Self {
+        self.client_reply = enabled;
+        self
+    }
+
+    /// Handles `SELECT <index>` without calling the handler, accepting indexes below `n`. See
+    /// [`Conn::db`].
+    pub fn databases(mut self, n: usize) -> Self {
+        self.databases = Some(n);
+        self
+    }
+
+    /// Replies to `INFO [section ...]` without calling the handler, see [`InfoBuilder`].
+    ///
+    /// The reply holds the `Server`, `Clients` and `Stats` sections filled by the server,
+    /// followed by the sections added by `f`.
+    pub fn info(mut self, f: impl Fn(&mut InfoBuilder) + Send + Sync + 'static) -> Self {
+        self.info = Some(Arc::new(f));
+        self
+    }
+
+    /// Closes the connections whose output waiting to be sent exceeds the limit, unlimited by
+    /// default.
+    ///
+    /// Without a limit, the replies written to a client that stops reading wait for it
+    /// forever.
+    pub fn output_limit(mut self, limit: OutputLimit) -> Self {
+        self.output_limit = Some(limit);
+        self
+    }
+
+    /// Calls `tap` with the raw bytes read from and written to each connection, e.g.
+    /// [`hexdump_tap`](crate::hexdump_tap) to debug what clients send.
+    ///
+    /// The bytes are passed as they cross the socket, so a frame can be split across calls
+    /// and a call can hold several frames.
+    pub fn tap(mut self, tap: impl Fn(ConnId, Direction, &[u8]) + Send + Sync + 'static) -> Self {
+        self.tap = Some(Arc::new(tap));
+        self
+    }
+
+    /// Records the commands whose handler takes at least `threshold`, keeping the latest
+    /// `max_len` of them. See [`Server::slowlog`].
+    pub fn slowlog(mut self, threshold: Duration, max_len: usize) -> Self {
+        self.slowlog = Some((threshold, max_len));
+        self
+    }
+
+    /// Sets how many bytes of each argument the slowlog keeps, defaults to 128 like in Redis.
+    pub fn slowlog_arg_len(mut self, len: usize) -> Self {
+        self.slowlog_arg_len = len;
+        self
+    }
+
+    /// Wraps the handler in a middleware, which is called with each command and passes it on
+    /// with [`Next::run`], or replies by itself to short-circuit it.
+    ///
+    /// Middlewares are called in the order they are added, the first one being the outermost.
+    /// `HELLO` and `CLIENT REPLY` don't go through them when they are handled by the server.
+    ///
+    /// ```no_run
+    /// use std::time::Instant;
+    ///
+    /// use redcon::{Command, Conn, Next, Server};
+    ///
+    /// let builder = Server::builder().wrap(|conn: Conn, cmd: Command, next: Next| async move {
+    ///     let name = cmd.name_escaped();
+    ///     let start = Instant::now();
+    ///     next.run(conn, cmd).await;
+    ///     eprintln!("{} took {:?}", name, start.elapsed());
+    /// });
+    /// ```
+    pub fn wrap<F, Fut>(mut self, middleware: F) -> Self
+    where
+        F: Fn(Conn, Command, Next) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.middlewares.push(Box::new(move |conn, cmd, next| {
+            Box::pin(middleware(conn, cmd, next))
+        }));
+        self
+    }
+
+    /// Binds the listeners and starts accepting connections in the background.
+    ///
+    /// The returned [`Server`] can be used to inspect and manage the connections.
+    pub async fn serve<H: Handler>(mut self, handler: H) -> Result<Server> {
+        let handler = Arc::new(Wrapped::new(handler, std::mem::take(&mut self.middlewares)));
+        let (server, listeners) = self.start().await?;
+        for (index, listener) in listeners.into_iter().enumerate() {
+            let shared = Arc::clone(&server.shared);
+            let handler = Arc::clone(&handler);
+            tokio::spawn(async move {
+                if let Err(err) = accept_loop(shared, index, listener, handler).await {
+                    eprintln!("could not accept connection: {}", err);
+                }
+            });
+        }
+        Ok(server)
+    }
+
+    /// Binds the listeners and accepts connections until an error occurs.
+    pub async fn run<H: Handler>(mut self, handler: H) -> Result<()> {
+        let handler = Arc::new(Wrapped::new(handler, std::mem::take(&mut self.middlewares)));
+        let (server, listeners) = self.start().await?;
+        // Connections outlive the accept loops, closes them if the future is dropped.
+        let _guard = KillOnDrop(server.clone());
+        let mut accept_loops = JoinSet::new();
+        for (index, listener) in listeners.into_iter().enumerate() {
+            accept_loops.spawn(accept_loop(
+                Arc::clone(&server.shared),
+                index,
+                listener,
+                Arc::clone(&handler),
+            ));
+        }
+        while let Some(res) = accept_loops.join_next().await {
+            res??;
+        }
+        Ok(())
+    }
+
+    async fn start(self) -> Result<(Server, Vec<TcpListener>)> {
+        if self.addrs.is_empty() {
+            bail!("no address to bind");
+        }
+
+        let mut listeners = Vec::with_capacity(self.addrs.len() * self.accept_loops);
+        let mut local_addrs = Vec::with_capacity(self.addrs.len());
+        for addr in &self.addrs {
+            let bound = if self.accept_loops == 1 {
+                TcpListener::bind(addr).await.map(|it| vec![it])
+            } else {
+                bind_reuseport(addr, self.accept_loops).await
+            }
+            .with_context(|| format!("could not bind {}", addr))?;
+            local_addrs.push(bound[0].local_addr()?);
+            listeners.extend(bound);
+        }
+
+        let slowlog_arg_len = self.slowlog_arg_len;
+        let shared = Arc::new(Shared {
+            local_addrs,
+            started: Instant::now(),
+            accepted: listeners.iter().map(|_| AtomicU64::new(0)).collect(),
+            commands: AtomicU64::new(0),
+            conns: Mutex::new(HashMap::new()),
+            on_disconnect: self.on_disconnect,
+            on_panic: self.on_panic,
+            accept_filter: self.accept_filter,
+            close_on_panic: self.close_on_panic,
+            read_buffer: self.read_buffer,
+            write_buffer: self.write_buffer,
+            flush_policy: self.flush_policy,
+            max_batch: self.max_batch,
+            max_in_flight: match self.processing_mode {
+                ProcessingMode::Sequential => 1,
+                ProcessingMode::Concurrent => self.max_in_flight,
+            },
+            yield_every: self.yield_every,
+            missing_reply: self.missing_reply,
+            read_options: self.read_options,
+            protocol_error_style: self.protocol_error_style,
+            rate_limit: self.rate_limit,
+            metrics: self.metrics,
+            hello: self.hello,
+            handler_timeout: self.handler_timeout,
+            timeout_error: self.timeout_error,
+            deferred_timeout: self.deferred_timeout,
+            client_reply: self.client_reply,
+            databases: self.databases,
+            info: self.info,
+            output_limit: self.output_limit,
+            tap: self.tap,
+            slowlog: self
+                .slowlog
+                .map(|(threshold, max_len)| Slowlog::new(threshold, max_len, slowlog_arg_len)),
+            draining: watch::channel(false).0,
+            paused: watch::channel(None).0,
+            cancel: CancellationToken::new(),
+            closed: Notify::new(),
+        });
+        Ok((Server { shared }, listeners))
+    }
+}
+
+/// Handle to a running server.
+#[derive(Clone)]
+pub struct Server {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    local_addrs: Vec<SocketAddr>,
+    started: Instant,
+    /// Number of connections accepted by each accept loop.
+    accepted: Vec<AtomicU64>,
+    /// Number of commands handled by the handler.
+    commands: AtomicU64,
+    conns: Mutex<HashMap<ConnId, Conn>>,
+    on_disconnect: Option<Arc<DisconnectHook>>,
+    on_panic: Option<Arc<PanicHook>>,
+    accept_filter: Option<Arc<AcceptFilter>>,
+    close_on_panic: bool,
+    read_buffer: usize,
+    write_buffer: usize,
+    flush_policy: FlushPolicy,
+    max_batch: usize,
+    max_in_flight: usize,
+    yield_every: usize,
+    missing_reply: MissingReplyPolicy,
+    read_options: ReadOptions,
+    protocol_error_style: ProtocolErrorStyle,
+    rate_limit: Option<RateLimit>,
+    metrics: Option<Arc<dyn Metrics>>,
+    hello: Option<HelloInfo>,
+    handler_timeout: Option<Duration>,
+    timeout_error: String,
+    deferred_timeout: Option<Duration>,
+    client_reply: bool,
+    databases: Option<usize>,
+    info: Option<Arc<InfoFn>>,
+    slowlog: Option<Slowlog>,
+    output_limit: Option<OutputLimit>,
+    tap: Option<Arc<TapFn>>,
+    /// Set once the server stops accepting connections and reading commands.
+    draining: watch::Sender<bool>,
+    /// When the pause started with [`Server::pause`] ends, if any.
+    paused: watch::Sender<Option<time::Instant>>,
+    /// Parent of the tokens of the connections, cancelled once the server starts draining.
+    cancel: CancellationToken,
+    /// Notified whenever a connection is removed from `conns`.
+    closed: Notify,
+}
+
+impl Shared {
+    fn start_draining(&self) {
+        self.draining.send_replace(true);
+        self.cancel.cancel();
+    }
+
+    /// Returns whether the command is handled by the server rather than the handler.
+    fn is_builtin(&self, cmd: &Command) -> bool {
+        self.client_reply && is_client_reply(cmd)
+            || self.databases.is_some() && cmd.is("select")
+            || self.info.is_some() && cmd.is("info")
+    }
+
+    /// Returns the reply to `INFO`.
+    fn info(&self, f: &InfoFn, cmd: &Command) -> Type {
+        let uptime = self.started.elapsed().as_secs();
+        let mut info = InfoBuilder::new();
+        info.section(
+            "Server",
+            [
+                ("redcon_version", env!("CARGO_PKG_VERSION").to_string()),
+                ("process_id", process::id().to_string()),
+                ("tcp_port", self.local_addrs[0].port().to_string()),
+                ("uptime_in_seconds", uptime.to_string()),
+                ("uptime_in_days", (uptime / (24 * 60 * 60)).to_string()),
+            ],
+        )
+        .section(
+            "Clients",
+            [("connected_clients", self.conns.lock().unwrap().len())],
+        )
+        .section(
+            "Stats",
+            [
+                (
+                    "total_connections_received",
+                    self.accepted
+                        .iter()
+                        .map(|it| it.load(Ordering::Relaxed))
+                        .sum::<u64>(),
+                ),
+                (
+                    "total_commands_processed",
+                    self.commands.load(Ordering::Relaxed),
+                ),
+            ],
+        );
+        f(&mut info);
+        info.build_filtered(&cmd[1..])
+    }
+
+    fn protocol_error(&self) {
+        if let Some(metrics) = &self.metrics {
+            metrics.on_protocol_error();
+        }
+    }
+
+    fn client_disconnected(&self) {
+        if let Some(metrics) = &self.metrics {
+            metrics.on_client_disconnected();
+        }
+    }
+
+    fn read_error(&self) {
+        if let Some(metrics) = &self.metrics {
+            metrics.on_read_error();
+        }
+    }
+
+    /// Replies with an error, unless the handler already started replying: the client can't
+    /// tell the error from the rest of a reply or from a reply to the next command, so the
+    /// connection is closed instead, like when a handler times out.
+    async fn handler_panicked(&self, conn: &Conn, panic: Box<dyn Any + Send>, replying: bool) {
+        let msg = panic
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown panic");
+        eprintln!("handler panicked on connection {}: {}", conn.id(), msg);
+
+        if let Some(hook) = &self.on_panic {
+            hook(conn, msg);
+        }
+
+        if self.close_on_panic {
+            conn.kill(DisconnectReason::HandlerPanicked);
+        } else if replying {
+            eprintln!(
+                "closing connection {}: handler panicked while replying",
+                conn.id()
+            );
+            conn.kill(DisconnectReason::HandlerPanicked);
+        } else if let Err(err) = conn.write_error("ERR internal error".to_string()).await {
+            eprintln!("could not write to client: {}", err);
+        }
+    }
+
+    async fn reply_missing(&self, conn: &Conn, name: &str) {
+        if conn.is_closed() {
+            return;
+        }
+        let res = match self.missing_reply {
+            MissingReplyPolicy::WriteNullAndWarn => {
+                eprintln!(
+                    "handler returned without replying to '{}' on connection {}",
+                    name,
+                    conn.id()
+                );
+                conn.write_null().await
+            }
+            MissingReplyPolicy::Ignore => Ok(()),
+            MissingReplyPolicy::Error => conn.write_err("no reply produced").await,
+        };
+        if let Err(err) = res {
+            eprintln!("could not write to client: {}", err);
+        }
+    }
+
+    async fn handler_timed_out(&self, conn: &Conn, replying: bool) {
+        if replying {
+            eprintln!(
+                "closing connection {}: handler timed out while replying",
+                conn.id()
+            );
+            conn.kill(DisconnectReason::HandlerTimedOut);
+        } else if let Err(err) = conn.write_error(self.timeout_error.clone()).await {
+            eprintln!("could not write to client: {}", err);
+        }
+    }
+}
+
+impl Server {
+    pub fn builder() -> Builder {
+        Builder::default()
+    }
+
+    /// Returns the slow commands recorded so far, newest first. Empty unless enabled with
+    /// [`Builder::slowlog`].
+    pub fn slowlog(&self) -> Vec<SlowlogEntry> {
+        self.shared
+            .slowlog
+            .as_ref()
+            .map_or_else(Vec::new, Slowlog::entries)
+    }
+
+    /// Stops reading commands from all the connections for `duration`, like `CLIENT PAUSE`.
+    ///
+    /// Commands read already are still handled, and new connections are accepted but their
+    /// commands wait as well. Pausing again replaces the pause in progress.
+    pub fn pause(&self, duration: Duration) {
+        self.shared
+            .paused
+            .send_replace(Some(time::Instant::now() + duration));
+    }
+
+    /// Ends the pause started with [`Server::pause`] early.
+    pub fn unpause(&self) {
+        self.shared.paused.send_replace(None);
+    }
+
+    /// Forgets the slow commands recorded so far.
+    pub fn slowlog_reset(&self) {
+        if let Some(slowlog) = &self.shared.slowlog {
+            slowlog.reset();
+        }
+    }
+
+    /// Returns the first address the server is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.shared.local_addrs[0]
+    }
+
+    /// Returns all the addresses the server is listening on, in the order they were bound.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.shared.local_addrs
+    }
+
+    /// Returns the number of open connections.
+    pub fn connection_count(&self) -> usize {
+        self.shared.conns.lock().unwrap().len()
+    }
+
+    /// Returns the open connections, in no particular order.
+    pub fn connections(&self) -> Vec<ConnInfo> {
+        let conns = self.shared.conns.lock().unwrap();
+        conns
+            .values()
+            .map(|conn| ConnInfo {
+                id: conn.id(),
+                peer_addr: conn.peer_addr(),
+                db: conn.db(),
+                stats: conn.stats(),
+            })
+            .collect()
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            connections_accepted: self
+                .shared
+                .accepted
+                .iter()
+                .map(|it| it.load(Ordering::Relaxed))
+                .sum(),
+            commands: self.shared.commands.load(Ordering::Relaxed),
+            uptime: self.shared.started.elapsed(),
+        }
+    }
+
+    /// Closes the connection with the given id.
+    ///
+    /// Pending writes are flushed before the socket is shut down. Returns
+    /// `false` if there is no such connection.
+    pub fn kill(&self, id: ConnId) -> bool {
+        let conns = self.shared.conns.lock().unwrap();
+        match conns.get(&id) {
+            Some(conn) => {
+                conn.kill(DisconnectReason::Killed);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Closes the connections from the given peer address.
+    ///
+    /// Returns `false` if there is no such connection.
+    pub fn kill_addr(&self, addr: SocketAddr) -> bool {
+        let conns = self.shared.conns.lock().unwrap();
+        let mut found = false;
+        for conn in conns.values() {
+            if conn.peer_addr() == Some(addr) {
+                conn.kill(DisconnectReason::Killed);
+                found = true;
+            }
+        }
+        found
+    }
+
+    /// Stops the server gracefully, returns the number of connections closed forcibly.
+    ///
+    /// The listeners are closed right away and connections stop reading commands, any
+    /// commands sent but not read yet are dropped. Each connection is closed once the handlers
+    /// of the commands already read finish. Connections whose handlers are still running when
+    /// the timeout expires are closed without waiting for them.
+    ///
+    /// The tokens returned by [`Conn::cancellation_token`] are cancelled right away, so
+    /// handlers can wrap up early.
+    pub async fn drain(&self, timeout: Duration) -> usize {
+        self.shared.start_draining();
+        if time::timeout(timeout, self.closed()).await.is_ok() {
+            return 0;
+        }
+
+        self.kill_all()
+    }
+
+    /// Stops accepting connections and closes all of them, resolves once they are closed.
+    ///
+    /// Pending writes are flushed before the sockets are shut down. Unlike
+    /// [`Server::drain`], running handlers are not waited for.
+    pub async fn shutdown(&self) {
+        self.kill_all();
+        self.closed().await;
+    }
+
+    /// Stops accepting connections and kills the open ones, returns how many were open.
+    fn kill_all(&self) -> usize {
+        self.shared.start_draining();
+        let conns = self.shared.conns.lock().unwrap();
+        for conn in conns.values() {
+            conn.kill(DisconnectReason::ServerShutdown);
+        }
+        conns.len()
+    }
+
+    /// Resolves once all the connections are closed.
+    async fn closed(&self) {
+        loop {
+            let closed = self.shared.closed.notified();
+            if self.shared.conns.lock().unwrap().is_empty() {
+                return;
+            }
+            closed.await;
+        }
+    }
+}
+
+/// Snapshot of the counters of a server, see [`Server::stats`].
+#[derive(Clone, Debug)]
+pub struct Stats {
+    /// Number of connections accepted since the server started.
+    pub connections_accepted: u64,
+    /// Number of commands handled since the server started.
+    pub commands: u64,
+    pub uptime: Duration,
+}
+
+/// An open connection of a server, see [`Server::connections`].
+#[derive(Clone, Debug)]
+pub struct ConnInfo {
+    pub id: ConnId,
+    pub peer_addr: Option<SocketAddr>,
+    /// See [`Conn::db`].
+    pub db: usize,
+    pub stats: ConnStats,
+}
+
+/// Kills all the connections of the server once dropped.
+struct KillOnDrop(Server);
+
+impl Drop for KillOnDrop {
+    fn drop(&mut self) {
+        self.0.kill_all();
+    }
+}
+
+#[cfg(all(
+    unix,
+    not(any(
+        target_os = "solaris",
+        target_os = "illumos",
+        target_os = "cygwin",
+        target_os = "nuttx"
+    ))
+))]
+async fn bind_reuseport(addr: &str, n: usize) -> io::Result<Vec<TcpListener>> {
+    let mut addr = lookup_host(addr).await?.next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "could not resolve to any address",
+        )
+    })?;
+
+    let mut listeners = Vec::with_capacity(n);
+    for _ in 0..n {
+        let socket = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        socket.set_reuseaddr(true)?;
+        socket.set_reuseport(true)?;
+        socket.bind(addr)?;
+        let listener = socket.listen(1024)?;
+        // Binding to port 0 picks a random port, the rest of the sockets must share it.
+        addr = listener.local_addr()?;
+        listeners.push(listener);
+    }
+    Ok(listeners)
+}
+
+#[cfg(not(all(
+    unix,
+    not(any(
+        target_os = "solaris",
+        target_os = "illumos",
+        target_os = "cygwin",
+        target_os = "nuttx"
+    ))
+)))]
+async fn bind_reuseport(_addr: &str, _n: usize) -> io::Result<Vec<TcpListener>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "multiple accept loops require SO_REUSEPORT, which is not supported on this platform",
+    ))
+}
+
+async fn accept_loop<H: Handler>(
+    shared: Arc<Shared>,
+    index: usize,
+    listener: TcpListener,
+    handler: Arc<H>,
+) -> Result<()> {
+    let mut draining = shared.draining.subscribe();
+    loop {
+        let (socket, peer_addr) = tokio::select! {
+            res = listener.accept() => res?,
+            _ = drained(&mut draining) => return Ok(()),
+        };
+        let decision = match &shared.accept_filter {
+            Some(filter) => filter(peer_addr),
+            None => AcceptDecision::Accept,
+        };
+        match decision {
+            AcceptDecision::Accept => {}
+            AcceptDecision::Reject => continue,
+            AcceptDecision::RejectWithError(err) => {
+                tokio::spawn(reject(socket, err));
+                continue;
+            }
+        }
+        shared.accepted[index].fetch_add(1, Ordering::Relaxed);
+        let shared = Arc::clone(&shared);
+        let handler = Arc::clone(&handler);
+        tokio::spawn(handle_connection(shared, socket, handler));
+    }
+}
+
+/// Replies to a rejected connection with the error, then closes it.
+async fn reject(mut socket: TcpStream, err: String) {
+    if let Err(err) = Type::Error(err).write(&mut socket).await {
+        eprintln!("could not write to client: {}", err);
+    }
+}
+
+async fn handle_connection<H: Handler>(shared: Arc<Shared>, socket: TcpStream, handler: Arc<H>) {
+    let (read, write) = socket.into_split();
+    let options = ConnOptions {
+        capacity: shared.write_buffer,
+        flush_policy: shared.flush_policy,
+        metrics: shared.metrics.clone(),
+        deferred_timeout: shared.deferred_timeout,
+        output_limit: shared.output_limit.clone(),
+        tap: shared.tap.clone(),
+    };
+    let conn = Conn::with_options(options, shared.cancel.child_token(), write);
+    let tap = shared.tap.clone().map(|f| Tap::new(conn.id(), f));
+    let read = CountingReader::new(
+        read,
+        Arc::clone(conn.counters()),
+        shared.metrics.clone(),
+        tap,
+    );
+    let mut read = RespReader::with_options(
+        BufReader::with_capacity(shared.read_buffer, read),
+        shared.read_options.clone().for_commands(),
+    );
+
+    shared.conns.lock().unwrap().insert(conn.id(), conn.clone());
+    if let Some(metrics) = &shared.metrics {
+        metrics.on_connection_opened(&conn);
+    }
+
+    let mut limiter = shared
+        .rate_limit
+        .as_ref()
+        .map(|limit| (TokenBucket::new(limit), limit.get_policy()));
+    // Number of commands rejected in a row by the rate limiter.
+    let mut rejected = 0;
+
+    let mut draining = shared.draining.subscribe();
+    let mut paused = shared.paused.subscribe();
+    let mut handlers = JoinSet::new();
+    // Held by the running handlers, a frame isn't handled until one is available.
+    let in_flight = Arc::new(Semaphore::new(shared.max_in_flight));
+
+    // A frame read while collecting a batch that couldn't be added to it.
+    let mut next = None;
+    // Number of frames read in a row without waiting for the client.
+    let mut buffered = 0;
+    // The frame read while waiting for a handler to finish, kept so that a drain requested
+    // meanwhile can still run it.
+    let read_ahead = Mutex::new(None);
+    let reason = 'conn: loop {
+        if next.is_some() || read.has_buffered_frame() {
+            buffered += 1;
+            if buffered >= shared.yield_every {
+                buffered = 0;
+                task::yield_now().await;
+            }
+        } else {
+            buffered = 0;
+        }
+        let frame = async {
+            let res = match next.take() {
+                Some(res) => res,
+                None => read.read_frame().await,
+            };
+            let closed = matches!(res, Err(ReadFailure::Closed));
+            *read_ahead.lock().unwrap() = Some(res);
+            // A read waiting for the client when the pause started is held back as well.
+            unpaused(&mut paused).await;
+            // The client going away is noticed while the handlers still run, so they can be
+            // told with `Conn::closed`. Other errors are replied to in order.
+            let permit = if closed {
+                None
+            } else {
+                Some(
+                    Arc::clone(&in_flight)
+                        .acquire_owned()
+                        .await
+                        .expect("semaphore is never closed"),
+                )
+            };
+            let res = read_ahead.lock().unwrap().take();
+            (permit, res.expect("the frame is kept until it is returned"))
+        };
+        let (permit, res) = tokio::select! {
+            frame = frame => frame,
+            _ = conn.killed() => {
+                shutdown(&conn).await;
+                break conn.kill_reason().expect("killed connections have a reason");
+            }
+            _ = drained(&mut draining) => {
+                finish_handlers(&conn, &mut handlers).await;
+                break conn.kill_reason().unwrap_or(DisconnectReason::ServerShutdown);
+            }
+            request = conn.drain_requested() => {
+                if let Some(Ok(ty)) = read_ahead.lock().unwrap().take() {
+                    run_read_ahead(&shared, &handler, &conn, &mut handlers, ty);
+                }
+                break drain_conn(&conn, &mut handlers, request).await;
+            }
+        };
+
+        let ty = match res {
+            Ok(it) => it,
+            Err(ReadFailure::Closed) => {
+                shared.client_disconnected();
+                break DisconnectReason::ClientClosed;
+            }
+            // Whatever follows the garbage can't be trusted to be a frame, like in Redis.
+            Err(ReadFailure::Protocol(err, pos)) => {
+                let reason = describe(&err, pos.as_ref());
+                shared.protocol_error();
+                eprintln!(
+                    "closing connection {}: protocol error: {}",
+                    conn.id(),
+                    reason
+                );
+                let msg = match shared.protocol_error_style {
+                    ProtocolErrorStyle::Detailed => format!("ERR Protocol error: {}", reason),
+                    ProtocolErrorStyle::Redis => {
+                        format!("ERR Protocol error: {}", redis_reason(&err, pos.as_ref()))
+                    }
+                };
+                if let Err(err) = close_with_error(&conn, &msg).await {
+                    eprintln!("could not write to client: {}", err);
+                }
+                break DisconnectReason::ProtocolError;
+            }
+            Err(ReadFailure::Io(err)) => {
+                shared.read_error();
+                eprintln!("closing connection {}: could not read: {}", conn.id(), err);
+                break DisconnectReason::ReadError;
+            }
+            // The whole frame is consumed, the connection can go on with the next one.
+            Err(ReadFailure::Rejected(err, pos)) => {
+                eprintln!(
+                    "rejected command from connection {}: {}",
+                    conn.id(),
+                    describe(&err, pos.as_ref())
+                );
+                let msg = format!("ERR {}", err);
+                if let Err(err) = conn.write_error(msg).await {
+                    eprintln!("could not write to client: {}", err);
+                }
+                continue;
+            }
+        };
+        let permit = permit.expect("a permit is acquired for every frame");
+
+        if let Some(info) = &shared.hello {
+            if is_hello(&ty) {
+                let cmd = type_to_command(ty).unwrap_or_default();
+                let scope = CommandScope::new(conn.id(), conn.take_muted());
+                if let Err(err) = SCOPE.scope(scope, hello(&conn, info, cmd)).await {
+                    eprintln!("could not write to client: {}", err);
+                }
+                continue;
+            }
+        }
+
+        let cmd = match type_to_command(ty) {
+            Ok(it) => it,
+            Err(_) => {
+                eprintln!("invalid command");
+                shared.protocol_error();
+                if let Err(err) = conn
+                    .write_error("ERR expected array of bulk strings".to_string())
+                    .await
+                {
+                    eprintln!("could not write to client: {}", err);
+                }
+                continue;
+            }
+        };
+
+        if shared.client_reply && is_client_reply(&cmd) {
+            // Consumes a pending skip, the reply to `CLIENT REPLY ON` is sent anyway.
+            conn.take_muted();
+            if let Err(err) = client_reply(&conn, &cmd).await {
+                eprintln!("could not write to client: {}", err);
+            }
+            continue;
+        }
+
+        if let Some(f) = shared.info.as_ref().filter(|_| cmd.is("info")) {
+            let scope = CommandScope::new(conn.id(), conn.take_muted());
+            let info = shared.info(f.as_ref(), &cmd);
+            if let Err(err) = SCOPE.scope(scope, conn.write(info)).await {
+                eprintln!("could not write to client: {}", err);
+            }
+            continue;
+        }
+
+        if let Some(databases) = shared.databases.filter(|_| cmd.is("select")) {
+            let scope = CommandScope::new(conn.id(), conn.take_muted());
+            if let Err(err) = SCOPE.scope(scope, select(&conn, &cmd, databases)).await {
+                eprintln!("could not write to client: {}", err);
+            }
+            continue;
+        }
+
+        if let Some((bucket, policy)) = &mut limiter {
+            match policy {
+                RateLimitPolicy::Delay => {
+                    while let Err(wait) = bucket.acquire(Instant::now()) {
+                        // Stops waiting like the read does, leaving the running handlers be.
+                        tokio::select! {
+                            _ = sleep(wait) => {}
+                            _ = conn.killed() => {
+                                shutdown(&conn).await;
+                                let reason = conn.kill_reason();
+                                break 'conn reason.expect("killed connections have a reason");
+                            }
+                            _ = drained(&mut draining) => {
+                                finish_handlers(&conn, &mut handlers).await;
+                                let reason = conn.kill_reason();
+                                break 'conn reason.unwrap_or(DisconnectReason::ServerShutdown);
+                            }
+                            request = conn.drain_requested() => {
+                                break 'conn drain_conn(&conn, &mut handlers, request).await;
+                            }
+                        }
+                    }
+                }
+                RateLimitPolicy::Reject { close_after } => {
+                    if bucket.acquire(Instant::now()).is_err() {
+                        rejected += 1;
+                        let err = "ERR rate limit exceeded";
+                        if close_after.is_some_and(|n| rejected >= n) {
+                            eprintln!("closing connection {}: rate limit exceeded", conn.id());
+                            if let Err(err) = close_with_error(&conn, err).await {
+                                eprintln!("could not write to client: {}", err);
+                            }
+                            break DisconnectReason::RateLimited;
+                        }
+                        if let Err(err) = conn.write_error(err.to_string()).await {
+                            eprintln!("could not write to client: {}", err);
+                        }
+                        continue;
+                    }
+                    rejected = 0;
+                }
+            }
+        }
+
+        // Replies are skipped per handler call, so the skipped command is handled on its own.
+        if shared.max_batch == 1 || conn.reply_mode() == ReplyMode::Skip {
+            spawn_handler(
+                &shared,
+                &handler,
+                &conn,
+                &mut handlers,
+                Some(permit),
+                Commands::One(cmd),
+            );
+            continue;
+        }
+
+        let mut cmds = vec![cmd];
+        // Commands over the rate limit are left to the next iteration.
+        while cmds.len() < shared.max_batch
+            && read.has_buffered_frame()
+            && limiter
+                .as_mut()
+                .is_none_or(|(bucket, _)| bucket.acquire(Instant::now()).is_ok())
+        {
+            match read.read_frame().await {
+                // Replies to `HELLO` are written before the handlers of the batch run.
+                Ok(ty) if shared.hello.is_some() && is_hello(&ty) => {
+                    next = Some(Ok(ty));
+                    break;
+                }
+                Ok(ty) => match type_to_command(ty) {
+                    Ok(cmd) if shared.is_builtin(&cmd) => {
+                        next = Some(Ok(Type::from(cmd)));
+                        break;
+                    }
+                    Ok(cmd) => cmds.push(cmd),
+                    Err(ty) => {
+                        next = Some(Ok(ty));
+                        break;
+                    }
+                },
+                Err(err) => {
+                    next = Some(Err(err));
+                    break;
+                }
+            }
+        }
+        spawn_handler(
+            &shared,
+            &handler,
+            &conn,
+            &mut handlers,
+            Some(permit),
+            Commands::Batch(cmds),
+        );
+    };
+
+    // Handlers keep running after the client goes away, they may still have work to do.
+    handlers.detach_all();
+    disconnected(&shared, &conn, reason);
+}
+
+/// Resolves once the server starts draining.
+async fn drained(draining: &mut watch::Receiver<bool>) {
+    // The sender lives as long as the server, so this can't fail.
+    let _ = draining.wait_for(|draining| *draining).await;
+}
+
+/// Waits for the server to be unpaused, see [`Server::pause`].
+async fn unpaused(paused: &mut watch::Receiver<Option<time::Instant>>) {
+    loop {
+        let until = match *paused.borrow_and_update() {
+            Some(until) if until > time::Instant::now() => until,
+            _ => return,
+        };
+        tokio::select! {
+            _ = time::sleep_until(until) => return,
+            // The sender lives as long as the server, so this can't fail.
+            _ = paused.changed() => {}
+        }
+    }
+}
+
+/// Waits for the running handlers of the connection and closes it, handlers still running
+/// when the connection is killed are aborted.
+async fn finish_handlers(conn: &Conn, handlers: &mut JoinSet<()>) {
+    tokio::select! {
+        _ = async { while handlers.join_next().await.is_some() {} } => {}
+        _ = conn.killed() => {}
+    }
+    handlers.abort_all();
+    shutdown(conn).await;
+}
+
+/// Runs the command read before a drain was requested, waiting for a handler to finish, like
+/// the commands in flight. Other frames are dropped, like the ones not read yet.
+///
+/// It runs without waiting for a permit, as the handler holding it may be the one draining.
+fn run_read_ahead<H: Handler>(
+    shared: &Arc<Shared>,
+    handler: &Arc<H>,
+    conn: &Conn,
+    handlers: &mut JoinSet<()>,
+    ty: Type,
+) {
+    if shared.hello.is_some() && is_hello(&ty) {
+        return;
+    }
+    match type_to_command(ty) {
+        Ok(cmd) if !shared.is_builtin(&cmd) => {
+            spawn_handler(shared, handler, conn, handlers, None, Commands::One(cmd));
+        }
+        _ => {}
+    }
+}
+
+/// Carries out [`Conn::drain`]: waits for the running handlers of the connection, except the
+/// one asking for the drain, writes the final frame and closes the connection.
+async fn drain_conn(
+    conn: &Conn,
+    handlers: &mut JoinSet<()>,
+    request: DrainRequest,
+) -> DisconnectReason {
+    let others = usize::from(request.from_handler);
+    let finished = time::timeout_at(request.deadline, async {
+        while handlers.len() > others && handlers.join_next().await.is_some() {}
+    });
+    let outcome = tokio::select! {
+        res = finished => match res {
+            Ok(()) => DrainOutcome::Completed,
+            Err(_) => DrainOutcome::TimedOut,
+        },
+        _ = conn.killed() => {
+            shutdown(conn).await;
+            return conn.kill_reason().expect("killed connections have a reason");
+        }
+    };
+    match outcome {
+        DrainOutcome::Completed => {
+            if let Err(err) = write_final_frame(conn, request.final_frame).await {
+                eprintln!("could not write to client: {}", err);
+            }
+        }
+        DrainOutcome::TimedOut => {
+            eprintln!(
+                "closing connection {}: handlers still running after the drain timeout",
+                conn.id()
+            );
+        }
+    }
+    shutdown(conn).await;
+    let _ = request.done.send(outcome);
+    DisconnectReason::Drained
+}
+
+async fn write_final_frame(conn: &Conn, frame: Option<Type>) -> Result<()> {
+    conn.cancel_deferred().await?;
+    if let Some(frame) = frame {
+        conn.write_type(&frame).await?;
+    }
+    conn.flush().await
+}
+
+fn disconnected(shared: &Shared, conn: &Conn, reason: DisconnectReason) {
+    conn.set_closed(reason);
+    shared.conns.lock().unwrap().remove(&conn.id());
+    if let Some(metrics) = &shared.metrics {
+        metrics.on_connection_closed(conn);
+    }
+    if let Some(hook) = &shared.on_disconnect {
+        hook(conn, reason);
+    }
+    shared.closed.notify_waiters();
+}
+
+async fn shutdown(conn: &Conn) {
+    if let Err(err) = conn.shutdown().await {
+        eprintln!("could not shutdown connection {}: {}", conn.id(), err);
+    }
+}
+
+/// Replies with the error and closes the connection.
+async fn close_with_error(conn: &Conn, err: &str) -> Result<()> {
+    conn.write_error(err.to_string()).await?;
+    conn.flush().await?;
+    conn.shutdown().await?;
+    Ok(())
+}
+
+enum Commands {
+    One(Command),
+    Batch(Vec<Command>),
+}
+
+impl Commands {
+    fn len(&self) -> usize {
+        match self {
+            Commands::One(_) => 1,
+            Commands::Batch(cmds) => cmds.len(),
+        }
+    }
+
+    fn as_slice(&self) -> &[Command] {
+        match self {
+            Commands::One(cmd) => std::slice::from_ref(cmd),
+            Commands::Batch(cmds) => cmds,
+        }
+    }
+
+    fn names(&self) -> Vec<String> {
+        let name = |cmd: &Command| String::from_utf8_lossy(cmd.name()).into_owned();
+        self.as_slice().iter().map(name).collect()
+    }
+}
+
+fn spawn_handler<H: Handler>(
+    shared: &Arc<Shared>,
+    handler: &Arc<H>,
+    conn: &Conn,
+    handlers: &mut JoinSet<()>,
+    permit: Option<OwnedSemaphorePermit>,
+    cmds: Commands,
+) {
+    // Reaps the finished handlers so the set doesn't grow with every command.
+    while handlers.try_join_next().is_some() {}
+
+    conn.counters().command_read();
+
+    let muted = conn.take_muted();
+
+    let shared = Arc::clone(shared);
+    let handler = Arc::clone(handler);
+    let conn = conn.clone();
+    handlers.spawn(async move {
+        // Released once the replies are flushed too.
+        let _permit = permit;
+        let n = cmds.len() as u64;
+        let names = shared.metrics.as_ref().map(|_| cmds.names());
+        let slow_args = shared.slowlog.as_ref().map(|slowlog| {
+            let cmds = cmds.as_slice();
+            cmds.iter().map(|cmd| slowlog.args(cmd)).collect::<Vec<_>>()
+        });
+        // Checked for single commands only, as a batch replies to all its commands at once.
+        let name = match &cmds {
+            Commands::One(cmd) if !muted && shared.missing_reply != MissingReplyPolicy::Ignore => {
+                Some(cmd.name_escaped())
+            }
+            _ => None,
+        };
+        let start = Instant::now();
+        let call = async {
+            match cmds {
+                Commands::One(cmd) => handler.call(conn.clone(), cmd).await,
+                Commands::Batch(cmds) => handler.call_batch(conn.clone(), cmds).await,
+            }
+        };
+        // Whether the handler finished in time or panicked, whether it started replying and
+        // whether it is expected to. The panic is caught within the scope, so what the handler
+        // wrote before panicking is still known.
+        let (res, replying, expects_reply) = SCOPE
+            .scope(CommandScope::new(conn.id(), muted), async {
+                let res = catch_unwind(async {
+                    match shared.handler_timeout {
+                        Some(timeout) => time::timeout(timeout, call).await.is_ok(),
+                        None => {
+                            call.await;
+                            true
+                        }
+                    }
+                })
+                .await;
+                SCOPE.with(|it| (res, it.replying.get(), it.expects_reply.get()))
+            })
+            .await;
+        let elapsed = start.elapsed();
+        shared.commands.fetch_add(n, Ordering::Relaxed);
+        conn.counters().commands_handled(n);
+        if let (Some(metrics), Some(names)) = (&shared.metrics, names) {
+            for name in &names {
+                metrics.on_command(name, elapsed);
+            }
+        }
+        if let (Some(slowlog), Some(slow_args)) = (&shared.slowlog, slow_args) {
+            for args in slow_args {
+                slowlog.record(&conn, elapsed, args);
+            }
+        }
+        match (res, name) {
+            (Ok(true), Some(name)) if !replying && expects_reply => {
+                shared.reply_missing(&conn, &name).await
+            }
+            (Ok(true), _) => {}
+            (Ok(false), _) => shared.handler_timed_out(&conn, replying).await,
+            (Err(panic), _) => shared.handler_panicked(&conn, panic, replying).await,
+        }
+        if shared.flush_policy == FlushPolicy::OnHandlerCompletion {
+            if let Err(err) = conn.flush().await {
+                eprintln!("could not write to client: {}", err);
+            }
+        }
+    });
+}
+
+/// Polls the future, catching any panic raised while doing so.
+async fn catch_unwind<Fut: Future>(fut: Fut) -> std::thread::Result<Fut::Output> {
+    let mut fut = pin!(fut);
+    future::poll_fn(
+        |cx| match panic::catch_unwind(AssertUnwindSafe(|| fut.as_mut().poll(cx))) {
+            Ok(Poll::Ready(it)) => Poll::Ready(Ok(it)),
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(panic) => Poll::Ready(Err(panic)),
+        },
+    )
+    .await
+}
+
+/// Converts an array of bulk strings to a command, returns the value back otherwise.
+fn type_to_command(ty: Type) -> Result<Command, Type> {
+    match ty {
+        Type::Array(arr) if arr.iter().all(|t| matches!(t, Type::BulkString(_))) => Ok(arr
+            .into_iter()
+            .filter_map(|t| match t {
+                Type::BulkString(bytes) => Some(bytes),
+                _ => None,
+            })
+            .collect()),
+        ty => Err(ty),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream};
+    use tokio::sync::{mpsc, oneshot};
+    use tokio::time::{sleep, timeout};
+
+    use super::*;
+    use crate::conn::Deferred;
+    use crate::metrics::AtomicMetrics;
+    use crate::output::OutputLimit;
+    use crate::resp::{Error, Protocol};
+
+    async fn connect(server: &Server) -> Result<BufStream<TcpStream>> {
+        let client = TcpStream::connect(server.local_addr()).await?;
+        Ok(BufStream::new(client))
+    }
+
+    async fn ping(client: &mut BufStream<TcpStream>) -> Result<Type> {
+        Type::Array(vec![Type::BulkString(b"ping".to_vec())])
+            .write(&mut *client)
+            .await?;
+        Ok(Type::read(client).await?)
+    }
+
+    #[tokio::test]
+    async fn connection_stats() -> Result<()> {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            // Flushed after the commands are counted, so the counters are up to date once the
+            // client reads the replies.
+            .flush_policy(FlushPolicy::OnHandlerCompletion)
+            .serve(|conn: Conn, cmd: Command| async move {
+                conn.write_bulk_bytes(&cmd[1]).await.unwrap();
+            })
+            .await?;
+
+        let mut client = connect(&server).await?;
+        wait_for_connections(&server, 1).await;
+        let info = server.connections();
+        assert_eq!(info.len(), 1);
+        assert_eq!((info[0].stats.commands, info[0].stats.bytes_read), (0, 0));
+        assert_eq!(info[0].peer_addr, Some(client.get_ref().local_addr()?));
+
+        let (mut read, mut written) = (0, 0);
+        for payload in ["a", "bb", &"c".repeat(1000)] {
+            let cmd = Type::from(vec!["echo", payload]);
+            let reply = Type::from(payload);
+            read += cmd.to_bytes().len() as u64;
+            written += reply.to_bytes().len() as u64;
+            cmd.write(&mut client).await?;
+            assert_eq!(Type::read(&mut client).await?, reply);
+        }
+        sleep(Duration::from_millis(50)).await;
+
+        let info = server.connections();
+        let stats = &info[0].stats;
+        assert_eq!(stats.commands, 3);
+        assert_eq!((stats.bytes_read, stats.bytes_written), (read, written));
+        assert!(stats.idle >= Duration::from_millis(50));
+        assert!(stats.age > stats.idle);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn disconnect_reasons() -> Result<()> {
+        let (disconnect_tx, mut disconnect_rx) = mpsc::unbounded_channel();
+        let (closed_tx, mut closed_rx) = mpsc::unbounded_channel();
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .rate_limit(RateLimit::new(1, 1).policy(RateLimitPolicy::Reject {
+                close_after: Some(1),
+            }))
+            .on_disconnect(move |conn: &Conn, reason| {
+                assert_eq!(conn.disconnect_reason(), Some(reason));
+                disconnect_tx.send(reason).unwrap();
+            })
+            .serve(move |conn: Conn, cmd: Command| {
+                let closed_tx = closed_tx.clone();
+                async move {
+                    if cmd.is("wait") {
+                        // Resolves with the reason too.
+                        let closed = conn.closed();
+                        tokio::spawn(async move {
+                            closed_tx.send(closed.await).unwrap();
+                        });
+                    }
+                    conn.write_ok().await.unwrap();
+                }
+            })
+            .await?;
+        async fn next_reason(
+            rx: &mut mpsc::UnboundedReceiver<DisconnectReason>,
+        ) -> DisconnectReason {
+            timeout(Duration::from_secs(1), rx.recv())
+                .await
+                .unwrap()
+                .unwrap()
+        }
+
+        let client = connect(&server).await?;
+        drop(client);
+        assert_eq!(
+            next_reason(&mut disconnect_rx).await,
+            DisconnectReason::ClientClosed
+        );
+
+        let mut client = connect(&server).await?;
+        client.write_all(b"\"oops\r\n").await?;
+        client.flush().await?;
+        assert_eq!(
+            next_reason(&mut disconnect_rx).await,
+            DisconnectReason::ProtocolError
+        );
+
+        let mut client = connect(&server).await?;
+        send(&mut client, &["ping"]).await?;
+        send(&mut client, &["ping"]).await?;
+        assert_eq!(
+            next_reason(&mut disconnect_rx).await,
+            DisconnectReason::RateLimited
+        );
+
+        let mut client = connect(&server).await?;
+        send(&mut client, &["wait"]).await?;
+        Type::read(&mut client).await?;
+        server.shutdown().await;
+        assert_eq!(
+            next_reason(&mut disconnect_rx).await,
+            DisconnectReason::ServerShutdown
+        );
+        assert_eq!(
+            closed_rx.recv().await,
+            Some(DisconnectReason::ServerShutdown)
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn kill_connection() -> Result<()> {
+        let (disconnect_tx, mut disconnect_rx) = mpsc::unbounded_channel();
+        let (id_tx, mut id_rx) = mpsc::unbounded_channel();
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .on_disconnect(move |conn: &Conn, reason| {
+                disconnect_tx.send((conn.id(), reason)).unwrap()
+            })
+            .serve(move |conn: Conn, _cmd: Command| {
+                let id_tx = id_tx.clone();
+                async move {
+                    id_tx.send(conn.id()).unwrap();
+                    conn.write_simple_string("pong".to_string()).await.unwrap();
+                }
+            })
+            .await?;
+
+        let mut first = connect(&server).await?;
+        let mut second = connect(&server).await?;
+        ping(&mut first).await?;
+        let first_id = id_rx.recv().await.unwrap();
+        ping(&mut second).await?;
+        let second_id = id_rx.recv().await.unwrap();
+
+        assert!(server.kill(first_id));
+
+        let res = timeout(Duration::from_secs(1), Type::read(&mut first)).await?;
+        assert!(matches!(res.unwrap_err(), Error::UnexpectedEof));
+        assert_eq!(
+            disconnect_rx.recv().await,
+            Some((first_id, DisconnectReason::Killed))
+        );
+
+        assert_eq!(
+            ping(&mut second).await?,
+            Type::SimpleString("pong".to_string())
+        );
+        assert_eq!(id_rx.recv().await, Some(second_id));
+
+        assert!(!server.kill(first_id));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn kill_connection_by_addr() -> Result<()> {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .serve(|conn: Conn, _cmd: Command| async move {
+                conn.write_simple_string("pong".to_string()).await.unwrap();
+            })
+            .await?;
+
+        let mut client = connect(&server).await?;
+        ping(&mut client).await?;
+
+        let addr = client.get_ref().local_addr()?;
+        assert!(server.kill_addr(addr));
+
+        let res = timeout(Duration::from_secs(1), Type::read(&mut client)).await?;
+        assert!(matches!(res.unwrap_err(), Error::UnexpectedEof));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn closed_notifies_handlers() -> Result<()> {
+        let (closed_tx, mut closed_rx) = mpsc::unbounded_channel();
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .serve(move |conn: Conn, _cmd: Command| {
+                let closed_tx = closed_tx.clone();
+                async move {
+                    assert!(!conn.is_closed());
+                    tokio::select! {
+                        _ = conn.closed() => closed_tx.send(conn.is_closed()).unwrap(),
+                        _ = sleep(Duration::from_secs(10)) => conn.write_pong().await.unwrap(),
+                    }
+                }
+            })
+            .await?;
+
+        let mut client = connect(&server).await?;
+        Type::Array(vec![Type::BulkString(b"scan".to_vec())])
+            .write(&mut client)
+            .await?;
+        client.flush().await?;
+        sleep(Duration::from_millis(50)).await;
+        drop(client);
+
+        let closed = timeout(Duration::from_secs(1), closed_rx.recv()).await?;
+        assert_eq!(closed, Some(true));
+
+        Ok(())
+    }
+
+    /// Replies with `cancelled` once the token of the connection is cancelled.
+    async fn wait_for_cancellation(conn: Conn, _cmd: Command) {
+        let token = conn.cancellation_token();
+        tokio::select! {
+            _ = token.cancelled() => {
+                conn.write_simple_string("cancelled".to_string()).await.unwrap();
+            }
+            _ = sleep(Duration::from_secs(10)) => conn.write_pong().await.unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn cancel_on_disconnect() -> Result<()> {
+        let (done_tx, mut done_rx) = mpsc::unbounded_channel();
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .serve(move |conn: Conn, cmd: Command| {
+                let done_tx = done_tx.clone();
+                async move {
+                    let token = conn.cancellation_token();
+                    wait_for_cancellation(conn, cmd).await;
+                    done_tx.send(token.is_cancelled()).unwrap();
+                }
+            })
+            .await?;
+
+        let mut client = connect(&server).await?;
+        Type::Array(vec![Type::BulkString(b"scan".to_vec())])
+            .write(&mut client)
+            .await?;
+        client.flush().await?;
+        sleep(Duration::from_millis(50)).await;
+        drop(client);
+
+        let done = timeout(Duration::from_secs(1), done_rx.recv()).await?;
+        assert_eq!(done, Some(true));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cancel_on_drain() -> Result<()> {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .serve(wait_for_cancellation)
+            .await?;
+
+        let mut client = connect(&server).await?;
+        Type::Array(vec![Type::BulkString(b"scan".to_vec())])
+            .write(&mut client)
+            .await?;
+        client.flush().await?;
+        sleep(Duration::from_millis(50)).await;
+
+        let start = Instant::now();
+        assert_eq!(server.drain(Duration::from_secs(5)).await, 0);
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(
+            Type::read(&mut client).await?,
+            Type::SimpleString("cancelled".to_string())
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handler_timeout() -> Result<()> {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .handler_timeout(Duration::from_millis(100))
+            .serve(|conn: Conn, cmd: Command| async move {
+                if cmd.is("slow") {
+                    sleep(Duration::from_secs(10)).await;
+                }
+                if cmd.is("partial") {
+                    conn.write_simple_string("partial".to_string())
+                        .await
+                        .unwrap();
+                    sleep(Duration::from_secs(10)).await;
+                }
+                conn.write_pong().await.unwrap();
+            })
+            .await?;
+
+        let mut client = connect(&server).await?;
+        let start = Instant::now();
+        Type::Array(vec![Type::BulkString(b"slow".to_vec())])
+            .write(&mut client)
+            .await?;
+        assert_eq!(
+            Type::read(&mut client).await?,
+            Type::Error("ERR command timed out".to_string())
+        );
+        assert!(start.elapsed() < Duration::from_millis(500));
+        assert_eq!(
+            ping(&mut client).await?,
+            Type::SimpleString("PONG".to_string())
+        );
+
+        // Part of the reply is out already, so the connection is closed.
+        Type::Array(vec![Type::BulkString(b"partial".to_vec())])
+            .write(&mut client)
+            .await?;
+        assert_eq!(
+            Type::read(&mut client).await?,
+            Type::SimpleString("partial".to_string())
+        );
+        let res = timeout(Duration::from_secs(1), Type::read(&mut client)).await?;
+        assert!(matches!(res.unwrap_err(), Error::UnexpectedEof));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handler_panic_replies_with_error() -> Result<()> {
+        let (panic_tx, mut panic_rx) = mpsc::unbounded_channel();
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .on_panic(move |_conn: &Conn, msg: &str| panic_tx.send(msg.to_string()).unwrap())
+            .serve(|conn: Conn, cmd: Command| async move {
+                if cmd.is("panic") {
+                    panic!("boom");
+                }
+                conn.write_simple_string("pong".to_string()).await.unwrap();
+            })
+            .await?;
+
+        let mut client = connect(&server).await?;
+
+        Type::Array(vec![Type::BulkString(b"panic".to_vec())])
+            .write(&mut client)
+            .await?;
+        assert_eq!(
+            Type::read(&mut client).await?,
+            Type::Error("ERR internal error".to_string())
+        );
+        assert_eq!(panic_rx.recv().await, Some("boom".to_string()));
+
+        assert_eq!(
+            ping(&mut client).await?,
+            Type::SimpleString("pong".to_string())
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handler_panic_after_reply_closes_connection() -> Result<()> {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .serve(|conn: Conn, _cmd: Command| async move {
+                conn.write_ok().await.unwrap();
+                panic!("boom");
+            })
+            .await?;
+
+        let mut client = connect(&server).await?;
+        assert_eq!(
+            ping(&mut client).await?,
+            Type::SimpleString("OK".to_string())
+        );
+        // No error follows the reply, it would be taken for the reply to the next command.
+        let res = timeout(Duration::from_secs(1), Type::read(&mut client)).await?;
+        assert!(matches!(res.unwrap_err(), Error::UnexpectedEof));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handler_panic_closes_connection() -> Result<()> {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .close_on_panic(true)
+            .serve(|_conn: Conn, _cmd: Command| async move {
+                panic!("boom");
+            })
+            .await?;
+
+        let mut client = connect(&server).await?;
+        let res = timeout(Duration::from_secs(1), ping(&mut client)).await?;
+        assert!(matches!(
+            res.unwrap_err().downcast_ref::<Error>(),
+            Some(Error::UnexpectedEof)
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn protocol_error_reply() -> Result<()> {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .serve(|conn: Conn, _cmd: Command| async move {
+                conn.write_pong().await.unwrap();
+            })
+            .await?;
+
+        let mut client = connect(&server).await?;
+        let pong = Type::SimpleString("PONG".to_string());
+        assert_eq!(ping(&mut client).await?, pong);
+        client.write_all(b"\"oops\r\n").await?;
+        client.flush().await?;
+        assert_eq!(
+            Type::read(&mut client).await?,
+            Type::Error(
+                r#"ERR Protocol error: unbalanced quotes in inline command at byte 14 near "\"oops\r\n""#
+                    .to_string()
+            )
+        );
+
+        // The connection is closed, what follows the garbage can't be trusted.
+        let res = timeout(Duration::from_secs(1), Type::read(&mut client)).await?;
+        assert!(matches!(res.unwrap_err(), Error::UnexpectedEof));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn inline_commands() -> Result<()> {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .serve(|conn: Conn, cmd: Command| async move {
+                let args = cmd.iter().map(|it| Type::BulkString(it.clone()));
+                conn.write_array(args).await.unwrap();
+            })
+            .await?;
+
+        let mut client = connect(&server).await?;
+        client
+            .write_all(b"PING\r\n\r\nset key \"hello world\"\r\n")
+            .await?;
+        client.flush().await?;
+        assert_eq!(Type::read(&mut client).await?, Type::from(vec!["PING"]));
+        assert_eq!(
+            Type::read(&mut client).await?,
+            Type::from(vec!["set", "key", "hello world"])
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_disconnects() -> Result<()> {
+        let metrics = Arc::new(AtomicMetrics::new());
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .metrics(Arc::clone(&metrics))
+            .serve(|conn: Conn, _cmd: Command| async move {
+                conn.write_pong().await.unwrap();
+            })
+            .await?;
+
+        // Between commands.
+        let mut client = connect(&server).await?;
+        ping(&mut client).await?;
+        drop(client);
+        wait_for_connections(&server, 0).await;
+
+        // In the middle of a bulk string.
+        let mut client = connect(&server).await?;
+        wait_for_connections(&server, 1).await;
+        client.write_all(b"*2\r\n$4\r\nping\r\n$10\r\nabc").await?;
+        client.flush().await?;
+        drop(client);
+        wait_for_connections(&server, 0).await;
+
+        // In the middle of a line.
+        let mut client = connect(&server).await?;
+        wait_for_connections(&server, 1).await;
+        client.write_all(b"*1\r\n$4").await?;
+        client.flush().await?;
+        drop(client);
+        wait_for_connections(&server, 0).await;
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.client_disconnects, 3);
+        assert_eq!((snapshot.protocol_errors, snapshot.read_errors), (0, 0));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn too_deep_nesting_closes_connection() -> Result<()> {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .serve(|conn: Conn, _cmd: Command| async move {
+                conn.write_simple_string("PONG".to_string()).await.unwrap();
+            })
+            .await?;
+
+        let mut client = connect(&server).await?;
+        client.write_all(&b"*1\r\n".repeat(10_000)).await?;
+        client.flush().await?;
+        let reply = timeout(Duration::from_secs(1), Type::read(&mut client)).await??;
+        assert_eq!(
+            reply,
+            Type::Error(
+                r#"ERR Protocol error: nesting too deep at byte 256 near "*1\r\n""#.to_string()
+            )
+        );
+        let res = timeout(Duration::from_secs(1), Type::read(&mut client)).await?;
+        assert!(matches!(res.unwrap_err(), Error::UnexpectedEof));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn too_long_line_closes_connection() -> Result<()> {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .read_options(ReadOptions::new().max_line_len(16))
+            .serve(|conn: Conn, _cmd: Command| async move {
+                conn.write_pong().await.unwrap();
+            })
+            .await?;
+
+        let mut client = connect(&server).await?;
+        client.write_all(&[b'+'; 1024]).await?;
+        client.flush().await?;
+        let reply = timeout(Duration::from_secs(1), Type::read(&mut client)).await??;
+        assert_eq!(
+            reply,
+            Type::Error(format!(
+                r#"ERR Protocol error: line too long at byte 0 near "{}""#,
+                "+".repeat(16)
+            ))
+        );
+        let res = timeout(Duration::from_secs(1), Type::read(&mut client)).await?;
+        assert!(matches!(res.unwrap_err(), Error::UnexpectedEof));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn redis_protocol_errors() -> Result<()> {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .protocol_error_style(ProtocolErrorStyle::Redis)
+            .serve(|conn: Conn, _cmd: Command| async move {
+                conn.write_pong().await.unwrap();
+            })
+            .await?;
+
+        let cases: &[(&[u8], &str)] = &[
+            (b"*-2\r\n", "invalid multibulk length"),
+            (b"*1\r\n$x\r\n", "invalid bulk length"),
+            (b"PING \"oops\r\n", "unbalanced quotes in request"),
+        ];
+        for (input, expected) in cases {
+            let mut client = connect(&server).await?;
+            client.write_all(b"PING\r\n").await?;
+            client.write_all(input).await?;
+            client.flush().await?;
+            let reply = timeout(Duration::from_secs(1), Type::read(&mut client)).await??;
+            assert_eq!(reply, Type::SimpleString("PONG".to_string()));
+            let reply = timeout(Duration::from_secs(1), Type::read(&mut client)).await??;
+            assert_eq!(
+                reply,
+                Type::Error(format!("ERR Protocol error: {}", expected))
+            );
+            let res = timeout(Duration::from_secs(1), Type::read(&mut client)).await?;
+            assert!(res.is_err());
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn value_too_large() -> Result<()> {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .read_options(ReadOptions::new().max_value_len(16))
+            .serve(|conn: Conn, _cmd: Command| async move {
+                conn.write_pong().await.unwrap();
+            })
+            .await?;
+
+        let mut client = connect(&server).await?;
+        send(&mut client, &["set", "key", &"x".repeat(1024)]).await?;
+        assert_eq!(
+            Type::read(&mut client).await?,
+            Type::Error("ERR value too large".to_string())
+        );
+        assert_eq!(ping(&mut client).await?, Type::SimpleString("PONG".into()));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cluster_redirects() -> Result<()> {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .serve(|conn: Conn, _cmd: Command| async move {
+                conn.write_moved(3999, "127.0.0.1:6381").await.unwrap();
+                conn.write_ask(3999, "127.0.0.1:6381").await.unwrap();
+            })
+            .await?;
+
+        let mut client = connect(&server).await?;
+        assert_eq!(
+            ping(&mut client).await?,
+            Type::Error("MOVED 3999 127.0.0.1:6381".to_string())
+        );
+        assert_eq!(
+            Type::read(&mut client).await?,
+            Type::Error("ASK 3999 127.0.0.1:6381".to_string())
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn write_array_from_iterator() -> Result<()> {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .serve(|conn: Conn, cmd: Command| async move {
+                conn.write_array(cmd.iter().map(|it| Type::BulkString(it.clone())))
+                    .await
+                    .unwrap();
+                conn.write_array(std::iter::empty::<Type>()).await.unwrap();
+                conn.write_array(vec![Some(1), None]).await.unwrap();
+            })
+            .await?;
+
+        let mut client = connect(&server).await?;
+        assert_eq!(
+            ping(&mut client).await?,
+            Type::Array(vec![Type::BulkString(b"ping".to_vec())])
+        );
+        assert_eq!(Type::read(&mut client).await?, Type::Array(vec![]));
+        assert_eq!(
+            Type::read(&mut client).await?,
+            Type::Array(vec![Type::Integer(1), Type::Null])
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn write_map_depends_on_protocol() -> Result<()> {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .serve(|conn: Conn, cmd: Command| async move {
+                if cmd.is("hello") {
+                    conn.set_protocol(Protocol::Resp3);
+                }
+                let pairs = vec![
+                    (Type::from("name"), Type::from("redcon")),
+                    (Type::from("modules"), Type::from(vec!["a", "b"])),
+                ];
+                conn.write_map(pairs).await.unwrap();
+            })
+            .await?;
+
+        let mut client = connect(&server).await?;
+        async fn reply(client: &mut BufStream<TcpStream>, cmd: &str, len: usize) -> Vec<u8> {
+            Type::Array(vec![Type::from(cmd.to_string())])
+                .write(&mut *client)
+                .await
+                .unwrap();
+            let mut buf = vec![0; len];
+            client.read_exact(&mut buf).await.unwrap();
+            buf
+        }
+
+        let resp2 =
+            b"*4\r\n$4\r\nname\r\n$6\r\nredcon\r\n$7\r\nmodules\r\n*2\r\n$1\r\na\r\n$1\r\nb\r\n";
+        assert_eq!(reply(&mut client, "ping", resp2.len()).await, resp2);
+        let resp3 =
+            b"%2\r\n$4\r\nname\r\n$6\r\nredcon\r\n$7\r\nmodules\r\n*2\r\n$1\r\na\r\n$1\r\nb\r\n";
+        assert_eq!(reply(&mut client, "hello", resp3.len()).await, resp3);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn values_downgraded_to_resp2() -> Result<()> {
+        fn nested() -> Type {
+            let tags = Type::Map(vec![
+                (Type::from("admins"), Type::from(vec!["alice"])),
+                (Type::from("users"), Type::from(vec!["bob", "carol"])),
+            ]);
+            let ttl = Type::Attribute {
+                attrs: vec![(Type::from("unit"), Type::from("s"))],
+                value: Box::new(Type::Integer(60)),
+            };
+            Type::Map(vec![(Type::from("tags"), tags), (Type::from("ttl"), ttl)])
+        }
+
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .serve(|conn: Conn, cmd: Command| async move {
+                if cmd.is("hello") {
+                    conn.set_protocol(Protocol::Resp3);
+                }
+                conn.write_type(&nested()).await.unwrap();
+            })
+            .await?;
+
+        let mut client = connect(&server).await?;
+        let flattened = Type::Array(vec![
+            Type::from("tags"),
+            Type::Array(vec![
+                Type::from("admins"),
+                Type::from(vec!["alice"]),
+                Type::from("users"),
+                Type::from(vec!["bob", "carol"]),
+            ]),
+            Type::from("ttl"),
+            Type::Integer(60),
+        ]);
+        assert_eq!(ping(&mut client).await?, flattened);
+
+        Type::from(vec!["hello"]).write(&mut client).await?;
+        let expected = nested().to_bytes();
+        let mut buf = vec![0; expected.len()];
+        timeout(Duration::from_secs(1), client.read_exact(&mut buf)).await??;
+        assert_eq!(buf, expected);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn write_binary_bulk_strings() -> Result<()> {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .serve(|conn: Conn, _cmd: Command| async move {
+                conn.write_bulk_bytes(b"\0\xff\r\n").await.unwrap();
+                conn.write_bytes(Bytes::from_static(b"\xff\xfe"))
+                    .await
+                    .unwrap();
+            })
+            .await?;
+
+        let mut client = connect(&server).await?;
+        assert_eq!(
+            ping(&mut client).await?,
+            Type::BulkString(b"\0\xff\r\n".to_vec())
+        );
+        assert_eq!(
+            Type::read(&mut client).await?,
+            Type::BulkString(b"\xff\xfe".to_vec())
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reply_shortcuts() -> Result<()> {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .serve(|conn: Conn, _cmd: Command| async move {
+                conn.write_ok().await.unwrap();
+                conn.write_pong().await.unwrap();
+                conn.write_queued().await.unwrap();
+                conn.write_nil().await.unwrap();
+                conn.write_wrongtype().await.unwrap();
+                conn.write_zero().await.unwrap();
+                conn.write_one().await.unwrap();
+            })
+            .await?;
+
+        let mut client = connect(&server).await?;
+        Type::Array(vec![Type::BulkString(b"ping".to_vec())])
+            .write(&mut client)
+            .await?;
+        let expected: &[u8] = b"+OK\r\n+PONG\r\n+QUEUED\r\n$-1\r\n\
+            -WRONGTYPE Operation against a key holding the wrong kind of value\r\n:0\r\n:1\r\n";
+        let mut buf = vec![0; expected.len()];
+        timeout(Duration::from_secs(1), client.read_exact(&mut buf)).await??;
+        assert_eq!(buf, expected);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn generic_write() -> Result<()> {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .serve(|conn: Conn, _cmd: Command| async move {
+                conn.write(42).await.unwrap();
+                conn.write("hello").await.unwrap();
+                conn.write(None::<String>).await.unwrap();
+                conn.write(vec![1i64, 2, 3]).await.unwrap();
+                // Downgraded to a flat array on RESP2 connections.
+                conn.write(Type::Map(vec![(Type::from("key"), Type::from(1))]))
+                    .await
+                    .unwrap();
+            })
+            .await?;
+
+        let mut client = connect(&server).await?;
+        Type::from(vec!["ping"]).write(&mut client).await?;
+        let expected: &[u8] =
+            b":42\r\n$5\r\nhello\r\n$-1\r\n*3\r\n:1\r\n:2\r\n:3\r\n*2\r\n$3\r\nkey\r\n:1\r\n";
+        let mut buf = vec![0; expected.len()];
+        timeout(Duration::from_secs(1), client.read_exact(&mut buf)).await??;
+        assert_eq!(buf, expected);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn capture_replies() -> Result<()> {
+        async fn three_frames(conn: Conn) -> Result<()> {
+            conn.write(1).await?;
+            conn.write_ok().await?;
+            conn.write("three").await
+        }
+
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .serve(|conn: Conn, cmd: Command| async move {
+                if !cmd.is("capture") {
+                    return three_frames(conn).await.unwrap();
+                }
+                let capture = conn.capture();
+                three_frames(Conn::clone(&capture)).await.unwrap();
+                // Stacked captures don't show up in the outer one.
+                let nested = capture.capture();
+                nested.write_pong().await.unwrap();
+                assert_eq!(nested.take(), vec![Type::SimpleString("PONG".into())]);
+                assert!(capture.begin_array(1).await.is_err());
+
+                let frames = capture.take();
+                assert_eq!(
+                    frames,
+                    vec![
+                        Type::from(1),
+                        Type::SimpleString("OK".into()),
+                        "three".into()
+                    ]
+                );
+                assert!(capture.take().is_empty());
+                conn.write(frames.len() as i64).await.unwrap();
+            })
+            .await?;
+
+        let mut client = connect(&server).await?;
+        send(&mut client, &["capture"]).await?;
+        send(&mut client, &["plain"]).await?;
+        // Only the count is written for the captured frames.
+        let expected: &[u8] = b":3\r\n:1\r\n+OK\r\n$5\r\nthree\r\n";
+        let mut buf = vec![0; expected.len()];
+        timeout(Duration::from_secs(1), client.read_exact(&mut buf)).await??;
+        assert_eq!(buf, expected);
+
+        Ok(())
+    }
+
+    async fn pipeline_pings(client: &mut BufStream<TcpStream>, n: usize) -> Result<Vec<Type>> {
+        let mut ping = Vec::new();
+        Type::Array(vec![Type::BulkString(b"ping".to_vec())])
+            .write(&mut ping)
+            .await?;
+        client.write_all(&ping.repeat(n)).await?;
+        client.flush().await?;
+
+        let mut replies = Vec::with_capacity(n);
+        for _ in 0..n {
+            replies.push(Type::read(client).await?);
+        }
+        Ok(replies)
+    }
+
+    /// Other connections are served while a large pipeline is dispatched. The in-flight permits
+    /// use up the cooperative budget of tokio, which makes the read loop yield as well, so this
+    /// also holds without [`Builder::yield_every`] and doesn't cover it on its own.
+    #[tokio::test]
+    async fn large_pipeline_doesnt_starve_other_conns() -> Result<()> {
+        const PIPELINED: u64 = 100_000;
+        let pings = Arc::new(AtomicU64::new(0));
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .processing_mode(ProcessingMode::Concurrent)
+            .serve({
+                let pings = Arc::clone(&pings);
+                move |conn: Conn, cmd: Command| {
+                    let pings = Arc::clone(&pings);
+                    async move {
+                        if cmd.is("ping") {
+                            pings.fetch_add(1, Ordering::Relaxed);
+                        }
+                        conn.write_pong().await.unwrap();
+                    }
+                }
+            })
+            .await?;
+
+        let (read, mut write) = connect(&server).await?.into_inner().into_split();
+        let drain = tokio::spawn(async move {
+            let mut read = BufReader::new(read);
+            for _ in 0..PIPELINED {
+                Type::read(&mut read).await.unwrap();
+            }
+        });
+        let mut ping = Vec::new();
+        Type::from(vec!["ping"]).write(&mut ping).await?;
+        write.write_all(&ping.repeat(PIPELINED as usize)).await?;
+        while pings.load(Ordering::Relaxed) == 0 {
+            task::yield_now().await;
+        }
+
+        // Served while the pipeline is being dispatched rather than once it drained.
+        let mut client = connect(&server).await?;
+        send(&mut client, &["echo"]).await?;
+        assert_eq!(
+            Type::read(&mut client).await?,
+            Type::SimpleString("PONG".into())
+        );
+        assert!(pings.load(Ordering::Relaxed) < PIPELINED);
+
+        timeout(Duration::from_secs(30), drain).await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn missing_reply() -> Result<()> {
+        let handler = |conn: Conn, cmd: Command| async move {
+            match cmd.name() {
+                b"ping" => conn.write_pong().await.unwrap(),
+                b"later" => {
+                    conn.no_reply();
+                    tokio::spawn(async move { conn.write_ok().await.unwrap() });
+                }
+                b"deferred" => {
+                    let deferred = conn.defer();
+                    tokio::spawn(deferred.resolve(1));
+                }
+                _ => {}
+            }
+        };
+        for (policy, reply) in [
+            (MissingReplyPolicy::WriteNullAndWarn, Some(Type::Null)),
+            (
+                MissingReplyPolicy::Error,
+                Some(Type::Error("ERR no reply produced".to_string())),
+            ),
+            (MissingReplyPolicy::Ignore, None),
+        ] {
+            let server = Server::builder()
+                .bind("127.0.0.1:0")
+                .missing_reply(policy)
+                .serve(handler)
+                .await?;
+            let mut client = connect(&server).await?;
+            send(&mut client, &["forget"]).await?;
+            if let Some(reply) = reply {
+                assert_eq!(Type::read(&mut client).await?, reply);
+            }
+            assert_eq!(ping(&mut client).await?, Type::SimpleString("PONG".into()));
+
+            // Replies written by another task or deferred are not missing.
+            send(&mut client, &["later"]).await?;
+            assert_eq!(
+                Type::read(&mut client).await?,
+                Type::SimpleString("OK".into())
+            );
+            send(&mut client, &["deferred"]).await?;
+            assert_eq!(Type::read(&mut client).await?, Type::Integer(1));
+            assert_eq!(ping(&mut client).await?, Type::SimpleString("PONG".into()));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn max_in_flight() -> Result<()> {
+        let running = Arc::new(AtomicU64::new(0));
+        let max_running = Arc::new(AtomicU64::new(0));
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .processing_mode(ProcessingMode::Concurrent)
+            .max_in_flight(8)
+            .serve({
+                let running = Arc::clone(&running);
+                let max_running = Arc::clone(&max_running);
+                move |conn: Conn, _cmd: Command| {
+                    let running = Arc::clone(&running);
+                    let max_running = Arc::clone(&max_running);
+                    async move {
+                        let n = running.fetch_add(1, Ordering::SeqCst) + 1;
+                        max_running.fetch_max(n, Ordering::SeqCst);
+                        tokio::task::yield_now().await;
+                        running.fetch_sub(1, Ordering::SeqCst);
+                        conn.write_pong().await.unwrap();
+                    }
+                }
+            })
+            .await?;
+
+        let mut client = connect(&server).await?;
+        let replies = pipeline_pings(&mut client, 10_000).await?;
+        assert_eq!(replies.len(), 10_000);
+        assert!(max_running.load(Ordering::SeqCst) <= 8);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sequential_processing() -> Result<()> {
+        // Commands of a connection, in the order their handlers ran.
+        let handled = Arc::new(Mutex::new(HashMap::<ConnId, Vec<String>>::new()));
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .serve({
+                let handled = Arc::clone(&handled);
+                move |conn: Conn, cmd: Command| {
+                    let handled = Arc::clone(&handled);
+                    async move {
+                        // Later commands sleep less, so they would overtake earlier ones if
+                        // they were handled concurrently.
+                        let n = cmd.arg_i64(1).unwrap() as u64;
+                        sleep(Duration::from_millis(10 - n)).await;
+                        let arg = cmd.arg_str(1).unwrap().to_string();
+                        handled
+                            .lock()
+                            .unwrap()
+                            .entry(conn.id())
+                            .or_default()
+                            .push(arg.clone());
+                        conn.write_bulk_string(arg).await.unwrap();
+                    }
+                }
+            })
+            .await?;
+
+        let mut clients = Vec::new();
+        for _ in 0..2 {
+            clients.push(connect(&server).await?);
+        }
+        let expected: Vec<String> = (0..10).map(|n| n.to_string()).collect();
+        for client in &mut clients {
+            for n in &expected {
+                Type::from(vec!["echo", n.as_str()])
+                    .write_to(&mut *client)
+                    .await?;
+            }
+            client.flush().await?;
+        }
+        for client in &mut clients {
+            for n in &expected {
+                assert_eq!(Type::read(&mut *client).await?, Type::from(n.as_str()));
+            }
+        }
+
+        let handled = handled.lock().unwrap();
+        assert_eq!(handled.len(), 2);
+        assert!(handled.values().all(|cmds| *cmds == expected));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rate_limit_delays_commands() -> Result<()> {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .rate_limit(RateLimit::new(100, 1))
+            .serve(|conn: Conn, _cmd: Command| async move {
+                conn.write_pong().await.unwrap();
+            })
+            .await?;
+
+        let mut client = connect(&server).await?;
+        let start = Instant::now();
+        let replies = timeout(Duration::from_secs(5), pipeline_pings(&mut client, 21)).await??;
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert!(replies
+            .iter()
+            .all(|it| *it == Type::SimpleString("PONG".to_string())));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rate_limit_delay_keeps_handlers_running() -> Result<()> {
+        let (id_tx, mut id_rx) = mpsc::unbounded_channel();
+        let (done_tx, mut done_rx) = mpsc::unbounded_channel();
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .rate_limit(RateLimit::new(1, 1))
+            .processing_mode(ProcessingMode::Concurrent)
+            .serve(move |conn: Conn, _cmd: Command| {
+                let (id_tx, done_tx) = (id_tx.clone(), done_tx.clone());
+                async move {
+                    id_tx.send(conn.id()).unwrap();
+                    sleep(Duration::from_millis(200)).await;
+                    done_tx.send(()).unwrap();
+                }
+            })
+            .await?;
+
+        // The second command waits for the rate limit while the first one is handled.
+        let mut client = connect(&server).await?;
+        let mut pings = Vec::new();
+        for _ in 0..2 {
+            Type::from(vec!["ping"]).write(&mut pings).await?;
+        }
+        client.write_all(&pings).await?;
+        client.flush().await?;
+        let id = id_rx.recv().await.unwrap();
+        assert!(server.kill(id));
+        assert_eq!(
+            timeout(Duration::from_millis(500), done_rx.recv()).await?,
+            Some(())
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rate_limit_rejects_commands() -> Result<()> {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .rate_limit(
+                RateLimit::new(10, 10).policy(RateLimitPolicy::Reject { close_after: None }),
+            )
+            .serve(|conn: Conn, _cmd: Command| async move {
+                conn.write_pong().await.unwrap();
+            })
+            .await?;
+
+        let mut client = connect(&server).await?;
+        let replies = timeout(Duration::from_secs(5), pipeline_pings(&mut client, 100)).await??;
+        let accepted = replies
+            .iter()
+            .filter(|it| **it == Type::SimpleString("PONG".to_string()))
+            .count();
+        let rejected = replies
+            .iter()
+            .filter(|it| **it == Type::Error("ERR rate limit exceeded".to_string()))
+            .count();
+        assert!((10..20).contains(&accepted), "{} accepted", accepted);
+        assert_eq!(accepted + rejected, 100);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rate_limit_closes_connection() -> Result<()> {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .rate_limit(RateLimit::new(1, 1).policy(RateLimitPolicy::Reject {
+                close_after: Some(3),
+            }))
+            .serve(|conn: Conn, _cmd: Command| async move {
+                conn.write_pong().await.unwrap();
+            })
+            .await?;
+
+        let mut client = connect(&server).await?;
+        let err = timeout(Duration::from_secs(1), pipeline_pings(&mut client, 5))
+            .await?
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::UnexpectedEof)
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn metrics() -> Result<()> {
+        let metrics = Arc::new(AtomicMetrics::new());
+        let (disconnect_tx, mut disconnect_rx) = mpsc::unbounded_channel();
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .metrics(Arc::clone(&metrics))
+            .on_disconnect(move |conn: &Conn, _reason| disconnect_tx.send(conn.id()).unwrap())
+            .serve(|conn: Conn, _cmd: Command| async move {
+                conn.write_pong().await.unwrap();
+            })
+            .await?;
+
+        let mut client = connect(&server).await?;
+        assert_eq!(
+            ping(&mut client).await?,
+            Type::SimpleString("PONG".to_string())
+        );
+        Type::SimpleString("ping".to_string())
+            .write(&mut client)
+            .await?;
+        let err = Type::read(&mut client).await?;
+        assert_eq!(metrics.snapshot().active_connections(), 1);
+        drop(client);
+        timeout(Duration::from_secs(1), disconnect_rx.recv()).await?;
+
+        let mut sent = Vec::new();
+        Type::Array(vec![Type::BulkString(b"ping".to_vec())])
+            .write(&mut sent)
+            .await?;
+        Type::SimpleString("ping".to_string())
+            .write(&mut sent)
+            .await?;
+        let mut received = b"+PONG\r\n".to_vec();
+        err.write(&mut received).await?;
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.connections_opened, 1);
+        assert_eq!(snapshot.connections_closed, 1);
+        assert_eq!(snapshot.commands, 1);
+        assert_eq!(snapshot.bytes_read, sent.len() as u64);
+        assert_eq!(snapshot.bytes_written, received.len() as u64);
+        assert_eq!(snapshot.protocol_errors, 1);
+        assert_eq!((snapshot.client_disconnects, snapshot.read_errors), (1, 0));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn drain_waits_for_in_flight_commands() -> Result<()> {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .serve(|conn: Conn, _cmd: Command| async move {
+                sleep(Duration::from_millis(200)).await;
+                conn.write_pong().await.unwrap();
+            })
+            .await?;
+
+        let mut client = connect(&server).await?;
+        Type::Array(vec![Type::BulkString(b"ping".to_vec())])
+            .write(&mut client)
+            .await?;
+        // Lets the server read the command before draining.
+        sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(server.drain(Duration::from_secs(1)).await, 0);
+        assert_eq!(
+            Type::read(&mut client).await?,
+            Type::SimpleString("PONG".to_string())
+        );
+        let res = Type::read(&mut client).await;
+        assert!(matches!(res.unwrap_err(), Error::UnexpectedEof));
+        assert!(TcpStream::connect(server.local_addr()).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn drain_timeout_closes_connections() -> Result<()> {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .serve(|conn: Conn, _cmd: Command| async move {
+                sleep(Duration::from_secs(10)).await;
+                conn.write_pong().await.unwrap();
+            })
+            .await?;
+
+        let mut client = connect(&server).await?;
+        Type::Array(vec![Type::BulkString(b"ping".to_vec())])
+            .write(&mut client)
+            .await?;
+        sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(server.drain(Duration::from_millis(100)).await, 1);
+        let res = timeout(Duration::from_secs(1), Type::read(&mut client)).await?;
+        assert!(matches!(res.unwrap_err(), Error::UnexpectedEof));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn shutdown_closes_connections() -> Result<()> {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .serve(|conn: Conn, _cmd: Command| async move {
+                conn.write_pong().await.unwrap();
+            })
+            .await?;
+
+        let mut clients = vec![connect(&server).await?, connect(&server).await?];
+        for client in &mut clients {
+            assert_eq!(ping(client).await?, Type::SimpleString("PONG".to_string()));
+        }
+
+        timeout(Duration::from_secs(1), server.shutdown()).await?;
+        for client in &mut clients {
+            let res = timeout(Duration::from_secs(1), Type::read(client)).await?;
+            assert!(matches!(res.unwrap_err(), Error::UnexpectedEof));
+        }
+
+        Ok(())
+    }
+
+    /// Polls until the server has `n` open connections.
+    async fn wait_for_connections(server: &Server, n: usize) {
+        timeout(Duration::from_secs(1), async {
+            while server.connection_count() != n {
+                sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("expected {} connections", n));
+    }
+
+    #[tokio::test]
+    async fn connection_count_and_stats() -> Result<()> {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .serve(|conn: Conn, _cmd: Command| async move {
+                conn.write_pong().await.unwrap();
+            })
+            .await?;
+        assert_eq!(server.connection_count(), 0);
+
+        let mut first = connect(&server).await?;
+        let mut second = connect(&server).await?;
+        wait_for_connections(&server, 2).await;
+        ping(&mut first).await?;
+        ping(&mut second).await?;
+        ping(&mut second).await?;
+
+        drop(first);
+        wait_for_connections(&server, 1).await;
+        drop(second);
+        wait_for_connections(&server, 0).await;
+
+        let stats = server.stats();
+        assert_eq!(stats.connections_accepted, 2);
+        assert_eq!(stats.commands, 3);
+        assert!(stats.uptime > Duration::ZERO);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn accept_filter() -> Result<()> {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .accept_filter(|addr| match addr.port() % 3 {
+                0 => AcceptDecision::Reject,
+                1 => AcceptDecision::RejectWithError("ERR go away".to_string()),
+                _ => AcceptDecision::Accept,
+            })
+            .serve(|conn: Conn, _cmd: Command| async move {
+                conn.write_pong().await.unwrap();
+            })
+            .await?;
+
+        let mut seen = [false; 3];
+        let mut accepted = Vec::new();
+        while seen.contains(&false) {
+            let mut client = connect(&server).await?;
+            let port = client.get_ref().local_addr()?.port();
+            seen[usize::from(port % 3)] = true;
+            let res = timeout(Duration::from_secs(1), ping(&mut client)).await?;
+            match port % 3 {
+                0 => assert!(res.is_err()),
+                1 => {
+                    assert_eq!(res?, Type::Error("ERR go away".to_string()));
+                    let res = timeout(Duration::from_secs(1), Type::read(&mut client)).await?;
+                    assert!(res.is_err());
+                }
+                _ => {
+                    assert_eq!(res?, Type::SimpleString("PONG".to_string()));
+                    accepted.push(client);
+                }
+            }
+        }
+
+        assert_eq!(server.connection_count(), accepted.len());
+        assert_eq!(server.stats().connections_accepted, accepted.len() as u64);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tap() -> Result<()> {
+        let tapped = Arc::new(Mutex::new(Vec::new()));
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .tap({
+                let tapped = Arc::clone(&tapped);
+                move |conn, direction, bytes: &[u8]| {
+                    tapped
+                        .lock()
+                        .unwrap()
+                        .push((conn, direction, bytes.to_vec()));
+                }
+            })
+            .serve(|conn: Conn, _cmd: Command| async move {
+                conn.write_pong().await.unwrap();
+            })
+            .await?;
+
+        let mut client = connect(&server).await?;
+        assert_eq!(
+            ping(&mut client).await?,
+            Type::SimpleString("PONG".to_string())
+        );
+
+        let tapped = tapped.lock().unwrap();
+        let bytes = |direction| {
+            tapped
+                .iter()
+                .filter(|it| it.1 == direction)
+                .flat_map(|it| it.2.clone())
+                .collect::<Vec<u8>>()
+        };
+        assert_eq!(bytes(Direction::In), b"*1\r\n$4\r\nping\r\n");
+        assert_eq!(bytes(Direction::Out), b"+PONG\r\n");
+        let conn = tapped[0].0;
+        assert!(tapped.iter().all(|it| it.0 == conn));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn broadcast_by_reference() -> Result<()> {
+        let subscribers = Arc::new(Mutex::new(Vec::new()));
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .serve({
+                let subscribers = Arc::clone(&subscribers);
+                move |conn: Conn, _cmd: Command| {
+                    subscribers.lock().unwrap().push(conn.clone());
+                    async move { conn.write_ok().await.unwrap() }
+                }
+            })
+            .await?;
+
+        let mut clients = Vec::new();
+        for _ in 0..3 {
+            let mut client = connect(&server).await?;
+            assert_eq!(
+                ping(&mut client).await?,
+                Type::SimpleString("OK".to_string())
+            );
+            clients.push(client);
+        }
+
+        // Built once and written as is to every subscriber.
+        let message = Type::Array(vec![
+            Type::from("message"),
+            Type::from("news"),
+            Type::from("x".repeat(1024)),
+        ]);
+        let conns = subscribers.lock().unwrap().clone();
+        for conn in &conns {
+            conn.write_type(&message).await?;
+        }
+        for client in &mut clients {
+            assert_eq!(Type::read(client).await?, message);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn bulk_from_reader() -> Result<()> {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .serve(|conn: Conn, cmd: Command| async move {
+                let data = vec![b'x'; 200_000];
+                if cmd.is("short") {
+                    let err = conn.write_bulk_from_reader(10, &b"abc"[..]).await;
+                    assert!(err.is_err());
+                    return;
+                }
+                // The rest of the reader is left unread.
+                conn.write_bulk_from_reader(150_000, &data[..])
+                    .await
+                    .unwrap();
+            })
+            .await?;
+
+        let mut client = connect(&server).await?;
+        assert_eq!(ping(&mut client).await?, Type::from("x".repeat(150_000)));
+        send(&mut client, &["short"]).await?;
+        // The incomplete reply closes the connection.
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await?;
+        assert_eq!(rest, b"$10\r\nabc");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn streamed_replies() -> Result<()> {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .serve(|conn: Conn, _cmd: Command| async move {
+                assert!(conn.begin_streamed_bulk().await.is_err());
+                conn.set_protocol(Protocol::Resp3);
+
+                let mut bulk = conn.begin_streamed_bulk().await.unwrap();
+                for chunk in [&b"Hello"[..], b"", b" wor", b"ld"] {
+                    bulk.chunk(chunk).await.unwrap();
+                }
+                bulk.finish().await.unwrap();
+
+                let mut array = conn.begin_streamed_array().await.unwrap();
+                for i in 0..3 {
+                    array.push(i).await.unwrap();
+                }
+                array.finish().await.unwrap();
+
+                let mut map = conn.begin_streamed_map().await.unwrap();
+                map.pair("a", 1).await.unwrap();
+                map.pair("b", Type::Null).await.unwrap();
+                map.finish().await.unwrap();
+            })
+            .await?;
+
+        let mut client = connect(&server).await?;
+        assert_eq!(
+            ping(&mut client).await?,
+            Type::BulkString(b"Hello world".to_vec())
+        );
+        assert_eq!(
+            Type::read(&mut client).await?,
+            Type::Array(vec![Type::Integer(0), Type::Integer(1), Type::Integer(2)])
+        );
+        assert_eq!(
+            Type::read(&mut client).await?,
+            Type::Map(vec![
+                (Type::from("a"), Type::Integer(1)),
+                (Type::from("b"), Type::Null),
+            ])
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn array_writer() -> Result<()> {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .serve(|conn: Conn, _cmd: Command| async move {
+                let mut rows = conn.begin_array(3).await.unwrap();
+                rows.element("header").await.unwrap();
+                let mut row = rows.begin_array(2).await.unwrap();
+                row.element(1).await.unwrap();
+                row.element(Type::Null).await.unwrap();
+                assert!(row.element(3).await.is_err());
+                row.finish().await.unwrap();
+                rows.element(2).await.unwrap();
+                rows.finish().await.unwrap();
+            })
+            .await?;
+
+        let mut client = connect(&server).await?;
+        assert_eq!(
+            ping(&mut client).await?,
+            Type::Array(vec![
+                Type::from("header"),
+                Type::Array(vec![Type::Integer(1), Type::Null]),
+                Type::Integer(2),
+            ])
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn incomplete_array_closes_connection() -> Result<()> {
+        let (res_tx, mut res_rx) = mpsc::unbounded_channel();
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .serve(move |conn: Conn, _cmd: Command| {
+                let res_tx = res_tx.clone();
+                async move {
+                    let mut array = conn.begin_array(2).await.unwrap();
+                    array.element(1).await.unwrap();
+                    let finished = array.finish().await;
+                    let written = conn.write_pong().await;
+                    res_tx.send((finished.is_err(), written.is_err())).unwrap();
+                }
+            })
+            .await?;
+
+        let mut client = connect(&server).await?;
+        let res = timeout(Duration::from_secs(1), ping(&mut client)).await?;
+        assert!(matches!(
+            res.unwrap_err().downcast_ref::<Error>(),
+            Some(Error::UnexpectedEof)
+        ));
+        assert_eq!(res_rx.recv().await, Some((true, true)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dropped_streamed_reply_closes_connection() -> Result<()> {
+        let (res_tx, mut res_rx) = mpsc::unbounded_channel();
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .serve(move |conn: Conn, cmd: Command| {
+                let res_tx = res_tx.clone();
+                async move {
+                    conn.set_protocol(Protocol::Resp3);
+                    if cmd.is("bulk") {
+                        let mut bulk = conn.begin_streamed_bulk().await.unwrap();
+                        bulk.chunk(b"partial").await.unwrap();
+                    } else if cmd.is("map") {
+                        let mut map = conn.begin_streamed_map().await.unwrap();
+                        map.pair("a", 1).await.unwrap();
+                    } else {
+                        let mut array = conn.begin_streamed_array().await.unwrap();
+                        array.push(1).await.unwrap();
+                    }
+                    // Dropped without finishing, like a handler returning early with `?`.
+                    res_tx.send(conn.write_pong().await.is_err()).unwrap();
+                }
+            })
+            .await?;
+
+        for name in ["bulk", "array", "map"] {
+            let mut client = connect(&server).await?;
+            Type::from(vec![name]).write(&mut client).await?;
+            let res = timeout(Duration::from_secs(1), Type::read(&mut client)).await?;
+            assert!(matches!(res.unwrap_err(), Error::UnexpectedEof));
+            assert_eq!(res_rx.recv().await, Some(true));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn info() -> Result<()> {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .info(|info| {
+                info.section("Keyspace", [("db0", "keys=1,expires=0")]);
+            })
+            .serve(|conn: Conn, _cmd: Command| async move {
+                conn.write_pong().await.unwrap();
+            })
+            .await?;
+
+        /// Parses the reply into the fields of each section.
+        fn parse(reply: Type) -> Vec<(String, HashMap<String, String>)> {
+            let info = match reply.as_str() {
+                Some(info) => info.to_string(),
+                None => panic!("unexpected reply {:?}", reply),
+            };
+            assert!(info.ends_with("\r\n"));
+            let mut sections = Vec::new();
+            for section in info.trim_end_matches("\r\n").split("\r\n\r\n") {
+                let mut lines = section.split("\r\n");
+                let name = lines.next().unwrap().strip_prefix("# ").unwrap();
+                let fields = lines
+                    .map(|line| {
+                        let (key, value) = line.split_once(':').unwrap();
+                        (key.to_string(), value.to_string())
+                    })
+                    .collect();
+                sections.push((name.to_string(), fields));
+            }
+            sections
+        }
+
+        let mut client = connect(&server).await?;
+        let _other = connect(&server).await?;
+        wait_for_connections(&server, 2).await;
+        ping(&mut client).await?;
+
+        send(&mut client, &["INFO"]).await?;
+        let sections = parse(Type::read(&mut client).await?);
+        let names: Vec<_> = sections.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["Server", "Clients", "Stats", "Keyspace"]);
+        let server_section = &sections[0].1;
+        assert_eq!(server_section["redcon_version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(
+            server_section["tcp_port"],
+            server.local_addr().port().to_string()
+        );
+        assert!(server_section["uptime_in_seconds"].parse::<u64>().is_ok());
+        assert_eq!(sections[1].1["connected_clients"], "2");
+        assert_eq!(sections[2].1["total_connections_received"], "2");
+        assert_eq!(sections[3].1["db0"], "keys=1,expires=0");
+
+        send(&mut client, &["info", "clients", "KEYSPACE"]).await?;
+        let sections = parse(Type::read(&mut client).await?);
+        let names: Vec<_> = sections.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["Clients", "Keyspace"]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn hello_responder() -> Result<()> {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .hello_info(|info| {
+                info.server("mykv")
+                    .version("1.2.3")
+                    .extra("role", "replica")
+                    .extra("shards", 1);
+            })
+            .serve(|conn: Conn, _cmd: Command| async move {
+                conn.write_pong().await.unwrap();
+            })
+            .await?;
+
+        let mut client = connect(&server).await?;
+        async fn send(client: &mut BufStream<TcpStream>, args: &[&str]) -> Result<()> {
+            Type::Array(args.iter().map(|&it| Type::from(it)).collect())
+                .write(client)
+                .await?;
+            Ok(())
+        }
+
+        send(&mut client, &["HELLO", "4"]).await?;
+        assert_eq!(
+            Type::read(&mut client).await?,
+            Type::Error("NOPROTO unsupported protocol version".to_string())
+        );
+
+        send(&mut client, &["hello"]).await?;
+        let reply = Type::read(&mut client).await?;
+        let fields = match reply {
+            Type::Array(fields) => fields,
+            reply => panic!("unexpected reply: {:?}", reply),
+        };
+        assert_eq!(fields.len(), 16);
+        assert_eq!(
+            fields[..6],
+            [
+                Type::from("server"),
+                Type::from("mykv"),
+                Type::from("version"),
+                Type::from("1.2.3"),
+                Type::from("proto"),
+                Type::Integer(2),
+            ]
+        );
+        assert_eq!(fields[10..12], [Type::from("role"), Type::from("replica")]);
+        assert_eq!(fields[14..], [Type::from("shards"), Type::Integer(1)]);
+
+        send(&mut client, &["HELLO", "3", "SETNAME", "client"]).await?;
+        let mut line = String::new();
+        client.read_line(&mut line).await?;
+        assert_eq!(line, "%8\r\n");
+
+        Ok(())
+    }
+
+    /// Lists with blocking pops, where `DROP` defers a reply and gives up on it.
+    #[derive(Default)]
+    struct Lists {
+        lists: Mutex<HashMap<Vec<u8>, Vec<Vec<u8>>>>,
+        waiters: Mutex<HashMap<Vec<u8>, Vec<Deferred>>>,
+    }
+
+    impl Handler for Lists {
+        async fn call(&self, conn: Conn, cmd: Command) {
+            if cmd.is("BLPOP") {
+                let popped = self
+                    .lists
+                    .lock()
+                    .unwrap()
+                    .get_mut(&cmd[1])
+                    .and_then(Vec::pop);
+                match popped {
+                    Some(value) => conn
+                        .write_array(vec![
+                            Type::BulkString(cmd[1].clone()),
+                            Type::BulkString(value),
+                        ])
+                        .await
+                        .unwrap(),
+                    None => {
+                        let deferred = conn.defer();
+                        let mut waiters = self.waiters.lock().unwrap();
+                        waiters.entry(cmd[1].clone()).or_default().push(deferred);
+                    }
+                }
+            } else if cmd.is("RPUSH") {
+                let waiter = self
+                    .waiters
+                    .lock()
+                    .unwrap()
+                    .get_mut(&cmd[1])
+                    .and_then(Vec::pop);
+                match waiter {
+                    Some(deferred) => {
+                        deferred
+                            .resolve(Command::new(cmd[1..3].to_vec()))
+                            .await
+                            .unwrap();
+                        conn.write_one().await.unwrap();
+                    }
+                    None => {
+                        let len = {
+                            let mut lists = self.lists.lock().unwrap();
+                            let list = lists.entry(cmd[1].clone()).or_default();
+                            list.push(cmd[2].clone());
+                            list.len()
+                        };
+                        conn.write_integer(len as i64).await.unwrap();
+                    }
+                }
+            } else if cmd.is("DROP") {
+                drop(conn.defer());
+            } else {
+                conn.write_pong().await.unwrap();
+            }
+        }
+    }
+
+    async fn send(client: &mut BufStream<TcpStream>, args: &[&str]) -> Result<()> {
+        Type::Array(args.iter().map(|&it| Type::from(it)).collect())
+            .write(client)
+            .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn middlewares() -> Result<()> {
+        let latencies = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&latencies);
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .wrap(move |conn: Conn, cmd: Command, next: Next| {
+                let latencies = Arc::clone(&recorded);
+                async move {
+                    let name = cmd.name_escaped();
+                    let start = Instant::now();
+                    next.run(conn, cmd).await;
+                    latencies.lock().unwrap().push((name, start.elapsed()));
+                }
+            })
+            .wrap(|conn: Conn, cmd: Command, next: Next| async move {
+                if cmd.is("flushall") {
+                    let err = "ERR maintenance mode".to_string();
+                    conn.write_error(err).await.unwrap();
+                } else {
+                    next.run(conn, cmd).await;
+                }
+            })
+            .serve(|conn: Conn, _cmd: Command| async move {
+                sleep(Duration::from_millis(50)).await;
+                conn.write_pong().await.unwrap();
+            })
+            .await?;
+
+        let mut client = connect(&server).await?;
+        assert_eq!(
+            ping(&mut client).await?,
+            Type::SimpleString("PONG".to_string())
+        );
+        send(&mut client, &["FLUSHALL"]).await?;
+        client.flush().await?;
+        assert_eq!(
+            Type::read(&mut client).await?,
+            Type::Error("ERR maintenance mode".to_string())
+        );
+
+        // The replies can be flushed before the latencies are recorded.
+        while latencies.lock().unwrap().len() < 2 {
+            sleep(Duration::from_millis(10)).await;
+        }
+        let latencies = latencies.lock().unwrap();
+        let names: Vec<&str> = latencies.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["ping", "FLUSHALL"]);
+        assert!(latencies[0].1 >= Duration::from_millis(50));
+        assert!(latencies[1].1 < Duration::from_millis(50));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pause() -> Result<()> {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .serve(|conn: Conn, _cmd: Command| async move {
+                conn.write_pong().await.unwrap();
+            })
+            .await?;
+        let pong = Type::SimpleString("PONG".to_string());
+
+        let mut client = connect(&server).await?;
+        assert_eq!(ping(&mut client).await?, pong);
+        let paused = Instant::now();
+        server.pause(Duration::from_millis(200));
+        assert_eq!(ping(&mut client).await?, pong);
+        assert!(paused.elapsed() >= Duration::from_millis(200));
+
+        // New connections wait as well, until the pause is lifted.
+        server.pause(Duration::from_secs(10));
+        let mut other = connect(&server).await?;
+        let reply = tokio::spawn(async move { ping(&mut other).await });
+        sleep(Duration::from_millis(50)).await;
+        assert!(!reply.is_finished());
+        server.unpause();
+        assert_eq!(timeout(Duration::from_secs(1), reply).await???, pong);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn slowlog() -> Result<()> {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .slowlog(Duration::from_millis(20), 2)
+            .slowlog_arg_len(8)
+            .serve(|conn: Conn, cmd: Command| async move {
+                if cmd.is("sleep") {
+                    sleep(Duration::from_millis(30)).await;
+                }
+                conn.write_ok().await.unwrap();
+            })
+            .await?;
+
+        let mut client = connect(&server).await?;
+        let huge = "x".repeat(1 << 20);
+        for args in [
+            &["SLEEP", "1"][..],
+            &["GET", "fast"],
+            &["SLEEP", "2"],
+            &["SLEEP", &huge],
+        ] {
+            send(&mut client, args).await?;
+            client.flush().await?;
+            Type::read(&mut client).await?;
+        }
+        // The replies can be flushed before the commands are recorded.
+        while server.slowlog().first().map(|it| it.id) != Some(2) {
+            sleep(Duration::from_millis(10)).await;
+        }
+
+        let entries = server.slowlog();
+        let args: Vec<_> = entries.iter().map(|it| it.args.clone()).collect();
+        assert_eq!(
+            args,
+            [
+                vec![
+                    "SLEEP".to_string(),
+                    "xxxxxxxx... (1048568 more bytes)".to_string()
+                ],
+                vec!["SLEEP".to_string(), "2".to_string()],
+            ]
+        );
+        for entry in &entries {
+            assert!(entry.duration >= Duration::from_millis(30));
+            assert_eq!(entry.peer_addr, Some(client.get_ref().local_addr()?));
+        }
+
+        server.slowlog_reset();
+        assert!(server.slowlog().is_empty());
+        send(&mut client, &["SLEEP", "3"]).await?;
+        client.flush().await?;
+        Type::read(&mut client).await?;
+        while server.slowlog().is_empty() {
+            sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(server.slowlog()[0].id, 3);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn output_limit() -> Result<()> {
+        let metrics = Arc::new(AtomicMetrics::new());
+        let (disconnect_tx, mut disconnect_rx) = mpsc::unbounded_channel();
+        let (pushed_tx, pushed_rx) = oneshot::channel();
+        let pushed_tx = Mutex::new(Some(pushed_tx));
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .metrics(Arc::clone(&metrics))
+            .output_limit(OutputLimit::new(64 * 1024))
+            .on_disconnect(move |conn, reason| {
+                let _ = disconnect_tx.send(
+                    conn.is_output_limit_exceeded()
+                        && reason == DisconnectReason::OutputLimitExceeded,
+                );
+            })
+            .serve(move |conn: Conn, _cmd: Command| {
+                let pushed_tx = pushed_tx.lock().unwrap().take();
+                async move {
+                    conn.no_reply();
+                    // Publishes without waiting for each message to be sent.
+                    tokio::spawn(async move {
+                        let mut pushed = 0;
+                        while !conn.is_output_limit_exceeded() {
+                            let conn = conn.clone();
+                            tokio::spawn(async move {
+                                let _ = conn.write_bulk_string("x".repeat(1024)).await;
+                            });
+                            pushed += 1024;
+                            tokio::task::yield_now().await;
+                        }
+                        let _ = pushed_tx.unwrap().send(pushed);
+                    });
+                }
+            })
+            .await?;
+
+        // Subscribes without ever reading the messages.
+        let mut client = connect(&server).await?;
+        send(&mut client, &["SUBSCRIBE", "news"]).await?;
+        client.flush().await?;
+
+        assert!(timeout(Duration::from_secs(5), disconnect_rx.recv())
+            .await?
+            .unwrap());
+        let pushed: usize = timeout(Duration::from_secs(5), pushed_rx).await??;
+        // What the socket buffers hold, plus the limit.
+        assert!(pushed < 64 * 1024 * 1024, "pushed {} bytes", pushed);
+        assert_eq!(metrics.snapshot().output_limit_disconnects, 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn deferred_reply() -> Result<()> {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .serve(Lists::default())
+            .await?;
+
+        let mut consumer = connect(&server).await?;
+        let mut producer = connect(&server).await?;
+        send(&mut consumer, &["BLPOP", "jobs", "0"]).await?;
+        sleep(Duration::from_millis(50)).await;
+        // Pipelined behind the blocking command, so its reply has to wait.
+        send(&mut consumer, &["PING"]).await?;
+        sleep(Duration::from_millis(50)).await;
+
+        send(&mut producer, &["RPUSH", "jobs", "job:1"]).await?;
+        assert_eq!(Type::read(&mut producer).await?, Type::Integer(1));
+        assert_eq!(
+            Type::read(&mut consumer).await?,
+            Type::from(vec!["jobs", "job:1"])
+        );
+        assert_eq!(
+            Type::read(&mut consumer).await?,
+            Type::SimpleString("PONG".to_string())
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn deferred_reply_null() -> Result<()> {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .deferred_timeout(Duration::from_millis(100))
+            .serve(Lists::default())
+            .await?;
+
+        let mut client = connect(&server).await?;
+        let start = Instant::now();
+        send(&mut client, &["BLPOP", "jobs", "0"]).await?;
+        assert_eq!(Type::read(&mut client).await?, Type::Null);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        send(&mut client, &["DROP"]).await?;
+        assert_eq!(Type::read(&mut client).await?, Type::Null);
+        assert_eq!(
+            ping(&mut client).await?,
+            Type::SimpleString("PONG".to_string())
+        );
+
+        Ok(())
+    }
+
+    async fn reply_mode_server() -> Result<Server> {
+        Server::builder()
+            .bind("127.0.0.1:0")
+            .client_reply(true)
+            .serve(|conn: Conn, cmd: Command| async move {
+                if cmd.is("twice") {
+                    conn.write_bulk_string("first".to_string()).await.unwrap();
+                }
+                conn.write_bulk_bytes(&cmd.join(&b' ')).await.unwrap();
+            })
+            .await
+    }
+
+    #[tokio::test]
+    async fn client_reply_off() -> Result<()> {
+        let server = reply_mode_server().await?;
+        let mut client = connect(&server).await?;
+
+        send(&mut client, &["CLIENT", "REPLY", "OFF"]).await?;
+        send(&mut client, &["get", "a"]).await?;
+        send(&mut client, &["twice"]).await?;
+        send(&mut client, &["client", "reply", "on"]).await?;
+        assert_eq!(
+            Type::read(&mut client).await?,
+            Type::SimpleString("OK".to_string())
+        );
+        send(&mut client, &["get", "b"]).await?;
+        assert_eq!(Type::read(&mut client).await?, Type::from("get b"));
+
+        send(&mut client, &["CLIENT", "REPLY", "MAYBE"]).await?;
+        assert_eq!(
+            Type::read(&mut client).await?,
+            Type::Error("ERR syntax error".to_string())
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_reply_skip() -> Result<()> {
+        let server = reply_mode_server().await?;
+        let mut client = connect(&server).await?;
+
+        // Both replies of the skipped command are dropped.
+        send(&mut client, &["CLIENT", "REPLY", "SKIP"]).await?;
+        send(&mut client, &["twice"]).await?;
+        send(&mut client, &["get", "a"]).await?;
+        assert_eq!(Type::read(&mut client).await?, Type::from("get a"));
+
+        send(&mut client, &["CLIENT", "REPLY", "SKIP"]).await?;
+        send(&mut client, &["get", "b"]).await?;
+        send(&mut client, &["twice"]).await?;
+        assert_eq!(Type::read(&mut client).await?, Type::from("first"));
+        assert_eq!(Type::read(&mut client).await?, Type::from("twice"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_reply_on() -> Result<()> {
+        let server = reply_mode_server().await?;
+        let mut client = connect(&server).await?;
+
+        send(&mut client, &["CLIENT", "REPLY", "ON"]).await?;
+        assert_eq!(
+            Type::read(&mut client).await?,
+            Type::SimpleString("OK".to_string())
+        );
+        send(&mut client, &["get", "a"]).await?;
+        assert_eq!(Type::read(&mut client).await?, Type::from("get a"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn select_db() -> Result<()> {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .databases(16)
+            .serve(|conn: Conn, _cmd: Command| async move {
+                conn.write(conn.db() as i64).await.unwrap();
+            })
+            .await?;
+
+        let mut client = connect(&server).await?;
+        send(&mut client, &["db"]).await?;
+        assert_eq!(Type::read(&mut client).await?, Type::Integer(0));
+        send(&mut client, &["SELECT", "2"]).await?;
+        assert_eq!(
+            Type::read(&mut client).await?,
+            Type::SimpleString("OK".to_string())
+        );
+        send(&mut client, &["db"]).await?;
+        assert_eq!(Type::read(&mut client).await?, Type::Integer(2));
+        let info = server.connections();
+        assert_eq!(info[0].db, 2);
+
+        for (args, err) in [
+            (&["select", "16"][..], "ERR DB index is out of range"),
+            (&["select", "-1"], "ERR DB index is out of range"),
+            (
+                &["select", "one"],
+                "ERR value is not an integer or out of range",
+            ),
+            (
+                &["select"],
+                "ERR wrong number of arguments for 'select' command",
+            ),
+        ] {
+            send(&mut client, args).await?;
+            assert_eq!(Type::read(&mut client).await?, Type::Error(err.to_string()));
+        }
+        send(&mut client, &["db"]).await?;
+        assert_eq!(Type::read(&mut client).await?, Type::Integer(2));
+
+        let mut other = connect(&server).await?;
+        send(&mut other, &["db"]).await?;
+        assert_eq!(Type::read(&mut other).await?, Type::Integer(0));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stateful_handler() -> Result<()> {
+        struct Counter {
+            count: Mutex<i64>,
+        }
+
+        impl Handler for Counter {
+            async fn call(&self, conn: Conn, _cmd: Command) {
+                let count = {
+                    let mut count = self.count.lock().unwrap();
+                    *count += 1;
+                    *count
+                };
+                conn.write_integer(count).await.unwrap();
+            }
+        }
+
+        let counter = Arc::new(Counter {
+            count: Mutex::new(0),
+        });
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .serve(Arc::clone(&counter))
+            .await?;
+
+        let mut client = connect(&server).await?;
+        assert_eq!(ping(&mut client).await?, Type::Integer(1));
+        assert_eq!(ping(&mut client).await?, Type::Integer(2));
+        assert_eq!(*counter.count.lock().unwrap(), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn listen_on_multiple_addresses() -> Result<()> {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .bind("127.0.0.1:0")
+            .serve(|conn: Conn, _cmd: Command| async move {
+                conn.write_simple_string("pong".to_string()).await.unwrap();
+            })
+            .await?;
+
+        assert_eq!(server.local_addrs().len(), 2);
+        for addr in server.local_addrs() {
+            let mut client = BufStream::new(TcpStream::connect(addr).await?);
+            assert_eq!(
+                ping(&mut client).await?,
+                Type::SimpleString("pong".to_string())
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn bind_failure_names_the_address() -> Result<()> {
+        let taken = TcpListener::bind("127.0.0.1:0").await?;
+        let taken_addr = taken.local_addr()?.to_string();
+
+        let err = match Server::builder()
+            .bind("127.0.0.1:0")
+            .bind(taken_addr.clone())
+            .serve(|_conn: Conn, _cmd: Command| async move {})
+            .await
+        {
+            Ok(_) => panic!("expected bind to fail"),
+            Err(err) => err,
+        };
+        assert_eq!(err.to_string(), format!("could not bind {}", taken_addr));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn multiple_accept_loops() -> Result<()> {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .accept_loops(4)
+            .serve(|conn: Conn, _cmd: Command| async move {
+                conn.write_simple_string("pong".to_string()).await.unwrap();
+            })
+            .await?;
+
+        let mut clients = Vec::new();
+        for _ in 0..64 {
+            let mut client = connect(&server).await?;
+            assert_eq!(
+                ping(&mut client).await?,
+                Type::SimpleString("pong".to_string())
+            );
+            clients.push(client);
+        }
+
+        let accepted: Vec<u64> = server
+            .shared
+            .accepted
+            .iter()
+            .map(|it| it.load(Ordering::Relaxed))
+            .collect();
+        assert_eq!(accepted.len(), 4);
+        assert_eq!(accepted.iter().sum::<u64>(), 64);
+        assert!(accepted.iter().all(|&n| n > 0), "{:?}", accepted);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn small_buffers() -> Result<()> {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .read_buffer(16)
+            .write_buffer(16)
+            .serve(|conn: Conn, cmd: Command| async move {
+                conn.write_value(cmd).await.unwrap();
+            })
+            .await?;
+
+        let mut client = connect(&server).await?;
+        let cmd = Type::Array(vec![
+            Type::BulkString(b"echo".to_vec()),
+            Type::from("x".repeat(1024)),
+        ]);
+        cmd.write(&mut client).await?;
+        assert_eq!(Type::read(&mut client).await?, cmd);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn flush_on_handler_completion() -> Result<()> {
+        let (written_tx, written_rx) = oneshot::channel();
+        let written_rx = Mutex::new(Some(written_rx));
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .flush_policy(FlushPolicy::OnHandlerCompletion)
+            .serve(move |conn: Conn, _cmd: Command| {
+                let written_rx = written_rx.lock().unwrap().take();
+                async move {
+                    conn.write_integer(1).await.unwrap();
+                    conn.write_integer(2).await.unwrap();
+                    if let Some(written_rx) = written_rx {
+                        written_rx.await.unwrap();
+                    }
+                }
+            })
+            .await?;
+
+        let mut client = connect(&server).await?;
+        Type::Array(vec![Type::BulkString(b"ping".to_vec())])
+            .write(&mut client)
+            .await?;
+
+        let res = timeout(Duration::from_millis(50), Type::read(&mut client)).await;
+        assert!(
+            res.is_err(),
+            "replies are flushed before the handler finishes"
+        );
+
+        written_tx.send(()).unwrap();
+        assert_eq!(Type::read(&mut client).await?, Type::Integer(1));
+        assert_eq!(Type::read(&mut client).await?, Type::Integer(2));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn batch_pipelined_commands() -> Result<()> {
+        struct Batches {
+            sizes: Mutex<Vec<usize>>,
+        }
+
+        impl Handler for Batches {
+            async fn call(&self, conn: Conn, cmd: Command) {
+                self.call_batch(conn, vec![cmd]).await
+            }
+
+            async fn call_batch(&self, conn: Conn, cmds: Vec<Command>) {
+                self.sizes.lock().unwrap().push(cmds.len());
+                for cmd in cmds {
+                    conn.write_bulk_bytes(&cmd[1]).await.unwrap();
+                }
+            }
+        }
+
+        let batches = Arc::new(Batches {
+            sizes: Mutex::new(vec![]),
+        });
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .max_batch(16)
+            .serve(Arc::clone(&batches))
+            .await?;
+
+        let mut client = connect(&server).await?;
+        let mut pipeline = vec![];
+        for i in 0..10 {
+            Type::Array(vec![
+                Type::BulkString(b"echo".to_vec()),
+                Type::from(i.to_string()),
+            ])
+            .write(&mut pipeline)
+            .await?;
+        }
+        client.write_all(&pipeline).await?;
+        client.flush().await?;
+
+        for i in 0..10 {
+            assert_eq!(Type::read(&mut client).await?, Type::from(i.to_string()));
+        }
+        let sizes = batches.sizes.lock().unwrap();
+        assert!(sizes.len() <= 2, "{:?}", sizes);
+        assert_eq!(sizes.iter().sum::<usize>(), 10);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn drain_conn() -> Result<()> {
+        let (outcome_tx, mut outcome_rx) = mpsc::unbounded_channel();
+        let parked = Arc::new(Mutex::new(Vec::new()));
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .processing_mode(ProcessingMode::Concurrent)
+            .serve(move |conn: Conn, cmd: Command| {
+                let outcome_tx = outcome_tx.clone();
+                let parked = Arc::clone(&parked);
+                async move {
+                    if cmd.is("block") {
+                        // Never resolved, the drain replies with a null instead.
+                        parked.lock().unwrap().push(conn.defer());
+                        return;
+                    }
+                    sleep(Duration::from_millis(cmd.arg_i64(1).unwrap() as u64)).await;
+                    conn.write_bulk_bytes(&cmd[0]).await.unwrap();
+                    if cmd.is("drain") {
+                        let bye = Type::Error("ERR going away".to_string());
+                        let timeout = Duration::from_millis(cmd.arg_i64(2).unwrap() as u64);
+                        let outcome = conn.drain(Some(bye), timeout).await.unwrap();
+                        outcome_tx.send(outcome).unwrap();
+                    }
+                }
+            })
+            .await?;
+
+        let mut client = connect(&server).await?;
+        let mut pipeline = Vec::new();
+        for args in [&["block"][..], &["drain", "50", "1000"], &["slow", "150"]] {
+            Type::from(args.to_vec()).write(&mut pipeline).await?;
+        }
+        client.write_all(&pipeline).await?;
+        client.flush().await?;
+        assert_eq!(Type::read(&mut client).await?, Type::Null);
+        assert_eq!(Type::read(&mut client).await?, Type::from("drain"));
+        assert_eq!(Type::read(&mut client).await?, Type::from("slow"));
+        assert_eq!(
+            Type::read(&mut client).await?,
+            Type::Error("ERR going away".to_string())
+        );
+        assert_eq!(client.read(&mut [0; 1]).await?, 0);
+        assert_eq!(outcome_rx.recv().await, Some(DrainOutcome::Completed));
+
+        // The slow command is still running when the drain times out.
+        let mut client = connect(&server).await?;
+        let mut pipeline = Vec::new();
+        for args in [&["drain", "50", "100"][..], &["slow", "5000"]] {
+            Type::from(args.to_vec()).write(&mut pipeline).await?;
+        }
+        client.write_all(&pipeline).await?;
+        client.flush().await?;
+        assert_eq!(Type::read(&mut client).await?, Type::from("drain"));
+        assert_eq!(client.read(&mut [0; 1]).await?, 0);
+        assert_eq!(outcome_rx.recv().await, Some(DrainOutcome::TimedOut));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn drain_sequential_conn() -> Result<()> {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .processing_mode(ProcessingMode::Sequential)
+            .serve(|conn: Conn, cmd: Command| async move {
+                conn.write_bulk_bytes(&cmd[0]).await.unwrap();
+                if cmd.is("drain") {
+                    // Lets the server read the next command while this one is running.
+                    sleep(Duration::from_millis(50)).await;
+                    let bye = Type::Error("ERR going away".to_string());
+                    conn.drain(Some(bye), Duration::from_secs(1)).await.unwrap();
+                }
+            })
+            .await?;
+
+        let mut client = connect(&server).await?;
+        let mut pipeline = Vec::new();
+        for args in [&["drain"][..], &["next"], &["unread"]] {
+            Type::from(args.to_vec()).write(&mut pipeline).await?;
+        }
+        client.write_all(&pipeline).await?;
+        client.flush().await?;
+        assert_eq!(Type::read(&mut client).await?, Type::from("drain"));
+        // Read while the drain was waiting for a handler, so it runs like the ones in flight.
+        assert_eq!(Type::read(&mut client).await?, Type::from("next"));
+        assert_eq!(
+            Type::read(&mut client).await?,
+            Type::Error("ERR going away".to_string())
+        );
+        assert_eq!(client.read(&mut [0; 1]).await?, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn push_messages() -> Result<()> {
+        let subscribers = Arc::new(Mutex::new(Vec::new()));
+        let parked = Arc::new(Mutex::new(Vec::new()));
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .serve({
+                let subscribers = Arc::clone(&subscribers);
+                let parked = Arc::clone(&parked);
+                move |conn: Conn, cmd: Command| {
+                    let subscribers = Arc::clone(&subscribers);
+                    let parked = Arc::clone(&parked);
+                    async move {
+                        if cmd.is("block") {
+                            parked.lock().unwrap().push(conn.defer());
+                            return;
+                        }
+                        if cmd.is("hello") {
+                            conn.set_protocol(Protocol::Resp3);
+                        }
+                        subscribers.lock().unwrap().push(conn.clone());
+                        let confirmation = vec![Type::from("subscribe"), Type::from("news")];
+                        conn.write(Type::Push(confirmation)).await.unwrap();
+                    }
+                }
+            })
+            .await?;
+
+        let mut resp3 = connect(&server).await?;
+        send(&mut resp3, &["hello"]).await?;
+        let confirmation = Type::Push(vec![Type::from("subscribe"), Type::from("news")]);
+        assert_eq!(Type::read(&mut resp3).await?, confirmation);
+        let mut resp2 = connect(&server).await?;
+        send(&mut resp2, &["subscribe"]).await?;
+        assert_eq!(
+            Type::read(&mut resp2).await?,
+            confirmation.clone().to_resp2()
+        );
+
+        // The push doesn't wait for the deferred reply.
+        send(&mut resp3, &["block"]).await?;
+        while parked.lock().unwrap().is_empty() {
+            sleep(Duration::from_millis(1)).await;
+        }
+        let conns = subscribers.lock().unwrap().clone();
+        for conn in &conns {
+            conn.write_push(["message", "news", "hello"]).await?;
+        }
+        let message = Type::Push(vec![
+            Type::from("message"),
+            Type::from("news"),
+            Type::from("hello"),
+        ]);
+        assert_eq!(Type::read(&mut resp3).await?, message);
+        assert_eq!(Type::read(&mut resp2).await?, message.to_resp2());
+        parked.lock().unwrap().clear();
+        // Nulls are written the RESP3 way on RESP3 connections.
+        let mut line = String::new();
+        resp3.read_line(&mut line).await?;
+        assert_eq!(line, "_\r\n");
+        Ok(())
+    }
+}
@@ -0,0 +1,98 @@
+use ::tower::{BoxError, Service, ServiceExt};
+
+use crate::command::Command;
+use crate::conn::Conn;
+use crate::handler::Handler;
+
+/// Adapts a [`tower::Service`] to a [`Handler`].
+///
+/// The service is cloned for each command and driven to readiness before it is called, so
+/// middleware like `ConcurrencyLimit` applies backpressure to the command. Errors returned by
+/// the service, including the ones produced by middleware, are replied as `-ERR <error>`.
+#[derive(Clone, Debug)]
+pub struct RespService<S> {
+    inner: S,
+}
+
+impl<S> RespService<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S> Handler for RespService<S>
+where
+    S: Service<(Conn, Command), Response = ()> + Clone + Send + Sync + 'static,
+    S::Future: Send,
+    S::Error: Into<BoxError>,
+{
+    async fn call(&self, conn: Conn, cmd: Command) {
+        let mut svc = self.inner.clone();
+        let res: Result<(), BoxError> = match svc.ready().await.map_err(Into::into) {
+            Ok(svc) => svc.call((conn.clone(), cmd)).await.map_err(Into::into),
+            Err(err) => Err(err),
+        };
+
+        if let Err(err) = res {
+            if let Err(err) = conn.write_error(format!("ERR {}", err)).await {
+                eprintln!("could not write to client: {}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use anyhow::Result;
+    use tokio::io::BufStream;
+    use tokio::net::TcpStream;
+    use tokio::time::sleep;
+    use tower::{service_fn, ServiceBuilder};
+
+    use super::*;
+    use crate::resp::Type;
+    use crate::server::Server;
+
+    #[tokio::test]
+    async fn timeout_layer_rejection_is_replied_as_error() -> Result<()> {
+        let svc = ServiceBuilder::new()
+            .timeout(Duration::from_millis(50))
+            .service(service_fn(|(conn, cmd): (Conn, Command)| async move {
+                if cmd.is("slow") {
+                    sleep(Duration::from_secs(1)).await;
+                }
+                conn.write_simple_string("ok".to_string()).await?;
+                Ok::<_, BoxError>(())
+            }));
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .serve(RespService::new(svc))
+            .await?;
+
+        let mut client = BufStream::new(TcpStream::connect(server.local_addr()).await?);
+
+        Type::Array(vec![Type::BulkString(b"slow".to_vec())])
+            .write(&mut client)
+            .await?;
+        assert_eq!(
+            Type::read(&mut client).await?,
+            Type::Error("ERR request timed out".to_string())
+        );
+
+        Type::Array(vec![Type::BulkString(b"fast".to_vec())])
+            .write(&mut client)
+            .await?;
+        assert_eq!(
+            Type::read(&mut client).await?,
+            Type::SimpleString("ok".to_string())
+        );
+
+        Ok(())
+    }
+}
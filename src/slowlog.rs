@@ -0,0 +1,135 @@
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use crate::command::Command;
+use crate::conn::{Conn, ConnId};
+
+/// Arguments past this many are replaced with a single `... (N more arguments)`, like in Redis.
+const MAX_ARGS: usize = 32;
+
+/// A command that took longer than the threshold, see [`Builder::slowlog`].
+///
+/// [`Builder::slowlog`]: crate::server::Builder::slowlog
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SlowlogEntry {
+    /// Increases with each entry, and keeps increasing after a reset.
+    pub id: u64,
+    /// When the handler finished.
+    pub timestamp: SystemTime,
+    /// Time spent in the handler. Commands handled in a batch share the duration of the batch.
+    pub duration: Duration,
+    /// The command name and arguments, truncated.
+    pub args: Vec<String>,
+    pub conn: ConnId,
+    pub peer_addr: Option<SocketAddr>,
+}
+
+/// Keeps the latest slow commands.
+pub(crate) struct Slowlog {
+    threshold: Duration,
+    max_len: usize,
+    arg_len: usize,
+    next_id: AtomicU64,
+    /// Newest first.
+    entries: Mutex<VecDeque<SlowlogEntry>>,
+}
+
+impl Slowlog {
+    pub(crate) fn new(threshold: Duration, max_len: usize, arg_len: usize) -> Self {
+        Self {
+            threshold,
+            max_len,
+            arg_len,
+            next_id: AtomicU64::new(0),
+            entries: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Copies the arguments that would be recorded, as the command is gone once handled.
+    ///
+    /// Only the kept part of each argument is copied, so huge arguments cost no more than
+    /// small ones.
+    pub(crate) fn args(&self, cmd: &Command) -> Vec<String> {
+        let mut args: Vec<String> = cmd
+            .iter()
+            .take(MAX_ARGS)
+            .map(|arg| truncate(arg, self.arg_len))
+            .collect();
+        if cmd.len() > MAX_ARGS {
+            args.pop();
+            args.push(format!("... ({} more arguments)", cmd.len() - MAX_ARGS + 1));
+        }
+        args
+    }
+
+    pub(crate) fn record(&self, conn: &Conn, duration: Duration, args: Vec<String>) {
+        if duration < self.threshold || self.max_len == 0 {
+            return;
+        }
+        let entry = SlowlogEntry {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            timestamp: SystemTime::now(),
+            duration,
+            args,
+            conn: conn.id(),
+            peer_addr: conn.peer_addr(),
+        };
+        let mut entries = self.entries.lock().unwrap();
+        entries.truncate(self.max_len - 1);
+        entries.push_front(entry);
+    }
+
+    pub(crate) fn entries(&self) -> Vec<SlowlogEntry> {
+        self.entries.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub(crate) fn reset(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
+
+/// Keeps the first `len` bytes of the argument, replacing the bytes that aren't UTF-8.
+fn truncate(arg: &[u8], len: usize) -> String {
+    if arg.len() <= len {
+        return String::from_utf8_lossy(arg).into_owned();
+    }
+    // Doesn't split a character, a UTF-8 character has at most 3 continuation bytes.
+    let mut end = len;
+    while end > len.saturating_sub(3) && end > 0 && arg[end] & 0xc0 == 0x80 {
+        end -= 1;
+    }
+    format!(
+        "{}... ({} more bytes)",
+        String::from_utf8_lossy(&arg[..end]),
+        arg.len() - end
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncated_args() {
+        let slowlog = Slowlog::new(Duration::ZERO, 10, 4);
+        let cmd: Command = vec!["SET".to_string(), "key".to_string(), "vääl".to_string()]
+            .into_iter()
+            .collect();
+        assert_eq!(slowlog.args(&cmd), ["SET", "key", "vä... (3 more bytes)"]);
+
+        let cmd: Command = (0..40).map(|i| i.to_string()).collect();
+        let args = slowlog.args(&cmd);
+        assert_eq!(args.len(), MAX_ARGS);
+        assert_eq!(args[30], "30");
+        assert_eq!(args[31], "... (9 more arguments)");
+
+        let cmd = Command::new(vec![b"GET".to_vec(), vec![0xff, 0xfe, 0xfd, 0xfc, 0x80]]);
+        assert_eq!(
+            slowlog.args(&cmd),
+            ["GET", "\u{fffd}\u{fffd}\u{fffd}... (2 more bytes)"]
+        );
+    }
+}
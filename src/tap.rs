@@ -0,0 +1,124 @@
+use std::fmt::Write;
+use std::sync::Arc;
+
+use crate::conn::ConnId;
+
+/// Whether bytes passed to a tap were read from or written to a connection, see
+/// [`Builder::tap`](crate::server::Builder::tap).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// Read from the client.
+    In,
+    /// Written to the client.
+    Out,
+}
+
+pub(crate) type TapFn = dyn Fn(ConnId, Direction, &[u8]) + Send + Sync;
+
+/// The tap of a connection.
+#[derive(Clone)]
+pub(crate) struct Tap {
+    conn: ConnId,
+    f: Arc<TapFn>,
+}
+
+impl Tap {
+    pub(crate) fn new(conn: ConnId, f: Arc<TapFn>) -> Self {
+        Self { conn, f }
+    }
+
+    pub(crate) fn read(&self, bytes: &[u8]) {
+        (self.f)(self.conn, Direction::In, bytes)
+    }
+
+    pub(crate) fn written(&self, bytes: &[u8]) {
+        (self.f)(self.conn, Direction::Out, bytes)
+    }
+}
+
+const HEXDUMP_WIDTH: usize = 16;
+
+/// Returns a tap printing a hexdump of at most `max_len` bytes at a time to stderr.
+///
+/// The hexdump is printed whatever the log level, the tap is meant to be set only while
+/// debugging. With the `tracing` feature it is logged with `tracing::debug!` instead, so it
+/// is only formatted and shown when DEBUG is enabled.
+///
+/// ```no_run
+/// # async fn run() -> anyhow::Result<()> {
+/// use redcon::{hexdump_tap, Command, Conn, Server};
+///
+/// Server::builder()
+///     .bind("127.0.0.1:6379")
+///     .tap(hexdump_tap(256))
+///     .run(|conn: Conn, _cmd: Command| async move {
+///         conn.write_pong().await.unwrap();
+///     })
+///     .await
+/// # }
+/// ```
+pub fn hexdump_tap(max_len: usize) -> impl Fn(ConnId, Direction, &[u8]) + Send + Sync + 'static {
+    move |conn, direction, bytes| {
+        #[cfg(feature = "tracing")]
+        if tracing::enabled!(tracing::Level::DEBUG) {
+            tracing::debug!("{}", hexdump(conn, direction, bytes, max_len).trim_end());
+        }
+        #[cfg(not(feature = "tracing"))]
+        eprint!("{}", hexdump(conn, direction, bytes, max_len));
+    }
+}
+
+fn hexdump(conn: ConnId, direction: Direction, bytes: &[u8], max_len: usize) -> String {
+    let arrow = match direction {
+        Direction::In => "<-",
+        Direction::Out => "->",
+    };
+    let mut out = format!("connection {} {} {} bytes\n", conn, arrow, bytes.len());
+    let shown = &bytes[..bytes.len().min(max_len)];
+    for (i, line) in shown.chunks(HEXDUMP_WIDTH).enumerate() {
+        let _ = write!(out, "{:08x} ", i * HEXDUMP_WIDTH);
+        for j in 0..HEXDUMP_WIDTH {
+            match line.get(j) {
+                Some(b) => {
+                    let _ = write!(out, " {:02x}", b);
+                }
+                None => out.push_str("   "),
+            }
+        }
+        out.push_str("  |");
+        out.extend(line.iter().map(|&b| {
+            if b.is_ascii_graphic() || b == b' ' {
+                b as char
+            } else {
+                '.'
+            }
+        }));
+        out.push_str("|\n");
+    }
+    if shown.len() < bytes.len() {
+        let _ = writeln!(out, "... ({} more bytes)", bytes.len() - shown.len());
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hexdump_is_bounded() {
+        let conn = ConnId::next();
+        assert_eq!(
+            hexdump(conn, Direction::In, b"*1\r\n$4\r\nping\r\n", 64),
+            format!(
+                "connection {} <- 14 bytes\n\
+                 00000000  2a 31 0d 0a 24 34 0d 0a 70 69 6e 67 0d 0a        |*1..$4..ping..|\n",
+                conn
+            )
+        );
+
+        let dump = hexdump(conn, Direction::Out, &[b'x'; 1024 * 1024], 20);
+        assert_eq!(dump.lines().count(), 4);
+        assert!(dump.ends_with("... (1048556 more bytes)\n"));
+    }
+}
@@ -0,0 +1,145 @@
+//! Runs the `kv` binary and talks to it over TCP.
+
+use std::net::TcpListener;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use anyhow::Result;
+use redcon::Type;
+use tokio::io::BufStream;
+use tokio::net::TcpStream;
+use tokio::time::sleep;
+
+/// Kills the server once dropped.
+struct KvServer(Child);
+
+impl Drop for KvServer {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+async fn start() -> Result<(KvServer, BufStream<TcpStream>)> {
+    let addr = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    let server = KvServer(
+        Command::new(env!("CARGO_BIN_EXE_kv"))
+            .arg(addr.to_string())
+            .stdout(Stdio::null())
+            .spawn()?,
+    );
+    for _ in 0..100 {
+        if let Ok(client) = TcpStream::connect(addr).await {
+            return Ok((server, BufStream::new(client)));
+        }
+        sleep(Duration::from_millis(20)).await;
+    }
+    anyhow::bail!("kv server didn't start listening on {}", addr)
+}
+
+async fn send(client: &mut BufStream<TcpStream>, args: &[&str]) -> Result<Type> {
+    Type::Array(args.iter().map(|&it| Type::from(it)).collect())
+        .write(&mut *client)
+        .await?;
+    Ok(Type::read(client).await?)
+}
+
+fn ok() -> Type {
+    Type::SimpleString("OK".to_string())
+}
+
+#[tokio::test]
+async fn string_commands() -> Result<()> {
+    let (_server, mut client) = start().await?;
+
+    assert_eq!(
+        send(&mut client, &["PING"]).await?,
+        Type::SimpleString("PONG".to_string())
+    );
+    assert_eq!(send(&mut client, &["ping", "hi"]).await?, Type::from("hi"));
+
+    assert_eq!(send(&mut client, &["GET", "user:1"]).await?, Type::Null);
+    assert_eq!(send(&mut client, &["SET", "user:1", "alice"]).await?, ok());
+    assert_eq!(send(&mut client, &["set", "user:2", "bob"]).await?, ok());
+    assert_eq!(send(&mut client, &["SET", "team:1", "red"]).await?, ok());
+    assert_eq!(
+        send(&mut client, &["GET", "user:1"]).await?,
+        Type::from("alice")
+    );
+
+    assert_eq!(
+        send(&mut client, &["EXISTS", "user:1", "user:3", "team:1"]).await?,
+        Type::Integer(2)
+    );
+    assert_eq!(
+        send(&mut client, &["KEYS", "user:*"]).await?,
+        Type::from(vec!["user:1", "user:2"])
+    );
+    assert_eq!(
+        send(&mut client, &["KEYS", "?eam:?"]).await?,
+        Type::from(vec!["team:1"])
+    );
+    assert_eq!(
+        send(&mut client, &["DEL", "user:1", "user:3"]).await?,
+        Type::Integer(1)
+    );
+    assert_eq!(send(&mut client, &["GET", "user:1"]).await?, Type::Null);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn expiry() -> Result<()> {
+    let (_server, mut client) = start().await?;
+
+    assert_eq!(
+        send(&mut client, &["SET", "session", "token", "EX", "1"]).await?,
+        ok()
+    );
+    assert_eq!(
+        send(&mut client, &["GET", "session"]).await?,
+        Type::from("token")
+    );
+    sleep(Duration::from_millis(1100)).await;
+    assert_eq!(send(&mut client, &["GET", "session"]).await?, Type::Null);
+    assert_eq!(
+        send(&mut client, &["EXISTS", "session"]).await?,
+        Type::Integer(0)
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn errors() -> Result<()> {
+    let (_server, mut client) = start().await?;
+
+    for (args, err) in [
+        (
+            &["GET"][..],
+            "ERR wrong number of arguments for 'get' command",
+        ),
+        (
+            &["SET", "key"],
+            "ERR wrong number of arguments for 'set' command",
+        ),
+        (&["SET", "key", "value", "PX", "10"], "ERR syntax error"),
+        (
+            &["SET", "key", "value", "EX", "soon"],
+            "ERR value is not an integer or out of range",
+        ),
+        (
+            &["SET", "key", "value", "EX", "0"],
+            "ERR invalid expire time in 'set' command",
+        ),
+        (
+            &["SET", "key", "value", "EX", "9223372036854775807"],
+            "ERR invalid expire time in 'set' command",
+        ),
+        (&["INCR", "key"], "ERR unknown command 'INCR'"),
+    ] {
+        assert_eq!(send(&mut client, args).await?, Type::Error(err.to_string()));
+    }
+
+    Ok(())
+}
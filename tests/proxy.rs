@@ -0,0 +1,136 @@
+//! Talks to a redcon server through a redcon proxy.
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use redcon::proxy::{self, Client, Disconnected};
+use redcon::{Command, Conn, Protocol, Server, Type};
+use tokio::io::{AsyncReadExt, BufStream};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+
+async fn upstream() -> Result<Server> {
+    Server::builder()
+        .bind("127.0.0.1:0")
+        .serve(|conn: Conn, cmd: Command| async move {
+            let reply = match cmd.name_uppercase() {
+                b"PING" => Type::SimpleString("PONG".to_string()),
+                b"NESTED" => Type::Array(vec![
+                    Type::Integer(1),
+                    Type::Array(vec![Type::from("a"), Type::Null]),
+                    Type::BulkString(b"\xff\r\n".to_vec()),
+                ]),
+                b"NOTIFY" => {
+                    conn.set_protocol(Protocol::Resp3);
+                    conn.write_push(vec!["invalidate", "key"]).await.unwrap();
+                    conn.write_push(vec!["message", "channel", "hi"])
+                        .await
+                        .unwrap();
+                    Type::SimpleString("OK".to_string())
+                }
+                _ => Type::Error(format!("ERR unknown command '{}'", cmd.name_escaped())),
+            };
+            conn.write_value(reply).await.unwrap();
+        })
+        .await
+}
+
+async fn proxy(upstream: &Server) -> Result<Server> {
+    let client = Arc::new(Mutex::new(Client::connect(upstream.local_addr()).await?));
+    Server::builder()
+        .bind("127.0.0.1:0")
+        .serve(move |conn: Conn, cmd: Command| {
+            let client = Arc::clone(&client);
+            async move {
+                let mut client = client.lock().await;
+                if let Err(err) = proxy::forward(&conn, &mut client, cmd.into()).await {
+                    assert!(err.is::<Disconnected>(), "{:?}", err);
+                    conn.write_error(format!("ERR {}", err)).await.unwrap();
+                }
+            }
+        })
+        .await
+}
+
+async fn send(client: &mut BufStream<TcpStream>, args: &[&str]) -> Result<()> {
+    Type::Array(args.iter().map(|&it| Type::from(it)).collect())
+        .write(&mut *client)
+        .await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn forwards_replies_verbatim() -> Result<()> {
+    let upstream = upstream().await?;
+    let proxy = proxy(&upstream).await?;
+    let mut client = BufStream::new(TcpStream::connect(proxy.local_addr()).await?);
+
+    send(&mut client, &["ping"]).await?;
+    assert_eq!(
+        Type::read(&mut client).await?,
+        Type::SimpleString("PONG".to_string())
+    );
+
+    send(&mut client, &["nested"]).await?;
+    let expected: &[u8] = b"*3\r\n:1\r\n*2\r\n$1\r\na\r\n$-1\r\n$3\r\n\xff\r\n\r\n";
+    let mut buf = vec![0; expected.len()];
+    client.read_exact(&mut buf).await?;
+    assert_eq!(buf, expected);
+
+    send(&mut client, &["flushall"]).await?;
+    assert_eq!(
+        Type::read(&mut client).await?,
+        Type::Error("ERR unknown command 'flushall'".to_string())
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn passes_pushes_through() -> Result<()> {
+    let upstream = upstream().await?;
+    let proxy = proxy(&upstream).await?;
+    let mut client = BufStream::new(TcpStream::connect(proxy.local_addr()).await?);
+
+    send(&mut client, &["notify"]).await?;
+    // Written as arrays to the RESP2 client.
+    assert_eq!(
+        Type::read(&mut client).await?,
+        Type::from(vec!["invalidate", "key"])
+    );
+    assert_eq!(
+        Type::read(&mut client).await?,
+        Type::from(vec!["message", "channel", "hi"])
+    );
+    assert_eq!(
+        Type::read(&mut client).await?,
+        Type::SimpleString("OK".to_string())
+    );
+
+    send(&mut client, &["ping"]).await?;
+    assert_eq!(
+        Type::read(&mut client).await?,
+        Type::SimpleString("PONG".to_string())
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn reports_upstream_disconnects() -> Result<()> {
+    let upstream = upstream().await?;
+    let proxy = proxy(&upstream).await?;
+    let mut client = BufStream::new(TcpStream::connect(proxy.local_addr()).await?);
+
+    send(&mut client, &["ping"]).await?;
+    Type::read(&mut client).await?;
+
+    upstream.shutdown().await;
+    send(&mut client, &["ping"]).await?;
+    assert_eq!(
+        Type::read(&mut client).await?,
+        Type::Error("ERR upstream disconnected".to_string())
+    );
+
+    Ok(())
+}
@@ -0,0 +1,53 @@
+//! Counts the allocations made while parsing pipelined commands.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use redcon::{RespReader, Type};
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+const FRAMES: usize = 1000;
+
+#[test]
+fn reading_pipelined_commands() {
+    let input = b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n".repeat(FRAMES);
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+
+    let allocations = runtime.block_on(async {
+        let mut reader = RespReader::new(input.as_slice());
+        let before = ALLOCATIONS.load(Ordering::Relaxed);
+        for _ in 0..FRAMES {
+            let ty = reader.read().await.unwrap();
+            assert!(matches!(ty, Type::Array(_)));
+        }
+        ALLOCATIONS.load(Ordering::Relaxed) - before
+    });
+
+    // Each frame needs its array and two strings, plus a boxed future per value read. Lines
+    // are read into a reused buffer, allocating for them used to take this to 14 per frame.
+    assert!(
+        allocations < FRAMES * 7,
+        "{} allocations for {} frames",
+        allocations,
+        FRAMES
+    );
+}